@@ -0,0 +1,1265 @@
+// Requests against the Jellyfin API. With `--record-api <dir>` every response is also saved as
+// a JSON file keyed by the request, and `--replay-api <dir>` answers requests from those files
+// without any network access, so a run can be reproduced from someone else's recording.
+// Only the URL, status and body are saved, request headers (and so the API tokens) never are.
+// Logins with a username/password are never recorded since the response contains a token.
+// Recordings carry a format version, see formats.rs.
+// Every request gets a short ID, sent as X-Request-Id so it can be found in the server's logs and
+// shown in --http-debug lines and error messages (and so in retry messages and failure lists).
+// Requests failing in a way that may pass (connection errors, timeouts, 5xx) are retried up to
+// the instance's max_retries times with exponential backoff, 4xx answers are returned right away.
+use crate::display::{truncate_display, MAX_ERROR_BODY_CHARS};
+use crate::error::MigrationError;
+use crate::formats::{self, API_RECORDING};
+use crate::rng::SplitMix64;
+use crate::timefmt;
+use crate::{build_auth_headers, InstanceConfig};
+use flate2::read::MultiGzDecoder;
+use indicatif::ProgressBar;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE, DATE};
+use reqwest::{Client, Response, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::future::Future;
+use std::io::{self, BufReader, Cursor, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tracing::info;
+
+// How often a starting instance is retried within its startup_grace_seconds
+const STARTUP_RETRY_INTERVAL: Duration = Duration::from_secs(3);
+// The backoff stops doubling after this many retries
+const MAX_BACKOFF_DOUBLINGS: u32 = 10;
+// Chunks of a streamed body received but not yet parsed
+const STREAM_CHUNKS_IN_FLIGHT: usize = 16;
+// Bodies gzipped more often than this (by a chain of misconfigured proxies) are left as they are
+const MAX_GZIP_LAYERS: usize = 3;
+// How much of a body that isn't JSON is shown as hex
+const HEX_PREVIEW_BYTES: usize = 16;
+// Seed of the request IDs when replaying, so a replayed run shows the same IDs every time
+const REPLAY_REQUEST_ID_SEED: u64 = 0;
+const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+pub enum ApiRecording {
+    Off,
+    Record(PathBuf),
+    Replay(PathBuf),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RecordedResponse {
+    #[serde(default)] // Checked before deserializing, see read_recording
+    version: u32,
+    method: String,
+    url: String,
+    status: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    content_type: Option<String>,
+    // Bodies that are valid JSON are kept as JSON so recordings are easy to read and edit
+    #[serde(skip_serializing_if = "Option::is_none")]
+    body: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    body_text: Option<String>,
+}
+
+impl RecordedResponse {
+    fn body_text(&self) -> String {
+        match (&self.body, &self.body_text) {
+            (Some(body), _) => body.to_string(),
+            (None, Some(text)) => text.clone(),
+            (None, None) => String::new(),
+        }
+    }
+}
+
+// Replaying a request that isn't in the recording, always fatal since the run can't be reproduced
+pub struct MissingRecording(String);
+
+// Shown like the plain string errors when main returns it
+impl fmt::Debug for MissingRecording {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.0)
+    }
+}
+
+impl fmt::Display for MissingRecording {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Error for MissingRecording {}
+
+// The part of the /Users/AuthenticateByName response that is used
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct AuthenticationResult {
+    access_token: String,
+}
+
+struct ApiResponse {
+    status: StatusCode,
+    content_type: Option<String>,
+    redirected_to: Option<String>, // Final URL when the request was redirected
+    date: Option<String>,          // The Date header, for clock.rs
+    text: String,
+}
+
+impl ApiResponse {
+    fn is_html(&self) -> bool {
+        is_html(self.content_type.as_deref())
+    }
+}
+
+fn is_html(content_type: Option<&str>) -> bool {
+    content_type.is_some_and(|content_type| content_type.trim_start().starts_with("text/html"))
+}
+
+fn content_type(response: &Response) -> Option<&str> {
+    response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+}
+
+// Reads the whole body of a live response. Also returns what decode_body undid.
+async fn read_response(
+    url: &str,
+    response: Response,
+) -> Result<(ApiResponse, Vec<&'static str>), Box<dyn Error + Send + Sync>> {
+    let status = response.status(); // Store status before consuming response
+    let content_type = content_type(&response).map(str::to_string);
+    let date = response
+        .headers()
+        .get(DATE)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    // Redirects are followed, e.g. to a reverse proxy's login page
+    let redirected_to = (response.url().as_str() != url).then(|| response.url().to_string());
+    let (text, undone) = decode_body(&response.bytes().await?)
+        .map_err(|e| format!("Unreadable response from {}: {}", url, e))?;
+    let response = ApiResponse {
+        status,
+        content_type,
+        redirected_to,
+        date,
+        text,
+    };
+    Ok((response, undone))
+}
+
+// Undoes what misconfigured reverse proxies do to bodies: gzip (with or without, or even
+// despite, a Content-Encoding header, since reqwest doesn't decode it) and a UTF-8 BOM in front of
+// the JSON. Returns the text and what was undone, for --http-debug.
+fn decode_body(bytes: &[u8]) -> Result<(String, Vec<&'static str>), String> {
+    let mut body = bytes.to_vec();
+    let mut undone = Vec::new();
+    for _ in 0..MAX_GZIP_LAYERS {
+        if !body.starts_with(&[0x1f, 0x8b]) {
+            break;
+        }
+        let mut decompressed = Vec::new();
+        MultiGzDecoder::new(body.as_slice())
+            .read_to_end(&mut decompressed)
+            .map_err(|e| format!("the body looks gzipped but can't be decompressed: {}", e))?;
+        body = decompressed;
+        undone.push("gzip");
+    }
+    if let Some(rest) = body.strip_prefix(b"\xEF\xBB\xBF") {
+        body = rest.to_vec();
+        undone.push("UTF-8 BOM");
+    }
+    let text = match String::from_utf8(body) {
+        Ok(text) => text,
+        Err(e) => String::from_utf8_lossy(e.as_bytes()).into_owned(),
+    };
+    Ok((text, undone))
+}
+
+// decode_body for a body that is read while it arrives
+fn decode_reader(
+    mut reader: Box<dyn Read + Send>,
+) -> io::Result<(Box<dyn Read + Send>, Vec<&'static str>)> {
+    let mut undone = Vec::new();
+    for _ in 0..MAX_GZIP_LAYERS {
+        let start;
+        (start, reader) = peek(reader, 2)?;
+        if start != [0x1f, 0x8b] {
+            break;
+        }
+        reader = Box::new(MultiGzDecoder::new(reader));
+        undone.push("gzip");
+    }
+    let start;
+    (start, reader) = peek(reader, 3)?;
+    if start == b"\xEF\xBB\xBF" {
+        reader.read_exact(&mut [0; 3])?;
+        undone.push("UTF-8 BOM");
+    }
+    Ok((reader, undone))
+}
+
+// The first `count` bytes (fewer at the end of the body) and a reader that still starts with them
+fn peek(
+    mut reader: Box<dyn Read + Send>,
+    count: usize,
+) -> io::Result<(Vec<u8>, Box<dyn Read + Send>)> {
+    let mut start = Vec::with_capacity(count);
+    (&mut reader).take(count as u64).read_to_end(&mut start)?;
+    Ok((start.clone(), Box::new(Cursor::new(start).chain(reader))))
+}
+
+// The body of a streamed response as a plain reader, fed chunk by chunk by post_json_streamed
+struct ChunkReader {
+    chunks: mpsc::Receiver<Result<Vec<u8>, String>>,
+    chunk: Vec<u8>,
+    position: usize,
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.chunk.len() {
+            match self.chunks.blocking_recv() {
+                Some(Ok(chunk)) => {
+                    self.chunk = chunk;
+                    self.position = 0;
+                }
+                Some(Err(e)) => return Err(io::Error::other(e)),
+                None => return Ok(0), // The whole body was read
+            }
+        }
+        let count = buf.len().min(self.chunk.len() - self.position);
+        buf[..count].copy_from_slice(&self.chunk[self.position..self.position + count]);
+        self.position += count;
+        Ok(count)
+    }
+}
+
+// e.g. "1f 8b 08 00", for bodies that still can't be parsed
+fn hex_preview(text: &str) -> String {
+    text.bytes()
+        .take(HEX_PREVIEW_BYTES)
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// An API endpoint answering with an HTML page is almost always a reverse proxy (login page,
+// wrong sub-path) rather than Jellyfin, so say that instead of showing a JSON parse error
+fn html_response_error(url: &str, response: &ApiResponse) -> String {
+    format!(
+        "{} returned an HTML page instead of JSON{}. This usually means a reverse proxy in front of Jellyfin wants its own login, or base_url is missing the sub-path Jellyfin is served under (e.g. https://example.com/jellyfin). Make sure the proxy lets API requests through to that path without its own authentication.",
+        url,
+        match response.redirected_to {
+            Some(ref final_url) => format!(" (after a redirect to {})", final_url),
+            None => String::new(),
+        }
+    )
+}
+
+pub struct ApiClient {
+    client: Client,
+    recording: ApiRecording,
+    http_debug: bool,
+    request_ids: Mutex<SplitMix64>,
+    jitter: Mutex<SplitMix64>,
+}
+
+// --http-debug: e.g. "HTTP GET http://host/Users [req a1b2c3] (headers: authorization,
+// x-emby-token, x-request-id) -> 200 OK". Only header names are printed so tokens never end up in
+// logs.
+fn print_http_debug(method: &str, url: &str, request_id: &str, headers: &HeaderMap, outcome: &str) {
+    let names: Vec<&str> = headers.keys().map(|name| name.as_str()).collect();
+    info!(
+        "HTTP {} {} [req {}] (headers: {}) -> {}",
+        method,
+        url,
+        request_id,
+        if names.is_empty() {
+            "none".to_string()
+        } else {
+            names.join(", ")
+        },
+        outcome
+    );
+}
+
+// e.g. "GET http://host:8096/Users" -> "GET_http___host_8096_Users.json". Requests with a body
+// (POSTed queries) also get a hash of it, since they differ only in the body.
+fn recording_file_name(method: &str, url: &str, body: Option<&serde_json::Value>) -> String {
+    let key: String = format!("{}_{}", method, url)
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    match body {
+        Some(body) => format!("{}_{:016x}.json", key, fnv1a(body.to_string().as_bytes())),
+        None => format!("{}.json", key),
+    }
+}
+
+// Stable across builds and platforms (unlike std's hasher) so recordings stay replayable
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+// Sends the request, and while the instance looks like it is still starting (connection refused,
+// 502 or 503) keeps retrying until its startup_grace_seconds have passed. After that the last
+// response or error is returned as usual.
+async fn send_with_startup_grace<F, Fut>(
+    instance_config: &InstanceConfig,
+    url: &str,
+    send: F,
+) -> Result<Response, reqwest::Error>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<Response, reqwest::Error>>,
+{
+    let grace = Duration::from_secs(instance_config.startup_grace_seconds);
+    let start = Instant::now();
+    let mut spinner: Option<ProgressBar> = None;
+    loop {
+        let result = send().await;
+        let starting_up = match &result {
+            Ok(response) => matches!(
+                response.status(),
+                StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE
+            ),
+            Err(e) => e.is_connect(),
+        };
+        let elapsed = start.elapsed();
+        if !starting_up || elapsed >= grace {
+            if let Some(spinner) = spinner {
+                spinner.finish_and_clear();
+                if !starting_up {
+                    info!(
+                        "{} is up after {}s.",
+                        instance_config.base_url,
+                        elapsed.as_secs()
+                    );
+                }
+            }
+            return result;
+        }
+        let spinner = spinner.get_or_insert_with(|| {
+            let spinner = ProgressBar::new_spinner();
+            spinner.enable_steady_tick(Duration::from_millis(100));
+            spinner
+        });
+        spinner.set_message(format!(
+            "Waiting for {} to start ({}): {}s of {}s",
+            url,
+            match result {
+                Ok(response) => response.status().to_string(),
+                Err(_) => "connection refused".to_string(),
+            },
+            elapsed.as_secs(),
+            grace.as_secs()
+        ));
+        tokio::time::sleep(STARTUP_RETRY_INTERVAL.min(grace - elapsed)).await;
+    }
+}
+
+// The HTTP client of a run. `request_timeout_secs` (0 for none) bounds every request from
+// connecting until the whole body has arrived.
+pub fn build_client(request_timeout_secs: u64) -> Result<Client, Box<dyn Error + Send + Sync>> {
+    let mut builder = Client::builder();
+    if request_timeout_secs > 0 {
+        builder = builder.timeout(Duration::from_secs(request_timeout_secs));
+    }
+    Ok(builder.build()?)
+}
+
+impl ApiClient {
+    pub fn new(
+        client: Client,
+        recording: ApiRecording,
+    ) -> Result<ApiClient, Box<dyn Error + Send + Sync>> {
+        match &recording {
+            ApiRecording::Record(dir) => {
+                fs::create_dir_all(dir)?;
+                info!("Recording API responses to: {}", dir.display());
+            }
+            ApiRecording::Replay(dir) => {
+                if !dir.is_dir() {
+                    return Err(format!(
+                        "API recording directory '{}' does not exist",
+                        dir.display()
+                    )
+                    .into());
+                }
+                info!(
+                    "Replaying API responses from: {} (no network access)",
+                    dir.display()
+                );
+            }
+            ApiRecording::Off => {}
+        }
+        // Fixed with --stable-timestamps too, since the seed is the current time
+        let seed = match recording {
+            ApiRecording::Replay(_) => REPLAY_REQUEST_ID_SEED,
+            _ => timefmt::now().timestamp_nanos_opt().unwrap_or_default() as u64,
+        };
+        Ok(ApiClient {
+            client,
+            recording,
+            http_debug: false,
+            request_ids: Mutex::new(SplitMix64::new(seed)),
+            jitter: Mutex::new(SplitMix64::new(seed.rotate_left(32))),
+        })
+    }
+
+    pub fn is_replaying(&self) -> bool {
+        matches!(self.recording, ApiRecording::Replay(_))
+    }
+
+    pub fn with_http_debug(mut self, http_debug: bool) -> ApiClient {
+        self.http_debug = http_debug;
+        self
+    }
+
+    // e.g. "a1b2c3": short enough for log lines, unique enough within a run
+    fn next_request_id(&self) -> String {
+        let mut ids = self.request_ids.lock().unwrap_or_else(|e| e.into_inner());
+        format!("{:06x}", ids.next_u64() >> 40)
+    }
+
+    // base_backoff_ms doubled for every retry after the first, then scaled by a random 50-150%
+    // so clients retrying against the same server spread out
+    fn backoff(&self, instance_config: &InstanceConfig, retry: u32) -> Duration {
+        let base = Duration::from_millis(instance_config.base_backoff_ms)
+            * 2u32.pow((retry - 1).min(MAX_BACKOFF_DOUBLINGS));
+        let mut jitter = self.jitter.lock().unwrap_or_else(|e| e.into_inner());
+        base.mul_f64(0.5 + jitter.next_f64())
+    }
+
+    // Sends the request (through send_with_startup_grace the first time when `at_startup`) and
+    // retries connection errors, timeouts and 5xx answers up to max_retries times. When they are
+    // used up the last response or error is returned as usual.
+    async fn send_with_retries<F, Fut>(
+        &self,
+        instance_config: &InstanceConfig,
+        url: &str,
+        request_id: &str,
+        at_startup: bool,
+        send: F,
+    ) -> Result<Response, reqwest::Error>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<Response, reqwest::Error>>,
+    {
+        let mut retry = 0;
+        loop {
+            let result = if at_startup && retry == 0 {
+                send_with_startup_grace(instance_config, url, &send).await
+            } else {
+                send().await
+            };
+            let reason = match &result {
+                Ok(response) if response.status().is_server_error() => {
+                    response.status().to_string()
+                }
+                // The URL is in the message already, the full error is shown if it's the last one
+                Err(e) if e.is_timeout() => "timed out".to_string(),
+                Err(e) if e.is_connect() => "couldn't connect".to_string(),
+                Err(e) if e.is_request() => "connection error".to_string(),
+                _ => return result,
+            };
+            if retry >= instance_config.max_retries {
+                return result;
+            }
+            retry += 1;
+            let delay = self.backoff(instance_config, retry);
+            info!(
+                "Request to {} failed ({}) [req {}], retrying in {:.1}s (retry {} of {})",
+                url,
+                reason,
+                request_id,
+                delay.as_secs_f64(),
+                retry,
+                instance_config.max_retries
+            );
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    // Returns the status and body of GET <url> (or POST <url> with a JSON body), from the
+    // recording when replaying. `at_startup` applies the instance's startup_grace_seconds.
+    async fn send(
+        &self,
+        instance_config: &InstanceConfig,
+        url: &str,
+        body: Option<&serde_json::Value>,
+        at_startup: bool,
+        request_id: &str,
+    ) -> Result<ApiResponse, Box<dyn Error + Send + Sync>> {
+        let method = if body.is_some() { "POST" } else { "GET" };
+        if let ApiRecording::Replay(dir) = &self.recording {
+            let path = dir.join(recording_file_name(method, url, body));
+            let recorded = match fs::read_to_string(&path) {
+                Ok(contents) => read_recording(&path, &contents)?,
+                Err(e) => {
+                    return Err(Box::new(MissingRecording(format!(
+                        "No recorded response for {} {} in '{}' (expected '{}': {}). Was the recording made with the same config?",
+                        method,
+                        url,
+                        dir.display(),
+                        path.display(),
+                        e
+                    ))))
+                }
+            };
+            return Ok(ApiResponse {
+                status: StatusCode::from_u16(recorded.status)?,
+                content_type: recorded.content_type.clone(),
+                redirected_to: None,
+                date: None,
+                text: recorded.body_text(),
+            });
+        }
+
+        let response = self
+            .send_live(instance_config, url, body, at_startup, request_id)
+            .await?;
+        let (response, undone) = read_response(url, response).await?;
+        if self.http_debug && !undone.is_empty() {
+            info!(
+                "HTTP {} {} [req {}]: undid {} in the body",
+                method,
+                url,
+                request_id,
+                undone.join(", then ")
+            );
+        }
+
+        if let ApiRecording::Record(dir) = &self.recording {
+            let json = serde_json::from_str::<serde_json::Value>(&response.text).ok();
+            let recorded = RecordedResponse {
+                version: API_RECORDING.current,
+                method: method.to_string(),
+                url: url.to_string(),
+                status: response.status.as_u16(),
+                content_type: response.content_type.clone(),
+                body_text: json.is_none().then(|| response.text.clone()),
+                body: json,
+            };
+            let path = dir.join(recording_file_name(method, url, body));
+            fs::write(&path, serde_json::to_string_pretty(&recorded)?)?;
+        }
+        Ok(response)
+    }
+
+    // Sends the request over the network (see send_with_retries) and prints it with --http-debug.
+    // The body is left to the caller.
+    async fn send_live(
+        &self,
+        instance_config: &InstanceConfig,
+        url: &str,
+        body: Option<&serde_json::Value>,
+        at_startup: bool,
+        request_id: &str,
+    ) -> Result<Response, Box<dyn Error + Send + Sync>> {
+        let mut headers = build_auth_headers(instance_config)?;
+        headers.insert(REQUEST_ID_HEADER, HeaderValue::from_str(request_id)?);
+        let send = || {
+            let request = match body {
+                Some(body) => self.client.post(url).json(body),
+                None => self.client.get(url),
+            };
+            request.headers(headers.clone()).send()
+        };
+        let result = self
+            .send_with_retries(instance_config, url, request_id, at_startup, send)
+            .await;
+        if self.http_debug {
+            let outcome = match &result {
+                Ok(response) => response.status().to_string(),
+                Err(e) => e.to_string(),
+            };
+            let method = if body.is_some() { "POST" } else { "GET" };
+            print_http_debug(method, url, request_id, &headers, &outcome);
+        }
+        Ok(result?)
+    }
+
+    // Exchanges the instance's username/password for an access token via
+    // /Users/AuthenticateByName. The token is only kept in memory and never recorded.
+    pub async fn authenticate(
+        &self,
+        instance_config: &mut InstanceConfig,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let Some(username) = instance_config.username.clone() else {
+            return Ok(()); // Uses api_token
+        };
+        let url = format!("{}/Users/AuthenticateByName", instance_config.base_url);
+        if let ApiRecording::Replay(_) = self.recording {
+            // Replayed requests are never sent, so there is nothing to authenticate
+            info!(
+                "Skipping authentication as '{}' for {} (replaying)",
+                username, url
+            );
+            return Ok(());
+        }
+
+        info!("Authenticating as '{}' at: {}", username, url);
+        let (auth_name, client_header) = instance_config.auth_header(None);
+        let request_id = self.next_request_id();
+        let mut headers = HeaderMap::new();
+        headers.insert(auth_name, HeaderValue::from_str(&client_header)?);
+        headers.insert(REQUEST_ID_HEADER, HeaderValue::from_str(&request_id)?);
+        let body = serde_json::json!({
+            "Username": username,
+            "Pw": instance_config.password.as_deref().unwrap_or(""),
+        });
+        let result = self
+            .send_with_retries(instance_config, &url, &request_id, true, || {
+                self.client
+                    .post(&url)
+                    .headers(headers.clone())
+                    .json(&body)
+                    .send()
+            })
+            .await;
+        if self.http_debug {
+            let outcome = match &result {
+                Ok(response) => response.status().to_string(),
+                Err(e) => e.to_string(),
+            };
+            print_http_debug("POST", &url, &request_id, &headers, &outcome);
+        }
+        let response =
+            result.map_err(|e| request_error(instance_config, Box::new(e), &request_id))?;
+        let status = response.status();
+        let (text, _) = decode_body(&response.bytes().await?)
+            .map_err(|e| format!("Unreadable response from {}: {}", url, e))?;
+        // Told apart by the caller, see endpoint::connect
+        if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN {
+            return Err(Box::new(MigrationError::ApiRequest {
+                instance: instance_config.base_url.clone(),
+                url,
+                status,
+                body: truncate_display(&text, MAX_ERROR_BODY_CHARS),
+                request_id,
+            }));
+        }
+        if !status.is_success() {
+            return Err(format!(
+                "Authentication as '{}' failed for {}: {} - {} [req {}]",
+                username,
+                url,
+                status,
+                truncate_display(&text, MAX_ERROR_BODY_CHARS),
+                request_id
+            )
+            .into());
+        }
+        let result: AuthenticationResult = serde_json::from_str(&text).map_err(|e| {
+            format!(
+                "Failed to parse the response from {}: {} (the body starts with bytes: {})",
+                url,
+                e,
+                hex_preview(&text)
+            )
+        })?;
+        instance_config.api_token = Some(result.access_token);
+        info!("Authenticated as '{}'.", username);
+        Ok(())
+    }
+
+    // GETs <base_url><path> and deserializes a successful response
+    #[allow(dead_code)] // Every request is made at startup so far
+    pub async fn get_json<T: DeserializeOwned>(
+        &self,
+        instance_config: &InstanceConfig,
+        path: &str,
+    ) -> Result<T, Box<dyn Error + Send + Sync>> {
+        self.json_request(instance_config, path, None, false).await
+    }
+
+    // POSTs `body` as JSON to <base_url><path> and deserializes a successful response. Only for
+    // requests that don't change anything on the server (e.g. queries), since they are recorded
+    // and replayed like GETs.
+    pub async fn post_json<T: DeserializeOwned>(
+        &self,
+        instance_config: &InstanceConfig,
+        path: &str,
+        body: &serde_json::Value,
+    ) -> Result<T, Box<dyn Error + Send + Sync>> {
+        self.json_request(instance_config, path, Some(body), false)
+            .await
+    }
+
+    // Same as get_json for the requests made before processing starts, which wait for an
+    // instance that is still starting up (see send_with_startup_grace)
+    pub async fn get_json_at_startup<T: DeserializeOwned>(
+        &self,
+        instance_config: &InstanceConfig,
+        path: &str,
+    ) -> Result<T, Box<dyn Error + Send + Sync>> {
+        self.json_request(instance_config, path, None, true).await
+    }
+
+    // get_json_at_startup that also returns the response's Date header (None when replaying)
+    pub async fn get_json_at_startup_dated<T: DeserializeOwned>(
+        &self,
+        instance_config: &InstanceConfig,
+        path: &str,
+    ) -> Result<(T, Option<String>), Box<dyn Error + Send + Sync>> {
+        self.dated_json_request(instance_config, path, None, true)
+            .await
+    }
+
+    async fn json_request<T: DeserializeOwned>(
+        &self,
+        instance_config: &InstanceConfig,
+        path: &str,
+        body: Option<&serde_json::Value>,
+        at_startup: bool,
+    ) -> Result<T, Box<dyn Error + Send + Sync>> {
+        let (value, _) = self
+            .dated_json_request(instance_config, path, body, at_startup)
+            .await?;
+        Ok(value)
+    }
+
+    async fn dated_json_request<T: DeserializeOwned>(
+        &self,
+        instance_config: &InstanceConfig,
+        path: &str,
+        body: Option<&serde_json::Value>,
+        at_startup: bool,
+    ) -> Result<(T, Option<String>), Box<dyn Error + Send + Sync>> {
+        let url = format!("{}{}", instance_config.base_url, path);
+        let request_id = self.next_request_id();
+        let response = self
+            .send(instance_config, &url, body, at_startup, &request_id)
+            .await
+            .map_err(|e| request_error(instance_config, e, &request_id))?;
+        check_response(instance_config, &url, &response, &request_id)?;
+        let value = serde_json::from_str(&response.text).map_err(|e| {
+            format!(
+                "Failed to parse the response from {}: {} (the body starts with bytes: {}) [req {}]",
+                url,
+                e,
+                hex_preview(&response.text),
+                request_id
+            )
+        })?;
+        Ok((value, response.date))
+    }
+    // POSTs like post_json, but hands the body of a successful response to `parse` while it is
+    // still arriving, so a response of any size is never held in memory as a whole. `parse` runs
+    // on a blocking thread and reads the body as a plain reader. Recorded responses are read
+    // whole, both when recording (to save them) and when replaying.
+    pub async fn post_json_streamed<T, P>(
+        &self,
+        instance_config: &InstanceConfig,
+        path: &str,
+        body: &serde_json::Value,
+        parse: P,
+    ) -> Result<T, Box<dyn Error + Send + Sync>>
+    where
+        T: Send + 'static,
+        P: FnOnce(&mut dyn Read) -> Result<T, String> + Send + 'static,
+    {
+        let url = format!("{}{}", instance_config.base_url, path);
+        let request_id = self.next_request_id();
+        let parse_error = |e: String| {
+            format!(
+                "Failed to parse the response from {}: {} [req {}]",
+                url, e, request_id
+            )
+        };
+        if !matches!(self.recording, ApiRecording::Off) {
+            let response = self
+                .send(instance_config, &url, Some(body), false, &request_id)
+                .await
+                .map_err(|e| request_error(instance_config, e, &request_id))?;
+            check_response(instance_config, &url, &response, &request_id)?;
+            return Ok(parse(&mut response.text.as_bytes()).map_err(parse_error)?);
+        }
+
+        let mut response = self
+            .send_live(instance_config, &url, Some(body), false, &request_id)
+            .await
+            .map_err(|e| request_error(instance_config, e, &request_id))?;
+        if !response.status().is_success() || is_html(content_type(&response)) {
+            let (response, _) = read_response(&url, response).await?;
+            check_response(instance_config, &url, &response, &request_id)?;
+            return Err(format!("Unexpected response from {} [req {}]", url, request_id).into());
+        }
+        let (sender, receiver) = mpsc::channel(STREAM_CHUNKS_IN_FLIGHT);
+        let http_debug = self.http_debug;
+        let debug_prefix = format!("HTTP POST {} [req {}]", url, request_id);
+        let parser = tokio::task::spawn_blocking(move || {
+            let chunks = ChunkReader {
+                chunks: receiver,
+                chunk: Vec::new(),
+                position: 0,
+            };
+            let (reader, undone) =
+                decode_reader(Box::new(chunks)).map_err(|e| format!("unreadable body: {}", e))?;
+            if http_debug && !undone.is_empty() {
+                info!(
+                    "{}: undid {} in the body",
+                    debug_prefix,
+                    undone.join(", then ")
+                );
+            }
+            parse(&mut BufReader::new(reader))
+        });
+        loop {
+            let chunk = match response.chunk().await {
+                Ok(Some(chunk)) => Ok(chunk.to_vec()),
+                Ok(None) => break,
+                Err(e) => Err(format!(
+                    "the connection failed while reading the body: {}",
+                    e
+                )),
+            };
+            let failed = chunk.is_err();
+            // Fails once the parser has stopped, it won't read any further
+            if sender.send(chunk).await.is_err() || failed {
+                break;
+            }
+        }
+        drop(sender);
+        let parsed = parser
+            .await
+            .map_err(|e| format!("Parsing the response from {} failed: {}", url, e))?;
+        Ok(parsed.map_err(parse_error)?)
+    }
+}
+
+// Older versions have the same fields and are read as they are, the file isn't rewritten
+fn read_recording(path: &Path, contents: &str) -> Result<RecordedResponse, String> {
+    let invalid = |e: String| format!("Invalid API recording '{}': {}", path.display(), e);
+    let value: serde_json::Value =
+        serde_json::from_str(contents).map_err(|e| invalid(e.to_string()))?;
+    let version = formats::json_version(&value).map_err(invalid)?;
+    API_RECORDING.check(path, version)?;
+    let mut recorded: RecordedResponse =
+        serde_json::from_value(value).map_err(|e| invalid(e.to_string()))?;
+    recorded.version = API_RECORDING.current;
+    Ok(recorded)
+}
+
+// Errors of send/send_live with the request ID, reqwest's as MigrationError::Http
+fn request_error(
+    instance_config: &InstanceConfig,
+    e: Box<dyn Error + Send + Sync>,
+    request_id: &str,
+) -> Box<dyn Error + Send + Sync> {
+    if e.is::<MissingRecording>() {
+        return e;
+    }
+    match e.downcast::<reqwest::Error>() {
+        Ok(source) => Box::new(MigrationError::Http {
+            instance: instance_config.base_url.clone(),
+            request_id: request_id.to_string(),
+            source: *source,
+        }),
+        Err(e) => format!("{} [req {}]", e, request_id).into(),
+    }
+}
+
+// Error statuses and HTML pages where JSON was expected
+fn check_response(
+    instance_config: &InstanceConfig,
+    url: &str,
+    response: &ApiResponse,
+    request_id: &str,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if !response.status.is_success() {
+        return Err(Box::new(MigrationError::ApiRequest {
+            instance: instance_config.base_url.clone(),
+            url: url.to_string(),
+            status: response.status,
+            body: truncate_display(&response.text, MAX_ERROR_BODY_CHARS),
+            request_id: request_id.to_string(),
+        }));
+    }
+    if response.is_html() {
+        return Err(format!(
+            "{} [req {}]",
+            html_response_error(url, response),
+            request_id
+        )
+        .into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn instance(base_url: &str, send_emby_token_header: bool) -> InstanceConfig {
+        InstanceConfig {
+            base_url: base_url.to_string(),
+            api_token: Some("token".to_string()),
+            username: None,
+            password: None,
+            startup_grace_seconds: 0,
+            max_retries: 0,
+            base_backoff_ms: 0,
+            auth_mode: crate::AuthMode::Token,
+            client: crate::ClientInfo::default(),
+            send_emby_token_header,
+            user_page_size: 0,
+        }
+    }
+
+    // Returns the headers the server received with the single GET /Users
+    async fn users_request_headers(
+        configure: impl FnOnce(&mut InstanceConfig),
+    ) -> wiremock::http::HeaderMap {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/Users"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!([])))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let api = ApiClient::new(Client::new(), ApiRecording::Off)
+            .unwrap()
+            .with_http_debug(true);
+        let mut instance = instance(&server.uri(), true);
+        configure(&mut instance);
+        let users: Vec<serde_json::Value> = api.get_json(&instance, "/Users").await.unwrap();
+        assert!(users.is_empty());
+        let mut requests = server.received_requests().await.unwrap();
+        assert_eq!(requests.len(), 1);
+        requests.remove(0).headers
+    }
+
+    #[tokio::test]
+    async fn sends_both_auth_headers_by_default() {
+        let headers = users_request_headers(|_| {}).await;
+        assert_eq!(
+            headers.get("authorization").unwrap(),
+            "MediaBrowser Token=\"token\""
+        );
+        assert_eq!(headers.get("x-emby-token").unwrap(), "token");
+    }
+
+    #[tokio::test]
+    async fn leaves_out_x_emby_token_when_disabled() {
+        let headers =
+            users_request_headers(|instance| instance.send_emby_token_header = false).await;
+        assert_eq!(
+            headers.get("authorization").unwrap(),
+            "MediaBrowser Token=\"token\""
+        );
+        assert!(headers.get("x-emby-token").is_none());
+    }
+
+    #[tokio::test]
+    async fn sends_x_emby_authorization_with_the_client_fields() {
+        let headers = users_request_headers(|instance| {
+            instance.auth_mode = crate::AuthMode::EmbyAuthorization;
+            instance.client.device_id = "migration-host".to_string();
+        })
+        .await;
+        assert!(headers.get("authorization").is_none());
+        assert_eq!(
+            headers.get("x-emby-authorization").unwrap(),
+            &format!(
+                "MediaBrowser Client=\"jellyfin_pr_migration\", Device=\"jellyfin_pr_migration\", DeviceId=\"migration-host\", Version=\"{}\", Token=\"token\"",
+                env!("CARGO_PKG_VERSION")
+            )
+        );
+        assert_eq!(headers.get("x-emby-token").unwrap(), "token");
+    }
+
+    #[tokio::test]
+    async fn request_ids_are_sent_and_named_in_errors() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/Items"))
+            .respond_with(ResponseTemplate::new(500).set_body_string("boom"))
+            .mount(&server)
+            .await;
+        let api = ApiClient::new(Client::new(), ApiRecording::Off).unwrap();
+        let error = api
+            .get_json::<serde_json::Value>(&instance(&server.uri(), true), "/Items")
+            .await
+            .unwrap_err()
+            .to_string();
+        let requests = server.received_requests().await.unwrap();
+        let request_id = requests[0].headers.get("x-request-id").unwrap();
+        assert_eq!(request_id.len(), 6);
+        assert!(
+            error.ends_with(&format!("[req {}]", request_id.to_str().unwrap())),
+            "{}",
+            error
+        );
+
+        // The same IDs on every replay
+        let dir = tempfile::tempdir().unwrap();
+        let replayed_ids = || {
+            let api = ApiClient::new(
+                Client::new(),
+                ApiRecording::Replay(dir.path().to_path_buf()),
+            )
+            .unwrap();
+            [api.next_request_id(), api.next_request_id()]
+        };
+        let ids = replayed_ids();
+        assert_ne!(ids[0], ids[1]);
+        assert_eq!(ids, replayed_ids());
+    }
+
+    #[tokio::test]
+    async fn reports_html_from_a_proxy_login_page_instead_of_a_parse_error() {
+        let server = MockServer::start().await;
+        // The proxy serves Jellyfin under /old and sends unauthenticated requests to its login page
+        Mock::given(method("GET"))
+            .and(path("/old/Users"))
+            .respond_with(
+                ResponseTemplate::new(302)
+                    .insert_header("location", format!("{}/login", server.uri()).as_str()),
+            )
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/login"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(
+                "<!DOCTYPE html><html><body>Sign in</body></html>",
+                "text/html; charset=utf-8",
+            ))
+            .mount(&server)
+            .await;
+        let api = ApiClient::new(Client::new(), ApiRecording::Off).unwrap();
+        let base_url = format!("{}/old", server.uri());
+        let error = api
+            .get_json::<Vec<serde_json::Value>>(&instance(&base_url, true), "/Users")
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(
+            error.contains("returned an HTML page instead of JSON"),
+            "{}",
+            error
+        );
+        assert!(
+            error.contains(&format!("redirect to {}/login", server.uri())),
+            "{}",
+            error
+        );
+        assert!(error.contains("sub-path"), "{}", error);
+        assert!(!error.contains("Failed to parse"), "{}", error);
+    }
+
+    fn gzip(bytes: &[u8]) -> Vec<u8> {
+        use flate2::write::GzEncoder;
+        use std::io::Write;
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(bytes).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[tokio::test]
+    async fn reads_bodies_with_a_bom_or_mislabelled_gzip() {
+        let users = br#"[{"Name": "alice", "Id": "a"}]"#;
+        let with_bom = [b"\xEF\xBB\xBF".as_slice(), users].concat();
+        let bodies = [
+            ("/bom", with_bom.clone()),
+            ("/gzip", gzip(users)),
+            ("/double-gzip-bom", gzip(&gzip(&with_bom))),
+            ("/garbage", b"\x00\x01not json".to_vec()),
+        ];
+        let server = MockServer::start().await;
+        for (endpoint, body) in bodies {
+            Mock::given(path(endpoint))
+                // The header claims plain JSON, whatever the body is
+                .respond_with(ResponseTemplate::new(200).set_body_raw(body, "application/json"))
+                .mount(&server)
+                .await;
+        }
+        let api = ApiClient::new(Client::new(), ApiRecording::Off).unwrap();
+        let instance = instance(&server.uri(), true);
+        for endpoint in ["/bom", "/gzip", "/double-gzip-bom"] {
+            let parsed: Vec<serde_json::Value> = api.get_json(&instance, endpoint).await.unwrap();
+            assert_eq!(parsed[0]["Name"], "alice", "{}", endpoint);
+            // The same while the body is streamed
+            let parsed: Vec<serde_json::Value> = api
+                .post_json_streamed(&instance, endpoint, &serde_json::json!({}), |reader| {
+                    serde_json::from_reader(reader).map_err(|e| e.to_string())
+                })
+                .await
+                .unwrap();
+            assert_eq!(parsed[0]["Name"], "alice", "{}", endpoint);
+        }
+        let error = api
+            .post_json_streamed(&instance, "/missing", &serde_json::json!({}), |_| Ok(()))
+            .await
+            .unwrap_err();
+        assert_eq!(MigrationError::from(error).exit_code(), 11);
+        let error = api
+            .get_json::<Vec<serde_json::Value>>(&instance, "/garbage")
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(
+            error.contains("the body starts with bytes: 00 01 6e 6f 74"),
+            "{}",
+            error
+        );
+    }
+
+    #[tokio::test]
+    async fn retries_5xx_and_connection_errors_but_not_4xx() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/Users"))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(2)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/Users"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!([])))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/Missing"))
+            .respond_with(ResponseTemplate::new(404))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/Broken"))
+            .respond_with(ResponseTemplate::new(500))
+            .mount(&server)
+            .await;
+        let api = ApiClient::new(Client::new(), ApiRecording::Off).unwrap();
+        let mut instance = instance(&server.uri(), true);
+        instance.max_retries = 2;
+        let requests_to = |endpoint: &'static str| {
+            let server = &server;
+            async move {
+                let requests = server.received_requests().await.unwrap();
+                requests.iter().filter(|r| r.url.path() == endpoint).count()
+            }
+        };
+
+        let users: Vec<serde_json::Value> = api.get_json(&instance, "/Users").await.unwrap();
+        assert!(users.is_empty());
+        assert_eq!(requests_to("/Users").await, 3);
+        assert!(api
+            .get_json::<serde_json::Value>(&instance, "/Missing")
+            .await
+            .is_err());
+        assert_eq!(requests_to("/Missing").await, 1);
+        // Once the retries are used up the last answer is the error
+        let error = api
+            .get_json::<serde_json::Value>(&instance, "/Broken")
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("500 Internal Server Error"), "{}", error);
+        assert_eq!(requests_to("/Broken").await, 3);
+
+        // Nothing listens on the port of a dropped listener
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        instance.base_url = format!("http://127.0.0.1:{}", port);
+        let error = api
+            .get_json::<serde_json::Value>(&instance, "/Users")
+            .await
+            .unwrap_err();
+        assert_eq!(MigrationError::from(error).exit_code(), 15);
+    }
+
+    #[tokio::test]
+    async fn timeouts_name_the_instance_and_url() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/Users"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(serde_json::json!([]))
+                    .set_delay(Duration::from_secs(5)),
+            )
+            .mount(&server)
+            .await;
+        let api = ApiClient::new(build_client(1).unwrap(), ApiRecording::Off).unwrap();
+        let error = api
+            .get_json::<serde_json::Value>(&instance(&server.uri(), true), "/Users")
+            .await
+            .unwrap_err();
+        let message = error.to_string();
+        assert!(
+            message.starts_with(&format!(
+                "{} didn't answer {}/Users within request_timeout_secs",
+                server.uri(),
+                server.uri()
+            )),
+            "{}",
+            message
+        );
+        assert_eq!(MigrationError::from(error).exit_code(), 15);
+    }
+
+    #[test]
+    fn recordings_round_trip_and_old_ones_are_still_read() {
+        let recorded = RecordedResponse {
+            version: API_RECORDING.current,
+            method: "GET".to_string(),
+            url: "http://old/Users".to_string(),
+            status: 200,
+            content_type: Some("application/json".to_string()),
+            body: Some(serde_json::json!([{"Name": "alice", "Id": "old-a"}])),
+            body_text: None,
+        };
+        let path = Path::new("GET_Users.json");
+        let contents = serde_json::to_string_pretty(&recorded).unwrap();
+        assert!(contents.contains("\"version\": 2"), "{}", contents);
+        let read = read_recording(path, &contents).unwrap();
+        assert_eq!(read.body_text(), recorded.body_text());
+        assert_eq!(read.content_type, recorded.content_type);
+
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/formats");
+        let old = fs::read_to_string(fixtures.join("recording_v1.json")).unwrap();
+        let read = read_recording(path, &old).unwrap();
+        assert_eq!(read.status, 200);
+        assert_eq!(read.body_text(), r#"[{"Id":"old-a","Name":"alice"}]"#);
+        let newer = fs::read_to_string(fixtures.join("recording_v99.json")).unwrap();
+        let error = read_recording(path, &newer).unwrap_err();
+        assert!(
+            error.ends_with("or record the responses again with --record-api."),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn backoff_doubles_with_jitter() {
+        let api = ApiClient::new(Client::new(), ApiRecording::Off).unwrap();
+        let mut instance = instance("http://old", true);
+        instance.base_backoff_ms = 1000;
+        for retry in 1..=4 {
+            let base = 1000.0 * f64::from(2u32.pow(retry - 1));
+            let delay = api.backoff(&instance, retry).as_millis() as f64;
+            assert!(delay >= base * 0.5 && delay <= base * 1.5, "{}", delay);
+        }
+        assert!(api.backoff(&instance, 100) <= Duration::from_millis(1000 * 1024 * 3 / 2));
+    }
+}
@@ -0,0 +1,164 @@
+// `bench` subcommand: times the SQLite insert path on generated records so changes to the
+// duplicate handling can be compared with reproducible numbers. Everything runs against fresh
+// in-memory databases, nothing on disk is touched.
+use crate::{check_and_insert_record_into_db, schema, TsvRecord};
+use rusqlite::{params_from_iter, Connection};
+use std::error::Error;
+use std::time::Instant;
+
+const BENCH_TABLE: &str = "PlaybackActivity";
+// Every this many records one earlier record is repeated, so the skip path is exercised too
+const DUPLICATE_EVERY: u64 = 10;
+
+#[derive(Debug, Clone, Copy)]
+enum BenchMode {
+    CheckThenInsert,        // What a normal run does
+    CheckThenInsertIndexed, // Same, with an index covering the duplicate check
+    InsertOrIgnoreUnique,   // A UNIQUE index does the duplicate check
+}
+
+impl BenchMode {
+    const ALL: [BenchMode; 3] = [
+        BenchMode::CheckThenInsert,
+        BenchMode::CheckThenInsertIndexed,
+        BenchMode::InsertOrIgnoreUnique,
+    ];
+
+    fn label(self) -> &'static str {
+        match self {
+            BenchMode::CheckThenInsert => "check+insert, no index",
+            BenchMode::CheckThenInsertIndexed => "check+insert, index",
+            BenchMode::InsertOrIgnoreUnique => "insert or ignore, unique",
+        }
+    }
+}
+
+// Small deterministic generator so every run benchmarks the same data
+struct Lcg(u64);
+
+impl Lcg {
+    fn next(&mut self, bound: u64) -> u64 {
+        self.0 = self
+            .0
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        (self.0 >> 33) % bound
+    }
+}
+
+fn generate_records(count: u64) -> Vec<TsvRecord> {
+    let mut rng = Lcg(42);
+    let mut records: Vec<TsvRecord> = Vec::with_capacity(count as usize);
+    for i in 0..count {
+        if i > 0 && i % DUPLICATE_EVERY == 0 {
+            let earlier = records[rng.next(records.len() as u64) as usize].clone();
+            records.push(earlier);
+            continue;
+        }
+        records.push(TsvRecord {
+            date_created: format!(
+                "2024-{:02}-{:02} {:02}:{:02}:{:02}.{:07}",
+                rng.next(12) + 1,
+                rng.next(28) + 1,
+                rng.next(24),
+                rng.next(60),
+                rng.next(60),
+                i
+            ),
+            user_id: format!("{:032x}", rng.next(20)),
+            item_id: format!("{:032x}", rng.next(5_000)),
+            item_type: ["Movie", "Episode", "Audio"][rng.next(3) as usize].to_string(),
+            item_name: format!("Item {}", rng.next(5_000)),
+            playback_method: ["DirectPlay", "DirectStream", "Transcode"][rng.next(3) as usize]
+                .to_string(),
+            client_name: ["Jellyfin Web", "Android TV", "Infuse"][rng.next(3) as usize].to_string(),
+            device_name: format!("Device {}", rng.next(10)),
+            play_duration: rng.next(10_000).to_string(),
+        });
+    }
+    records
+}
+
+fn create_table(conn: &Connection, mode: BenchMode) -> Result<(), rusqlite::Error> {
+    let columns: Vec<String> = schema::expected_columns()
+        .iter()
+        .map(|(name, declaration)| format!("{} {}", name, declaration))
+        .collect();
+    conn.execute_batch(&format!(
+        "CREATE TABLE {} ({});",
+        BENCH_TABLE,
+        columns.join(", ")
+    ))?;
+    let index = match mode {
+        BenchMode::CheckThenInsert => return Ok(()),
+        BenchMode::CheckThenInsertIndexed => "CREATE INDEX",
+        BenchMode::InsertOrIgnoreUnique => "CREATE UNIQUE INDEX",
+    };
+    conn.execute_batch(&format!(
+        "{} BenchDedup ON {} ({});",
+        index,
+        BENCH_TABLE,
+        schema::column_names().join(", ")
+    ))
+}
+
+// Returns (inserted, skipped, seconds)
+fn run_mode(mode: BenchMode, records: &[TsvRecord]) -> Result<(u64, u64, f64), rusqlite::Error> {
+    let conn = Connection::open_in_memory()?;
+    create_table(&conn, mode)?;
+    let insert_or_ignore = format!(
+        "INSERT OR IGNORE INTO {} ({}) VALUES ({})",
+        BENCH_TABLE,
+        schema::column_names().join(", "),
+        vec!["?"; schema::column_names().len()].join(", ")
+    );
+
+    let all_columns: Vec<usize> = (0..schema::column_names().len()).collect();
+    let (mut inserted, mut skipped) = (0, 0);
+    let start = Instant::now();
+    conn.execute_batch("BEGIN IMMEDIATE TRANSACTION;")?;
+    for record in records {
+        let was_inserted = match mode {
+            BenchMode::CheckThenInsert | BenchMode::CheckThenInsertIndexed => {
+                check_and_insert_record_into_db(&conn, BENCH_TABLE, record, &all_columns, true)?
+            }
+            BenchMode::InsertOrIgnoreUnique => {
+                conn.prepare_cached(&insert_or_ignore)?
+                    .execute(params_from_iter(record.fields()))?
+                    == 1
+            }
+        };
+        if was_inserted {
+            inserted += 1;
+        } else {
+            skipped += 1;
+        }
+    }
+    conn.execute_batch("COMMIT;")?;
+    Ok((inserted, skipped, start.elapsed().as_secs_f64()))
+}
+
+pub fn run_bench(record_count: u64) -> Result<(), Box<dyn Error + Send + Sync>> {
+    println!(
+        "Generating {} synthetic records (every {}th repeats an earlier one)...",
+        record_count, DUPLICATE_EVERY
+    );
+    let records = generate_records(record_count);
+
+    println!(
+        "\n{:<26} {:>10} {:>10} {:>10} {:>12}",
+        "Mode", "Inserted", "Skipped", "Seconds", "Rows/sec"
+    );
+    for mode in BenchMode::ALL {
+        let (inserted, skipped, seconds) = run_mode(mode, &records)?;
+        println!(
+            "{:<26} {:>10} {:>10} {:>10.3} {:>12.0}",
+            mode.label(),
+            inserted,
+            skipped,
+            seconds,
+            records.len() as f64 / seconds.max(f64::EPSILON)
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,220 @@
+// `--check-only`: compares the input TSV with the destination database without writing anything,
+// for a cron job that alerts while the old server still records history. A record is "newer" when
+// its DateCreated is after the latest DateCreated the destination has for its (mapped) user.
+// Exit codes: 0 up to date, 2 newer records found, 1 on errors.
+use crate::futuredates::FutureDates;
+use crate::retention::{parse_date_created, RetentionPolicy};
+use crate::userfilter::UserFilter;
+use crate::{Config, ProcessingStats, TsvRecord};
+use chrono::Utc;
+use clap::ValueEnum;
+use rusqlite::{Connection, OpenFlags};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+
+pub const EXIT_NEWER_RECORDS: i32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct UserBacklog {
+    pub newer_records: u64,
+    pub destination_latest: Option<String>, // None if the destination has nothing for this user
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct CheckResult {
+    pub records_checked: u64,
+    pub newer_records: u64,
+    pub per_user: BTreeMap<String, UserBacklog>, // Keyed by the user ID written to the destination
+}
+
+impl CheckResult {
+    pub fn is_up_to_date(&self) -> bool {
+        self.newer_records == 0
+    }
+
+    pub fn print(&self, format: OutputFormat) -> Result<(), Box<dyn Error + Send + Sync>> {
+        match format {
+            OutputFormat::Json => {
+                let mut value = serde_json::to_value(self)?;
+                value["status"] = if self.is_up_to_date() {
+                    "up_to_date"
+                } else {
+                    "behind"
+                }
+                .into();
+                println!("{}", serde_json::to_string(&value)?);
+            }
+            OutputFormat::Text if self.is_up_to_date() => println!(
+                "OK: destination is up to date ({} records checked)",
+                self.records_checked
+            ),
+            OutputFormat::Text => {
+                println!(
+                    "BEHIND: {} of {} records are newer than the destination",
+                    self.newer_records, self.records_checked
+                );
+                for (user_id, backlog) in &self.per_user {
+                    println!(
+                        "  '{}': {} newer (destination latest: {})",
+                        user_id,
+                        backlog.newer_records,
+                        backlog.destination_latest.as_deref().unwrap_or("none")
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// UserId -> latest DateCreated in the destination table
+fn latest_per_user(
+    db_path: &str,
+    table_name: &str,
+) -> Result<HashMap<String, String>, Box<dyn Error + Send + Sync>> {
+    let conn = Connection::open_with_flags(db_path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let mut stmt = conn.prepare(&format!(
+        "SELECT UserId, MAX(DateCreated) FROM \"{}\" GROUP BY UserId",
+        table_name
+    ))?;
+    let rows = stmt.query_map([], |row| {
+        Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+    })?;
+    Ok(rows.collect::<Result<_, _>>()?)
+}
+
+// Compares parsed dates so "2024-01-01 10:00:00" and "2024-01-01 10:00:00.0000000" are equal,
+// falling back to comparing the text
+fn is_newer(date_created: &str, latest: &str) -> bool {
+    match (parse_date_created(date_created), parse_date_created(latest)) {
+        (Some(created), Some(latest)) => created > latest,
+        _ => date_created > latest,
+    }
+}
+
+fn check_records(
+    records: impl Iterator<Item = Result<TsvRecord, csv::Error>>,
+    user_id_map: &HashMap<String, String>,
+    retention: &RetentionPolicy,
+    user_filter: &UserFilter,
+    future_dates: &FutureDates,
+    latest: &HashMap<String, String>,
+) -> Result<CheckResult, Box<dyn Error + Send + Sync>> {
+    let mut result = CheckResult::default();
+    let mut retention_stats = ProcessingStats::default(); // Dropped records are never migrated
+    for record in records {
+        let mut record = record?;
+        result.records_checked += 1;
+        if !user_filter.retain(&record, &mut retention_stats)
+            || !retention.retain(&record, &mut retention_stats)
+            || !future_dates.apply(&mut record, &mut retention_stats)
+        {
+            continue;
+        }
+        let user_id = user_id_map.get(&record.user_id).unwrap_or(&record.user_id);
+        let destination_latest = latest.get(user_id);
+        if destination_latest.is_none_or(|latest| is_newer(&record.date_created, latest)) {
+            result.newer_records += 1;
+            let backlog = result.per_user.entry(user_id.clone()).or_default();
+            backlog.newer_records += 1;
+            backlog.destination_latest = destination_latest.cloned();
+        }
+    }
+    Ok(result)
+}
+
+pub fn run_check(
+    config: &Config,
+    user_id_map: &HashMap<String, String>,
+    retention: &RetentionPolicy,
+) -> Result<CheckResult, Box<dyn Error + Send + Sync>> {
+    let db_path = config
+        .sqlite_db_path
+        .as_deref()
+        .ok_or("--check-only needs sqlite_db_path to compare the input against")?;
+    let table_name = config
+        .sqlite_table_name
+        .as_deref()
+        .unwrap_or("PlaybackActivity");
+    let latest = latest_per_user(db_path, table_name)?;
+    let mut rdr = csv::ReaderBuilder::new()
+        .delimiter(b'\t')
+        .has_headers(false)
+        .comment(Some(b'#'))
+        .from_path(&config.input_tsv_file_path)?;
+    check_records(
+        rdr.deserialize(),
+        user_id_map,
+        retention,
+        &config.user_filter,
+        &FutureDates::new(config, Utc::now()),
+        &latest,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config_from_toml;
+
+    fn record(date_created: &str, user_id: &str) -> Result<TsvRecord, csv::Error> {
+        Ok(TsvRecord {
+            date_created: date_created.to_string(),
+            user_id: user_id.to_string(),
+            item_id: "item".to_string(),
+            item_type: "Movie".to_string(),
+            item_name: "Name".to_string(),
+            playback_method: "DirectPlay".to_string(),
+            client_name: "Web".to_string(),
+            device_name: "TV".to_string(),
+            play_duration: "60".to_string(),
+        })
+    }
+
+    #[test]
+    fn counts_records_newer_than_the_destination_per_user() {
+        let user_id_map = HashMap::from([
+            ("old-a".to_string(), "new-a".to_string()),
+            ("old-b".to_string(), "new-b".to_string()),
+        ]);
+        let latest = HashMap::from([(
+            "new-a".to_string(),
+            "2024-01-02 10:00:00.0000000".to_string(),
+        )]);
+        let records = vec![
+            record("2024-01-01 10:00:00", "old-a"),
+            record("2024-01-02 10:00:00", "old-a"), // Same time, only the precision differs
+            record("2024-01-03 10:00:00", "old-a"),
+            record("2024-01-01 10:00:00", "old-b"), // Nothing for new-b in the destination
+        ];
+        let result = check_records(
+            records.into_iter(),
+            &user_id_map,
+            &RetentionPolicy::default(),
+            &UserFilter::default(),
+            &FutureDates::new(
+                &config_from_toml("input_tsv_file_path = \"in.tsv\""),
+                Utc::now(),
+            ),
+            &latest,
+        )
+        .unwrap();
+        assert_eq!(result.records_checked, 4);
+        assert_eq!(result.newer_records, 2);
+        assert_eq!(result.per_user["new-a"].newer_records, 1);
+        assert_eq!(
+            result.per_user["new-a"].destination_latest.as_deref(),
+            Some("2024-01-02 10:00:00.0000000")
+        );
+        assert_eq!(result.per_user["new-b"].destination_latest, None);
+        assert!(!result.is_up_to_date());
+    }
+}
@@ -0,0 +1,598 @@
+// The command line tool: parses the arguments, loads the config and runs what they ask for, the
+// migration itself with its previews, checks and watch mode, or one of the subcommands. main.rs
+// only calls run() and turns its error into the exit code.
+use crate::{
+    api, bench, check, columnar, connect_instances, devices, downsample, extract, history,
+    instances, load_config, load_or_build_user_map, logging, messages, prepare_config,
+    prepare_processing, process_tsv_file, progress, resume, review, schema, selftest, summary,
+    timefmt, units, usercontext, watch, writable, ApiClient, MigrationError, Prepared, RunMode,
+    DEFAULT_CONFIG_FILE,
+};
+use clap::{Parser, Subcommand};
+use std::error::Error;
+use std::io::{self, IsTerminal, Write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use tracing::{error, info};
+
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+struct CliArgs {
+    /// Config file to use, layered over the user config. Defaults to ./config.toml if it exists
+    #[clap(short, long, value_parser)]
+    config_file_path: Option<String>,
+    /// Review the user map (accept, skip or change each mapping, assign unmatched users), then do
+    /// a dry run, show its summary and ask for confirmation before running for real. Unmatched
+    /// users are listed with their avatar, libraries, admin flag and last activity
+    #[clap(long, conflicts_with = "watch")]
+    interactive: bool,
+    /// Dry run that opens the SQLite destination read-only to predict which records would be inserted or skipped
+    #[clap(long, conflicts_with_all = ["watch", "interactive"])]
+    dry_run_with_db: bool,
+    /// Fetch the users and go through the whole input without writing anything, counting what would
+    /// be written. The SQLite database isn't opened at all, see --dry-run-with-db for duplicates
+    #[clap(long, conflicts_with_all = ["watch", "interactive", "dry_run_with_db"])]
+    dry_run: bool,
+    /// At the end of the run, print the stats as one line on stderr: `JPM_SUMMARY: ` followed by a
+    /// JSON object with version, mode, started_at, finished_at, records_processed, records_changed,
+    /// records_unchanged, records_already_migrated, records_unmapped, records_device_renamed,
+    /// records_skipped_user_filter, records_dropped_retention, records_rejected_field_length, records_future_date,
+    /// records_rejected_future_date, records_dropped_unmapped,
+    /// records_unmapped_to_fallback, rows_merged,
+    /// session_seconds_reclaimed, records_rolled_up, rollup_rows_written, play_durations_rounded,
+    /// play_durations_converted, records_inserted_sqlite, records_skipped_sqlite,
+    /// outputs_disabled, destinations (label, rows_before, rows_after, bytes_before, bytes_after
+    /// per output), changes_per_user (old ID -> {new_id, count}), per_user_stats_truncated,
+    /// clock_skew_secs (instance -> seconds ahead of this machine) and explained ({line, steps}
+    /// per --explain line). Not printed in watch mode.
+    #[clap(long, conflicts_with = "watch")]
+    summary_line: bool,
+    /// Show a full screen dashboard instead of the progress bar (falls back to the progress bar
+    /// when stdout isn't a terminal). Press q to stop and commit or roll back.
+    #[clap(long, conflicts_with = "watch")]
+    tui: bool,
+    /// Ignore the user config directory (e.g. ~/.config/jellyfin_pr_migration/) for reproducible runs
+    #[clap(long)]
+    no_user_config: bool,
+    /// Only warn about unknown config keys instead of refusing to start (same as strict_config = false)
+    #[clap(long)]
+    lenient_config: bool,
+    /// Keep running and process lines appended to the input TSV as it grows
+    #[clap(long)]
+    watch: bool,
+    /// How often (in seconds) to poll the input TSV for new lines in watch mode
+    #[clap(long, value_parser, default_value_t = 5)]
+    watch_interval_secs: u64,
+    /// Write suggested [device_name_map] entries (based on the new instance's /Devices) to this file and exit
+    #[clap(long, value_parser)]
+    suggest_device_map: Option<String>,
+    /// Directory with one `*.toml` per instance (plus optional `defaults.toml`) to use instead of the config file
+    #[clap(long, value_parser, requires_all = ["from", "to"])]
+    instances_dir: Option<String>,
+    /// Name (file stem) of the instance in --instances-dir to migrate from
+    #[clap(long, value_parser, requires = "instances_dir")]
+    from: Option<String>,
+    /// Name (file stem) of the instance in --instances-dir to migrate to
+    #[clap(long, value_parser, requires = "instances_dir")]
+    to: Option<String>,
+    /// Save every API response as a JSON file in this directory (tokens are never saved)
+    #[clap(long, value_parser, conflicts_with = "replay_api")]
+    record_api: Option<PathBuf>,
+    /// Answer API requests from a directory written by --record-api instead of the network
+    #[clap(long, value_parser)]
+    replay_api: Option<PathBuf>,
+    /// Print every API request with the names of the headers sent (never their values) and the status
+    #[clap(long)]
+    http_debug: bool,
+    /// Only check whether the input has records newer than the destination's latest per user,
+    /// without writing anything. Exits 0 if up to date, 2 if newer records were found, 1 on errors
+    #[clap(long, conflicts_with_all = ["watch", "interactive", "dry_run_with_db", "tui", "summary_line"])]
+    check_only: bool,
+    /// Format of the --check-only result
+    #[clap(long, value_enum, default_value_t = check::OutputFormat::Text, requires = "check_only")]
+    output: check::OutputFormat,
+    /// Write to sqlite_db_path even if it looks like one of Jellyfin's own databases
+    #[clap(long)]
+    force_unrecognized_db: bool,
+    /// Let --dry-run-with-db or --check-only run when the outputs aren't writable (e.g. a read-only
+    /// mount), since they only read them
+    #[clap(long)]
+    read_only_ok: bool,
+    /// Print only the counts of the user mapping instead of a line per user (for instances with
+    /// thousands of users)
+    #[clap(long)]
+    summary_only: bool,
+    /// Language of the user mapping and the processing summary (en, de). The JPM_SUMMARY line,
+    /// the manifest and the unmapped report stay in English
+    #[clap(long, value_name = "LANG", default_value = "en")]
+    lang: String,
+    /// How much to print: error, warn, info (progress and summary), debug (also the line per
+    /// user of the user mapping and the decision for every record) or trace
+    #[clap(long, value_enum, default_value_t = logging::LogLevel::Info)]
+    log_level: logging::LogLevel,
+    /// With input_source = "old_instance", stop when a window can't be extracted instead of
+    /// skipping it and listing it at the end
+    #[clap(long)]
+    strict_extraction: bool,
+    /// Analyze the input and exit without contacting the instances or writing anything: shows
+    /// which unit (seconds or ticks) its PlayDuration values look like
+    #[clap(long, conflicts_with_all = ["watch", "interactive", "dry_run_with_db", "check_only"])]
+    analyze: bool,
+    /// Keep only this fraction (e.g. 0.05) of each user's rows per ItemType, with PlayDuration
+    /// scaled so the totals stay about the same, to make a small demo dataset
+    #[clap(long, value_name = "FRACTION", conflicts_with_all = ["watch", "check_only", "analyze"])]
+    downsample: Option<f64>,
+    /// Seed for --downsample: the same seed keeps the same rows
+    #[clap(long, default_value_t = 1, requires = "downsample")]
+    downsample_seed: u64,
+    /// Serve the run's progress as JSON on GET /progress at this address, e.g. 127.0.0.1:9321
+    #[clap(long, value_name = "ADDR")]
+    progress_listen: Option<std::net::SocketAddr>,
+    /// Use 2000-01-01T00:00:00Z for every timestamp and zero for every measured duration in the
+    /// summary, --summary-line, the manifest and the history, so repeated runs with the same input
+    /// and config produce identical output (e.g. for tests or outputs tracked in git)
+    #[clap(long)]
+    stable_timestamps: bool,
+    /// Stop at the next checkpoint once the run has taken this long (e.g. 45m, 1h30m), commit what
+    /// was done, write --state-file and exit with code 3 so a later run can continue from there
+    #[clap(long, value_name = "DURATION", value_parser = resume::parse_duration, requires = "state_file")]
+    max_runtime: Option<Duration>,
+    /// Where --max-runtime records how far the run got. A run given an existing state file skips
+    /// the records that were done and removes the file when it reaches the end of the input
+    #[clap(long, value_name = "PATH", conflicts_with_all = ["watch", "interactive", "dry_run", "dry_run_with_db", "check_only", "analyze", "downsample"])]
+    state_file: Option<PathBuf>,
+    /// Run even though instance_old and instance_new are the same server (same base_url or server
+    /// Id), e.g. to remap users within one server
+    #[clap(long)]
+    allow_same_instance: bool,
+    /// Map old users whose name several new users share to the first of them (in the order the new
+    /// instance lists its users) instead of leaving them unmapped
+    #[clap(long)]
+    allow_ambiguous_first_match: bool,
+    /// Carry on with an empty user list when fetching the users of an instance fails, instead of
+    /// stopping (same as allow_partial_user_fetch = true)
+    #[clap(long)]
+    ignore_fetch_errors: bool,
+    /// Trace what happens to this line of the input (1-based, as in an editor): its raw bytes, the
+    /// parsed fields, every filter and map lookup, the line written and SQLite's duplicate check.
+    /// Can be given several times, the traces are printed at the end of the summary
+    #[clap(long, value_name = "LINE", conflicts_with_all = ["watch", "check_only", "analyze"])]
+    explain: Vec<u64>,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Time the SQLite insert path on generated records and print rows/sec per mode (no config needed)
+    Bench {
+        /// Number of synthetic records to insert in each mode
+        #[clap(long, value_parser, default_value_t = 5_000)]
+        records: u64,
+    },
+    /// Run the whole pipeline on generated data in a temporary directory and check the results
+    /// (no config, network or Jellyfin instance needed)
+    SelfTest {
+        /// Keep the generated input and outputs instead of deleting them
+        #[clap(long)]
+        keep_artifacts: bool,
+    },
+    /// Print the runs recorded in a destination database and the user map each one used
+    History {
+        /// The destination SQLite database (sqlite_db_path of the runs)
+        db: PathBuf,
+    },
+}
+
+pub async fn run() -> Result<(), MigrationError> {
+    let cli_args = CliArgs::try_parse().unwrap_or_else(|e| {
+        // clap exits 2 on a usage error, which --check-only uses for "newer records found"
+        if e.use_stderr() && std::env::args_os().any(|arg| arg == "--check-only") {
+            let _ = e.print();
+            std::process::exit(1);
+        }
+        e.exit()
+    });
+    let check_only = cli_args.check_only;
+    // --check-only promises 0, 2 or 1, so its failures exit 1 whatever their class
+    run_with(cli_args).await.map_err(|e| match e {
+        MigrationError::Other(_) => e,
+        e if check_only => MigrationError::Other(Box::new(e)),
+        e => e,
+    })
+}
+
+async fn run_with(cli_args: CliArgs) -> Result<(), MigrationError> {
+    let started = Instant::now();
+    match &cli_args.command {
+        Some(Command::Bench { records }) => return Ok(bench::run_bench(*records)?),
+        Some(Command::History { db }) => return Ok(history::print_history(db)?),
+        Some(Command::SelfTest { keep_artifacts }) => {
+            return Ok(selftest::run_self_test(*keep_artifacts).await?)
+        }
+        None => {}
+    }
+    // The confirmation prompt can't be answered without a terminal, so don't start at all
+    if cli_args.interactive && !(io::stdin().is_terminal() && io::stdout().is_terminal()) {
+        return Err(
+            "--interactive needs a terminal (stdin and stdout must be a TTY). Aborting.".into(),
+        );
+    }
+    if cli_args.stable_timestamps {
+        timefmt::use_stable_timestamps();
+    }
+    messages::init(&cli_args.lang).map_err(MigrationError::config)?;
+    logging::init(cli_args.log_level);
+    info!("Starting Jellyfin TSV updater.");
+    // Stopped when it is dropped at the end of main, however the run ends
+    let _progress_server = cli_args
+        .progress_listen
+        .map(progress::ProgressServer::start)
+        .transpose()?;
+
+    // Load configuration
+    let mut config = if let (Some(dir), Some(from), Some(to)) =
+        (&cli_args.instances_dir, &cli_args.from, &cli_args.to)
+    {
+        info!(
+            "Loading configuration from instances directory '{}' (from '{}' to '{}')",
+            dir, from, to
+        );
+        match instances::load_config_from_instances_dir(dir, from, to) {
+            Ok(cfg) => cfg,
+            Err(e) => {
+                error!(
+                    "Failed to load configuration from instances directory '{}': {}",
+                    dir, e
+                );
+                return Err(MigrationError::config(e));
+            }
+        }
+    } else {
+        let config_file_path = cli_args.config_file_path.as_deref();
+        info!(
+            "Attempting to load configuration from: {}{}",
+            config_file_path.unwrap_or(DEFAULT_CONFIG_FILE),
+            if cli_args.no_user_config {
+                ""
+            } else {
+                " (layered over the user config if present)"
+            }
+        );
+        match load_config(config_file_path, !cli_args.no_user_config) {
+            Ok(cfg) => cfg,
+            Err(e) => {
+                error!(
+                    "Failed to load configuration using '{}' or fallback 'config.example.toml': {}",
+                    config_file_path.unwrap_or(DEFAULT_CONFIG_FILE),
+                    e
+                );
+                return Err(e);
+            }
+        }
+    };
+
+    config.allow_ambiguous_first_match = cli_args.allow_ambiguous_first_match;
+    config.explain_lines = cli_args.explain.clone();
+
+    prepare_config(&mut config, cli_args.lenient_config)?;
+    if config.user_map_input_path.is_some() && cli_args.suggest_device_map.is_some() {
+        return Err(MigrationError::config(
+            "--suggest-device-map needs the new instance's devices and can't be used with user_map_input_path.",
+        ));
+    }
+    if config.output_tsv_file_path.is_some()
+        && columnar::output_format(&config) == columnar::OutputFormat::Parquet
+        && (cli_args.watch || cli_args.state_file.is_some())
+    {
+        // The file is only readable once closed, so it can't grow batch by batch or be resumed
+        return Err(MigrationError::config(
+            "Parquet output can't be used with --watch or --state-file.",
+        ));
+    }
+    if cli_args.watch && config.input_source == extract::InputSource::OldInstance {
+        return Err("input_source = \"old_instance\" can't be used with --watch.".into());
+    }
+    let dry_run = cli_args.dry_run || config.dry_run;
+    if config.dry_run && (cli_args.watch || cli_args.interactive) {
+        return Err(MigrationError::config(
+            "dry_run = true can't be used with --watch or --interactive.",
+        ));
+    }
+    let read_only_run = dry_run || cli_args.dry_run_with_db || cli_args.check_only;
+    if cli_args.read_only_ok && !read_only_run {
+        return Err(
+            "--read-only-ok only works with --dry-run, --dry-run-with-db or --check-only.".into(),
+        );
+    }
+    if cli_args.analyze {
+        return Ok(units::print_analysis(&config)?);
+    }
+    if let Some(fraction) = cli_args.downsample {
+        if !(fraction > 0.0 && fraction <= 1.0) {
+            return Err(format!(
+                "--downsample must be a fraction above 0 and at most 1, got {}.",
+                fraction
+            )
+            .into());
+        }
+        config.downsample = Some(downsample::Downsample {
+            fraction,
+            seed: cli_args.downsample_seed,
+        });
+    }
+    if let Some(ref state_file) = cli_args.state_file {
+        if dry_run {
+            return Err("dry_run = true can't be used with --state-file.".into());
+        }
+        if config.input_source == extract::InputSource::OldInstance {
+            // Extracting again would replace the input the state file refers to
+            return Err("--state-file can't be used with input_source = \"old_instance\".".into());
+        }
+        let checkpoint = resume::Checkpoint::new(
+            state_file.clone(),
+            cli_args.max_runtime,
+            started,
+            &config.input_tsv_file_path,
+        )?;
+        if checkpoint.records_done() > 0
+            && config.output_tsv_file_path.is_some()
+            && !config.output_tsv_append
+        {
+            info!("Appending to the TSV output after the records written by the stopped run.");
+            config.output_tsv_append = true;
+        }
+        config.checkpoint = Some(checkpoint);
+    }
+    // Before anything is fetched or written, so a wrong sqlite_db_path can't touch the server's data.
+    // A plain dry run never opens the database (not even read-only, it could be locked by a writer).
+    if let Some(ref db_path) = config.sqlite_db_path {
+        if !dry_run {
+            schema::check_destination_db(db_path, cli_args.force_unrecognized_db)?;
+        }
+    }
+    // Also before the input is read, a read-only destination otherwise only fails at the first write.
+    // Not in a plain dry run, whose outputs are never opened and the probes would write to them.
+    if cli_args.suggest_device_map.is_none() && !dry_run {
+        let unwritable = writable::check_outputs_writable(&config);
+        if !unwritable.is_empty() {
+            if cli_args.read_only_ok {
+                info!(
+                    "Outputs aren't writable, continuing since this run only reads them (--read-only-ok): {}",
+                    unwritable.join("; ")
+                );
+            } else {
+                return Err(format!(
+                    "Output check failed: {}. Use a writable destination{}.",
+                    unwritable.join("; "),
+                    if read_only_run {
+                        ", or pass --read-only-ok since this run only reads it"
+                    } else {
+                        ""
+                    }
+                )
+                .into());
+            }
+        }
+    }
+
+    let recording = match (&cli_args.record_api, &cli_args.replay_api) {
+        (Some(dir), _) => api::ApiRecording::Record(dir.clone()),
+        (None, Some(dir)) => api::ApiRecording::Replay(dir.clone()),
+        (None, None) => api::ApiRecording::Off,
+    };
+    let api = ApiClient::new(api::build_client(config.request_timeout_secs)?, recording)?
+        .with_http_debug(cli_args.http_debug);
+    let map_loaded = config.user_map_input_path.is_some();
+    if !map_loaded {
+        connect_instances(
+            &mut config,
+            &api,
+            cli_args.allow_same_instance,
+            cli_args.check_only,
+        )
+        .await?;
+    }
+
+    if let Some(ref suggestion_path) = cli_args.suggest_device_map {
+        devices::suggest_device_name_map(&config, &api, suggestion_path).await?;
+        return Ok(());
+    }
+
+    let (old_users_vec, new_users_vec, mut user_id_map) = load_or_build_user_map(
+        &mut config,
+        &api,
+        cli_args.ignore_fetch_errors,
+        cli_args.summary_only,
+    )
+    .await?;
+    // The context comes from the instances, which a loaded map doesn't contact
+    if cli_args.interactive && !map_loaded {
+        usercontext::print_unmatched(
+            &api,
+            &config.instance_old,
+            &config.instance_new,
+            &old_users_vec,
+            &new_users_vec,
+            &user_id_map,
+        )
+        .await;
+    }
+    if cli_args.interactive {
+        progress::PROGRESS.set_phase("reviewing_user_map");
+        let (reviewed, merges) =
+            review::review_user_map(&old_users_vec, &new_users_vec, &user_id_map)?;
+        user_id_map = reviewed;
+        config.intentional_merges = merges;
+    }
+    // --check-only only compares dates per user, it doesn't need the items
+    let Prepared {
+        retention,
+        rollup,
+        audit,
+    } = prepare_processing(
+        &mut config,
+        &api,
+        &old_users_vec,
+        &new_users_vec,
+        &user_id_map,
+        !cli_args.check_only,
+        cli_args.strict_extraction,
+    )
+    .await?;
+    if cli_args.check_only {
+        let result = check::run_check(&config, &user_id_map, &retention)?;
+        result.print(cli_args.output)?;
+        if !result.is_up_to_date() {
+            std::process::exit(check::EXIT_NEWER_RECORDS);
+        }
+        return Ok(());
+    }
+    if cli_args.state_file.is_some()
+        && (rollup.is_active() || config.session_merge_window_secs.is_some())
+    {
+        // Their rows are only complete once every record has been read
+        return Err(
+            "--state-file can't be used with output_mode = \"daily_rollup\" or session_merge_window_secs."
+                .into(),
+        );
+    }
+    if cli_args.watch && rollup.is_active() {
+        // A day's rows can't be written while more plays of that day may still be appended
+        return Err("output_mode = \"daily_rollup\" can't be used with --watch.".into());
+    }
+    if cli_args.watch && config.session_merge_window_secs.is_some() {
+        // Merging needs every record sorted by date, which a growing file never has
+        return Err("session_merge_window_secs can't be used with --watch.".into());
+    }
+    if cli_args.watch {
+        watch::watch_tsv_file(
+            &config,
+            &user_id_map,
+            &retention,
+            Duration::from_secs(cli_args.watch_interval_secs),
+        )
+        .await?;
+    } else if cli_args.interactive {
+        // Preview everything first and only run for real once the user has seen the results
+        let preview = process_tsv_file(
+            &config,
+            &user_id_map,
+            &retention,
+            &rollup,
+            Some(&audit),
+            RunMode::DryRun,
+            false,
+        )
+        .await?;
+        progress::PROGRESS.set_phase("waiting_for_confirmation");
+        if !confirm("\nProceed with actual migration? [y/N] ")? {
+            info!("Aborted. Nothing was written.");
+            if cli_args.summary_line {
+                eprintln!("{}", summary::summary_line(&preview));
+            }
+            return Ok(());
+        }
+        let stats = process_tsv_file(
+            &config,
+            &user_id_map,
+            &retention,
+            &rollup,
+            Some(&audit),
+            RunMode::Normal,
+            cli_args.tui,
+        )
+        .await?;
+        if cli_args.summary_line {
+            eprintln!("{}", summary::summary_line(&stats));
+        }
+    } else {
+        let mode = if cli_args.dry_run_with_db {
+            RunMode::DryRunWithDb
+        } else if dry_run {
+            RunMode::DryRun
+        } else {
+            RunMode::Normal
+        };
+        let stats = process_tsv_file(
+            &config,
+            &user_id_map,
+            &retention,
+            &rollup,
+            Some(&audit),
+            mode,
+            cli_args.tui,
+        )
+        .await?;
+        if cli_args.summary_line {
+            eprintln!("{}", summary::summary_line(&stats));
+        }
+        if stats.checkpoint.out_of_time {
+            std::process::exit(resume::EXIT_PARTIAL);
+        }
+    }
+
+    info!("\nJellyfin TSV updater finished successfully.");
+    Ok(())
+}
+
+// Asks a yes/no question on the terminal, anything but "y"/"yes" counts as no
+fn confirm(prompt: &str) -> Result<bool, Box<dyn Error + Send + Sync>> {
+    print!("{}", prompt);
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn summary_line_help_lists_every_field() {
+        let command = CliArgs::command();
+        let help = command
+            .get_arguments()
+            .find(|arg| arg.get_id() == "summary_line")
+            .and_then(|arg| arg.get_help())
+            .unwrap()
+            .to_string();
+        let list = help
+            .split_once("JSON object with ")
+            .and_then(|(_, rest)| rest.split_once(". Not printed"))
+            .unwrap()
+            .0;
+        // The parentheses describe a field's contents, they aren't fields of their own
+        let mut depth = 0;
+        let top_level: String = list
+            .chars()
+            .filter(|c| {
+                match c {
+                    '(' => depth += 1,
+                    ')' => depth -= 1,
+                    _ => return depth == 0,
+                }
+                false
+            })
+            .collect();
+        let mut documented: Vec<&str> = top_level
+            .split([',', ' '])
+            .filter(|word| !word.is_empty() && *word != "and")
+            .collect();
+        documented.sort_unstable();
+
+        let line = summary::summary_line(&crate::ProcessingStats::default());
+        let json: serde_json::Value =
+            serde_json::from_str(line.strip_prefix(summary::SUMMARY_LINE_PREFIX).unwrap()).unwrap();
+        let mut serialized: Vec<&str> = json
+            .as_object()
+            .unwrap()
+            .keys()
+            .map(String::as_str)
+            .collect();
+        serialized.sort_unstable();
+        assert_eq!(documented, serialized);
+    }
+}
@@ -0,0 +1,139 @@
+// Clock skew of each instance relative to this machine, from the Date header of the preflight
+// /System/Info response. The header has a resolution of a second, plenty for the minutes that
+// matter. Skews are printed at startup, warned about above clock_skew_tolerance_secs and kept in
+// the summary and --summary-line. --check-only compares DateCreated values written by the old
+// server with ones written by the new server, so there a skew between the two instances above the
+// tolerance stops the run instead.
+use crate::timefmt;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tracing::{info, warn};
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct ClockSkew {
+    pub instance: &'static str, // "instance_old" or "instance_new"
+    pub skew_secs: i64,         // Positive when the instance's clock is ahead of this machine's
+}
+
+// None for a missing or unparseable header. Always zero with --stable-timestamps.
+pub fn measure(
+    instance: &'static str,
+    date_header: Option<&str>,
+    received_at: DateTime<Utc>,
+) -> Option<ClockSkew> {
+    let server_time = DateTime::parse_from_rfc2822(date_header?.trim()).ok()?;
+    let skew_secs = if timefmt::stable_timestamps() {
+        0
+    } else {
+        (server_time.with_timezone(&Utc) - received_at).num_seconds()
+    };
+    Some(ClockSkew {
+        instance,
+        skew_secs,
+    })
+}
+
+// e.g. "40m 0s behind", "3s ahead" or "in sync"
+pub fn describe(skew_secs: i64) -> String {
+    if skew_secs == 0 {
+        return "in sync".to_string();
+    }
+    let seconds = skew_secs.unsigned_abs();
+    let (hours, minutes, seconds) = (seconds / 3600, (seconds % 3600) / 60, seconds % 60);
+    let amount = if hours > 0 {
+        format!("{}h {}m {}s", hours, minutes, seconds)
+    } else if minutes > 0 {
+        format!("{}m {}s", minutes, seconds)
+    } else {
+        format!("{}s", seconds)
+    };
+    format!(
+        "{} {}",
+        amount,
+        if skew_secs > 0 { "ahead" } else { "behind" }
+    )
+}
+
+// Warns about every skew above the tolerance. With `time_sensitive` (the flag that needs the
+// servers to agree) a skew between the instances above it is an error.
+pub fn check(
+    skews: &[ClockSkew],
+    tolerance_secs: u64,
+    time_sensitive: Option<&str>,
+) -> Result<(), String> {
+    for skew in skews {
+        if skew.skew_secs.unsigned_abs() > tolerance_secs {
+            warn!(
+                "The clock of {} is {} of this machine (more than clock_skew_tolerance_secs = {}). DateCreated values it wrote are off by as much.",
+                skew.instance,
+                describe(skew.skew_secs),
+                tolerance_secs
+            );
+        }
+    }
+    let [old, new] = skews else {
+        return Ok(()); // Not measured on both sides
+    };
+    let between = new.skew_secs - old.skew_secs;
+    match time_sensitive {
+        Some(flag) if between.unsigned_abs() > tolerance_secs => Err(format!(
+            "The clock of instance_new is {} of instance_old's, more than clock_skew_tolerance_secs = {}. {} compares DateCreated values written by both, so its result would be off by as much. Fix the servers' clocks (e.g. enable NTP) or raise clock_skew_tolerance_secs.",
+            describe(between),
+            tolerance_secs,
+            flag
+        )),
+        _ => Ok(()),
+    }
+}
+
+pub fn print_summary(skews: &[ClockSkew]) {
+    if skews.is_empty() {
+        return;
+    }
+    info!(
+        "  Clock skew vs this machine: {}",
+        skews
+            .iter()
+            .map(|skew| format!("{} {}", skew.instance, describe(skew.skew_secs)))
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn measures_skew_from_the_date_header() {
+        let received_at = DateTime::parse_from_rfc3339("2024-05-01T12:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let old = measure(
+            "instance_old",
+            Some("Wed, 01 May 2024 11:20:00 GMT"),
+            received_at,
+        )
+        .unwrap();
+        assert_eq!(old.skew_secs, -2400);
+        assert_eq!(describe(old.skew_secs), "40m 0s behind");
+        let new = measure(
+            "instance_new",
+            Some("Wed, 01 May 2024 12:00:02 GMT"),
+            received_at,
+        )
+        .unwrap();
+        assert_eq!(describe(new.skew_secs), "2s ahead");
+        assert_eq!(
+            measure("instance_old", Some("yesterday"), received_at),
+            None
+        );
+        assert_eq!(measure("instance_old", None, received_at), None);
+
+        let skews = [old, new];
+        assert!(check(&skews, 60, None).is_ok());
+        let error = check(&skews, 60, Some("--check-only")).unwrap_err();
+        assert!(error.contains("40m 2s ahead"), "{}", error);
+        assert!(check(&skews, 3600, Some("--check-only")).is_ok());
+    }
+}
@@ -0,0 +1,410 @@
+// Parquet output for loading the history straight into DuckDB, Polars and the like. Selected with
+// `output_format = "parquet"`, or by giving output_tsv_file_path a `.parquet` extension. Columns are
+// typed: DateCreated as a timestamp (microseconds, no timezone, as written by Jellyfin), PlayDuration
+// as int64 and the rest as utf8, in the order of output_columns. Rows are buffered and written in
+// row groups of `parquet_row_group_size`, compressed with `parquet_compression`. The file can only
+// be written whole, so appending, --watch and --state-file are refused for it. Only built with the
+// `parquet` cargo feature, the arrow/parquet crates more than double the build time.
+use crate::Config;
+use serde::Deserialize;
+use std::path::Path;
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputFormat {
+    Tsv,
+    Parquet,
+}
+
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ParquetCompression {
+    #[default]
+    Snappy,
+    Zstd,
+    #[serde(rename = "none")]
+    Uncompressed,
+}
+
+// The format of the file at output_tsv_file_path: output_format when set, otherwise by extension
+pub fn output_format(config: &Config) -> OutputFormat {
+    match config.output_format {
+        Some(format) => format,
+        None => match config.output_tsv_file_path {
+            Some(ref path)
+                if Path::new(path)
+                    .extension()
+                    .is_some_and(|extension| extension.eq_ignore_ascii_case("parquet")) =>
+            {
+                OutputFormat::Parquet
+            }
+            _ => OutputFormat::Tsv,
+        },
+    }
+}
+
+// For log lines, e.g. "Parquet Output will be written to: ..."
+pub fn format_name(config: &Config) -> &'static str {
+    match output_format(config) {
+        OutputFormat::Tsv => "TSV",
+        OutputFormat::Parquet => "Parquet",
+    }
+}
+
+pub fn validate(config: &Config) -> Result<(), String> {
+    if config.output_tsv_file_path.is_none() || output_format(config) != OutputFormat::Parquet {
+        return Ok(());
+    }
+    if !cfg!(feature = "parquet") {
+        return Err(
+            "Parquet output isn't included in this build. Rebuild with `cargo build --release --features parquet`."
+                .to_string(),
+        );
+    }
+    if config.output_tsv_append {
+        return Err(
+            "output_tsv_append can't be used with Parquet output, a Parquet file can't be appended to."
+                .to_string(),
+        );
+    }
+    if config.parquet_row_group_size == 0 {
+        return Err("parquet_row_group_size must be at least 1.".to_string());
+    }
+    Ok(())
+}
+
+#[cfg(feature = "parquet")]
+pub use writer::ParquetSink;
+
+#[cfg(feature = "parquet")]
+mod writer {
+    use super::ParquetCompression;
+    use crate::retention::parse_date_created;
+    use crate::sinks::{file_size, Measurement, OutputFile, OutputSink, SinkStats, WriteOutcome};
+    use crate::{output, schema, Config, TsvRecord};
+    use arrow_array::builder::{Int64Builder, StringBuilder, TimestampMicrosecondBuilder};
+    use arrow_array::{ArrayRef, RecordBatch};
+    use arrow_schema::{DataType, Field, Schema, SchemaRef, TimeUnit};
+    use parquet::arrow::ArrowWriter;
+    use parquet::basic::{Compression, ZstdLevel};
+    use parquet::file::properties::WriterProperties;
+    use std::error::Error;
+    use std::fs::File;
+    use std::sync::Arc;
+
+    enum Value<'a> {
+        Null,
+        Int64(i64), // Also the microseconds of a timestamp
+        Utf8(&'a str),
+    }
+
+    enum ColumnBuilder {
+        Timestamp(TimestampMicrosecondBuilder),
+        Int64(Int64Builder),
+        Utf8(StringBuilder),
+    }
+
+    impl ColumnBuilder {
+        fn for_column(name: &str) -> ColumnBuilder {
+            match name {
+                "DateCreated" => ColumnBuilder::Timestamp(TimestampMicrosecondBuilder::new()),
+                "PlayDuration" => ColumnBuilder::Int64(Int64Builder::new()),
+                _ => ColumnBuilder::Utf8(StringBuilder::new()),
+            }
+        }
+
+        fn data_type(&self) -> DataType {
+            match self {
+                ColumnBuilder::Timestamp(_) => DataType::Timestamp(TimeUnit::Microsecond, None),
+                ColumnBuilder::Int64(_) => DataType::Int64,
+                ColumnBuilder::Utf8(_) => DataType::Utf8,
+            }
+        }
+
+        // An empty value is written as null
+        fn parse<'a>(&self, name: &str, value: &'a str) -> Result<Value<'a>, String> {
+            let value = value.trim();
+            Ok(match self {
+                ColumnBuilder::Utf8(_) => Value::Utf8(value),
+                _ if value.is_empty() => Value::Null,
+                ColumnBuilder::Timestamp(_) => {
+                    let date = parse_date_created(value)
+                        .ok_or_else(|| format!("{} '{}' isn't a date and time", name, value))?;
+                    Value::Int64(date.and_utc().timestamp_micros())
+                }
+                ColumnBuilder::Int64(_) => Value::Int64(
+                    value
+                        .parse()
+                        .map_err(|_| format!("{} '{}' isn't a whole number", name, value))?,
+                ),
+            })
+        }
+
+        fn append(&mut self, value: Value) {
+            match (self, value) {
+                (ColumnBuilder::Timestamp(builder), Value::Int64(micros)) => {
+                    builder.append_value(micros)
+                }
+                (ColumnBuilder::Timestamp(builder), _) => builder.append_null(),
+                (ColumnBuilder::Int64(builder), Value::Int64(number)) => {
+                    builder.append_value(number)
+                }
+                (ColumnBuilder::Int64(builder), _) => builder.append_null(),
+                (ColumnBuilder::Utf8(builder), Value::Utf8(text)) => builder.append_value(text),
+                (ColumnBuilder::Utf8(builder), _) => builder.append_null(),
+            }
+        }
+
+        fn finish(&mut self) -> ArrayRef {
+            match self {
+                ColumnBuilder::Timestamp(builder) => Arc::new(builder.finish()),
+                ColumnBuilder::Int64(builder) => Arc::new(builder.finish()),
+                ColumnBuilder::Utf8(builder) => Arc::new(builder.finish()),
+            }
+        }
+    }
+
+    pub struct ParquetSink {
+        path: String,
+        columns: Vec<(usize, &'static str, ColumnBuilder)>, // (index into TsvRecord::fields, name, values)
+        schema: SchemaRef,
+        writer: Option<ArrowWriter<File>>, // None once the file has been closed
+        buffered: usize,
+        row_group_size: usize,
+        stats: SinkStats,
+    }
+
+    impl ParquetSink {
+        pub fn open(
+            config: &Config,
+            path: &str,
+        ) -> Result<ParquetSink, Box<dyn Error + Send + Sync>> {
+            let names = schema::column_names();
+            let columns: Vec<(usize, &'static str, ColumnBuilder)> =
+                output::selected_columns(config)?
+                    .into_iter()
+                    .map(|index| (index, names[index], ColumnBuilder::for_column(names[index])))
+                    .collect();
+            let schema = Arc::new(Schema::new(
+                columns
+                    .iter()
+                    .map(|(_, name, builder)| Field::new(*name, builder.data_type(), true))
+                    .collect::<Vec<_>>(),
+            ));
+            let compression = match config.parquet_compression {
+                ParquetCompression::Snappy => Compression::SNAPPY,
+                ParquetCompression::Zstd => Compression::ZSTD(ZstdLevel::default()),
+                ParquetCompression::Uncompressed => Compression::UNCOMPRESSED,
+            };
+            let properties = WriterProperties::builder()
+                .set_compression(compression)
+                .set_max_row_group_size(config.parquet_row_group_size)
+                .build();
+            let writer =
+                ArrowWriter::try_new(File::create(path)?, schema.clone(), Some(properties))?;
+            Ok(ParquetSink {
+                path: path.to_string(),
+                columns,
+                schema,
+                writer: Some(writer),
+                buffered: 0,
+                row_group_size: config.parquet_row_group_size,
+                stats: SinkStats::default(),
+            })
+        }
+
+        // Writes the buffered rows out as one row group
+        fn write_row_group(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+            if self.buffered == 0 {
+                return Ok(());
+            }
+            let writer = self
+                .writer
+                .as_mut()
+                .ok_or("the Parquet file has already been closed")?;
+            let arrays: Vec<ArrayRef> = self
+                .columns
+                .iter_mut()
+                .map(|(_, _, builder)| builder.finish())
+                .collect();
+            writer.write(&RecordBatch::try_new(self.schema.clone(), arrays)?)?;
+            writer.flush()?;
+            self.buffered = 0;
+            Ok(())
+        }
+
+        // Writes what is left and the footer, the file is only readable after this
+        fn close(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+            self.write_row_group()?;
+            if let Some(writer) = self.writer.take() {
+                writer.close()?;
+            }
+            Ok(())
+        }
+    }
+
+    impl OutputSink for ParquetSink {
+        fn name(&self) -> String {
+            format!("Parquet '{}'", self.path)
+        }
+
+        fn output_file(&self) -> Option<OutputFile> {
+            Some(OutputFile {
+                kind: "parquet",
+                path: self.path.clone(),
+            })
+        }
+
+        fn begin(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+            self.stats = SinkStats::default();
+            Ok(())
+        }
+
+        fn write(
+            &mut self,
+            record: &TsvRecord,
+        ) -> Result<WriteOutcome, Box<dyn Error + Send + Sync>> {
+            let fields = record.fields();
+            // Every value is parsed before any is appended, a bad one can't leave the columns uneven
+            let values = self
+                .columns
+                .iter()
+                .map(|(index, name, builder)| builder.parse(name, fields[*index]))
+                .collect::<Result<Vec<_>, _>>()?;
+            for ((_, _, builder), value) in self.columns.iter_mut().zip(values) {
+                builder.append(value);
+            }
+            self.buffered += 1;
+            if self.buffered >= self.row_group_size {
+                self.write_row_group()?;
+            }
+            self.stats.count(WriteOutcome::Written);
+            Ok(WriteOutcome::Written)
+        }
+
+        fn finalize(&mut self) -> Result<SinkStats, Box<dyn Error + Send + Sync>> {
+            self.close()?;
+            Ok(self.stats)
+        }
+
+        fn measure(&mut self) -> Result<Option<Measurement>, Box<dyn Error + Send + Sync>> {
+            Ok(Some(Measurement {
+                label: self.name(),
+                rows: None,
+                bytes: file_size(&self.path),
+            }))
+        }
+
+        // Rows can't be taken back, close the file so what was written is at least readable
+        fn rollback(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+            self.close()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config_from_toml;
+
+    fn config_with(extra: &str) -> Config {
+        config_from_toml(&format!(
+            "input_tsv_file_path = \"unused.tsv\"\n{}\n[instance_old]\nbase_url = \"http://old\"\napi_token = \"x\"\n\
+            [instance_new]\nbase_url = \"http://new\"\napi_token = \"y\"\n",
+            extra
+        ))
+    }
+
+    #[test]
+    fn format_follows_the_setting_or_the_extension() {
+        let config = config_with("output_tsv_file_path = \"out.Parquet\"");
+        assert_eq!(output_format(&config), OutputFormat::Parquet);
+        let config = config_with("output_tsv_file_path = \"out.parquet\"\noutput_format = \"tsv\"");
+        assert_eq!(output_format(&config), OutputFormat::Tsv);
+        let config = config_with("output_tsv_file_path = \"out.tsv\"");
+        assert_eq!(format_name(&config), "TSV");
+
+        let config =
+            config_with("output_tsv_file_path = \"out.parquet\"\noutput_tsv_append = true");
+        let error = validate(&config).unwrap_err();
+        if cfg!(feature = "parquet") {
+            assert!(error.contains("output_tsv_append"), "{}", error);
+        } else {
+            assert!(error.contains("--features parquet"), "{}", error);
+        }
+    }
+
+    #[cfg(feature = "parquet")]
+    #[test]
+    fn written_file_reads_back_with_typed_columns() {
+        use crate::sinks::OutputSink;
+        use crate::TsvRecord;
+        use arrow_array::cast::AsArray;
+        use arrow_array::types::{Int64Type, TimestampMicrosecondType};
+        use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.parquet").display().to_string();
+        let config = config_with(&format!(
+            "output_tsv_file_path = {:?}\nparquet_row_group_size = 2\nparquet_compression = \"zstd\"\n\
+            output_columns = [\"DateCreated\", \"UserId\", \"PlayDuration\"]",
+            path
+        ));
+        validate(&config).unwrap();
+        let mut sink = ParquetSink::open(&config, &path).unwrap();
+        sink.begin().unwrap();
+        let record = |date_created: &str, user_id: &str, play_duration: &str| TsvRecord {
+            date_created: date_created.to_string(),
+            user_id: user_id.to_string(),
+            item_id: "item".to_string(),
+            item_type: "Movie".to_string(),
+            item_name: "Name".to_string(),
+            playback_method: "DirectPlay".to_string(),
+            client_name: "Web".to_string(),
+            device_name: "TV".to_string(),
+            play_duration: play_duration.to_string(),
+        };
+        sink.write(&record("2024-01-01 10:00:00.0000000", "new-a", "60"))
+            .unwrap();
+        sink.write(&record("2024-01-02 11:30:00", "new-b", "3600"))
+            .unwrap();
+        // Rejected without leaving a partial row behind
+        assert!(sink.write(&record("yesterday", "new-a", "60")).is_err());
+        assert!(sink
+            .write(&record("2024-01-03 12:00:00", "new-a", "1.5"))
+            .is_err());
+        sink.write(&record("2024-01-03 12:00:00", "new-a", ""))
+            .unwrap();
+        assert_eq!(sink.finalize().unwrap().written, 3);
+
+        let builder =
+            ParquetRecordBatchReaderBuilder::try_new(std::fs::File::open(&path).unwrap()).unwrap();
+        assert_eq!(builder.metadata().num_row_groups(), 2);
+        assert_eq!(builder.metadata().file_metadata().num_rows(), 3);
+        let names: Vec<String> = builder
+            .schema()
+            .fields()
+            .iter()
+            .map(|field| field.name().clone())
+            .collect();
+        assert_eq!(names, ["DateCreated", "UserId", "PlayDuration"]);
+        let batches: Vec<_> = builder.build().unwrap().map(Result::unwrap).collect();
+        assert_eq!(batches.len(), 1); // The reader's batches span row groups
+        let first = &batches[0];
+        assert_eq!(
+            first
+                .column(0)
+                .as_primitive::<TimestampMicrosecondType>()
+                .value(1),
+            chrono::NaiveDate::from_ymd_opt(2024, 1, 2)
+                .unwrap()
+                .and_hms_opt(11, 30, 0)
+                .unwrap()
+                .and_utc()
+                .timestamp_micros()
+        );
+        assert_eq!(first.column(1).as_string::<i32>().value(1), "new-b");
+        assert_eq!(first.column(2).as_primitive::<Int64Type>().value(0), 60);
+        assert!(first.column(2).is_null(2));
+    }
+}
@@ -0,0 +1,234 @@
+// Suggests DeviceName mappings by comparing the device names found in the input TSV
+// with the devices currently registered on the new instance. The suggestions are only
+// written to a file for review; applying them is done through `[device_name_map]`.
+use crate::api::ApiClient;
+use crate::matching;
+use crate::{Config, InstanceConfig, TsvRecord};
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::fs;
+use tracing::info;
+
+// Suggestions scoring at least this are written as active entries, lower ones are commented out
+const SUGGESTION_THRESHOLD: f64 = 0.75;
+// Below this a candidate isn't worth showing at all
+const MIN_GUESS_SCORE: f64 = 0.4;
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct DeviceInfo {
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct DevicesResponse {
+    items: Vec<DeviceInfo>,
+}
+
+async fn fetch_device_names(
+    instance_config: &InstanceConfig,
+    api: &ApiClient,
+) -> Result<BTreeSet<String>, Box<dyn Error + Send + Sync>> {
+    info!(
+        "Fetching devices from: {}/Devices",
+        instance_config.base_url
+    );
+    let devices: DevicesResponse = api.get_json_at_startup(instance_config, "/Devices").await?;
+    Ok(devices.items.into_iter().map(|d| d.name).collect())
+}
+
+fn collect_input_device_names(
+    input_path: &str,
+) -> Result<BTreeSet<String>, Box<dyn Error + Send + Sync>> {
+    let mut rdr = csv::ReaderBuilder::new()
+        .delimiter(b'\t')
+        .has_headers(false) // Input TSV does not have headers
+        .comment(Some(b'#')) // Skips the optional schema stamp of files written by this tool
+        .from_path(input_path)?;
+    let mut names = BTreeSet::new();
+    for result in rdr.deserialize() {
+        let record: TsvRecord = result?;
+        names.insert(record.device_name);
+    }
+    Ok(names)
+}
+
+fn best_match<'a>(old_name: &str, candidates: &'a BTreeSet<String>) -> Option<(&'a String, f64)> {
+    candidates
+        .iter()
+        .map(|candidate| (candidate, matching::similarity(old_name, candidate)))
+        // BTreeSet order makes ties resolve to the alphabetically first name
+        .fold(None, |best: Option<(&String, f64)>, current| match best {
+            Some(b) if b.1 >= current.1 => Some(b),
+            _ => Some(current),
+        })
+}
+
+fn toml_quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+pub async fn suggest_device_name_map(
+    config: &Config,
+    api: &ApiClient,
+    output_path: &str,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    info!("\nBuilding DeviceName mapping suggestions...");
+    let input_names = collect_input_device_names(&config.input_tsv_file_path)?;
+    info!(
+        "Found {} distinct DeviceName values in {}",
+        input_names.len(),
+        config.input_tsv_file_path
+    );
+    let new_names = fetch_device_names(&config.instance_new, api).await?;
+    info!("Found {} devices on the new instance.", new_names.len());
+
+    let mut suggested = BTreeMap::new();
+    let mut weak = BTreeMap::new();
+    let mut unmatched = Vec::new();
+    for old_name in &input_names {
+        if new_names.contains(old_name) || config.device_name_map.contains_key(old_name) {
+            continue; // Already matches or already mapped, nothing to suggest
+        }
+        match best_match(old_name, &new_names) {
+            Some((new_name, score)) if score >= SUGGESTION_THRESHOLD => {
+                suggested.insert(old_name, (new_name, score));
+            }
+            Some((new_name, score)) if score >= MIN_GUESS_SCORE => {
+                weak.insert(old_name, (new_name, score));
+            }
+            _ => unmatched.push(old_name),
+        }
+    }
+
+    let mut contents = String::new();
+    contents.push_str(
+        "# Proposed DeviceName mappings (input DeviceName -> device name on the new instance).\n",
+    );
+    contents.push_str("# Review every entry, then copy the ones you want into the [device_name_map] section of your config.\n");
+    contents.push_str(
+        "# Commented entries scored below the suggestion threshold and are only best guesses.\n",
+    );
+    contents.push_str("[device_name_map]\n");
+    for (old_name, (new_name, score)) in &suggested {
+        contents.push_str(&format!(
+            "{} = {} # similarity {:.2}\n",
+            toml_quote(old_name),
+            toml_quote(new_name),
+            score
+        ));
+    }
+    for (old_name, (new_name, score)) in &weak {
+        contents.push_str(&format!(
+            "# {} = {} # similarity {:.2}\n",
+            toml_quote(old_name),
+            toml_quote(new_name),
+            score
+        ));
+    }
+    for old_name in &unmatched {
+        contents.push_str(&format!(
+            "# {} has no candidate on the new instance\n",
+            toml_quote(old_name)
+        ));
+    }
+    fs::write(output_path, contents)?;
+
+    info!(
+        "Wrote {} suggested and {} low-confidence DeviceName mappings to: {}",
+        suggested.len(),
+        weak.len(),
+        output_path
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{api_client, config_from_toml};
+    use serde_json::json;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn names(names: &[&str]) -> BTreeSet<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn best_match_picks_the_closest_and_the_first_of_a_tie() {
+        let candidates = names(&["Kitchen iPad", "LivingRoomTV", "Pixel 8", "Pixel 7"]);
+        let (name, score) = best_match("Living Room TV", &candidates).unwrap();
+        assert_eq!((name.as_str(), score), ("LivingRoomTV", 1.0));
+        // One letter off both phones: the alphabetically first wins
+        let (name, score) = best_match("Pixel", &candidates).unwrap();
+        assert_eq!(name, "Pixel 7");
+        assert_eq!(score, matching::similarity("Pixel", "Pixel 8"));
+        assert!(best_match("Pixel", &BTreeSet::new()).is_none());
+    }
+
+    #[tokio::test]
+    async fn writes_close_matches_weak_guesses_and_unmatched_names() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/Devices"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"Items": [
+                {"Name": "Kitchen iPad"},
+                {"Name": "LivingRoomTV"},
+                {"Name": "Pixel 7"},
+                {"Name": "Pixel 8"},
+            ]})))
+            .mount(&server)
+            .await;
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input.tsv");
+        let devices = [
+            "Living Room TV", // The same once spaces and case are ignored
+            "Kitchen Pad",    // One letter missing
+            "Pixel",          // As close to both phones
+            "Kitchen",        // Below the suggestion threshold, a commented guess
+            "Zune",           // Below MIN_GUESS_SCORE for everything
+            "Pixel 8",        // Already the new name
+            "Old Phone",      // Already in [device_name_map]
+        ];
+        let lines: Vec<String> = devices
+            .iter()
+            .map(|device| {
+                format!(
+                    "2024-01-01 10:00:00\told-a\ti1\tMovie\tA\tDirectPlay\tWeb\t{}\t60",
+                    device
+                )
+            })
+            .collect();
+        fs::write(&input, lines.join("\n")).unwrap();
+        let config = config_from_toml(&format!(
+            "input_tsv_file_path = {:?}\n\
+             [instance_old]\nbase_url = \"http://old.invalid\"\napi_token = \"x\"\n\
+             [instance_new]\nbase_url = {:?}\napi_token = \"y\"\n\
+             [device_name_map]\n\"Old Phone\" = \"Pixel 8\"\n",
+            input.display().to_string(),
+            server.uri()
+        ));
+        let output = dir.path().join("proposed.toml");
+        suggest_device_name_map(
+            &config,
+            &api_client(&config).unwrap(),
+            output.to_str().unwrap(),
+        )
+        .await
+        .unwrap();
+
+        let written = fs::read_to_string(&output).unwrap();
+        let (_, entries) = written.split_once("[device_name_map]\n").unwrap();
+        assert_eq!(
+            entries,
+            "\"Kitchen Pad\" = \"Kitchen iPad\" # similarity 0.91\n\
+             \"Living Room TV\" = \"LivingRoomTV\" # similarity 1.00\n\
+             \"Pixel\" = \"Pixel 7\" # similarity 0.83\n\
+             # \"Kitchen\" = \"Kitchen iPad\" # similarity 0.64\n\
+             # \"Zune\" has no candidate on the new instance\n"
+        );
+    }
+}
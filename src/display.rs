@@ -0,0 +1,135 @@
+// Helpers for rendering values in logs and reports
+
+const ELLIPSIS: char = '…';
+
+// Limits for values that can be arbitrarily long when echoed into logs
+pub const MAX_ERROR_BODY_CHARS: usize = 500;
+pub const MAX_RECORD_DISPLAY_CHARS: usize = 300;
+
+// Shortens `s` to at most `max` characters (including the trailing ellipsis when shortened).
+// Cuts only on char boundaries so multi-byte text (CJK, emoji, ...) never panics or produces invalid UTF-8.
+pub fn truncate_display(s: &str, max: usize) -> String {
+    truncate_with_marker(s, max, &ELLIPSIS.to_string())
+}
+
+// Same as truncate_display with any marker. If the marker alone is longer than `max` only as much
+// of it as fits is kept.
+pub fn truncate_with_marker(s: &str, max: usize, marker: &str) -> String {
+    if s.chars().count() <= max {
+        return s.to_string();
+    }
+    let marker_chars = marker.chars().count().min(max);
+    let mut truncated: String = s.chars().take(max - marker_chars).collect();
+    truncated.extend(marker.chars().take(marker_chars));
+    truncated
+}
+
+// 606221 -> "606,221"
+pub fn format_count(n: u64) -> String {
+    let digits = n.to_string();
+    let mut formatted = String::new();
+    for (i, digit) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            formatted.push(',');
+        }
+        formatted.push(digit);
+    }
+    formatted
+}
+
+// Sizes in whole units, e.g. "58 MB" (1 MB = 1024 * 1024 bytes)
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KB", "MB", "GB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.0} {}", value, UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn leaves_short_values_alone() {
+        assert_eq!(truncate_display("The Matrix", 10), "The Matrix");
+        assert_eq!(truncate_display("", 0), "");
+    }
+
+    #[test]
+    fn truncates_ascii_with_ellipsis() {
+        assert_eq!(truncate_display("The Matrix Reloaded", 10), "The Matri…");
+        assert_eq!(truncate_display("abc", 1), "…");
+        assert_eq!(truncate_display("abc", 0), "");
+    }
+
+    #[test]
+    fn truncates_cjk_on_char_boundaries() {
+        assert_eq!(truncate_display("千と千尋の神隠し", 4), "千と千…");
+    }
+
+    #[test]
+    fn truncates_emoji_on_char_boundaries() {
+        assert_eq!(truncate_display("🎬🍿📺🎞️", 3), "🎬🍿…");
+    }
+
+    #[test]
+    fn truncates_combining_characters_on_char_boundaries() {
+        // "e" followed by a combining acute accent, repeated
+        let value = "e\u{301}e\u{301}e\u{301}";
+        assert_eq!(truncate_display(value, 4), "e\u{301}e…");
+    }
+
+    #[test]
+    fn truncates_with_a_longer_marker() {
+        assert_eq!(
+            truncate_with_marker("千と千尋の神隠し", 6, "[..]"),
+            "千と[..]"
+        );
+        assert_eq!(truncate_with_marker("abcdef", 2, "[..]"), "[.");
+        assert_eq!(truncate_with_marker("abc", 3, "[..]"), "abc");
+    }
+
+    #[test]
+    fn formats_counts_and_sizes() {
+        assert_eq!(format_count(0), "0");
+        assert_eq!(format_count(999), "999");
+        assert_eq!(format_count(1_018_552), "1,018,552");
+        assert_eq!(format_bytes(512), "512 B");
+        assert_eq!(format_bytes(58 * 1024 * 1024), "58 MB");
+        assert_eq!(format_bytes(3 * 1024 * 1024 * 1024 * 1024), "3072 GB");
+    }
+
+    fn tricky_text() -> impl Strategy<Value = String> {
+        prop::collection::vec(
+            prop_oneof![
+                any::<char>(),
+                Just('千'),
+                Just('🍿'),
+                Just('\u{301}'),  // combining acute accent
+                Just('\u{200d}'), // zero width joiner
+                Just('👩'),
+            ],
+            0..64,
+        )
+        .prop_map(|chars| chars.into_iter().collect())
+    }
+
+    proptest! {
+        #[test]
+        fn never_exceeds_max_and_keeps_a_prefix(s in tricky_text(), max in 0usize..80) {
+            let truncated = truncate_display(&s, max);
+            prop_assert!(truncated.chars().count() <= max);
+            // Output is a String so it is valid UTF-8 by construction, check it is a prefix of the input
+            let kept = truncated.strip_suffix(ELLIPSIS).unwrap_or(&truncated);
+            prop_assert!(s.starts_with(kept));
+            if s.chars().count() <= max {
+                prop_assert_eq!(&truncated, &s);
+            }
+        }
+    }
+}
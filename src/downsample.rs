@@ -0,0 +1,230 @@
+// `--downsample <fraction>`: keeps a seeded random share of the rows of each (UserId, ItemType)
+// stratum, e.g. to publish a small example dataset with the shape of a real history. Every stratum
+// keeps at least one row so light users and rare item types don't disappear, and the PlayDuration
+// of the kept rows is scaled so each stratum's total stays about the same. The scale is only known
+// once every row has been read, so the kept rows are buffered and written in input order at the end.
+use crate::rng::SplitMix64;
+use crate::{ProcessingStats, TsvRecord};
+use std::collections::{BTreeMap, HashMap};
+use tracing::info;
+
+// Per-stratum lines in the summary before the rest are only counted
+const MAX_STRATA_SHOWN: usize = 20;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Downsample {
+    pub fraction: f64,
+    pub seed: u64,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct StratumStats {
+    pub rows: u64,
+    pub kept: u64,
+    pub input_seconds: u64,
+    pub output_seconds: u64,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct DownsampleStats {
+    pub rows_dropped: u64,
+    pub strata: BTreeMap<(String, String), StratumStats>, // Keyed by (UserId, ItemType)
+}
+
+impl DownsampleStats {
+    pub fn print_summary(&self, downsample: &Downsample) {
+        let total = |f: fn(&StratumStats) -> u64| self.strata.values().map(f).sum::<u64>();
+        let (input, output) = (total(|s| s.input_seconds), total(|s| s.output_seconds));
+        info!(
+            "  Downsampled to {} (seed {}): kept {} of {} rows in {} strata",
+            downsample.fraction,
+            downsample.seed,
+            total(|s| s.kept),
+            total(|s| s.rows),
+            self.strata.len()
+        );
+        info!(
+            "    Total PlayDuration {}s -> {}s ({})",
+            input,
+            output,
+            describe_error(input, output)
+        );
+        for ((user_id, item_type), stratum) in self.strata.iter().take(MAX_STRATA_SHOWN) {
+            info!(
+                "    {} / {}: kept {} of {}, PlayDuration {}s -> {}s ({})",
+                user_id,
+                item_type,
+                stratum.kept,
+                stratum.rows,
+                stratum.input_seconds,
+                stratum.output_seconds,
+                describe_error(stratum.input_seconds, stratum.output_seconds)
+            );
+        }
+        if self.strata.len() > MAX_STRATA_SHOWN {
+            info!(
+                "    ... and {} more strata",
+                self.strata.len() - MAX_STRATA_SHOWN
+            );
+        }
+    }
+}
+
+fn describe_error(input: u64, output: u64) -> String {
+    let error = output as i64 - input as i64;
+    if input == 0 {
+        return format!("error {:+}s", error);
+    }
+    format!(
+        "error {:+}s, {:+.2}%",
+        error,
+        100.0 * error as f64 / input as f64
+    )
+}
+
+#[derive(Default)]
+struct Stratum {
+    stats: StratumStats,
+    kept_seconds: u64, // Before scaling
+    // A random row among the ones not kept, written if the stratum would otherwise be empty
+    fallback: Option<(u64, TsvRecord)>,
+}
+
+pub struct Downsampler {
+    fraction: f64,
+    rng: SplitMix64,
+    strata: HashMap<(String, String), Stratum>,
+    kept: Vec<(u64, TsvRecord)>, // With the position in the input
+    rows: u64,
+}
+
+fn seconds(record: &TsvRecord) -> u64 {
+    record.play_duration.trim().parse().unwrap_or(0)
+}
+
+impl Downsampler {
+    pub fn new(downsample: Downsample) -> Downsampler {
+        Downsampler {
+            fraction: downsample.fraction,
+            rng: SplitMix64::new(downsample.seed),
+            strata: HashMap::new(),
+            kept: Vec::new(),
+            rows: 0,
+        }
+    }
+
+    pub fn push(&mut self, record: TsvRecord) {
+        self.rows += 1;
+        let key = (record.user_id.clone(), record.item_type.clone());
+        let stratum = self.strata.entry(key).or_default();
+        stratum.stats.rows += 1;
+        stratum.stats.input_seconds += seconds(&record);
+        if self.rng.next_f64() < self.fraction {
+            stratum.stats.kept += 1;
+            stratum.kept_seconds += seconds(&record);
+            self.kept.push((self.rows, record));
+        } else {
+            // Reservoir sampling of one row among the ones not kept
+            let not_kept = stratum.stats.rows - stratum.stats.kept;
+            if self.rng.next_f64() * (not_kept as f64) < 1.0 {
+                stratum.fallback = Some((self.rows, record));
+            }
+        }
+    }
+
+    // The kept rows in input order with their PlayDuration scaled
+    pub fn finish(self, stats: &mut ProcessingStats) -> Vec<TsvRecord> {
+        let mut kept = self.kept;
+        let mut strata = self.strata;
+        for stratum in strata.values_mut() {
+            if stratum.stats.kept == 0 {
+                if let Some((position, record)) = stratum.fallback.take() {
+                    stratum.stats.kept += 1;
+                    stratum.kept_seconds += seconds(&record);
+                    kept.push((position, record));
+                }
+            }
+        }
+        kept.sort_by_key(|(position, _)| *position);
+
+        let records: Vec<TsvRecord> = kept
+            .into_iter()
+            .map(|(_, mut record)| {
+                let key = (record.user_id.clone(), record.item_type.clone());
+                let stratum = strata.get_mut(&key).expect("every kept row has a stratum");
+                if let Ok(duration) = record.play_duration.trim().parse::<u64>() {
+                    if stratum.kept_seconds > 0 {
+                        let scale =
+                            stratum.stats.input_seconds as f64 / stratum.kept_seconds as f64;
+                        let scaled = (duration as f64 * scale).round() as u64;
+                        record.play_duration = scaled.to_string();
+                        stratum.stats.output_seconds += scaled;
+                    }
+                }
+                record
+            })
+            .collect();
+        stats.downsample.rows_dropped = self.rows - records.len() as u64;
+        stats.downsample.strata = strata
+            .into_iter()
+            .map(|(key, stratum)| (key, stratum.stats))
+            .collect();
+        records
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(user_id: &str, item_type: &str, play_duration: u64) -> TsvRecord {
+        TsvRecord {
+            date_created: "2024-01-01 10:00:00".to_string(),
+            user_id: user_id.to_string(),
+            item_id: "item".to_string(),
+            item_type: item_type.to_string(),
+            item_name: "Item".to_string(),
+            playback_method: "DirectPlay".to_string(),
+            client_name: "Web".to_string(),
+            device_name: "TV".to_string(),
+            play_duration: play_duration.to_string(),
+        }
+    }
+
+    fn run(seed: u64) -> (Vec<TsvRecord>, ProcessingStats) {
+        let mut downsampler = Downsampler::new(Downsample {
+            fraction: 0.1,
+            seed,
+        });
+        for i in 0..2000 {
+            downsampler.push(record("heavy", "Episode", 1200 + (i * 7) % 1800));
+        }
+        downsampler.push(record("light", "Movie", 5400)); // A single play
+        let mut stats = ProcessingStats::default();
+        let records = downsampler.finish(&mut stats);
+        (records, stats)
+    }
+
+    #[test]
+    fn keeps_a_share_of_every_stratum_and_about_its_total() {
+        let (records, stats) = run(7);
+        let heavy = &stats.downsample.strata[&("heavy".to_string(), "Episode".to_string())];
+        assert!((150..250).contains(&heavy.kept), "{:?}", heavy);
+        let error = heavy.output_seconds as f64 / heavy.input_seconds as f64 - 1.0;
+        assert!(error.abs() < 0.001, "{:?}", heavy);
+        let light = &stats.downsample.strata[&("light".to_string(), "Movie".to_string())];
+        assert_eq!((light.kept, light.output_seconds), (1, 5400));
+        assert_eq!(records.len() as u64, heavy.kept + light.kept);
+        assert_eq!(stats.downsample.rows_dropped, 2001 - records.len() as u64);
+
+        // The same rows for the same seed
+        let durations = |records: &[TsvRecord]| {
+            records
+                .iter()
+                .map(|record| record.play_duration.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(durations(&records), durations(&run(7).0));
+        assert_ne!(durations(&records), durations(&run(8).0));
+    }
+}
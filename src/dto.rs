@@ -0,0 +1,159 @@
+// Checks of the JSON shapes read from the Jellyfin API. Unknown fields are always ignored and
+// fields renamed between server versions are accepted through serde aliases. When a response
+// still can't be deserialized it is parsed again as plain JSON to report which expected fields
+// are missing, instead of serde's error for the first bad element.
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::error::Error;
+
+pub struct ExpectedField {
+    pub name: &'static str,
+    pub aliases: &'static [&'static str], // Must match the #[serde(alias)]es of the struct
+}
+
+pub struct Shape {
+    pub what: &'static str, // e.g. "user"
+    pub fields: &'static [ExpectedField],
+}
+
+// JellyfinUser in lib.rs
+pub const USER_SHAPE: Shape = Shape {
+    what: "user",
+    fields: &[
+        ExpectedField {
+            name: "Id",
+            aliases: &["id"],
+        },
+        ExpectedField {
+            name: "Name",
+            aliases: &["name"],
+        },
+    ],
+};
+
+// Deserializes a JSON array of `shape`s, with a field-level report if that fails
+pub fn parse_list<T: DeserializeOwned>(
+    value: Value,
+    shape: &Shape,
+    url: &str,
+) -> Result<Vec<T>, Box<dyn Error + Send + Sync>> {
+    match serde_json::from_value::<Vec<T>>(value.clone()) {
+        Ok(list) => Ok(list),
+        Err(e) => Err(format!(
+            "Unexpected {} list in the response from {}: {} (serde: {})",
+            shape.what,
+            url,
+            diagnose(&value, shape),
+            e
+        )
+        .into()),
+    }
+}
+
+fn diagnose(value: &Value, shape: &Shape) -> String {
+    let Some(items) = value.as_array() else {
+        return format!("expected a JSON array but got {}", json_type(value));
+    };
+    let mut problems = Vec::new();
+    for field in shape.fields {
+        let mut missing = 0;
+        let mut not_a_string = 0;
+        for item in items {
+            match std::iter::once(field.name)
+                .chain(field.aliases.iter().copied())
+                .find_map(|key| item.get(key))
+            {
+                None => missing += 1,
+                Some(value) if !value.is_string() => not_a_string += 1,
+                Some(_) => {}
+            }
+        }
+        let accepted = std::iter::once(field.name)
+            .chain(field.aliases.iter().copied())
+            .collect::<Vec<_>>()
+            .join(", ");
+        if missing > 0 {
+            problems.push(format!(
+                "{} of {} {}s are missing {} (accepted names: {})",
+                missing,
+                items.len(),
+                shape.what,
+                field.name,
+                accepted
+            ));
+        }
+        if not_a_string > 0 {
+            problems.push(format!(
+                "{} of {} {}s have a {} that isn't a string",
+                not_a_string,
+                items.len(),
+                shape.what,
+                field.name
+            ));
+        }
+    }
+    if let Some(first) = items.first() {
+        let present = match first.as_object() {
+            Some(object) => object.keys().cloned().collect::<Vec<_>>().join(", "),
+            None => format!("(not an object but {})", json_type(first)),
+        };
+        problems.push(format!("fields of the first {}: {}", shape.what, present));
+    }
+    problems.join("; ")
+}
+
+fn json_type(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "a boolean",
+        Value::Number(_) => "a number",
+        Value::String(_) => "a string",
+        Value::Array(_) => "an array",
+        Value::Object(_) => "an object",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::JellyfinUser;
+
+    fn users(fixture: &str) -> Result<Vec<JellyfinUser>, Box<dyn Error + Send + Sync>> {
+        let path = format!(
+            "{}/tests/fixtures/users/{}",
+            env!("CARGO_MANIFEST_DIR"),
+            fixture
+        );
+        let value = serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+        parse_list(value, &USER_SHAPE, "http://test/Users")
+    }
+
+    #[test]
+    fn parses_users_from_every_server_version() {
+        for fixture in ["10.8.json", "10.9.json", "10.10.json", "camel_case.json"] {
+            let users = users(fixture).unwrap();
+            let names: Vec<&str> = users.iter().map(|user| user.name.as_str()).collect();
+            assert_eq!(names, vec!["alice", "bob"], "{}", fixture);
+            assert_eq!(
+                users[0].id, "5f1d2a3b4c5d6e7f8091a2b3c4d5e6f7",
+                "{}",
+                fixture
+            );
+        }
+    }
+
+    #[test]
+    fn reports_the_missing_fields() {
+        let error = users("missing_id.json").unwrap_err().to_string();
+        assert!(
+            error.contains("1 of 2 users are missing Id (accepted names: Id, id)"),
+            "{}",
+            error
+        );
+        assert!(
+            error.contains("fields of the first user: Name, UserId"),
+            "{}",
+            error
+        );
+    }
+}
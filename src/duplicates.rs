@@ -0,0 +1,84 @@
+// Where the records skipped as duplicates fall in time, to tell an expected overlap with an earlier
+// run from a wrong input. Only the range and a count per day are kept, never every timestamp.
+use std::collections::HashMap;
+use tracing::info;
+
+// Busiest days listed in the summary
+const TOP_DAYS: usize = 10;
+
+#[derive(Debug, Default)]
+pub struct DuplicateDates {
+    earliest: Option<String>,
+    latest: Option<String>,
+    per_day: HashMap<String, u64>,
+}
+
+impl DuplicateDates {
+    // DateCreated is "YYYY-MM-DD HH:MM:SS..." so comparing the strings orders them in time
+    pub fn record(&mut self, date_created: &str) {
+        if self.earliest.as_deref().is_none_or(|e| date_created < e) {
+            self.earliest = Some(date_created.to_string());
+        }
+        if self.latest.as_deref().is_none_or(|l| date_created > l) {
+            self.latest = Some(date_created.to_string());
+        }
+        let day = date_created.get(..10).unwrap_or(date_created);
+        match self.per_day.get_mut(day) {
+            Some(count) => *count += 1,
+            None => {
+                self.per_day.insert(day.to_string(), 1);
+            }
+        }
+    }
+
+    // Days with the most duplicates, most first (ties by date)
+    fn top_days(&self) -> Vec<(&str, u64)> {
+        let mut days: Vec<(&str, u64)> = self
+            .per_day
+            .iter()
+            .map(|(day, count)| (day.as_str(), *count))
+            .collect();
+        days.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+        days.truncate(TOP_DAYS);
+        days
+    }
+
+    pub fn print_summary(&self) {
+        let (Some(earliest), Some(latest)) = (&self.earliest, &self.latest) else {
+            return; // Nothing was skipped
+        };
+        info!("  Duplicates DateCreated range: {} to {}", earliest, latest);
+        info!(
+            "  Duplicates per day (top {} of {} days):",
+            TOP_DAYS.min(self.per_day.len()),
+            self.per_day.len()
+        );
+        for (day, count) in self.top_days() {
+            info!("    {}: {}", day, count);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tracks_the_range_and_busiest_days() {
+        let mut dates = DuplicateDates::default();
+        for date in [
+            "2024-01-02 10:00:00.0000000",
+            "2024-01-01 09:00:00",
+            "2024-01-02 11:00:00",
+            "2024-01-03 23:59:59",
+        ] {
+            dates.record(date);
+        }
+        assert_eq!(dates.earliest.as_deref(), Some("2024-01-01 09:00:00"));
+        assert_eq!(dates.latest.as_deref(), Some("2024-01-03 23:59:59"));
+        assert_eq!(
+            dates.top_days(),
+            vec![("2024-01-02", 2), ("2024-01-01", 1), ("2024-01-03", 1)]
+        );
+    }
+}
@@ -0,0 +1,429 @@
+// Preflight of each instance's base_url. A scheme/port mix-up (e.g. https:// on Jellyfin's HTTP
+// port 8096) otherwise only shows up as a confusing TLS error, so failures get a targeted hint and
+// a successful response is checked against the address the server reports for itself.
+// Only warnings are printed, the configured URL is never changed after normalize_base_url. The
+// response's Date header also gives the instance's clock skew, see clock.rs, and its server Id
+// tells whether instance_old and instance_new are the same server (see same_instance).
+// /System/Info needs a valid token, so it also validates the credentials: a 401/403 there (or
+// from /Users/AuthenticateByName) is collected for both instances and stops the run with a
+// message naming the instance and the setting to fix, see check_credentials.
+use crate::api::ApiClient;
+use crate::clock::{self, ClockSkew};
+use crate::error::MigrationError;
+use crate::InstanceConfig;
+use chrono::Utc;
+use reqwest::{StatusCode, Url};
+use serde::Deserialize;
+use std::error::Error;
+use tracing::{info, instrument, warn};
+
+// Jellyfin's default ports
+const DEFAULT_HTTP_PORT: u16 = 8096;
+const DEFAULT_HTTPS_PORT: u16 = 8920;
+
+// The part of /System/Info that is used
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct SystemInfo {
+    local_address: Option<String>,
+    server_name: Option<String>,
+    version: Option<String>,
+    id: Option<String>,
+}
+
+// What the preflight learned about an instance, each None when the request failed
+#[derive(Debug, Default)]
+pub struct Preflight {
+    pub clock_skew: Option<ClockSkew>, // When the response had a Date header
+    pub server_id: Option<String>,
+    pub rejected: Option<StatusCode>, // The instance refused the credentials
+}
+
+// Authenticates (with username/password) and runs the preflight. A rejected login is returned as
+// `rejected` like a rejected api_token, so the other instance is still checked.
+#[instrument(name = "instance", skip_all, fields(base_url = %instance_config.base_url))]
+pub async fn connect(
+    api: &ApiClient,
+    instance_config: &mut InstanceConfig,
+    instance: &'static str,
+) -> Result<Preflight, Box<dyn Error + Send + Sync>> {
+    if let Err(e) = api.authenticate(instance_config).await {
+        return match rejected_status(e.as_ref()) {
+            Some(status) => Ok(Preflight {
+                rejected: Some(status),
+                ..Preflight::default()
+            }),
+            None => Err(e),
+        };
+    }
+    Ok(preflight(api, instance_config, instance).await)
+}
+
+pub async fn preflight(
+    api: &ApiClient,
+    instance_config: &InstanceConfig,
+    instance: &'static str,
+) -> Preflight {
+    if api.is_replaying() {
+        // Nothing would reach the server, and older recordings don't have the response
+        return Preflight::default();
+    }
+    let base_url = &instance_config.base_url;
+    match api
+        .get_json_at_startup_dated::<SystemInfo>(instance_config, "/System/Info")
+        .await
+    {
+        Ok((info, date)) => {
+            let skew = clock::measure(instance, date.as_deref(), Utc::now());
+            // The version helps when a response doesn't have the shape this version expects
+            info!(
+                "{} is {}, {} accepted",
+                base_url,
+                describe_server(&info),
+                credentials_setting(instance_config)
+            );
+            if let Some(ref skew) = skew {
+                info!(
+                    "Clock of {}: {} compared to this machine",
+                    base_url,
+                    clock::describe(skew.skew_secs)
+                );
+            }
+            for warning in mismatch_warnings(base_url, &info) {
+                warn!("{}", warning);
+            }
+            Preflight {
+                clock_skew: skew,
+                server_id: info.id,
+                rejected: None,
+            }
+        }
+        Err(e) if rejected_status(e.as_ref()).is_some() => Preflight {
+            rejected: rejected_status(e.as_ref()),
+            ..Preflight::default()
+        },
+        Err(e) => {
+            warn!(
+                "Preflight request to {}/System/Info failed: {}",
+                base_url, e
+            );
+            let tls_error = e
+                .downcast_ref::<reqwest::Error>()
+                .is_some_and(|e| looks_like_tls_error(e));
+            let request_failed = e.downcast_ref::<reqwest::Error>().is_some();
+            if let Some(hint) = failure_hint(base_url, tls_error, request_failed) {
+                warn!("Hint: {}", hint);
+            }
+            Preflight::default()
+        }
+    }
+}
+
+// Stops the run when either instance refused its credentials, with a line per instance
+pub fn check_credentials(
+    instances: [(&InstanceConfig, &str, &Preflight); 2],
+) -> Result<(), MigrationError> {
+    let rejected: Vec<String> = instances
+        .into_iter()
+        .filter_map(|(instance_config, instance, preflight)| {
+            let status = preflight.rejected?;
+            Some(format!(
+                "{} for {} {} rejected by {} ({}). Check {} in [{}] of the config.",
+                match instance_config.username {
+                    Some(_) => "Username/password",
+                    None => "API token",
+                },
+                instance,
+                match instance_config.username {
+                    Some(_) => "were",
+                    None => "was",
+                },
+                instance_config.base_url,
+                status,
+                credentials_setting(instance_config),
+                instance
+            ))
+        })
+        .collect();
+    if rejected.is_empty() {
+        return Ok(());
+    }
+    Err(MigrationError::CredentialsRejected(rejected.join("\n")))
+}
+
+// The status of a request the instance answered with 401 or 403
+fn rejected_status(error: &(dyn Error + 'static)) -> Option<StatusCode> {
+    match error.downcast_ref::<MigrationError>() {
+        Some(MigrationError::ApiRequest { status, .. })
+            if *status == StatusCode::UNAUTHORIZED || *status == StatusCode::FORBIDDEN =>
+        {
+            Some(*status)
+        }
+        _ => None,
+    }
+}
+
+fn credentials_setting(instance_config: &InstanceConfig) -> &'static str {
+    match instance_config.username {
+        Some(_) => "username and password",
+        None => "api_token",
+    }
+}
+
+// e.g. "'Living room' (Jellyfin 10.9.0)"
+fn describe_server(info: &SystemInfo) -> String {
+    let version = format!(
+        "Jellyfin {}",
+        info.version.as_deref().unwrap_or("(version unknown)")
+    );
+    match info.server_name {
+        Some(ref name) => format!("'{}' ({})", name, version),
+        None => version,
+    }
+}
+
+// Why instance_old and instance_new look like the same server (the same base_url, or the same
+// server Id behind two URLs), None when they don't. The URLs are already normalized.
+pub fn same_instance(
+    old_base_url: &str,
+    new_base_url: &str,
+    old: &Preflight,
+    new: &Preflight,
+) -> Option<String> {
+    if old_base_url == new_base_url {
+        return Some(format!("both base_urls are {}", old_base_url));
+    }
+    match (&old.server_id, &new.server_id) {
+        (Some(old_id), Some(new_id)) if old_id.eq_ignore_ascii_case(new_id) => Some(format!(
+            "{} and {} both report server Id {}",
+            old_base_url, new_base_url, old_id
+        )),
+        _ => None,
+    }
+}
+
+// reqwest only exposes TLS problems through the text of the underlying errors
+fn looks_like_tls_error(error: &(dyn Error + 'static)) -> bool {
+    let mut current = Some(error);
+    while let Some(e) = current {
+        let text = e.to_string().to_lowercase();
+        if ["tls", "ssl", "certificate", "handshake", "corrupt message"]
+            .iter()
+            .any(|needle| text.contains(needle))
+        {
+            return true;
+        }
+        current = e.source();
+    }
+    false
+}
+
+// `request_failed` is set for errors where no HTTP response was received at all
+fn failure_hint(base_url: &str, tls_error: bool, request_failed: bool) -> Option<String> {
+    let url = Url::parse(base_url).ok()?;
+    let host = url.host_str()?;
+    match (url.scheme(), url.port_or_known_default()?) {
+        ("https", DEFAULT_HTTP_PORT) if tls_error => Some(format!(
+            "{} is Jellyfin's default HTTP port, which doesn't speak TLS. Try http://{}:{} (or https://{}:{} if HTTPS is enabled on the server).",
+            DEFAULT_HTTP_PORT, host, DEFAULT_HTTP_PORT, host, DEFAULT_HTTPS_PORT
+        )),
+        ("http", DEFAULT_HTTPS_PORT) if request_failed => Some(format!(
+            "{} is Jellyfin's default HTTPS port, which expects TLS. Try https://{}:{} (or http://{}:{}).",
+            DEFAULT_HTTPS_PORT, host, DEFAULT_HTTPS_PORT, host, DEFAULT_HTTP_PORT
+        )),
+        _ => None,
+    }
+}
+
+// Compares the configured scheme/port with the server's LocalAddress. Behind a reverse proxy the
+// two legitimately differ, so only the same host or the same port with another scheme is reported.
+fn mismatch_warnings(base_url: &str, info: &SystemInfo) -> Vec<String> {
+    let (Ok(configured), Some(Ok(local))) = (
+        Url::parse(base_url),
+        info.local_address.as_deref().map(Url::parse),
+    ) else {
+        return Vec::new();
+    };
+    let same_host = configured.host_str() == local.host_str();
+    let same_port = configured.port_or_known_default() == local.port_or_known_default();
+    let same_scheme = configured.scheme() == local.scheme();
+    let mut warnings = Vec::new();
+    if (same_host || same_port) && !same_scheme {
+        warnings.push(format!(
+            "{} uses {} but the server reports its local address as {}. If requests fail, check the scheme in base_url.",
+            base_url,
+            configured.scheme(),
+            local
+        ));
+    } else if same_host && !same_port {
+        warnings.push(format!(
+            "{} uses port {} but the server reports its local address as {}. If requests fail, check the port in base_url.",
+            base_url,
+            configured.port_or_known_default().unwrap_or_default(),
+            local
+        ));
+    }
+    warnings
+}
+
+// Adds http:// when the scheme is missing and drops trailing slashes, keeping any sub-path so an
+// instance served behind a reverse proxy under e.g. /jellyfin works (endpoints are appended to it)
+pub fn normalize_base_url(raw: &str) -> Result<String, String> {
+    let trimmed = raw.trim();
+    let with_scheme = if trimmed.contains("://") {
+        trimmed.to_string()
+    } else {
+        format!("http://{}", trimmed)
+    };
+    let url =
+        Url::parse(&with_scheme).map_err(|e| format!("'{}' is not a valid URL: {}", raw, e))?;
+    if !matches!(url.scheme(), "http" | "https") || url.host_str().is_none() {
+        return Err(format!(
+            "'{}' must be an http:// or https:// URL with a host",
+            raw
+        ));
+    }
+    if url.query().is_some() || url.fragment().is_some() {
+        return Err(format!(
+            "'{}' must not have a query string or fragment, use the path Jellyfin is served under",
+            raw
+        ));
+    }
+    Ok(url.as_str().trim_end_matches('/').to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(local_address: &str) -> SystemInfo {
+        SystemInfo {
+            local_address: Some(local_address.to_string()),
+            server_name: None,
+            version: None,
+            id: None,
+        }
+    }
+
+    fn with_id(id: &str) -> Preflight {
+        Preflight {
+            clock_skew: None,
+            server_id: Some(id.to_string()),
+            rejected: None,
+        }
+    }
+
+    #[test]
+    fn detects_the_same_server_by_url_or_id() {
+        let unknown = Preflight::default();
+        assert_eq!(
+            same_instance("http://a:8096", "http://a:8096", &unknown, &unknown).unwrap(),
+            "both base_urls are http://a:8096"
+        );
+        // One server reached through a proxy and directly
+        let reason = same_instance(
+            "https://jf.example.com",
+            "http://192.168.1.5:8096",
+            &with_id("4fa3"),
+            &with_id("4FA3"),
+        )
+        .unwrap();
+        assert!(reason.ends_with("both report server Id 4fa3"), "{}", reason);
+        assert!(same_instance(
+            "http://a:8096",
+            "http://b:8096",
+            &with_id("4fa3"),
+            &with_id("9c01")
+        )
+        .is_none());
+        assert!(
+            same_instance("http://a:8096", "http://b:8096", &unknown, &with_id("9c01")).is_none()
+        );
+    }
+
+    #[test]
+    fn names_the_instances_and_settings_of_rejected_credentials() {
+        let token = InstanceConfig {
+            base_url: "http://old:8096".to_string(),
+            ..Default::default()
+        };
+        let login = InstanceConfig {
+            base_url: "http://new:8096".to_string(),
+            username: Some("admin".to_string()),
+            ..Default::default()
+        };
+        let accepted = Preflight::default();
+        assert!(check_credentials([
+            (&token, "instance_old", &accepted),
+            (&login, "instance_new", &accepted)
+        ])
+        .is_ok());
+
+        let rejected = |status| Preflight {
+            rejected: Some(status),
+            ..Preflight::default()
+        };
+        let error = check_credentials([
+            (&token, "instance_old", &rejected(StatusCode::UNAUTHORIZED)),
+            (&login, "instance_new", &rejected(StatusCode::FORBIDDEN)),
+        ])
+        .unwrap_err();
+        assert_eq!(error.exit_code(), 17);
+        assert_eq!(
+            error.to_string(),
+            "API token for instance_old was rejected by http://old:8096 (401 Unauthorized). Check api_token in [instance_old] of the config.\n\
+             Username/password for instance_new were rejected by http://new:8096 (403 Forbidden). Check username and password in [instance_new] of the config."
+        );
+    }
+
+    #[test]
+    fn hints_at_the_scheme_port_pair() {
+        let hint = failure_hint("https://192.168.1.5:8096", true, true).unwrap();
+        assert!(hint.contains("http://192.168.1.5:8096"));
+        assert!(failure_hint("https://192.168.1.5:8096", false, false).is_none());
+        let hint = failure_hint("http://192.168.1.5:8920", false, true).unwrap();
+        assert!(hint.contains("https://192.168.1.5:8920"));
+        assert!(failure_hint("http://192.168.1.5:8096", false, true).is_none());
+    }
+
+    #[test]
+    fn warns_about_mismatches_with_the_local_address() {
+        assert_eq!(
+            mismatch_warnings("https://192.168.1.5:8096", &info("http://192.168.1.5:8096")).len(),
+            1
+        );
+        assert_eq!(
+            mismatch_warnings("http://192.168.1.5:8097", &info("http://192.168.1.5:8096")).len(),
+            1
+        );
+        // Reverse proxy in front of the server
+        assert!(mismatch_warnings(
+            "https://jellyfin.example.com",
+            &info("http://192.168.1.5:8096")
+        )
+        .is_empty());
+        assert!(
+            mismatch_warnings("http://192.168.1.5:8096", &info("http://192.168.1.5:8096"))
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn normalizes_base_urls_keeping_sub_paths() {
+        assert_eq!(
+            normalize_base_url("192.168.1.5:8096/").unwrap(),
+            "http://192.168.1.5:8096"
+        );
+        assert_eq!(
+            normalize_base_url(" https://Example.com/old// ").unwrap(),
+            "https://example.com/old"
+        );
+        assert_eq!(
+            normalize_base_url("https://example.com/new").unwrap(),
+            "https://example.com/new"
+        );
+        assert!(normalize_base_url("https://example.com/old?x=1").is_err());
+        assert!(normalize_base_url("ftp://example.com").is_err());
+        assert!(normalize_base_url("http://").is_err());
+    }
+}
@@ -0,0 +1,185 @@
+// The failure classes a run can end with. main exits with a distinct code per class so wrapper
+// scripts can tell a bad config from an unreachable server or a broken database without parsing
+// the message, and code embedding the migration can match on them. Where there is an underlying
+// error (config, reqwest, csv, rusqlite, I/O) the variant keeps it as its source. Most code below
+// the entry points still returns Box<dyn Error + Send + Sync> (Send so the future of run_migration
+// can be spawned on a multi-threaded runtime). Converting one of those picks the class from what
+// the box holds (a MigrationError built deeper down, a rusqlite or I/O error) and otherwise keeps
+// it as Other with its message unchanged.
+use reqwest::StatusCode;
+use std::error::Error;
+use std::fmt;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum MigrationError {
+    // Validation errors of our own have no source, errors of the config crate keep theirs
+    #[error("{message}")]
+    ConfigLoad {
+        message: String,
+        source: Option<config::ConfigError>,
+    },
+    // The instance answered with an error status
+    #[error("API request failed for {url}: {status} - {body} [req {request_id}]")]
+    ApiRequest {
+        instance: String, // Its base_url
+        url: String,
+        status: StatusCode,
+        body: String, // Truncated for display
+        request_id: String,
+    },
+    // The instance couldn't be reached, or the connection failed before a complete response
+    #[error("{} [req {request_id}]", describe_http_error(.instance, .source))]
+    Http {
+        instance: String, // Its base_url
+        request_id: String,
+        source: reqwest::Error,
+    },
+    // The users were fetched but the user map couldn't be built as configured, e.g. an override
+    // or split_user names a user the instance doesn't have
+    #[error("{0}")]
+    UserMapping(String),
+    // An instance answered 401/403 to the startup checks, one line per instance
+    #[error("{0}")]
+    CredentialsRejected(String),
+    #[error("Invalid record at line {line} of the input TSV: {source}")]
+    TsvParse { line: u64, source: csv::Error },
+    #[error("SQLite error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("{0}")]
+    Io(#[from] std::io::Error),
+    #[error("{0}")]
+    Other(Box<dyn Error + Send + Sync>),
+}
+
+impl MigrationError {
+    pub fn config(error: impl fmt::Display) -> MigrationError {
+        MigrationError::ConfigLoad {
+            message: error.to_string(),
+            source: None,
+        }
+    }
+
+    pub fn user_mapping(error: impl fmt::Display) -> MigrationError {
+        MigrationError::UserMapping(error.to_string())
+    }
+
+    // 1 stays the code for anything unclassified (and for every error of --check-only, see
+    // cli::run), 2 and 3 are taken by --check-only and --max-runtime
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            MigrationError::Other(_) => 1,
+            MigrationError::ConfigLoad { .. } => 10,
+            MigrationError::ApiRequest { .. } => 11,
+            MigrationError::TsvParse { .. } => 12,
+            MigrationError::Sqlite(_) => 13,
+            MigrationError::Io(_) => 14,
+            MigrationError::Http { .. } => 15,
+            MigrationError::UserMapping(_) => 16,
+            MigrationError::CredentialsRejected(_) => 17,
+        }
+    }
+}
+
+// A timeout says which instance didn't answer, a hung instance otherwise looks like a network error
+fn describe_http_error(instance: &str, source: &reqwest::Error) -> String {
+    match source.url() {
+        Some(url) if source.is_timeout() => format!(
+            "{} didn't answer {} within request_timeout_secs ({})",
+            instance, url, source
+        ),
+        _ => source.to_string(),
+    }
+}
+
+impl From<Box<dyn Error + Send + Sync>> for MigrationError {
+    fn from(error: Box<dyn Error + Send + Sync>) -> MigrationError {
+        let error = match error.downcast::<MigrationError>() {
+            Ok(error) => return *error,
+            Err(error) => error,
+        };
+        let error = match error.downcast::<rusqlite::Error>() {
+            Ok(error) => return MigrationError::Sqlite(*error),
+            Err(error) => error,
+        };
+        match error.downcast::<std::io::Error>() {
+            Ok(error) => MigrationError::Io(*error),
+            Err(error) => MigrationError::Other(error),
+        }
+    }
+}
+
+impl From<config::ConfigError> for MigrationError {
+    fn from(error: config::ConfigError) -> MigrationError {
+        MigrationError::ConfigLoad {
+            message: error.to_string(),
+            source: Some(error),
+        }
+    }
+}
+
+// A file that can't be opened is an I/O error, anything else csv reports is about a record
+impl From<csv::Error> for MigrationError {
+    fn from(error: csv::Error) -> MigrationError {
+        if error.is_io_error() {
+            return MigrationError::Io(error.into());
+        }
+        MigrationError::TsvParse {
+            line: error.position().map_or(0, |position| position.line()),
+            source: error,
+        }
+    }
+}
+
+impl From<String> for MigrationError {
+    fn from(message: String) -> MigrationError {
+        MigrationError::Other(message.into())
+    }
+}
+
+impl From<&str> for MigrationError {
+    fn from(message: &str) -> MigrationError {
+        MigrationError::Other(message.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn boxed_errors_keep_their_class_and_message() {
+        let api: Box<dyn Error + Send + Sync> = Box::new(MigrationError::ApiRequest {
+            instance: "http://old".to_string(),
+            url: "http://old/Users".to_string(),
+            status: StatusCode::UNAUTHORIZED,
+            body: "denied".to_string(),
+            request_id: "a1b2c3".to_string(),
+        });
+        let api = MigrationError::from(api);
+        assert_eq!(api.exit_code(), 11);
+        assert_eq!(
+            api.to_string(),
+            "API request failed for http://old/Users: 401 Unauthorized - denied [req a1b2c3]"
+        );
+
+        let sqlite: Box<dyn Error + Send + Sync> = Box::new(rusqlite::Error::InvalidQuery);
+        assert_eq!(MigrationError::from(sqlite).exit_code(), 13);
+        let io: Box<dyn Error + Send + Sync> = Box::new(std::io::Error::other("disk full"));
+        assert_eq!(MigrationError::from(io).exit_code(), 14);
+        let other = MigrationError::from(Box::<dyn Error + Send + Sync>::from("something else"));
+        assert_eq!(
+            (other.exit_code(), other.to_string().as_str()),
+            (1, "something else")
+        );
+        assert_eq!(MigrationError::config("missing").exit_code(), 10);
+        let parse = MigrationError::from(config::ConfigError::Message("bad".to_string()));
+        assert_eq!((parse.exit_code(), parse.to_string().as_str()), (10, "bad"));
+        assert!(parse.source().is_some());
+        assert_eq!(MigrationError::user_mapping("no such user").exit_code(), 16);
+        assert_eq!(
+            MigrationError::CredentialsRejected("rejected".to_string()).exit_code(),
+            17
+        );
+    }
+}
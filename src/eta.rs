@@ -0,0 +1,146 @@
+// The "remaining (est.)" of the processing progress bar. indicatif's own estimate averages over the
+// whole run so far, which is far too optimistic at the start: the first records are often all
+// duplicates and skipped quickly, the later ones are real SQLite inserts. Here the rate is measured
+// over the last WINDOW_BATCHES batches of BATCH_RECORDS records and the estimate recomputed at the
+// end of each batch, so it follows the slower phase within a few batches. The first estimate is
+// kept and compared with how long the rest of the run really took in the summary, to tune the
+// window. SQLite is committed once at the end of the run, which the estimate doesn't include.
+use crate::timefmt;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+pub const BATCH_RECORDS: u64 = 1_000;
+const WINDOW_BATCHES: usize = 10;
+
+pub struct Eta {
+    total: u64,
+    samples: VecDeque<(Instant, u64)>, // Time and position at the end of the recent batches
+    initial: Option<(Instant, u64, Duration)>, // When, after how many records and what was estimated
+}
+
+// The first estimate against the time the records after it really took, for the summary
+#[derive(Debug, Default, Clone)]
+pub struct EtaAccuracy {
+    pub after_records: u64,
+    pub estimated: Duration,
+    pub actual: Duration,
+}
+
+impl Eta {
+    // `position` is where the run starts, e.g. after the records skipped with --state-file
+    pub fn new(total: u64, position: u64) -> Eta {
+        Eta::starting_at(Instant::now(), total, position)
+    }
+
+    fn starting_at(now: Instant, total: u64, position: u64) -> Eta {
+        Eta {
+            total,
+            samples: VecDeque::from([(now, position)]),
+            initial: None,
+        }
+    }
+
+    // Called after every record, a new estimate at the end of each batch
+    pub fn record(&mut self, position: u64) -> Option<Duration> {
+        if !position.is_multiple_of(BATCH_RECORDS) {
+            return None;
+        }
+        self.sample(Instant::now(), position)
+    }
+
+    fn sample(&mut self, now: Instant, position: u64) -> Option<Duration> {
+        self.samples.push_back((now, position));
+        if self.samples.len() > WINDOW_BATCHES + 1 {
+            self.samples.pop_front();
+        }
+        let (since, from) = *self.samples.front()?;
+        let elapsed = now.duration_since(since).as_secs_f64();
+        let done = position.saturating_sub(from);
+        if done == 0 || elapsed <= 0.0 {
+            return None;
+        }
+        let remaining = Duration::from_secs_f64(
+            self.total.saturating_sub(position) as f64 * elapsed / done as f64,
+        );
+        self.initial.get_or_insert((now, position, remaining));
+        Some(remaining)
+    }
+
+    // None when the run ended before the first estimate
+    pub fn accuracy(&self) -> Option<EtaAccuracy> {
+        self.accuracy_at(Instant::now())
+    }
+
+    fn accuracy_at(&self, now: Instant) -> Option<EtaAccuracy> {
+        let (at, after_records, estimated) = self.initial?;
+        Some(EtaAccuracy {
+            after_records,
+            estimated,
+            actual: now.duration_since(at),
+        })
+    }
+}
+
+impl EtaAccuracy {
+    // How far off the estimate was, e.g. "-35%" when the rest took longer than estimated
+    pub fn deviation(&self) -> String {
+        let actual = self.actual.as_secs_f64();
+        if timefmt::stable_timestamps() || actual <= 0.0 {
+            return "+0%".to_string();
+        }
+        format!(
+            "{:+.0}%",
+            (self.estimated.as_secs_f64() - actual) / actual * 100.0
+        )
+    }
+}
+
+// Like indicatif's {elapsed_precise}, so both read alike in the bar
+pub fn format_precise(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    format!(
+        "{:02}:{:02}:{:02}",
+        secs / 3600,
+        (secs % 3600) / 60,
+        secs % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn follows_the_recent_rate_and_keeps_the_first_estimate() {
+        let start = Instant::now();
+        let mut eta = Eta::starting_at(start, 100_000, 0);
+        // Fast batches first: 1,000 records a second, so 99 seconds for the other 99,000
+        let first = eta.sample(start + Duration::from_secs(1), 1_000).unwrap();
+        assert_eq!(first.as_secs(), 99);
+        for batch in 2..=5 {
+            eta.sample(start + Duration::from_secs(batch), batch * 1_000);
+        }
+        // Then ten times slower, and once the window holds only slow batches so is the estimate
+        let mut now = start + Duration::from_secs(5);
+        let mut remaining = Duration::ZERO;
+        for batch in 6..=(5 + WINDOW_BATCHES as u64) {
+            now += Duration::from_secs(10);
+            remaining = eta.sample(now, batch * 1_000).unwrap();
+        }
+        assert_eq!(remaining.as_secs(), 85 * 10);
+
+        let accuracy = eta.accuracy_at(now).unwrap();
+        assert_eq!(accuracy.after_records, 1_000);
+        assert_eq!(accuracy.estimated.as_secs(), 99);
+        assert_eq!(accuracy.actual.as_secs(), 104);
+        assert_eq!(accuracy.deviation(), "-5%");
+    }
+
+    #[test]
+    fn no_estimate_before_the_first_batch() {
+        let mut eta = Eta::new(10, 0);
+        assert_eq!(eta.record(5), None);
+        assert!(eta.accuracy().is_none());
+        assert_eq!(format_precise(Duration::from_secs(3_725)), "01:02:05");
+    }
+}
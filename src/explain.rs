@@ -0,0 +1,393 @@
+// `--explain <LINE>` (repeatable): a step by step account of what happened to single lines of the
+// input, for "why is this row missing/wrong in the output" questions. Each explained line shows its
+// raw bytes, the parsed fields, every filter and rule with its outcome and what it changed, the map
+// lookups (user, item, device), the line written and, for SQLite, the duplicate check with the
+// existing row it matched. The traces are collected while the input is processed, so they show
+// what the run really did, and are printed at the end of the summary (and in --summary-line).
+// A dry run doesn't open SQLite, the duplicate check needs --dry-run-with-db.
+use crate::mapping::MappingDirection;
+use crate::sinks::SinkSet;
+use crate::{output, Config, ProcessingStats, TsvRecord};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fs;
+use std::io::{BufRead, BufReader};
+use tracing::{info, warn};
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Trace {
+    pub line: u64,
+    pub steps: Vec<String>,
+    #[serde(skip)]
+    last: Option<TsvRecord>, // As of the previous step, to show what each step changed
+}
+
+#[derive(Debug, Default)]
+pub struct Explainer {
+    pending: BTreeMap<u64, Vec<u8>>, // Requested line -> its raw bytes, until it is read
+    beyond_end: Vec<u64>,            // Requested lines the input doesn't have
+    input_lines: u64,
+    columns: Vec<usize>, // Written by the TSV output
+    dry_run: bool,
+    current: Option<Trace>, // Of the line being processed
+    done: Vec<Trace>,
+}
+
+impl Explainer {
+    // Reads the requested lines' raw bytes up front, the csv reader only hands out parsed fields.
+    // Reading stops at the highest requested line, so the input is only read to its end (and its
+    // lines counted) when a requested line is past it.
+    pub fn new(config: &Config, dry_run: bool) -> Result<Explainer, Box<dyn Error + Send + Sync>> {
+        let Some(&last) = config.explain_lines.iter().max() else {
+            return Ok(Explainer::default());
+        };
+        let mut pending = BTreeMap::new();
+        let mut reader = BufReader::new(fs::File::open(&config.input_tsv_file_path)?);
+        let mut input_lines = 0;
+        let mut raw = Vec::new();
+        while input_lines < last && reader.read_until(b'\n', &mut raw)? > 0 {
+            input_lines += 1;
+            if config.explain_lines.contains(&input_lines) {
+                if raw.last() == Some(&b'\n') {
+                    raw.pop();
+                }
+                pending.insert(input_lines, raw.clone());
+            }
+            raw.clear();
+        }
+        Ok(Explainer {
+            beyond_end: config
+                .explain_lines
+                .iter()
+                .copied()
+                .filter(|line| !pending.contains_key(line))
+                .collect(),
+            pending,
+            input_lines,
+            columns: output::selected_columns(config)?,
+            dry_run,
+            current: None,
+            done: Vec::new(),
+        })
+    }
+
+    // Called for every record read, starts a trace if `line` was requested
+    pub fn start(&mut self, line: u64) {
+        self.done.extend(self.current.take());
+        if let Some(raw) = self.pending.remove(&line) {
+            self.current = Some(Trace {
+                line,
+                steps: vec![raw_step(&raw)],
+                last: None,
+            });
+        }
+    }
+
+    // A record the run reads without processing it, e.g. one done by the run that wrote the
+    // --state-file
+    pub fn skipped(&mut self, line: u64, reason: &str) {
+        if let Some(raw) = self.pending.remove(&line) {
+            self.done.push(Trace {
+                line,
+                steps: vec![raw_step(&raw), reason.to_string()],
+                last: None,
+            });
+        }
+    }
+
+    pub fn parsed(&mut self, result: &Result<TsvRecord, csv::Error>) {
+        let Some(trace) = self.current.as_mut() else {
+            return;
+        };
+        match result {
+            Ok(record) => {
+                trace
+                    .steps
+                    .push(format!("Parsed fields: {}", describe_fields(record)));
+                trace.last = Some(record.clone());
+            }
+            Err(e) => {
+                trace
+                    .steps
+                    .push(format!("Not parsed: {}. The run stops here.", e));
+                // The run fails, so there is no summary to print it in
+                for line in trace.lines() {
+                    warn!("{}", line);
+                }
+            }
+        }
+    }
+
+    // Records a filter or rule's outcome and what it changed, returning `kept`
+    pub fn rule(&mut self, name: &str, kept: bool, record: &TsvRecord) -> bool {
+        if let Some(trace) = self.current.as_mut() {
+            let outcome = if kept { "kept" } else { "dropped" };
+            let changes = trace.changes(record);
+            trace
+                .steps
+                .push(format!("{}: {}{}", name, outcome, changes));
+        }
+        kept
+    }
+
+    // Before transform_record, the lookups it is about to make
+    pub fn lookups(
+        &mut self,
+        config: &Config,
+        user_id_map: &HashMap<String, String>,
+        record: &TsvRecord,
+    ) {
+        let Some(trace) = self.current.as_mut() else {
+            return;
+        };
+        let user = if config.split_plan.splits(&record.user_id) {
+            "split by date ([[split_user]]), the user map isn't used".to_string()
+        } else if config.mapping_direction == MappingDirection::Auto
+            && user_id_map.values().any(|new_id| *new_id == record.user_id)
+        {
+            "already belongs to the new instance, kept".to_string()
+        } else {
+            match user_id_map.get(&record.user_id) {
+                Some(new_id) => format!("found, -> '{}'", new_id),
+                None => format!(
+                    "not found, unmapped_user_policy = \"{}\"",
+                    config.unmapped_user_policy.name()
+                ),
+            }
+        };
+        trace.steps.push(format!(
+            "User map lookup of UserId '{}': {}",
+            record.user_id, user
+        ));
+        let item = match config.item_id_map.lookup(&record.item_id) {
+            None => "not made, map_item_ids is off".to_string(),
+            Some(Some(new_id)) => format!("found, -> '{}'", new_id),
+            Some(None) => "not found, kept".to_string(),
+        };
+        trace.steps.push(format!(
+            "Item map lookup of ItemId '{}': {}",
+            record.item_id, item
+        ));
+        if !config.device_name_map.is_empty() {
+            let device = match config.device_name_map.get(&record.device_name) {
+                Some(new_name) => format!("found, -> '{}'", new_name),
+                None => "not found, kept".to_string(),
+            };
+            trace.steps.push(format!(
+                "device_name_map lookup of DeviceName '{}': {}",
+                record.device_name, device
+            ));
+        }
+    }
+
+    pub fn transformed(&mut self, result: &Result<bool, String>, record: &TsvRecord) {
+        match result {
+            Ok(kept) => {
+                self.rule("Mapping and normalization", *kept, record);
+            }
+            Err(e) => {
+                if let Some(trace) = self.current.as_mut() {
+                    trace.steps.push(format!("Mapping failed: {}", e));
+                    for line in trace.lines() {
+                        warn!("{}", line);
+                    }
+                }
+            }
+        }
+    }
+
+    // The record goes to a later stage instead of straight to the outputs
+    pub fn held(&mut self, reason: &str) {
+        if let Some(trace) = self.current.as_mut() {
+            trace.steps.push(reason.to_string());
+        }
+    }
+
+    // Before the record is written: the line it becomes and what each output will do with it
+    pub fn output(&mut self, record: &TsvRecord, sinks: &SinkSet) {
+        let Some(trace) = self.current.as_mut() else {
+            return;
+        };
+        match output::format_line(&self.columns, record) {
+            Ok(line) => trace
+                .steps
+                .push(format!("Output line: {}", line.as_bytes().escape_ascii())),
+            Err(e) => trace
+                .steps
+                .push(format!("Output line couldn't be formatted: {}", e)),
+        }
+        let decisions = sinks.explain_write(record);
+        if decisions.is_empty() {
+            trace.steps.push(if self.dry_run {
+                "Not written: no output is opened in a dry run, use --dry-run-with-db to see SQLite's duplicate check".to_string()
+            } else {
+                "Not written: no output is configured".to_string()
+            });
+        }
+        trace.steps.extend(decisions);
+    }
+
+    // The lines that weren't read get a trace saying why, in the order they were asked for
+    pub fn finish(mut self, stats: &mut ProcessingStats) {
+        self.done.extend(self.current.take());
+        for (line, raw) in self.pending {
+            let reason = if raw.first() == Some(&b'#') {
+                "a comment, skipped"
+            } else if raw.trim_ascii().is_empty() {
+                "empty, skipped"
+            } else {
+                "not processed: the run stopped before it"
+            };
+            self.done.push(Trace {
+                line,
+                steps: vec![raw_step(&raw), format!("The line is {}", reason)],
+                last: None,
+            });
+        }
+        for line in self.beyond_end {
+            self.done.push(Trace {
+                line,
+                steps: vec![format!("The input only has {} lines", self.input_lines)],
+                last: None,
+            });
+        }
+        self.done.sort_by_key(|trace| trace.line);
+        stats.explained = self.done;
+    }
+}
+
+impl Trace {
+    // e.g. ", DateCreated '2037-01-01 10:00:00' -> '2024-06-01 12:00:00'", empty when unchanged
+    fn changes(&mut self, record: &TsvRecord) -> String {
+        let Some(last) = self.last.replace(record.clone()) else {
+            return String::new();
+        };
+        let names = crate::schema::column_names();
+        last.fields()
+            .iter()
+            .zip(record.fields())
+            .zip(names)
+            .filter(|((before, after), _)| *before != after)
+            .map(|((before, after), name)| format!(", {} '{}' -> '{}'", name, before, after))
+            .collect()
+    }
+
+    fn lines(&self) -> impl Iterator<Item = String> + '_ {
+        std::iter::once(format!("  Line {}:", self.line))
+            .chain(self.steps.iter().map(|step| format!("    {}", step)))
+    }
+}
+
+fn raw_step(raw: &[u8]) -> String {
+    format!("Raw line ({} bytes): {}", raw.len(), raw.escape_ascii())
+}
+
+fn describe_fields(record: &TsvRecord) -> String {
+    crate::schema::column_names()
+        .iter()
+        .zip(record.fields())
+        .map(|(name, value)| format!("{} '{}'", name, value))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+pub fn print_summary(traces: &[Trace]) {
+    if traces.is_empty() {
+        return;
+    }
+    info!("  Explained lines (--explain):");
+    for trace in traces {
+        for line in trace.lines() {
+            info!("  {}", line);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config_from_toml;
+
+    #[test]
+    fn traces_requested_lines_and_explains_the_ones_never_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("in.tsv");
+        fs::write(
+            &input,
+            "# jpm-schema: v1\n2024-01-01 10:00:00\told-a\titem\tMovie\tFilm\tDirectPlay\tWeb\tTV\t60\n",
+        )
+        .unwrap();
+        let mut config =
+            config_from_toml(&format!("input_tsv_file_path = \"{}\"", input.display()));
+        config.explain_lines = vec![2, 1, 9];
+        let mut explainer = Explainer::new(&config, true).unwrap();
+
+        explainer.start(2);
+        let mut record = csv::StringRecord::from(
+            "2024-01-01 10:00:00\told-a\titem\tMovie\tFilm\tDirectPlay\tWeb\tTV\t60"
+                .split('\t')
+                .collect::<Vec<_>>(),
+        )
+        .deserialize::<TsvRecord>(None);
+        explainer.parsed(&record);
+        let record = record.as_mut().unwrap();
+        record.date_created = "2024-01-01T10:00:00".to_string();
+        assert!(explainer.rule("Future DateCreated", true, record));
+        explainer.output(record, &SinkSet::default());
+        let mut stats = ProcessingStats::default();
+        explainer.finish(&mut stats);
+
+        let lines: Vec<u64> = stats.explained.iter().map(|trace| trace.line).collect();
+        assert_eq!(lines, [1, 2, 9]);
+        assert_eq!(
+            stats.explained[0].steps[1],
+            "The line is a comment, skipped"
+        );
+        let steps = &stats.explained[1].steps;
+        assert_eq!(
+            steps[0],
+            "Raw line (62 bytes): 2024-01-01 10:00:00\\told-a\\titem\\tMovie\\tFilm\\tDirectPlay\\tWeb\\tTV\\t60"
+        );
+        assert!(steps[1]
+            .starts_with("Parsed fields: DateCreated '2024-01-01 10:00:00', UserId 'old-a'"));
+        assert_eq!(
+            steps[2],
+            "Future DateCreated: kept, DateCreated '2024-01-01 10:00:00' -> '2024-01-01T10:00:00'"
+        );
+        assert!(steps[3].starts_with("Output line: 2024-01-01T10:00:00\\told-a\\t"));
+        assert!(steps[4].starts_with("Not written: no output is opened in a dry run"));
+        assert_eq!(stats.explained[2].steps, ["The input only has 2 lines"]);
+    }
+
+    #[test]
+    fn reads_the_input_only_up_to_the_highest_requested_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("in.tsv");
+        fs::write(&input, "a\nb\nc\nd\ne\n").unwrap();
+        let mut config =
+            config_from_toml(&format!("input_tsv_file_path = \"{}\"", input.display()));
+        config.explain_lines = vec![1, 3];
+        let explainer = Explainer::new(&config, false).unwrap();
+        assert_eq!(explainer.input_lines, 3);
+        assert!(explainer.beyond_end.is_empty());
+
+        config.explain_lines = vec![3, 7];
+        let mut explainer = Explainer::new(&config, false).unwrap();
+        assert_eq!(explainer.input_lines, 5);
+        explainer.skipped(
+            3,
+            "The line was done by the run that wrote the --state-file, skipped",
+        );
+        let mut stats = ProcessingStats::default();
+        explainer.finish(&mut stats);
+        assert_eq!(
+            stats.explained[0].steps,
+            [
+                "Raw line (1 bytes): c",
+                "The line was done by the run that wrote the --state-file, skipped"
+            ]
+        );
+        assert_eq!(stats.explained[1].steps, ["The input only has 5 lines"]);
+    }
+}
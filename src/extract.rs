@@ -0,0 +1,514 @@
+// input_source = "old_instance": instead of reading an export made by hand, the playback history is
+// pulled from the old instance through the PlaybackReporting plugin's custom query endpoint and
+// written to input_tsv_file_path, which the run then processes as usual. One query for years of
+// history times out on slow servers, so the range is found with a MIN/MAX query first and then
+// pulled in windows of extract_window_days. Each window is retried on its own with backoff. Its
+// response is parsed while it arrives and every row is appended to the file as soon as it has been
+// parsed, so memory stays flat however large a window is. A failed attempt is cut off the file
+// again before the retry. A window that keeps failing is skipped and listed at the end, or stops
+// the run with --strict-extraction.
+use crate::api::{ApiClient, MissingRecording};
+use crate::logging;
+use crate::retention::parse_date_created;
+use crate::Config;
+use chrono::{Duration as ChronoDuration, NaiveDateTime};
+use indicatif::{ProgressBar, ProgressStyle};
+use serde::de::{self, DeserializeSeed, Deserializer, IgnoredAny, MapAccess, SeqAccess, Visitor};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::future::Future;
+use std::io::{Read, Seek, SeekFrom};
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+const QUERY_PATH: &str = "/user_usage_stats/submit_custom_query";
+const TABLE: &str = "PlaybackActivity";
+const COLUMNS: &str =
+    "DateCreated, UserId, ItemId, ItemType, ItemName, PlaybackMethod, ClientName, DeviceName, PlayDuration";
+// Doubled after every failed attempt of a window
+const RETRY_BACKOFF: Duration = Duration::from_secs(2);
+
+// Where the records come from
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum InputSource {
+    #[default]
+    Tsv,
+    OldInstance,
+}
+
+// The parts of the plugin's response that are used (the column names, as "colums", aren't)
+#[derive(Deserialize)]
+struct QueryResult {
+    #[serde(default)]
+    results: Vec<Vec<Value>>,
+    #[serde(default)]
+    message: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Window {
+    start: NaiveDateTime,
+    end: NaiveDateTime, // Exclusive
+}
+
+impl fmt::Display for Window {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} to {}",
+            self.start.format("%Y-%m-%d %H:%M:%S"),
+            self.end.format("%Y-%m-%d %H:%M:%S")
+        )
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ExtractionReport {
+    pub rows: u64,
+    pub windows: usize,
+    pub skipped: Vec<(String, String)>, // (window range, last error)
+}
+
+impl ExtractionReport {
+    pub fn print(&self, config: &Config) {
+        info!(
+            "Extracted {} rows from the old instance in {} window(s) of {} days.",
+            self.rows, self.windows, config.extract_window_days
+        );
+        if !self.skipped.is_empty() {
+            error!(
+                "\n!!! {} window(s) failed after {} attempts and were skipped, their plays are NOT in the input:",
+                self.skipped.len(),
+                config.extract_max_attempts
+            );
+            for (range, error) in &self.skipped {
+                error!("  {}: {}", range, error);
+            }
+            error!("Run again later (or with a smaller extract_window_days) to fetch them.");
+        }
+    }
+}
+
+pub async fn extract_to_tsv(
+    config: &Config,
+    api: &ApiClient,
+    strict: bool,
+) -> Result<ExtractionReport, Box<dyn Error + Send + Sync>> {
+    extract_with_backoff(config, api, strict, RETRY_BACKOFF).await
+}
+
+async fn extract_with_backoff(
+    config: &Config,
+    api: &ApiClient,
+    strict: bool,
+    backoff: Duration,
+) -> Result<ExtractionReport, Box<dyn Error + Send + Sync>> {
+    info!(
+        "\nExtracting playback history from the old instance into '{}'...",
+        config.input_tsv_file_path
+    );
+    let mut report = ExtractionReport::default();
+    let bracket_sql = format!("SELECT MIN(DateCreated), MAX(DateCreated) FROM {}", TABLE);
+    let bracket = with_retries(config, backoff, || query(config, api, &bracket_sql)).await?;
+    let bounds = bracket
+        .first()
+        .and_then(|row| Some((row.first()?.as_str(), row.get(1)?.as_str())));
+    // Only replaced once the old instance has answered
+    let file = File::create(&config.input_tsv_file_path)?;
+    let Some((min, max)) = bounds.filter(|(min, _)| !min.is_empty()) else {
+        info!("The old instance has no playback history.");
+        return Ok(report);
+    };
+    // Boundaries are compared as text, so they are written with the same date/time separator
+    let separator = if min.as_bytes().get(10) == Some(&b'T') {
+        'T'
+    } else {
+        ' '
+    };
+    let (Some(min), Some(max)) = (parse_date_created(min), parse_date_created(max)) else {
+        return Err(format!(
+            "Unexpected DateCreated range from the old instance: '{}' to '{}'",
+            min, max
+        )
+        .into());
+    };
+    let windows = windows(min, max, config.extract_window_days);
+    report.windows = windows.len();
+
+    let pb = ProgressBar::new(windows.len() as u64);
+    pb.set_style(
+        ProgressStyle::default_bar()
+            .template("Extracting [{bar:40.cyan/blue}] {pos}/{len} windows - {msg}")
+            .expect("valid template")
+            .progress_chars("#>-"),
+    );
+    let attached = logging::attach_progress_bar(&pb);
+    for window in windows {
+        let sql = format!(
+            "SELECT {} FROM {} WHERE DateCreated >= '{}' AND DateCreated < '{}' ORDER BY DateCreated",
+            COLUMNS,
+            TABLE,
+            window.start.format(&format!("%Y-%m-%d{}%H:%M:%S", separator)),
+            window.end.format(&format!("%Y-%m-%d{}%H:%M:%S", separator))
+        );
+        match with_retries(config, backoff, || stream_window(config, api, &sql, &file)).await {
+            Ok(rows) => report.rows += rows,
+            Err(e) if e.is::<MissingRecording>() => return Err(e),
+            Err(e) if strict => {
+                pb.abandon();
+                return Err(format!(
+                    "Extracting {} failed after {} attempts (--strict-extraction): {}",
+                    window, config.extract_max_attempts, e
+                )
+                .into());
+            }
+            Err(e) => report.skipped.push((window.to_string(), e.to_string())),
+        }
+        pb.inc(1);
+        pb.set_message(format!("{} rows", report.rows));
+    }
+    drop(attached);
+    pb.finish_and_clear();
+    Ok(report)
+}
+
+// Whole days from the day of the first record until after the last one
+fn windows(min: NaiveDateTime, max: NaiveDateTime, days: u64) -> Vec<Window> {
+    let length = ChronoDuration::days(days.max(1) as i64);
+    let mut start = min.date().and_time(Default::default());
+    let mut windows = Vec::new();
+    while start <= max {
+        windows.push(Window {
+            start,
+            end: start + length,
+        });
+        start += length;
+    }
+    windows
+}
+
+async fn with_retries<T, F, Fut>(
+    config: &Config,
+    backoff: Duration,
+    attempt_query: F,
+) -> Result<T, Box<dyn Error + Send + Sync>>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<T, Box<dyn Error + Send + Sync>>>,
+{
+    let attempts = config.extract_max_attempts.max(1);
+    let mut attempt = 1;
+    loop {
+        match attempt_query().await {
+            Ok(rows) => return Ok(rows),
+            Err(e) if e.is::<MissingRecording>() => return Err(e),
+            Err(e) if attempt < attempts => {
+                let wait = backoff * 2u32.pow(attempt - 1);
+                warn!(
+                    "Query failed (attempt {} of {}), retrying in {}s: {}",
+                    attempt,
+                    attempts,
+                    wait.as_secs(),
+                    e
+                );
+                tokio::time::sleep(wait).await;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+async fn query(
+    config: &Config,
+    api: &ApiClient,
+    sql: &str,
+) -> Result<Vec<Vec<String>>, Box<dyn Error + Send + Sync>> {
+    let body = json!({ "CustomQueryString": sql, "ReplaceUserId": false });
+    let result: QueryResult = api
+        .post_json(&config.instance_old, QUERY_PATH, &body)
+        .await?;
+    // SQL errors come back as a message with no results
+    if result.results.is_empty() && !result.message.is_empty() {
+        return Err(format!("the PlaybackReporting plugin answered: {}", result.message).into());
+    }
+    Ok(result
+        .results
+        .into_iter()
+        .map(|row| row.into_iter().map(cell).collect())
+        .collect())
+}
+
+// Appends the rows of the window's query to `file` while the response arrives and returns how
+// many there were. A failed attempt is cut off again, so the retry starts at the same place.
+async fn stream_window(
+    config: &Config,
+    api: &ApiClient,
+    sql: &str,
+    file: &File,
+) -> Result<u64, Box<dyn Error + Send + Sync>> {
+    let mut position = file;
+    let start = position.stream_position()?;
+    let output = file.try_clone()?;
+    let body = json!({ "CustomQueryString": sql, "ReplaceUserId": false });
+    let result = api
+        .post_json_streamed(&config.instance_old, QUERY_PATH, &body, move |reader| {
+            write_rows(reader, output)
+        })
+        .await;
+    if result.is_err() {
+        file.set_len(start)?;
+        position.seek(SeekFrom::Start(start))?;
+    }
+    result
+}
+
+// Parses the plugin's response from `reader`, writing each row of its results as a TSV line
+fn write_rows(reader: &mut dyn Read, output: File) -> Result<u64, String> {
+    let mut wtr = csv::WriterBuilder::new()
+        .delimiter(b'\t')
+        .has_headers(false)
+        .from_writer(output);
+    let mut rows = StreamedRows {
+        wtr: &mut wtr,
+        count: 0,
+        write_error: None,
+        message: String::new(),
+    };
+    let mut deserializer = serde_json::Deserializer::from_reader(reader);
+    let parsed = (&mut rows)
+        .deserialize(&mut deserializer)
+        .and_then(|()| deserializer.end());
+    if let Some(e) = rows.write_error {
+        return Err(format!(
+            "writing row {} to the input TSV failed: {}",
+            rows.count + 1,
+            e
+        ));
+    }
+    // serde_json names the line and column, the row says how far into the results that is
+    parsed.map_err(|e| format!("{} (after row {} of the results)", e, rows.count))?;
+    if rows.count == 0 && !rows.message.is_empty() {
+        return Err(format!(
+            "the PlaybackReporting plugin answered: {}",
+            rows.message
+        ));
+    }
+    let count = rows.count;
+    wtr.flush()
+        .map_err(|e| format!("writing the input TSV failed: {}", e))?;
+    Ok(count)
+}
+
+// Deserializes the plugin's response like QueryResult, but hands every row of "results" to the
+// writer as soon as it has been parsed instead of collecting them
+struct StreamedRows<'a> {
+    wtr: &'a mut csv::Writer<File>,
+    count: u64,
+    write_error: Option<csv::Error>,
+    message: String,
+}
+
+impl StreamedRows<'_> {
+    fn write(&mut self, row: Vec<Value>) -> Result<(), csv::Error> {
+        self.wtr.write_record(row.into_iter().map(cell))?;
+        self.count += 1;
+        Ok(())
+    }
+}
+
+impl<'de> DeserializeSeed<'de> for &mut StreamedRows<'_> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de> Visitor<'de> for &mut StreamedRows<'_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("the PlaybackReporting plugin's query result")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "results" => map.next_value_seed(Results(&mut *self))?,
+                "message" => self.message = map.next_value::<Option<String>>()?.unwrap_or_default(),
+                _ => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// The "results" array of StreamedRows
+struct Results<'a, 'b>(&'a mut StreamedRows<'b>);
+
+impl<'de> DeserializeSeed<'de> for Results<'_, '_> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de> Visitor<'de> for Results<'_, '_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("an array of rows")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        while let Some(row) = seq.next_element::<Vec<Value>>()? {
+            if let Err(e) = self.0.write(row) {
+                self.0.write_error = Some(e);
+                return Err(de::Error::custom("the row couldn't be written"));
+            }
+        }
+        Ok(())
+    }
+}
+
+// A JSON value as the text of a TSV field
+fn cell(value: Value) -> String {
+    match value {
+        Value::String(text) => text,
+        Value::Null => String::new(),
+        other => other.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::ApiRecording;
+    use crate::config_from_toml;
+    use reqwest::Client;
+    use std::fs;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, Request, Respond, ResponseTemplate};
+
+    // Serves one play per day of January 2024. The window starting on Jan 11 breaks off after two
+    // rows once, the one starting on Jan 21 always fails.
+    struct Plugin {
+        calls_jan_11: Arc<AtomicUsize>,
+    }
+
+    impl Respond for Plugin {
+        fn respond(&self, request: &Request) -> ResponseTemplate {
+            let body: Value = serde_json::from_slice(&request.body).unwrap();
+            let sql = body["CustomQueryString"].as_str().unwrap();
+            if sql.starts_with("SELECT MIN") {
+                return ResponseTemplate::new(200).set_body_json(json!({
+                    "colums": ["MIN", "MAX"],
+                    "results": [["2024-01-01 08:00:00.0000000", "2024-01-31 08:00:00.0000000"]],
+                    "message": ""
+                }));
+            }
+            let start = &sql[sql.find(">= '").unwrap() + 4..][..19];
+            let end = &sql[sql.find("< '").unwrap() + 3..][..19];
+            if start.starts_with("2024-01-21") {
+                return ResponseTemplate::new(500);
+            }
+            let broken_off = start.starts_with("2024-01-11")
+                && self.calls_jan_11.fetch_add(1, Ordering::SeqCst) == 0;
+            let rows: Vec<Value> = (1..=31)
+                .map(|day| format!("2024-01-{:02} 08:00:00.0000000", day))
+                .filter(|date| date.as_str() >= start && date.as_str() < end)
+                .map(|date| {
+                    json!([
+                        date,
+                        "u1",
+                        "i1",
+                        "Movie",
+                        "A",
+                        "DirectPlay",
+                        "Web",
+                        "TV",
+                        60
+                    ])
+                })
+                .collect();
+            let body = json!({"colums": [], "results": rows}).to_string();
+            if broken_off {
+                let second_row_end = body.match_indices("60]").nth(1).unwrap().0 + 3;
+                return ResponseTemplate::new(200).set_body_string(&body[..second_row_end]);
+            }
+            ResponseTemplate::new(200).set_body_string(body)
+        }
+    }
+
+    #[tokio::test]
+    async fn extracts_in_windows_retrying_and_skipping_failed_ones() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path(QUERY_PATH))
+            .respond_with(Plugin {
+                calls_jan_11: Arc::new(AtomicUsize::new(0)),
+            })
+            .mount(&server)
+            .await;
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input.tsv");
+        let config = config_from_toml(&format!(
+            "input_tsv_file_path = '{}'\ninput_source = 'old_instance'\nextract_window_days = 10\n\
+            [instance_old]\nbase_url = '{}'\napi_token = 'x'\nmax_retries = 0\n\
+            [instance_new]\nbase_url = 'http://new'\napi_token = 'y'\n",
+            input.display(),
+            server.uri()
+        ));
+        let api = ApiClient::new(Client::new(), ApiRecording::Off).unwrap();
+
+        let report = extract_with_backoff(&config, &api, false, Duration::ZERO)
+            .await
+            .unwrap();
+        // Jan 1-10, 11-20 (after a retry), 21-30 (skipped) and 31
+        assert_eq!(report.windows, 4);
+        assert_eq!(report.rows, 21);
+        assert_eq!(report.skipped.len(), 1);
+        assert!(report.skipped[0]
+            .0
+            .starts_with("2024-01-21 00:00:00 to 2024-01-31"));
+        // Without the two rows of the broken off attempt
+        let tsv = fs::read_to_string(&input).unwrap();
+        assert_eq!(tsv.lines().count(), 21);
+        assert_eq!(tsv.matches("2024-01-11 08").count(), 1);
+        assert_eq!(
+            tsv.lines().next().unwrap(),
+            "2024-01-01 08:00:00.0000000\tu1\ti1\tMovie\tA\tDirectPlay\tWeb\tTV\t60"
+        );
+
+        let error = extract_with_backoff(&config, &api, true, Duration::ZERO)
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("2024-01-21"), "{}", error);
+    }
+
+    #[test]
+    fn malformed_json_names_the_row_reached() {
+        let output = tempfile::tempfile().unwrap();
+        let body = r#"{"colums": [], "results": [["a", 1], ["b", 2], ["c" 3]]}"#;
+        let error = write_rows(&mut body.as_bytes(), output).unwrap_err();
+        assert!(error.ends_with("(after row 2 of the results)"), "{}", error);
+
+        let output = tempfile::tempfile().unwrap();
+        let body = r#"{"colums": [], "results": [], "message": "no such table"}"#;
+        let error = write_rows(&mut body.as_bytes(), output).unwrap_err();
+        assert_eq!(
+            error,
+            "the PlaybackReporting plugin answered: no such table"
+        );
+    }
+}
@@ -0,0 +1,151 @@
+// Format versions of the files this tool writes and later reads back: the --state-file, the API
+// recordings of --record-api/--replay-api and the user map of user_map_output_path/
+// user_map_input_path. JSON files carry a "version" field and the user map a "# jpm-user-map: vN"
+// first line. Files written before the versions were added count as version 1. Reading a file
+// older than `current` upgrades it (see each format for how), a file older than `oldest` can't be
+// upgraded and one newer than `current` was written by a newer binary; both are refused with the
+// file, the versions and how to get a file this binary can use, instead of being misread.
+use serde_json::Value;
+use std::path::Path;
+use tracing::info;
+
+pub struct FileFormat {
+    pub name: &'static str,
+    pub current: u32,
+    pub oldest: u32,              // Oldest version that can still be upgraded
+    pub regenerate: &'static str, // How to get a file in the current version
+}
+
+// Version 2 adds the version field, the state is unchanged. Older files are rewritten in place.
+pub const STATE_FILE: FileFormat = FileFormat {
+    name: "State file",
+    current: 2,
+    oldest: 1,
+    regenerate: "remove it to process the input from the beginning",
+};
+
+// Version 2 adds the version field. Older files are read as they are and never rewritten, since
+// recordings are often kept as test fixtures.
+pub const API_RECORDING: FileFormat = FileFormat {
+    name: "API recording",
+    current: 2,
+    oldest: 1,
+    regenerate: "record the responses again with --record-api",
+};
+
+// Version 2 adds the "# jpm-user-map: v2" line, the columns are unchanged
+pub const USER_MAP: FileFormat = FileFormat {
+    name: "User map",
+    current: 2,
+    oldest: 1,
+    regenerate: "write it again with user_map_output_path",
+};
+
+pub const USER_MAP_STAMP_PREFIX: &str = "# jpm-user-map: v";
+
+impl FileFormat {
+    // Ok(true) when the file is older than the current version and has to be upgraded
+    pub fn check(&self, path: &Path, found: u32) -> Result<bool, String> {
+        if found > self.current {
+            return Err(format!(
+                "{} '{}' has format version {}, but this version of jellyfin_pr_migration ({}) only reads up to version {}. Run it with the release that wrote the file or a newer one, or {}.",
+                self.name,
+                path.display(),
+                found,
+                env!("CARGO_PKG_VERSION"),
+                self.current,
+                self.regenerate
+            ));
+        }
+        if found < self.oldest {
+            return Err(format!(
+                "{} '{}' has format version {}, which can't be upgraded to version {} any more. {}.",
+                self.name,
+                path.display(),
+                found,
+                self.current,
+                capitalize(self.regenerate)
+            ));
+        }
+        Ok(found < self.current)
+    }
+
+    pub fn print_upgrade(&self, path: &Path, found: u32, rewritten: bool) {
+        info!(
+            "{} '{}' had format version {} and {} version {}.",
+            self.name,
+            path.display(),
+            found,
+            if rewritten {
+                "was upgraded in place to"
+            } else {
+                "is read as"
+            },
+            self.current
+        );
+    }
+}
+
+// The "version" field of a JSON file, 1 when it has none
+pub fn json_version(value: &Value) -> Result<u32, String> {
+    match value.get("version") {
+        None => Ok(1),
+        Some(version) => version
+            .as_u64()
+            .and_then(|version| u32::try_from(version).ok())
+            .ok_or_else(|| format!("\"version\" must be a whole number, got {}", version)),
+    }
+}
+
+// The version of a "# jpm-user-map: vN" line, None for any other line
+pub fn user_map_version(line: &str) -> Option<Result<u32, String>> {
+    let version = line.strip_prefix(USER_MAP_STAMP_PREFIX)?;
+    Some(
+        version
+            .trim()
+            .parse()
+            .map_err(|_| format!("'{}' isn't a version", version.trim())),
+    )
+}
+
+fn capitalize(text: &str) -> String {
+    let mut chars = text.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_versions() {
+        let path = Path::new("state.json");
+        assert_eq!(STATE_FILE.check(path, 2), Ok(false));
+        assert_eq!(STATE_FILE.check(path, 1), Ok(true));
+        let error = STATE_FILE.check(path, 3).unwrap_err();
+        assert!(
+            error.starts_with("State file 'state.json' has format version 3, but this version of jellyfin_pr_migration"),
+            "{}",
+            error
+        );
+        assert!(error.ends_with("or remove it to process the input from the beginning."));
+        let retired = FileFormat {
+            oldest: 2,
+            ..STATE_FILE
+        };
+        assert_eq!(
+            retired.check(path, 1).unwrap_err(),
+            "State file 'state.json' has format version 1, which can't be upgraded to version 2 any more. Remove it to process the input from the beginning."
+        );
+
+        assert_eq!(json_version(&serde_json::json!({"a": 1})), Ok(1));
+        assert_eq!(json_version(&serde_json::json!({"version": 7})), Ok(7));
+        assert!(json_version(&serde_json::json!({"version": "2"})).is_err());
+        assert_eq!(user_map_version("# jpm-user-map: v2"), Some(Ok(2)));
+        assert!(user_map_version("# jpm-user-map: vX").unwrap().is_err());
+        assert_eq!(user_map_version("# reviewed"), None);
+    }
+}
@@ -0,0 +1,325 @@
+// Records whose DateCreated is later than the run's start plus future_date_slack_hours, e.g. a
+// cluster dated 2037 written by an old server with a wrong clock, which would stay at the top of
+// the plugin's "last N days" views forever. future_date_policy decides what happens to them:
+// "keep" (the default) writes them unchanged, "reject" drops them and "clamp_to_now" sets their
+// DateCreated to the run's start. They are counted and the furthest ahead listed in the summary
+// either way. There is no separate timezone detection: the summary's hint goes by how far ahead
+// the furthest record is. Within the largest UTC offset (14 hours) it looks like local times
+// taken for UTC, further ahead like a wrong clock, shown with the old instance's clock skew.
+use crate::clock::{self, ClockSkew};
+use crate::retention::parse_date_created;
+use crate::{Config, ProcessingStats, TsvRecord};
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use serde::Deserialize;
+use tracing::{debug, info};
+
+// How many of the furthest ahead records the summary lists
+const MAX_WORST_SHOWN: usize = 5;
+// UTC+14 (Line Islands), the furthest a local time can be ahead of UTC
+const MAX_UTC_OFFSET_HOURS: i64 = 14;
+// 100 years, well past any clock error while far from chrono's date range
+const MAX_SLACK_HOURS: u64 = 100 * 366 * 24;
+
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FutureDatePolicy {
+    #[default]
+    Keep,
+    Reject,     // Drops the record
+    ClampToNow, // DateCreated becomes the run's start
+}
+
+impl FutureDatePolicy {
+    pub fn name(self) -> &'static str {
+        match self {
+            FutureDatePolicy::Keep => "keep",
+            FutureDatePolicy::Reject => "reject",
+            FutureDatePolicy::ClampToNow => "clamp_to_now",
+        }
+    }
+}
+
+pub fn default_future_date_slack_hours() -> u64 {
+    24
+}
+
+pub fn validate(config: &Config) -> Result<(), String> {
+    if config.future_date_slack_hours > MAX_SLACK_HOURS {
+        return Err(format!(
+            "future_date_slack_hours is {}, it can be at most {} (100 years). To write records dated in the future unchanged, use future_date_policy = \"keep\".",
+            config.future_date_slack_hours, MAX_SLACK_HOURS
+        ));
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+pub struct FutureDates {
+    now: NaiveDateTime,
+    limit: Option<NaiveDateTime>, // Later DateCreated values are in the future, None past chrono's range
+    policy: FutureDatePolicy,
+}
+
+#[derive(Debug, Default)]
+pub struct FutureDateStats {
+    pub records: u64, // Past the limit, whatever the policy did with them
+    pub records_rejected: u64,
+    pub records_clamped: u64,
+    run_started: Option<NaiveDateTime>,
+    // (DateCreated, UserId, ItemName) of the furthest ahead records, latest first
+    worst: Vec<(NaiveDateTime, String, String)>,
+}
+
+impl FutureDates {
+    // `now` is the run's start, watch mode passes the start of each batch
+    pub fn new(config: &Config, now: DateTime<Utc>) -> FutureDates {
+        let now = now.naive_utc();
+        // validate() bounds the slack, this only guards against a config that skipped it
+        let slack = i64::try_from(config.future_date_slack_hours)
+            .ok()
+            .and_then(Duration::try_hours);
+        FutureDates {
+            now,
+            limit: slack.and_then(|slack| now.checked_add_signed(slack)),
+            policy: config.future_date_policy,
+        }
+    }
+
+    // Returns false if the record is rejected. Records with a DateCreated that can't be parsed
+    // are always kept.
+    pub fn apply(&self, record: &mut TsvRecord, stats: &mut ProcessingStats) -> bool {
+        let Some(created) = parse_date_created(&record.date_created) else {
+            return true;
+        };
+        if self.limit.is_none_or(|limit| created <= limit) {
+            return true;
+        }
+        stats.future_dates.count(self.now, created, record);
+        debug!(
+            "Record {} '{}' of UserId '{}' is in the future, future_date_policy = \"{}\"",
+            record.date_created,
+            record.item_name,
+            record.user_id,
+            self.policy.name()
+        );
+        match self.policy {
+            FutureDatePolicy::Keep => true,
+            FutureDatePolicy::Reject => {
+                stats.future_dates.records_rejected += 1;
+                false
+            }
+            FutureDatePolicy::ClampToNow => {
+                stats.future_dates.records_clamped += 1;
+                // Keeps the record's separator, the plugin has written both
+                let format = if record.date_created.contains('T') {
+                    "%Y-%m-%dT%H:%M:%S"
+                } else {
+                    "%Y-%m-%d %H:%M:%S"
+                };
+                record.date_created = self.now.format(format).to_string();
+                true
+            }
+        }
+    }
+}
+
+impl FutureDateStats {
+    fn count(&mut self, now: NaiveDateTime, created: NaiveDateTime, record: &TsvRecord) {
+        self.records += 1;
+        self.run_started.get_or_insert(now);
+        if self.worst.len() == MAX_WORST_SHOWN
+            && self.worst.last().is_some_and(|(date, ..)| *date >= created)
+        {
+            return;
+        }
+        let at = self.worst.partition_point(|(date, ..)| *date >= created);
+        self.worst.insert(
+            at,
+            (created, record.user_id.clone(), record.item_name.clone()),
+        );
+        self.worst.truncate(MAX_WORST_SHOWN);
+    }
+
+    pub fn print_summary(&self, config: &Config, clock_skews: &[ClockSkew]) {
+        if self.records == 0 {
+            return;
+        }
+        info!(
+            "  Records dated more than {}h after the run's start (future_date_policy = \"{}\"): {}",
+            config.future_date_slack_hours,
+            config.future_date_policy.name(),
+            self.records
+        );
+        match config.future_date_policy {
+            FutureDatePolicy::Keep => info!("    All were written unchanged."),
+            FutureDatePolicy::Reject => info!("    Rejected: {}", self.records_rejected),
+            FutureDatePolicy::ClampToNow => info!(
+                "    DateCreated set to the run's start: {}",
+                self.records_clamped
+            ),
+        }
+        info!("    Furthest ahead:");
+        for (created, user_id, item_name) in &self.worst {
+            info!("      {} (UserId '{}', '{}')", created, user_id, item_name);
+        }
+        if let Some(hint) = self.hint(clock_skews) {
+            info!("    {}", hint);
+        }
+    }
+
+    fn hint(&self, clock_skews: &[ClockSkew]) -> Option<String> {
+        let (furthest, ..) = self.worst.first()?;
+        let ahead = *furthest - self.run_started?;
+        if ahead <= Duration::hours(MAX_UTC_OFFSET_HOURS) {
+            return Some(format!(
+                "The furthest is {} ahead, within the largest UTC offset ({}h): this looks like a timezone issue, local times written or read as UTC, rather than a wrong clock. Check the timezone of the server that wrote them.",
+                describe_ahead(ahead),
+                MAX_UTC_OFFSET_HOURS
+            ));
+        }
+        let old_clock = clock_skews
+            .iter()
+            .find(|skew| skew.instance == "instance_old")
+            .map(|skew| match skew.skew_secs {
+                0 => " The clock of instance_old is in sync now, so it was probably off when they were written.".to_string(),
+                secs => format!(
+                    " The clock of instance_old is still {} compared to this machine.",
+                    clock::describe(secs)
+                ),
+            })
+            .unwrap_or_default();
+        Some(format!(
+            "The furthest is {} ahead, more than any timezone offset: this looks like a wrong clock on the server that wrote them rather than a timezone issue.{}",
+            describe_ahead(ahead),
+            old_clock
+        ))
+    }
+}
+
+// e.g. "9h 30m" or "4,752 days"
+fn describe_ahead(ahead: Duration) -> String {
+    if ahead >= Duration::days(2) {
+        return format!(
+            "{} days",
+            crate::display::format_count(ahead.num_days() as u64)
+        );
+    }
+    format!("{}h {}m", ahead.num_hours(), ahead.num_minutes() % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config_from_toml;
+    use chrono::TimeZone;
+
+    fn future_dates(policy: &str, now: DateTime<Utc>) -> FutureDates {
+        FutureDates::new(
+            &config_from_toml(&format!(
+                "input_tsv_file_path = \"in.tsv\"\nfuture_date_policy = \"{}\"",
+                policy
+            )),
+            now,
+        )
+    }
+
+    fn record(date_created: &str) -> TsvRecord {
+        TsvRecord {
+            date_created: date_created.to_string(),
+            user_id: "u1".to_string(),
+            item_id: "item".to_string(),
+            item_type: "Movie".to_string(),
+            item_name: "Film".to_string(),
+            playback_method: "DirectPlay".to_string(),
+            client_name: "Web".to_string(),
+            device_name: "TV".to_string(),
+            play_duration: "60".to_string(),
+        }
+    }
+
+    #[test]
+    fn rejects_or_clamps_records_past_the_slack_and_lists_the_furthest() {
+        let now = Utc.with_ymd_and_hms(2024, 6, 1, 12, 0, 0).unwrap();
+        let mut stats = ProcessingStats::default();
+        let reject = future_dates("reject", now);
+        // Within the default slack of a day
+        assert!(reject.apply(&mut record("2024-06-02 11:00:00"), &mut stats));
+        assert!(reject.apply(&mut record("not a date"), &mut stats));
+        for date in [
+            "2037-01-01 10:00:00",
+            "2024-06-03 10:00:00",
+            "2038-01-19 03:14:07",
+            "2037-05-01 10:00:00",
+            "2030-01-01 10:00:00",
+            "2031-01-01 10:00:00",
+        ] {
+            assert!(!reject.apply(&mut record(date), &mut stats));
+        }
+        let future = &stats.future_dates;
+        assert_eq!((future.records, future.records_rejected), (6, 6));
+        let worst: Vec<String> = future
+            .worst
+            .iter()
+            .map(|(date, ..)| date.to_string())
+            .collect();
+        assert_eq!(
+            worst,
+            [
+                "2038-01-19 03:14:07",
+                "2037-05-01 10:00:00",
+                "2037-01-01 10:00:00",
+                "2031-01-01 10:00:00",
+                "2030-01-01 10:00:00"
+            ]
+        );
+        let hint = future.hint(&[]).unwrap();
+        assert!(
+            hint.starts_with("The furthest is 4,979 days ahead"),
+            "{}",
+            hint
+        );
+        assert!(hint.contains("wrong clock"), "{}", hint);
+
+        let mut stats = ProcessingStats::default();
+        let mut clamped = record("2024-06-03T10:00:00.1234567");
+        assert!(future_dates("clamp_to_now", now).apply(&mut clamped, &mut stats));
+        assert_eq!(clamped.date_created, "2024-06-01T12:00:00");
+        assert_eq!(stats.future_dates.records_clamped, 1);
+        let mut kept = record("2037-01-01 10:00:00");
+        assert!(future_dates("keep", now).apply(&mut kept, &mut stats));
+        assert_eq!(kept.date_created, "2037-01-01 10:00:00");
+        assert_eq!(stats.future_dates.records, 2);
+    }
+
+    #[test]
+    fn hints_at_a_timezone_issue_within_the_largest_utc_offset() {
+        let now = Utc.with_ymd_and_hms(2024, 6, 1, 12, 0, 0).unwrap();
+        let mut config = config_from_toml("input_tsv_file_path = \"in.tsv\"");
+        config.future_date_slack_hours = 1;
+        let mut stats = ProcessingStats::default();
+        FutureDates::new(&config, now).apply(&mut record("2024-06-01 21:30:00"), &mut stats);
+        let hint = stats.future_dates.hint(&[]).unwrap();
+        assert!(hint.starts_with("The furthest is 9h 30m ahead"), "{}", hint);
+        assert!(hint.contains("timezone issue"), "{}", hint);
+    }
+
+    #[test]
+    fn a_slack_past_the_date_range_is_refused_and_never_panics() {
+        let now = Utc.with_ymd_and_hms(2024, 6, 1, 12, 0, 0).unwrap();
+        let mut config = config_from_toml("input_tsv_file_path = \"in.tsv\"");
+        config.future_date_slack_hours = MAX_SLACK_HOURS;
+        assert!(validate(&config).is_ok());
+        config.future_date_slack_hours = MAX_SLACK_HOURS + 1;
+        assert!(validate(&config)
+            .unwrap_err()
+            .starts_with("future_date_slack_hours is"));
+        // Past chrono's range without validation, no record counts as in the future
+        for slack in [i64::MAX as u64 / 3600, u64::MAX] {
+            config.future_date_slack_hours = slack;
+            let mut stats = ProcessingStats::default();
+            assert!(FutureDates::new(&config, now)
+                .apply(&mut record("9999-12-31 23:59:59"), &mut stats));
+            assert_eq!(stats.future_dates.records, 0);
+        }
+    }
+}
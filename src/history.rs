@@ -0,0 +1,333 @@
+// Audit trail in the destination database. Every committed SQLite run adds a row to
+// jpm_migrations and its full user map to jpm_user_map, in the same transaction as the records,
+// so "which new account did this old user become" can still be answered long after the report is
+// gone. The `history` subcommand prints them.
+use crate::sinks::SinkStats;
+use crate::timefmt;
+use crate::{Config, JellyfinUser};
+use chrono::SecondsFormat;
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension};
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::Path;
+
+const MIGRATIONS_TABLE: &str = "jpm_migrations";
+const USER_MAP_TABLE: &str = "jpm_user_map";
+
+// How an old user was paired with a new one
+const MATCHED_BY_NAME: &str = "name";
+const UNMATCHED: &str = "unmatched";
+const MERGED: &str = "merge"; // One of several old users merged into the new user, see merges.rs
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct UserMapEntry {
+    pub old_id: String,
+    pub old_name: String,
+    pub new_id: Option<String>,
+    pub new_name: Option<String>,
+    pub match_method: String,
+}
+
+// What is stored for a run, besides the counts known only when it commits
+#[derive(Debug, Clone)]
+pub struct RunAudit {
+    input_path: String,
+    old_base_url: String,
+    new_base_url: String,
+    users: Vec<UserMapEntry>,
+}
+
+impl RunAudit {
+    // Every old user is listed, the ones without a match with an empty new side
+    pub fn new(
+        config: &Config,
+        old_users: &[JellyfinUser],
+        new_users: &[JellyfinUser],
+        user_id_map: &HashMap<String, String>,
+    ) -> RunAudit {
+        let new_names: HashMap<&str, &str> = new_users
+            .iter()
+            .map(|user| (user.id.as_str(), user.name.as_str()))
+            .collect();
+        let users = old_users
+            .iter()
+            .map(|old_user| {
+                let new_id = user_id_map.get(&old_user.id);
+                UserMapEntry {
+                    old_id: old_user.id.clone(),
+                    old_name: old_user.name.clone(),
+                    new_id: new_id.cloned(),
+                    new_name: new_id
+                        .and_then(|id| new_names.get(id.as_str()))
+                        .map(|name| name.to_string()),
+                    match_method: if config.merge_plan.merges(&old_user.id) {
+                        MERGED
+                    } else if new_id.is_some() {
+                        MATCHED_BY_NAME
+                    } else {
+                        UNMATCHED
+                    }
+                    .to_string(),
+                }
+            })
+            .collect();
+        RunAudit {
+            input_path: config.input_tsv_file_path.clone(),
+            // Empty for a section left out with user_map_input_path
+            old_base_url: config.instance_old.base_url.clone(),
+            new_base_url: config.instance_new.base_url.clone(),
+            users,
+        }
+    }
+
+    // Called inside the run's open transaction, right before it commits
+    pub fn write(
+        &self,
+        conn: &Connection,
+        table_name: &str,
+        stats: SinkStats,
+    ) -> Result<(), rusqlite::Error> {
+        conn.execute_batch(&format!(
+            "CREATE TABLE IF NOT EXISTS {MIGRATIONS_TABLE} (
+                Id INTEGER PRIMARY KEY AUTOINCREMENT,
+                FinishedAt TEXT NOT NULL,
+                Host TEXT,
+                ToolVersion TEXT NOT NULL,
+                InputPath TEXT NOT NULL,
+                OldBaseUrl TEXT NOT NULL,
+                NewBaseUrl TEXT NOT NULL,
+                TableName TEXT NOT NULL,
+                RowsInserted INTEGER NOT NULL,
+                RowsSkipped INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS {USER_MAP_TABLE} (
+                MigrationId INTEGER NOT NULL REFERENCES {MIGRATIONS_TABLE}(Id),
+                OldUserId TEXT NOT NULL,
+                OldUserName TEXT NOT NULL,
+                NewUserId TEXT,
+                NewUserName TEXT,
+                MatchMethod TEXT NOT NULL
+            );"
+        ))?;
+        conn.execute(
+            &format!(
+                "INSERT INTO {MIGRATIONS_TABLE} (FinishedAt, Host, ToolVersion, InputPath, OldBaseUrl, NewBaseUrl, TableName, RowsInserted, RowsSkipped) \
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)"
+            ),
+            params![
+                timefmt::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+                gethostname::gethostname().to_str(),
+                env!("CARGO_PKG_VERSION"),
+                self.input_path,
+                self.old_base_url,
+                self.new_base_url,
+                table_name,
+                stats.written as i64,
+                stats.skipped as i64,
+            ],
+        )?;
+        let migration_id = conn.last_insert_rowid();
+        let mut insert = conn.prepare(&format!(
+            "INSERT INTO {USER_MAP_TABLE} (MigrationId, OldUserId, OldUserName, NewUserId, NewUserName, MatchMethod) \
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)"
+        ))?;
+        for user in &self.users {
+            insert.execute(params![
+                migration_id,
+                user.old_id,
+                user.old_name,
+                user.new_id,
+                user.new_name,
+                user.match_method,
+            ])?;
+        }
+        Ok(())
+    }
+}
+
+struct PastRun {
+    id: i64,
+    finished_at: String,
+    host: Option<String>,
+    tool_version: String,
+    input_path: String,
+    old_base_url: String,
+    new_base_url: String,
+    table_name: String,
+    rows_inserted: i64,
+    rows_skipped: i64,
+}
+
+// `history <db>`: prints every recorded run, oldest first, with its user map
+pub fn print_history(db_path: &Path) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if !db_path.is_file() {
+        return Err(format!("'{}' does not exist.", db_path.display()).into());
+    }
+    let conn = Connection::open_with_flags(db_path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let has_history = conn
+        .query_row(
+            "SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1",
+            [MIGRATIONS_TABLE],
+            |_| Ok(()),
+        )
+        .optional()?
+        .is_some();
+    if !has_history {
+        println!(
+            "No migration history in '{}' (no {} table).",
+            db_path.display(),
+            MIGRATIONS_TABLE
+        );
+        return Ok(());
+    }
+
+    let runs: Vec<PastRun> = conn
+        .prepare(&format!(
+            "SELECT Id, FinishedAt, Host, ToolVersion, InputPath, OldBaseUrl, NewBaseUrl, TableName, RowsInserted, RowsSkipped \
+             FROM {MIGRATIONS_TABLE} ORDER BY Id"
+        ))?
+        .query_map([], |row| {
+            Ok(PastRun {
+                id: row.get(0)?,
+                finished_at: row.get(1)?,
+                host: row.get(2)?,
+                tool_version: row.get(3)?,
+                input_path: row.get(4)?,
+                old_base_url: row.get(5)?,
+                new_base_url: row.get(6)?,
+                table_name: row.get(7)?,
+                rows_inserted: row.get(8)?,
+                rows_skipped: row.get(9)?,
+            })
+        })?
+        .collect::<Result<_, _>>()?;
+    println!(
+        "{} recorded migration run(s) in '{}':",
+        runs.len(),
+        db_path.display()
+    );
+    for run in runs {
+        println!(
+            "\nRun {} finished {} on {} (version {})",
+            run.id,
+            run.finished_at,
+            run.host.as_deref().unwrap_or("unknown host"),
+            run.tool_version
+        );
+        println!("  Input:  {}", run.input_path);
+        println!("  From:   {} -> {}", run.old_base_url, run.new_base_url);
+        println!(
+            "  Table:  {} ({} inserted, {} skipped as duplicates)",
+            run.table_name, run.rows_inserted, run.rows_skipped
+        );
+        print_user_map(&user_map(&conn, run.id)?);
+    }
+    Ok(())
+}
+
+fn user_map(conn: &Connection, migration_id: i64) -> Result<Vec<UserMapEntry>, rusqlite::Error> {
+    conn.prepare(&format!(
+        "SELECT OldUserId, OldUserName, NewUserId, NewUserName, MatchMethod \
+         FROM {USER_MAP_TABLE} WHERE MigrationId = ?1 ORDER BY OldUserName, OldUserId"
+    ))?
+    .query_map([migration_id], |row| {
+        Ok(UserMapEntry {
+            old_id: row.get(0)?,
+            old_name: row.get(1)?,
+            new_id: row.get(2)?,
+            new_name: row.get(3)?,
+            match_method: row.get(4)?,
+        })
+    })?
+    .collect()
+}
+
+fn print_user_map(users: &[UserMapEntry]) {
+    if users.is_empty() {
+        println!("  No user map recorded.");
+        return;
+    }
+    let name_width = users
+        .iter()
+        .map(|user| user.old_name.chars().count())
+        .max()
+        .unwrap_or(0);
+    let id_width = users
+        .iter()
+        .map(|user| user.old_id.len())
+        .max()
+        .unwrap_or(0);
+    println!("  User map:");
+    for user in users {
+        let new_side = match (&user.new_id, &user.new_name) {
+            (Some(id), Some(name)) => format!("{} ({})", id, name),
+            (Some(id), None) => id.clone(),
+            (None, _) => "-".to_string(),
+        };
+        println!(
+            "    {:<name_width$}  {:<id_width$} -> {}  [{}]",
+            user.old_name, user.old_id, new_side, user.match_method
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config_from_toml;
+
+    fn user(id: &str, name: &str) -> JellyfinUser {
+        JellyfinUser {
+            id: id.to_string(),
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn writes_the_run_and_its_user_map() {
+        let config = config_from_toml(
+            "input_tsv_file_path = \"in.tsv\"\n\
+            [instance_old]\nbase_url = \"http://old\"\napi_token = \"x\"\n\
+            [instance_new]\nbase_url = \"http://new\"\napi_token = \"y\"\n",
+        );
+        let user_id_map = HashMap::from([("old-alice".to_string(), "new-alice".to_string())]);
+        let audit = RunAudit::new(
+            &config,
+            &[user("old-alice", "alice"), user("old-bob", "bob")],
+            &[user("new-alice", "alice")],
+            &user_id_map,
+        );
+        let conn = Connection::open_in_memory().unwrap();
+        let stats = SinkStats {
+            written: 7,
+            skipped: 2,
+        };
+        audit.write(&conn, "PlaybackActivity", stats).unwrap();
+        audit.write(&conn, "PlaybackActivity", stats).unwrap();
+
+        let runs: i64 = conn
+            .query_row("SELECT COUNT(*) FROM jpm_migrations", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(runs, 2);
+        assert_eq!(
+            user_map(&conn, 2).unwrap(),
+            vec![
+                UserMapEntry {
+                    old_id: "old-alice".to_string(),
+                    old_name: "alice".to_string(),
+                    new_id: Some("new-alice".to_string()),
+                    new_name: Some("alice".to_string()),
+                    match_method: "name".to_string(),
+                },
+                UserMapEntry {
+                    old_id: "old-bob".to_string(),
+                    old_name: "bob".to_string(),
+                    new_id: None,
+                    new_name: None,
+                    match_method: "unmatched".to_string(),
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,177 @@
+// Builds the configuration from a directory holding one `*.toml` file per instance (named by file
+// stem, each with `base_url`/`api_token`) and an optional `defaults.toml` whose `[defaults]` table
+// supplies the shared, non-instance settings.
+use crate::{strict, Config};
+use config::{Config as AppConfig, ConfigError, File, Map, Value};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+const DEFAULTS_FILE_STEM: &str = "defaults";
+
+fn read_table(path: &Path) -> Result<Map<String, Value>, ConfigError> {
+    AppConfig::builder()
+        .add_source(File::from(path))
+        .build()?
+        .try_deserialize::<Map<String, Value>>()
+}
+
+pub fn load_config_from_instances_dir(
+    dir: &str,
+    from: &str,
+    to: &str,
+) -> Result<Config, ConfigError> {
+    let entries = fs::read_dir(dir).map_err(|e| {
+        ConfigError::Message(format!(
+            "Failed to read instances directory '{}': {}",
+            dir, e
+        ))
+    })?;
+
+    let mut instance_files: BTreeMap<String, PathBuf> = BTreeMap::new();
+    let mut defaults_file: Option<PathBuf> = None;
+    for entry in entries {
+        let path = entry
+            .map_err(|e| ConfigError::Message(format!("Failed to read '{}': {}", dir, e)))?
+            .path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some("toml") {
+            continue;
+        }
+        let Some(stem) = path.file_stem().and_then(|stem| stem.to_str()) else {
+            continue;
+        };
+        if stem == DEFAULTS_FILE_STEM {
+            defaults_file = Some(path);
+        } else {
+            instance_files.insert(stem.to_string(), path);
+        }
+    }
+
+    let available: Vec<&str> = instance_files.keys().map(String::as_str).collect();
+    info!(
+        "Found {} instance files in '{}': {}",
+        available.len(),
+        dir,
+        available.join(", ")
+    );
+    let find_instance = |name: &str| {
+        instance_files.get(name).ok_or_else(|| {
+            ConfigError::Message(format!(
+                "No instance file named '{}.toml' in '{}'. Available instances: {}",
+                name,
+                dir,
+                available.join(", ")
+            ))
+        })
+    };
+    let old_path = find_instance(from)?;
+    let new_path = find_instance(to)?;
+
+    let mut builder = AppConfig::builder();
+    if let Some(ref path) = defaults_file {
+        let defaults: Map<String, Value> = AppConfig::builder()
+            .add_source(File::from(path.as_path()))
+            .build()?
+            .get(DEFAULTS_FILE_STEM)?;
+        for (key, value) in defaults {
+            builder = builder.set_default(key, value)?;
+        }
+        info!("Loaded shared [defaults] from: {}", path.display());
+    }
+
+    for (section, name, path) in [
+        ("instance_old", from, old_path),
+        ("instance_new", to, new_path),
+    ] {
+        for (key, value) in read_table(path)? {
+            builder = builder.set_override(format!("{}.{}", section, key), value)?;
+        }
+        info!(
+            "Loaded {} from instance '{}': {}",
+            section,
+            name,
+            path.display()
+        );
+    }
+
+    strict::deserialize_config(builder.build()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{api_client, fetch_users_from_instance};
+    use serde_json::json;
+    use wiremock::matchers::{header, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    // A server answering /Users with one user, only for the given token
+    async fn server_with_user(token: &str, user_name: &str) -> MockServer {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/Users"))
+            .and(header("x-emby-token", token))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(json!([{"Id": format!("{}-id", user_name), "Name": user_name}])),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+        server
+    }
+
+    fn write_instance(dir: &Path, name: &str, base_url: &str, token: &str) {
+        fs::write(
+            dir.join(format!("{}.toml", name)),
+            format!("base_url = {:?}\napi_token = {:?}\n", base_url, token),
+        )
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn picks_the_named_instances_and_fetches_from_each() {
+        let home = server_with_user("home-token", "alice").await;
+        let office = server_with_user("office-token", "bob").await;
+        let dir = tempfile::tempdir().unwrap();
+        write_instance(dir.path(), "home", &home.uri(), "home-token");
+        write_instance(dir.path(), "office", &office.uri(), "office-token");
+        write_instance(dir.path(), "cabin", "http://cabin.invalid", "cabin-token");
+        fs::write(
+            dir.path().join("defaults.toml"),
+            "[defaults]\ninput_tsv_file_path = \"in.tsv\"\nrequest_timeout_secs = 5\n",
+        )
+        .unwrap();
+
+        let config =
+            load_config_from_instances_dir(dir.path().to_str().unwrap(), "home", "office").unwrap();
+        assert_eq!(config.input_tsv_file_path, "in.tsv");
+        assert_eq!(config.request_timeout_secs, 5);
+        let api = api_client(&config).unwrap();
+        let old_users = fetch_users_from_instance(config.instance_old(), &api)
+            .await
+            .unwrap();
+        let new_users = fetch_users_from_instance(config.instance_new(), &api)
+            .await
+            .unwrap();
+        assert_eq!(old_users[0].name, "alice");
+        assert_eq!(new_users[0].name, "bob");
+    }
+
+    #[test]
+    fn an_unknown_instance_lists_the_available_ones() {
+        let dir = tempfile::tempdir().unwrap();
+        write_instance(dir.path(), "home", "http://home.invalid", "x");
+        write_instance(dir.path(), "office", "http://office.invalid", "y");
+        let error = load_config_from_instances_dir(dir.path().to_str().unwrap(), "home", "attic")
+            .unwrap_err()
+            .to_string();
+        assert!(
+            error.contains("No instance file named 'attic.toml'")
+                && error.contains("Available instances: home, office"),
+            "{}",
+            error
+        );
+    }
+}
@@ -0,0 +1,147 @@
+// ItemIds in old Emby-era exports can be wrapped, as `{3fa85f64-5717-...}` or `urn:uuid:3fa85f64-...`,
+// which breaks the plugin's joins with the library on the new server. GUID-like ItemIds are unwrapped
+// and, per item_id_format, also rewritten as 32 lowercase hex digits ("n", what Jellyfin stores) or
+// lowercase with hyphens ("d"). Anything that isn't a GUID once unwrapped (live TV channel IDs,
+// virtual items) passes through untouched. Rows are counted per pattern found in the summary.
+use crate::display::format_count;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use tracing::info;
+
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ItemIdFormat {
+    #[default]
+    Keep, // Only unwrapped, case and hyphens as in the input
+    N,
+    D,
+}
+
+const URN_PREFIX: &str = "urn:uuid:";
+
+#[derive(Debug, Default)]
+pub struct ItemIdStats {
+    pub rows: u64,
+    pub patterns: BTreeMap<&'static str, u64>, // A row can have several
+}
+
+impl ItemIdStats {
+    pub fn print_summary(&self, format: ItemIdFormat) {
+        if self.rows == 0 {
+            return;
+        }
+        info!(
+            "  ItemIds normalized (item_id_format = \"{}\"): {}",
+            match format {
+                ItemIdFormat::Keep => "keep",
+                ItemIdFormat::N => "n",
+                ItemIdFormat::D => "d",
+            },
+            format_count(self.rows)
+        );
+        for (pattern, rows) in &self.patterns {
+            info!("    {}: {}", pattern, format_count(*rows));
+        }
+    }
+}
+
+fn is_guid(value: &str) -> bool {
+    let bytes = value.as_bytes();
+    match bytes.len() {
+        32 => bytes.iter().all(u8::is_ascii_hexdigit),
+        36 => bytes.iter().enumerate().all(|(i, b)| match i {
+            8 | 13 | 18 | 23 => *b == b'-',
+            _ => b.is_ascii_hexdigit(),
+        }),
+        _ => false,
+    }
+}
+
+// Rewrites the value in place when it is a GUID in another form than the configured one
+pub fn normalize(value: &mut String, format: ItemIdFormat, stats: &mut ItemIdStats) {
+    let mut patterns = Vec::new();
+    let mut guid = value.as_str();
+    if guid
+        .get(..URN_PREFIX.len())
+        .is_some_and(|prefix| prefix.eq_ignore_ascii_case(URN_PREFIX))
+    {
+        guid = &guid[URN_PREFIX.len()..];
+        patterns.push("urn:uuid: prefix");
+    }
+    if let Some(inner) = guid.strip_prefix('{').and_then(|g| g.strip_suffix('}')) {
+        guid = inner;
+        patterns.push("{} braces");
+    }
+    if !is_guid(guid) {
+        return;
+    }
+    let normalized = match format {
+        ItemIdFormat::Keep => guid.to_string(),
+        ItemIdFormat::N | ItemIdFormat::D => {
+            if guid.bytes().any(|b| b.is_ascii_uppercase()) {
+                patterns.push("uppercase");
+            }
+            let hex = guid.replace('-', "").to_ascii_lowercase();
+            if format == ItemIdFormat::N {
+                if guid.len() == 36 {
+                    patterns.push("hyphens removed");
+                }
+                hex
+            } else {
+                if guid.len() == 32 {
+                    patterns.push("hyphens added");
+                }
+                format!(
+                    "{}-{}-{}-{}-{}",
+                    &hex[..8],
+                    &hex[8..12],
+                    &hex[12..16],
+                    &hex[16..20],
+                    &hex[20..]
+                )
+            }
+        }
+    };
+    if patterns.is_empty() {
+        return;
+    }
+    stats.rows += 1;
+    for pattern in patterns {
+        *stats.patterns.entry(pattern).or_default() += 1;
+    }
+    *value = normalized;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // One ItemId per pattern with what each item_id_format makes of it
+    #[test]
+    fn normalizes_every_pattern_in_the_fixtures() {
+        let path = format!(
+            "{}/tests/fixtures/item_ids/patterns.tsv",
+            env!("CARGO_MANIFEST_DIR")
+        );
+        let fixtures = std::fs::read_to_string(path).unwrap();
+        let formats = [ItemIdFormat::Keep, ItemIdFormat::N, ItemIdFormat::D];
+        let mut stats = formats.map(|_| ItemIdStats::default());
+        for line in fixtures.lines().filter(|line| !line.starts_with('#')) {
+            let fields: Vec<&str> = line.split('\t').collect();
+            for (i, format) in formats.into_iter().enumerate() {
+                let mut value = fields[1].to_string();
+                normalize(&mut value, format, &mut stats[i]);
+                assert_eq!(value, fields[2 + i], "{} with {:?}", fields[0], format);
+            }
+        }
+        // "plain" and "hyphenated" already are in the "keep" format, the last four aren't GUIDs
+        assert_eq!(stats[0].rows, 5);
+        assert_eq!(stats[0].patterns["{} braces"], 3);
+        assert_eq!(stats[0].patterns["urn:uuid: prefix"], 3);
+        assert_eq!(stats[1].rows, 6);
+        assert_eq!(stats[1].patterns["hyphens removed"], 5);
+        assert_eq!(stats[1].patterns["uppercase"], 1);
+        assert_eq!(stats[2].rows, 6);
+        assert_eq!(stats[2].patterns["hyphens added"], 2);
+    }
+}
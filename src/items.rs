@@ -0,0 +1,391 @@
+// `map_item_ids`: a new server that scanned the library from scratch gives every item a new ItemId,
+// so migrated rows would point at items that no longer exist. The items of both instances are
+// fetched from /Items and each old item is matched to a new one by, in this order:
+// 1. a provider ID (IMDb, TVDB, TMDb, MusicBrainz, then any other the server knows) of the same
+//    item type that only one new item has,
+// 2. the file name of its path, when only one new item of the type has it (survives a different
+//    mount point),
+// 3. its name (with series, season and episode number for episodes). Same-named items (e.g. a
+//    remake) are told apart by RunTimeTicks within item_runtime_tolerance_secs.
+// Names alone collide too easily, which is why they come last. Items that stay ambiguous or aren't
+// on the new instance are listed and keep their ItemId.
+use crate::api::ApiClient;
+use crate::display::format_count;
+use crate::itemid::{self, ItemIdFormat, ItemIdStats};
+use crate::users::split_query_result;
+use crate::InstanceConfig;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use tracing::{info, instrument, warn};
+
+const ITEM_PAGE_SIZE: usize = 1000;
+// The item types PlaybackReporting logs plays of
+const ITEM_TYPES: &str = "Movie,Episode,Audio,MusicVideo,Video,AudioBook";
+// Tried in this order, other providers after them alphabetically
+const PREFERRED_PROVIDERS: [&str; 5] = [
+    "imdb",
+    "tvdb",
+    "tmdb",
+    "musicbrainztrack",
+    "musicbrainzrecording",
+];
+// How many ambiguous items are listed by name
+const AMBIGUOUS_SAMPLE: usize = 10;
+const TICKS_PER_SECOND: i64 = 10_000_000;
+
+#[derive(Debug, Deserialize, Default, Clone)]
+#[serde(rename_all = "PascalCase")]
+pub struct Item {
+    pub id: String,
+    #[serde(default)]
+    pub name: String,
+    #[serde(default, rename = "Type")]
+    pub item_type: String,
+    #[serde(default)]
+    pub path: Option<String>,
+    #[serde(default)]
+    pub provider_ids: BTreeMap<String, Option<String>>,
+    #[serde(default)]
+    pub run_time_ticks: Option<i64>,
+    #[serde(default)]
+    pub series_name: Option<String>,
+    #[serde(default)]
+    pub parent_index_number: Option<i32>, // Season
+    #[serde(default)]
+    pub index_number: Option<i32>, // Episode
+}
+
+impl Item {
+    // Lowercased, most reliable first
+    fn provider_ids(&self) -> Vec<(String, String)> {
+        let mut ids: Vec<(String, String)> = self
+            .provider_ids
+            .iter()
+            .filter_map(|(provider, id)| {
+                let id = id.as_deref()?.trim();
+                (!id.is_empty()).then(|| (provider.to_lowercase(), id.to_lowercase()))
+            })
+            .collect();
+        ids.sort_by_key(|(provider, _)| {
+            (
+                PREFERRED_PROVIDERS
+                    .iter()
+                    .position(|preferred| preferred == provider)
+                    .unwrap_or(PREFERRED_PROVIDERS.len()),
+                provider.clone(),
+            )
+        });
+        ids
+    }
+
+    fn file_name(&self) -> Option<String> {
+        let path = self.path.as_deref()?;
+        let name = path.rsplit(['/', '\\']).next()?;
+        (!name.is_empty()).then(|| name.to_lowercase())
+    }
+
+    fn name_key(&self) -> String {
+        format!(
+            "{}|{}|{:?}|{:?}",
+            self.series_name.as_deref().unwrap_or("").to_lowercase(),
+            self.name.trim().to_lowercase(),
+            self.parent_index_number,
+            self.index_number
+        )
+    }
+
+    fn describe(&self) -> String {
+        match self.series_name {
+            Some(ref series) => format!("'{}' of '{}' ({})", self.name, series, self.item_type),
+            None => format!("'{}' ({})", self.name, self.item_type),
+        }
+    }
+}
+
+fn comparable_id(id: &str) -> String {
+    id.replace('-', "").to_lowercase()
+}
+
+// Old ItemId (compared ignoring GUID dashes and case) -> new ItemId
+#[derive(Debug, Default, Clone)]
+pub struct ItemIdMap {
+    pub enabled: bool,
+    map: HashMap<String, String>,
+}
+
+#[derive(Debug, Default)]
+pub struct ItemMapStats {
+    pub records_changed: u64,
+    pub records_unmapped: u64, // Their ItemId isn't one the map knows, so it was kept
+}
+
+impl ItemMapStats {
+    pub fn print_summary(&self) {
+        info!(
+            "  Total records with ItemId changed: {}",
+            format_count(self.records_changed)
+        );
+        if self.records_unmapped > 0 {
+            info!(
+                "  Records whose ItemId has no match on the new instance (kept as is): {}",
+                format_count(self.records_unmapped)
+            );
+        }
+    }
+}
+
+impl ItemIdMap {
+    // For --explain: None without map_item_ids, otherwise the new ItemId the map has if any
+    pub fn lookup(&self, item_id: &str) -> Option<Option<&str>> {
+        self.enabled
+            .then(|| self.map.get(&comparable_id(item_id)).map(String::as_str))
+    }
+
+    // Rewrites the ItemId when the map has it, in the configured item_id_format
+    pub fn apply(&self, item_id: &mut String, format: ItemIdFormat, stats: &mut ItemMapStats) {
+        if !self.enabled {
+            return;
+        }
+        let Some(new_id) = self.map.get(&comparable_id(item_id)) else {
+            stats.records_unmapped += 1;
+            return;
+        };
+        let mut new_id = new_id.clone();
+        itemid::normalize(&mut new_id, format, &mut ItemIdStats::default());
+        if *item_id != new_id {
+            *item_id = new_id;
+            stats.records_changed += 1;
+        }
+    }
+}
+
+#[instrument(name = "instance", skip_all, fields(base_url = %instance_config.base_url))]
+pub async fn fetch_items(
+    instance_config: &InstanceConfig,
+    api: &ApiClient,
+) -> Result<Vec<Item>, Box<dyn Error + Send + Sync>> {
+    let mut items: Vec<Item> = Vec::new();
+    loop {
+        let path = format!(
+            "/Items?Recursive=true&IncludeItemTypes={}&Fields=ProviderIds,Path&startIndex={}&limit={}",
+            ITEM_TYPES,
+            items.len(),
+            ITEM_PAGE_SIZE
+        );
+        let url = format!("{}{}", instance_config.base_url, path);
+        let value = api.get_json_at_startup(instance_config, &path).await?;
+        let (page, total) = split_query_result(value);
+        let page: Vec<Item> = serde_json::from_value(page)
+            .map_err(|e| format!("Unexpected /Items response from {}: {}", url, e))?;
+        let page_len = page.len();
+        items.extend(page);
+        let reached_total = total.is_some_and(|total| items.len() as u64 >= total);
+        if page_len < ITEM_PAGE_SIZE || reached_total {
+            info!(
+                "Fetched {} items from {}",
+                format_count(items.len() as u64),
+                instance_config.base_url
+            );
+            return Ok(items);
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct ItemMatchStats {
+    pub by_provider_id: u64,
+    pub by_file_name: u64,
+    pub by_name: u64,
+    pub by_runtime: u64, // By name, told apart from same-named items by RunTimeTicks
+    pub ambiguous: Vec<String>,
+    pub not_found: u64,
+}
+
+impl ItemMatchStats {
+    fn mapped(&self) -> u64 {
+        self.by_provider_id + self.by_file_name + self.by_name + self.by_runtime
+    }
+
+    pub fn print(&self, old_items: usize) {
+        info!(
+            "  Mapped {} of {} old items ({} by provider ID, {} by file name, {} by name, {} by name and runtime), {} ambiguous, {} not found on the new instance.",
+            format_count(self.mapped()),
+            format_count(old_items as u64),
+            format_count(self.by_provider_id),
+            format_count(self.by_file_name),
+            format_count(self.by_name),
+            format_count(self.by_runtime),
+            format_count(self.ambiguous.len() as u64),
+            format_count(self.not_found)
+        );
+        for ambiguous in self.ambiguous.iter().take(AMBIGUOUS_SAMPLE) {
+            warn!("  {}. Not mapped.", ambiguous);
+        }
+        if self.ambiguous.len() > AMBIGUOUS_SAMPLE {
+            warn!(
+                "  ... and {} more ambiguous items.",
+                self.ambiguous.len() - AMBIGUOUS_SAMPLE
+            );
+        }
+    }
+}
+
+fn index<K: std::hash::Hash + Eq>(
+    items: &[Item],
+    keys: impl Fn(&Item) -> Vec<K>,
+) -> HashMap<K, Vec<&Item>> {
+    let mut index: HashMap<K, Vec<&Item>> = HashMap::new();
+    for item in items {
+        for key in keys(item) {
+            index.entry(key).or_default().push(item);
+        }
+    }
+    index
+}
+
+pub fn build_map(
+    old_items: &[Item],
+    new_items: &[Item],
+    runtime_tolerance_secs: u64,
+) -> (ItemIdMap, ItemMatchStats) {
+    let by_provider = index(new_items, |item| {
+        item.provider_ids()
+            .into_iter()
+            .map(|(provider, id)| (item.item_type.clone(), provider, id))
+            .collect()
+    });
+    let by_file_name = index(new_items, |item| {
+        item.file_name()
+            .map(|name| (item.item_type.clone(), name))
+            .into_iter()
+            .collect()
+    });
+    let by_name = index(new_items, |item| {
+        vec![(item.item_type.clone(), item.name_key())]
+    });
+    let tolerance_ticks = runtime_tolerance_secs as i64 * TICKS_PER_SECOND;
+
+    let mut map = HashMap::new();
+    let mut stats = ItemMatchStats::default();
+    for old in old_items {
+        let provider_match = old.provider_ids().into_iter().find_map(|(provider, id)| {
+            match by_provider.get(&(old.item_type.clone(), provider, id))?[..] {
+                [new] => Some(new),
+                _ => None, // Shared by several new items, maybe a less common provider is unique
+            }
+        });
+        if let Some(new) = provider_match {
+            map.insert(comparable_id(&old.id), new.id.clone());
+            stats.by_provider_id += 1;
+            continue;
+        }
+        let file_match = old.file_name().and_then(|name| {
+            match by_file_name.get(&(old.item_type.clone(), name))?[..] {
+                [new] => Some(new),
+                _ => None,
+            }
+        });
+        if let Some(new) = file_match {
+            map.insert(comparable_id(&old.id), new.id.clone());
+            stats.by_file_name += 1;
+            continue;
+        }
+        let candidates = by_name
+            .get(&(old.item_type.clone(), old.name_key()))
+            .map_or(&[][..], Vec::as_slice);
+        match candidates {
+            [] => stats.not_found += 1,
+            [new] => {
+                map.insert(comparable_id(&old.id), new.id.clone());
+                stats.by_name += 1;
+            }
+            _ => {
+                let within: Vec<&&Item> = candidates
+                    .iter()
+                    .filter(|new| match (old.run_time_ticks, new.run_time_ticks) {
+                        (Some(a), Some(b)) => (a - b).abs() <= tolerance_ticks,
+                        _ => false,
+                    })
+                    .collect();
+                if let [new] = within[..] {
+                    map.insert(comparable_id(&old.id), new.id.clone());
+                    stats.by_runtime += 1;
+                } else {
+                    stats.ambiguous.push(format!(
+                        "{} matches {} new items by name and {} of them by runtime",
+                        old.describe(),
+                        candidates.len(),
+                        within.len()
+                    ));
+                }
+            }
+        }
+    }
+    (ItemIdMap { enabled: true, map }, stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn items(value: serde_json::Value) -> Vec<Item> {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn prefers_provider_ids_then_file_names_then_names() {
+        let old = items(json!([
+            {"Id": "o1", "Name": "Dune", "Type": "Movie", "ProviderIds": {"Imdb": "tt0087182"}, "RunTimeTicks": 82_000_000_000i64},
+            {"Id": "o2", "Name": "Dune", "Type": "Movie", "ProviderIds": {"Imdb": "tt1160419"}, "RunTimeTicks": 93_000_000_000i64},
+            {"Id": "o3", "Name": "Home Video", "Type": "Video", "Path": "/mnt/old/videos/birthday.mkv"},
+            {"Id": "o4", "Name": "Pilot", "Type": "Episode", "SeriesName": "Show", "ParentIndexNumber": 1, "IndexNumber": 1},
+            {"Id": "o5", "Name": "Heat", "Type": "Movie", "RunTimeTicks": 102_000_000_000i64},
+            {"Id": "o6", "Name": "Heat", "Type": "Movie", "RunTimeTicks": 50_000_000_000i64},
+            {"Id": "o7", "Name": "Gone", "Type": "Movie"}
+        ]));
+        let new = items(json!([
+            // Same name and the IMDb IDs swapped in order, only the provider ID tells them apart
+            {"Id": "n2", "Name": "Dune", "Type": "Movie", "ProviderIds": {"Imdb": "TT1160419"}},
+            {"Id": "n1", "Name": "Dune", "Type": "Movie", "ProviderIds": {"Imdb": "tt0087182", "Tmdb": null}},
+            {"Id": "n3", "Name": "birthday", "Type": "Video", "Path": "D:\\media\\videos\\Birthday.mkv"},
+            {"Id": "n4", "Name": "Pilot", "Type": "Episode", "SeriesName": "Show", "ParentIndexNumber": 1, "IndexNumber": 1},
+            {"Id": "n4b", "Name": "Pilot", "Type": "Episode", "SeriesName": "Other Show", "ParentIndexNumber": 1, "IndexNumber": 1},
+            {"Id": "n5", "Name": "Heat", "Type": "Movie", "RunTimeTicks": 102_300_000_000i64},
+            {"Id": "n5b", "Name": "Heat", "Type": "Movie", "RunTimeTicks": 60_000_000_000i64}
+        ]));
+        let (map, stats) = build_map(&old, &new, 60);
+        let mapped = |old_id: &str| map.map.get(old_id).map(String::as_str);
+        assert_eq!(mapped("o1"), Some("n1"));
+        assert_eq!(mapped("o2"), Some("n2"));
+        assert_eq!(mapped("o3"), Some("n3"));
+        assert_eq!(mapped("o4"), Some("n4"));
+        assert_eq!(mapped("o5"), Some("n5"));
+        assert_eq!(mapped("o6"), None);
+        assert_eq!(mapped("o7"), None);
+        assert_eq!(
+            (
+                stats.by_provider_id,
+                stats.by_file_name,
+                stats.by_name,
+                stats.by_runtime,
+                stats.ambiguous.len(),
+                stats.not_found
+            ),
+            (2, 1, 1, 1, 1, 1)
+        );
+
+        let mut item_stats = ItemMapStats::default();
+        let mut item_id = "O-1".to_string();
+        map.apply(&mut item_id, ItemIdFormat::Keep, &mut item_stats);
+        assert_eq!(item_id, "n1");
+        let mut item_id = "o7".to_string();
+        map.apply(&mut item_id, ItemIdFormat::Keep, &mut item_stats);
+        assert_eq!(item_id, "o7");
+        assert_eq!(
+            (item_stats.records_changed, item_stats.records_unmapped),
+            (1, 1)
+        );
+    }
+}
@@ -0,0 +1,355 @@
+// In-memory set of the dedup keys already in the destination table, so the duplicate check can be
+// answered without a query per record. Keys are canonicalized the way SQLite compares the bound
+// TSV values against the stored ones, so a hit here means check_and_insert_record_into_db would
+// have found the row too. Loading streams rows on the calling thread and hashes them on workers.
+use crate::{schema, TsvRecord};
+use rusqlite::types::Value;
+use rusqlite::Connection;
+use std::collections::hash_map::RandomState;
+use std::collections::HashSet;
+use std::error::Error;
+use std::hash::BuildHasher;
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Rows handed to a worker at a time
+const PRELOAD_CHUNK_ROWS: usize = 4096;
+// Rows sampled to estimate the size of a key
+const ESTIMATE_SAMPLE_ROWS: usize = 1000;
+// Extra shards per worker to keep lock contention low
+const SHARDS_PER_WORKER: usize = 4;
+
+// The parts of SQLite's type affinity rules that matter for the `=` checks in the duplicate query
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Affinity {
+    Text,
+    Numeric, // INTEGER, REAL and NUMERIC all compare numerically
+    Blob,
+}
+
+fn affinity_of(declared_type: &str) -> Affinity {
+    let declared = declared_type.to_uppercase();
+    if declared.contains("INT") {
+        Affinity::Numeric
+    } else if declared.contains("CHAR") || declared.contains("CLOB") || declared.contains("TEXT") {
+        Affinity::Text
+    } else if declared.contains("BLOB") || declared.trim().is_empty() {
+        Affinity::Blob
+    } else {
+        Affinity::Numeric // REAL/FLOA/DOUB and everything else (e.g. DATETIME)
+    }
+}
+
+// Renders a number the same way whether it was stored as 100, 100.0 or '100'
+fn canonical_number(n: f64) -> String {
+    if n.fract() == 0.0 && n.abs() < 9.0e15 {
+        format!("{}", n as i64)
+    } else {
+        format!("{}", n)
+    }
+}
+
+// Key for a bound text value compared against a column with the given affinity
+fn text_key(value: &str, affinity: Affinity) -> String {
+    if affinity == Affinity::Numeric {
+        // SQLite only converts well-formed numbers, never "inf"/"nan"
+        if let Some(n) = value.trim().parse::<f64>().ok().filter(|n| n.is_finite()) {
+            return canonical_number(n);
+        }
+    }
+    value.to_string()
+}
+
+// Key for a stored value, None for NULL since NULL never compares equal
+fn stored_key(value: Value, affinity: Affinity) -> Option<String> {
+    match value {
+        Value::Null => None,
+        Value::Text(text) => Some(text_key(&text, affinity)),
+        Value::Integer(i) if affinity == Affinity::Numeric => Some(canonical_number(i as f64)),
+        Value::Real(r) if affinity == Affinity::Numeric => Some(canonical_number(r)),
+        // Without numeric affinity a stored number never equals a bound text value
+        Value::Integer(i) => Some(format!("\0integer:{}", i)),
+        Value::Real(r) => Some(format!("\0real:{}", r)),
+        Value::Blob(_) => Some("\0blob".to_string()),
+    }
+}
+
+type Key = Vec<String>;
+
+pub struct DedupKeySet {
+    dedup_columns: Vec<usize>, // Indexes into TsvRecord::fields
+    affinities: Vec<Affinity>, // Of each dedup column
+    hasher: RandomState,       // Picks the shard, shared by loading and lookups
+    shards: Vec<HashSet<Key>>,
+    load_duration: Duration,
+}
+
+// Columns selected for the dedup key and their affinities. Columns missing from the table (which
+// auto_migrate_schema would add) are read as the default they would get.
+fn key_columns(
+    conn: &Connection,
+    table_name: &str,
+    dedup_columns: &[usize],
+) -> Result<(Vec<String>, Vec<Affinity>), rusqlite::Error> {
+    let declared: Vec<(String, String)> = conn
+        .prepare(&format!("PRAGMA table_info({})", table_name))?
+        .query_map([], |row| Ok((row.get(1)?, row.get(2)?)))?
+        .collect::<Result<_, _>>()?;
+    let mut select_exprs = Vec::new();
+    let mut affinities = Vec::new();
+    for &column in dedup_columns {
+        let (name, declaration) = schema::expected_columns()[column];
+        match declared
+            .iter()
+            .find(|(existing, _)| existing.eq_ignore_ascii_case(name))
+        {
+            Some((existing, declared_type)) => {
+                select_exprs.push(existing.clone());
+                affinities.push(affinity_of(declared_type));
+            }
+            None => {
+                let default = declaration
+                    .split_once("DEFAULT ")
+                    .map(|(_, default)| default)
+                    .unwrap_or("NULL");
+                select_exprs.push(default.to_string());
+                affinities.push(affinity_of(declaration));
+            }
+        }
+    }
+    Ok((select_exprs, affinities))
+}
+
+// Rough memory needed to preload the table's keys, from COUNT(*) and a sample of rows
+pub fn estimate_preload_bytes(
+    conn: &Connection,
+    table_name: &str,
+    dedup_columns: &[usize],
+) -> Result<u64, rusqlite::Error> {
+    let (select_exprs, _) = key_columns(conn, table_name, dedup_columns)?;
+    let row_count: i64 =
+        conn.query_row(&format!("SELECT COUNT(*) FROM {}", table_name), [], |row| {
+            row.get(0)
+        })?;
+    let sample_text_bytes: Option<f64> = conn.query_row(
+        &format!(
+            "SELECT AVG({}) FROM (SELECT * FROM {} LIMIT {})",
+            select_exprs
+                .iter()
+                .map(|expr| format!("IFNULL(LENGTH({}), 0)", expr))
+                .collect::<Vec<_>>()
+                .join(" + "),
+            table_name,
+            ESTIMATE_SAMPLE_ROWS
+        ),
+        [],
+        |row| row.get(0),
+    )?;
+    // Text plus a String header per column, the Vec header and the hash table's slot overhead
+    let per_key =
+        sample_text_bytes.unwrap_or(0.0) as u64 + 24 * (select_exprs.len() as u64 + 1) + 16;
+    Ok(row_count.max(0) as u64 * per_key)
+}
+
+// Streams the rows of the query to the workers in chunks, dropping the sender when done
+fn stream_rows(
+    conn: &Connection,
+    query: &str,
+    column_count: usize,
+    tx: SyncSender<Vec<Vec<Value>>>,
+) -> Result<(), rusqlite::Error> {
+    let mut stmt = conn.prepare(query)?;
+    let mut rows = stmt.query([])?;
+    let mut chunk = Vec::with_capacity(PRELOAD_CHUNK_ROWS);
+    while let Some(row) = rows.next()? {
+        let values = (0..column_count)
+            .map(|i| row.get::<_, Value>(i))
+            .collect::<Result<Vec<_>, _>>()?;
+        chunk.push(values);
+        if chunk.len() == PRELOAD_CHUNK_ROWS {
+            let full = std::mem::replace(&mut chunk, Vec::with_capacity(PRELOAD_CHUNK_ROWS));
+            if tx.send(full).is_err() {
+                return Ok(()); // Workers are gone, the scope will report why
+            }
+        }
+    }
+    if !chunk.is_empty() {
+        let _ = tx.send(chunk);
+    }
+    Ok(())
+}
+
+impl DedupKeySet {
+    pub fn load(
+        conn: &Connection,
+        table_name: &str,
+        dedup_columns: Vec<usize>,
+    ) -> Result<DedupKeySet, Box<dyn Error + Send + Sync>> {
+        let workers = thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1)
+            .min(8);
+        DedupKeySet::load_with_workers(conn, table_name, dedup_columns, workers)
+    }
+
+    fn load_with_workers(
+        conn: &Connection,
+        table_name: &str,
+        dedup_columns: Vec<usize>,
+        workers: usize,
+    ) -> Result<DedupKeySet, Box<dyn Error + Send + Sync>> {
+        let start = Instant::now();
+        let (select_exprs, affinities) = key_columns(conn, table_name, &dedup_columns)?;
+        let query = format!("SELECT {} FROM {}", select_exprs.join(", "), table_name);
+
+        let hasher = RandomState::new();
+        let shard_count = workers * SHARDS_PER_WORKER;
+        let shards: Vec<Mutex<HashSet<Key>>> = (0..shard_count)
+            .map(|_| Mutex::new(HashSet::new()))
+            .collect();
+        let (tx, rx) = sync_channel::<Vec<Vec<Value>>>(workers * 2);
+        let rx = Mutex::new(rx);
+
+        thread::scope(|scope| {
+            for _ in 0..workers {
+                scope.spawn(|| loop {
+                    let Ok(chunk) = rx.lock().expect("preload receiver poisoned").recv() else {
+                        break;
+                    };
+                    for row in chunk {
+                        // Rows containing NULLs can never match the duplicate check
+                        let key: Option<Key> = row
+                            .into_iter()
+                            .zip(&affinities)
+                            .map(|(value, affinity)| stored_key(value, *affinity))
+                            .collect();
+                        if let Some(key) = key {
+                            let shard = hasher.hash_one(&key) as usize % shard_count;
+                            shards[shard]
+                                .lock()
+                                .expect("preload shard poisoned")
+                                .insert(key);
+                        }
+                    }
+                });
+            }
+            stream_rows(conn, &query, select_exprs.len(), tx)
+        })?;
+
+        Ok(DedupKeySet {
+            dedup_columns,
+            affinities,
+            hasher,
+            shards: shards
+                .into_iter()
+                .map(|shard| shard.into_inner().expect("preload shard poisoned"))
+                .collect(),
+            load_duration: start.elapsed(),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(HashSet::len).sum()
+    }
+
+    pub fn load_duration(&self) -> Duration {
+        self.load_duration
+    }
+
+    fn key_of(&self, record: &TsvRecord) -> Key {
+        let fields = record.fields();
+        self.dedup_columns
+            .iter()
+            .zip(&self.affinities)
+            .map(|(&column, affinity)| text_key(fields[column], *affinity))
+            .collect()
+    }
+
+    pub fn contains(&self, record: &TsvRecord) -> bool {
+        let key = self.key_of(record);
+        let shard = self.hasher.hash_one(&key) as usize % self.shards.len();
+        self.shards[shard].contains(&key)
+    }
+
+    // Returns true if the record's key was not present yet (so a real run would insert it)
+    pub fn insert(&mut self, record: &TsvRecord) -> bool {
+        let key = self.key_of(record);
+        let shard = self.hasher.hash_one(&key) as usize % self.shards.len();
+        self.shards[shard].insert(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusqlite::params;
+
+    fn table(rows: usize) -> Connection {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE PlaybackActivity (DateCreated DATETIME NOT NULL, UserId TEXT, ItemId TEXT, \
+            ItemType TEXT, ItemName TEXT, PlaybackMethod TEXT, ClientName TEXT, DeviceName TEXT, PlayDuration INT);",
+        )
+        .unwrap();
+        let tx = conn.transaction().unwrap();
+        {
+            let mut insert = tx
+                .prepare("INSERT INTO PlaybackActivity VALUES (?, ?, ?, 'Movie', 'M', 'DirectPlay', 'Web', 'TV', ?)")
+                .unwrap();
+            for i in 0..rows {
+                // Rows come in pairs with the same key, PlayDuration stored once as an integer and
+                // once as a real; every 100th pair has a NULL PlayDuration and no key at all
+                let pair = i / 2;
+                let play_duration = match (pair % 100, i % 2) {
+                    (0, _) => Value::Null,
+                    (_, 0) => Value::Integer(pair as i64),
+                    _ => Value::Real(pair as f64),
+                };
+                insert
+                    .execute(params![
+                        format!("2024-01-{:02} 10:00:00", pair % 28 + 1),
+                        format!("user-{}", pair % 7),
+                        format!("item-{}", pair),
+                        play_duration
+                    ])
+                    .unwrap();
+            }
+        }
+        tx.commit().unwrap();
+        conn
+    }
+
+    fn keys(set: &DedupKeySet) -> HashSet<&Key> {
+        set.shards.iter().flatten().collect()
+    }
+
+    #[test]
+    fn parallel_preload_builds_the_same_keys_as_one_thread() {
+        let rows = PRELOAD_CHUNK_ROWS * 3 + 17;
+        let conn = table(rows);
+        let columns: Vec<usize> = (0..schema::expected_columns().len()).collect();
+        let single =
+            DedupKeySet::load_with_workers(&conn, "PlaybackActivity", columns.clone(), 1).unwrap();
+        let parallel =
+            DedupKeySet::load_with_workers(&conn, "PlaybackActivity", columns, 4).unwrap();
+
+        let pairs = rows.div_ceil(2);
+        let expected = pairs - pairs.div_ceil(100);
+        assert_eq!(single.len(), expected);
+        assert_eq!(parallel.len(), expected);
+        assert_eq!(keys(&single), keys(&parallel));
+    }
+
+    #[test]
+    fn preload_of_an_empty_table() {
+        let conn = table(0);
+        for workers in [1, 4] {
+            let set =
+                DedupKeySet::load_with_workers(&conn, "PlaybackActivity", vec![0, 1, 2], workers)
+                    .unwrap();
+            assert_eq!(set.len(), 0);
+        }
+    }
+}
@@ -0,0 +1,2507 @@
+// The migration engine. The command line tool (cli.rs, run by main.rs) is one user of it, code that
+// embeds the migration can call the same steps: load_config, fetch_users_from_instance and
+// create_user_id_map, or run_migration for the whole run, which returns a MigrationSummary of
+// what was done instead of only printing it.
+use config::Config as AppConfig; // Renamed to avoid conflict with our Config struct
+use directories::ProjectDirs;
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION};
+use rusqlite::types::Value;
+use rusqlite::Connection;
+use rusqlite::{params, params_from_iter};
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, BufReader, IsTerminal};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use display::truncate_display;
+use messages::msg;
+use timefmt::{humanize_duration, TimeFormatter};
+use tracing::{debug, error, info, info_span, instrument, warn};
+
+mod api;
+mod bench;
+mod check;
+pub mod cli;
+mod clock;
+mod columnar;
+mod devices;
+mod display;
+mod downsample;
+mod dto;
+mod duplicates;
+mod endpoint;
+mod error;
+mod eta;
+mod explain;
+mod extract;
+mod formats;
+mod futuredates;
+mod history;
+mod instances;
+mod itemid;
+mod items;
+mod keyset;
+mod limits;
+mod logging;
+mod manifest;
+mod mapfile;
+mod mapping;
+mod matching;
+mod merges;
+mod messages;
+mod output;
+mod overrides;
+mod paths;
+mod playduration;
+mod progress;
+mod resume;
+mod retention;
+mod review;
+mod rng;
+mod rollup;
+mod schema;
+mod selftest;
+mod sessions;
+mod shadow;
+mod sinks;
+mod split;
+mod strict;
+mod summary;
+mod timefmt;
+mod tracked;
+mod tui;
+mod units;
+mod unmapped;
+mod usercontext;
+mod userfilter;
+mod users;
+mod wal;
+mod watch;
+mod writable;
+
+pub use api::ApiClient;
+pub use error::MigrationError;
+pub use summary::{MigrationSummary, UserChanges};
+
+#[derive(Debug, Deserialize)]
+pub struct Config {
+    input_tsv_file_path: String,
+    // "tsv" (default) or "old_instance" to extract the input from the old instance, see extract.rs
+    #[serde(default)]
+    input_source: extract::InputSource,
+    #[serde(default = "default_extract_window_days")]
+    extract_window_days: u64,
+    // Tries per window (and for the MIN/MAX query) before it is given up on
+    #[serde(default = "default_extract_max_attempts")]
+    extract_max_attempts: u32,
+    output_tsv_file_path: Option<String>,
+    sqlite_db_path: Option<String>,
+    sqlite_table_name: Option<String>,
+    #[serde(default)]
+    output_tsv_append: bool,
+    #[serde(default)]
+    output_tsv_headers: bool,
+    #[serde(default)]
+    output_tsv_schema_comment: bool,
+    #[serde(default)]
+    sink_failure_policy: sinks::SinkFailurePolicy,
+    // Load the table's existing dedup keys up front instead of querying for each record
+    #[serde(default)]
+    preload_dedup_keys: bool,
+    // Preloading falls back to the per-record check if the keys are estimated to need more
+    #[serde(default = "default_max_preload_memory_mb")]
+    max_preload_memory_mb: u64,
+    // Columns left out of the SQLite duplicate check, e.g. ones that vary for the same play
+    #[serde(default)]
+    dedup_ignore_columns: Vec<String>,
+    // Checkpoint mode run on a WAL-mode SQLite destination at the end of the run, see wal.rs
+    #[serde(default)]
+    sqlite_wal_checkpoint: wal::WalCheckpoint,
+    // JSON inventory of the files written by the run (not written in dry runs or watch mode)
+    output_manifest_path: Option<String>,
+    // Columns written to the TSV output, in this order (all of them when unset)
+    output_columns: Option<Vec<String>>,
+    // "tsv" or "parquet" for the file at output_tsv_file_path, by its extension when unset, see columnar.rs
+    output_format: Option<columnar::OutputFormat>,
+    // Rows per Parquet row group
+    #[serde(default = "default_parquet_row_group_size")]
+    parquet_row_group_size: usize,
+    // "snappy" (default), "zstd" or "none"
+    #[serde(default)]
+    #[cfg_attr(not(feature = "parquet"), allow(dead_code))]
+    parquet_compression: columnar::ParquetCompression,
+    #[serde(default)]
+    auto_migrate_schema: bool,
+    // Same as passing --dry-run
+    #[serde(default)]
+    dry_run: bool,
+    // Same as passing --ignore-fetch-errors
+    #[serde(default)]
+    allow_partial_user_fetch: bool,
+    #[serde(default)]
+    summary_sort_order: SummarySortOrder,
+    // "forward" (default) or "auto" for inputs mixing old and new user IDs, see mapping.rs
+    #[serde(default)]
+    mapping_direction: mapping::MappingDirection,
+    // What happens to records whose UserId isn't in the user map, see mapping.rs
+    #[serde(default)]
+    unmapped_user_policy: mapping::UnmappedUserPolicy,
+    // A new instance user ID, with unmapped_user_policy = "fallback"
+    fallback_user_id: Option<String>,
+    // "exact" (default), "case_insensitive" or "normalized", see matching.rs
+    user_match_mode: Option<matching::UserMatchMode>,
+    // Older spelling of user_match_mode = "case_insensitive"
+    #[serde(default)]
+    match_case_insensitive: bool,
+    // Map still unmatched users to the most similar new name scoring at least this (0.0 to 1.0)
+    fuzzy_match_threshold: Option<f64>,
+    // TSV/CSV of old_id,new_id pairs mapped ahead of the automatic matching, see overrides.rs
+    user_map_override_path: Option<String>,
+    // Old user ID or name -> new user ID or name, also ahead of the automatic matching
+    #[serde(default)]
+    user_map: BTreeMap<String, String>,
+    // What happens when several old users are mapped to one new user, see merges.rs
+    #[serde(default)]
+    many_to_one_policy: merges::ManyToOnePolicy,
+    // TSV of the merged users, with many_to_one_policy = "merge"
+    merge_manifest_path: Option<String>,
+    // TSV of the users left without a mapping on both sides, see unmapped.rs
+    unmapped_report_path: Option<String>,
+    // TSV the user map is written to once it is built, see mapfile.rs
+    user_map_output_path: Option<String>,
+    // A map written by user_map_output_path (possibly edited) used instead of fetching the users
+    user_map_input_path: Option<String>,
+    // Distinct user IDs tracked in the per-user statistics before the rest count as "(other)"
+    max_tracked_users: Option<usize>,
+    // Limit for each request to an instance, including reading its whole response (0: none)
+    #[serde(default = "default_request_timeout_secs")]
+    request_timeout_secs: u64,
+    // Clock skew of an instance (or between them) that is warned about, see clock.rs
+    #[serde(default = "default_clock_skew_tolerance_secs")]
+    clock_skew_tolerance_secs: u64,
+    // IANA timezone name for the local rendering of run timestamps, defaults to the system timezone
+    report_timezone: Option<String>,
+    // Input DeviceName -> DeviceName written to the outputs
+    #[serde(default)]
+    device_name_map: BTreeMap<String, String>,
+    // Column name -> maximum length in characters, overriding the defaults in limits.rs
+    #[serde(default)]
+    field_max_lengths: BTreeMap<String, usize>,
+    // What to do with a value over its maximum length
+    #[serde(default)]
+    field_length_policy: limits::FieldLengthPolicy,
+    // DateCreated later than the run's start plus this many hours is in the future
+    #[serde(default = "futuredates::default_future_date_slack_hours")]
+    future_date_slack_hours: u64,
+    // What to do with a record dated in the future
+    #[serde(default)]
+    future_date_policy: futuredates::FutureDatePolicy,
+    // Drop records whose DateCreated is older than this many days (default: keep everything)
+    retention_days: Option<u64>,
+    // User name (on the old instance) -> days or "unlimited", replacing retention_days for that user
+    #[serde(default)]
+    retention_overrides: BTreeMap<String, retention::Retention>,
+    // Names or IDs of old users to migrate, leaving out everyone else, see userfilter.rs
+    #[serde(default)]
+    include_users: Vec<String>,
+    // Or the names or IDs of old users to leave out (only one of the two can be set)
+    #[serde(default)]
+    exclude_users: Vec<String>,
+    // "rows" (default) or "daily_rollup", see rollup.rs
+    #[serde(default)]
+    output_mode: rollup::OutputMode,
+    // User name (on the old instance) -> output_mode for that user
+    #[serde(default)]
+    output_mode_overrides: BTreeMap<String, rollup::OutputMode>,
+    // Old users whose history is divided among several new users by date, see split.rs
+    #[serde(default)]
+    split_user: Vec<split::SplitUser>,
+    // Merge rows of the same play session logged within this many seconds of each other (default: off)
+    session_merge_window_secs: Option<u64>,
+    // How the PlayDuration of merged rows is combined
+    #[serde(default)]
+    session_merge_duration: sessions::MergeDuration,
+    // "nearest" (default), "down" or "up" for PlayDuration values with a fractional part
+    #[serde(default)]
+    play_duration_rounding: playduration::Rounding,
+    // "keep" (default), "n" or "d" for GUID-like ItemIds, see itemid.rs
+    #[serde(default)]
+    item_id_format: itemid::ItemIdFormat,
+    // Replace ItemIds with the new instance's IDs of the same items, see items.rs
+    #[serde(default)]
+    map_item_ids: bool,
+    // Same-named items whose RunTimeTicks differ by at most this are taken as the same item
+    #[serde(default = "default_item_runtime_tolerance_secs")]
+    item_runtime_tolerance_secs: u64,
+    // "seconds" (default), "ticks" or "auto" (detected from the input), see units.rs
+    #[serde(default)]
+    play_duration_unit: units::UnitSetting,
+    // DateCreated range, e.g. "2019-01-01/2021-06-30" -> unit of the PlayDuration values logged in it
+    #[serde(default)]
+    duration_unit_overrides: BTreeMap<String, units::Unit>,
+    // Path prefix written on another machine -> prefix on this one, see paths.rs
+    #[serde(default)]
+    path_prefix_map: BTreeMap<String, String>,
+    // Refuse keys that don't match any setting (see strict.rs), on unless set to false
+    #[serde(default = "default_strict_config")]
+    strict_config: bool,
+    // Only optional with user_map_input_path, a left out section has an empty base_url
+    #[serde(default)]
+    instance_old: InstanceConfig,
+    #[serde(default)]
+    instance_new: InstanceConfig,
+    // Keys in the config files that no setting uses, filled in by strict::deserialize_config
+    #[serde(skip)]
+    unknown_keys: Vec<String>,
+    // Unit of each record's PlayDuration, filled in by units::resolve before processing
+    #[serde(skip)]
+    duration_units: units::UnitPlan,
+    // Old user ID -> rules of [[split_user]], filled in by split::resolve once the users are known
+    #[serde(skip)]
+    split_plan: split::SplitPlan,
+    // Resolved from include_users/exclude_users once the old users are known
+    #[serde(skip)]
+    user_filter: userfilter::UserFilter,
+    // From --downsample
+    #[serde(skip)]
+    downsample: Option<downsample::Downsample>,
+    // From --state-file and --max-runtime
+    #[serde(skip)]
+    checkpoint: Option<resume::Checkpoint>,
+    // Measured in the preflight
+    #[serde(skip)]
+    clock_skews: Vec<clock::ClockSkew>,
+    // Read from user_map_override_path and [user_map] at startup
+    #[serde(skip)]
+    user_map_overrides: overrides::UserMapOverrides,
+    // Instances ("old", "new") whose users couldn't be fetched, with --ignore-fetch-errors
+    #[serde(skip)]
+    user_fetch_failures: Vec<&'static str>,
+    // From --allow-ambiguous-first-match
+    #[serde(skip)]
+    allow_ambiguous_first_match: bool,
+    // Old users create_user_id_map left unmapped, for the summary
+    #[serde(skip)]
+    unmapped_old_users: UnmappedOldUsers,
+    // Built from both instances' /Items with map_item_ids
+    #[serde(skip)]
+    item_id_map: items::ItemIdMap,
+    // Input lines to trace, from --explain
+    #[serde(skip)]
+    explain_lines: Vec<u64>,
+    // New user IDs picked for a merge in --interactive
+    #[serde(skip)]
+    intentional_merges: BTreeSet<String>,
+    // Filled in by merges::resolve
+    #[serde(skip)]
+    merge_plan: merges::MergePlan,
+}
+
+fn default_parquet_row_group_size() -> usize {
+    100_000
+}
+
+fn default_item_runtime_tolerance_secs() -> u64 {
+    60
+}
+
+fn default_strict_config() -> bool {
+    true
+}
+
+fn default_extract_window_days() -> u64 {
+    30
+}
+
+fn default_extract_max_attempts() -> u32 {
+    3
+}
+
+fn default_max_preload_memory_mb() -> u64 {
+    1024
+}
+
+fn default_clock_skew_tolerance_secs() -> u64 {
+    60
+}
+
+fn default_request_timeout_secs() -> u64 {
+    30
+}
+
+// Config from a TOML string, for tests that drive the processing functions directly
+#[cfg(test)]
+fn config_from_toml(toml: &str) -> Config {
+    AppConfig::builder()
+        .add_source(config::File::from_str(toml, config::FileFormat::Toml))
+        .build()
+        .and_then(strict::deserialize_config)
+        .unwrap()
+}
+
+impl Config {
+    // The instances as configured, for fetch_users_from_instance
+    pub fn instance_old(&self) -> &InstanceConfig {
+        &self.instance_old
+    }
+
+    pub fn instance_new(&self) -> &InstanceConfig {
+        &self.instance_new
+    }
+
+    // report_timezone is validated at startup, an invalid one falls back to the system timezone here
+    fn time_formatter(&self) -> TimeFormatter {
+        TimeFormatter::new(self.report_timezone.as_deref())
+            .unwrap_or_else(|_| TimeFormatter::new(None).expect("system timezone is always valid"))
+    }
+
+    // user_match_mode, or what match_case_insensitive selects when it isn't set
+    fn user_match_mode(&self) -> matching::UserMatchMode {
+        match self.user_match_mode {
+            Some(mode) => mode,
+            None if self.match_case_insensitive => matching::UserMatchMode::CaseInsensitive,
+            None => matching::UserMatchMode::Exact,
+        }
+    }
+}
+
+// Order of the per-user lines in the final changes summary
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum SummarySortOrder {
+    #[default]
+    OldId,
+    ChangeCount, // Most changed first, ties broken by old ID
+}
+
+// Either api_token or username/password (exchanged for a token at startup) must be set
+#[derive(Deserialize)]
+pub struct InstanceConfig {
+    base_url: String,
+    api_token: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+    // Keep retrying startup requests this long while the instance answers 502/503 or refuses
+    // connections (e.g. right after a container restart)
+    #[serde(default)]
+    startup_grace_seconds: u64,
+    // Retry a request this many times after a connection error, timeout or 5xx answer, waiting
+    // base_backoff_ms, then twice that and so on (with jitter) in between. 4xx answers never are.
+    #[serde(default = "default_max_retries")]
+    max_retries: u32,
+    #[serde(default = "default_base_backoff_ms")]
+    base_backoff_ms: u64,
+    #[serde(default)]
+    auth_mode: AuthMode,
+    // Sent with auth_mode = "emby_authorization" and when logging in with username/password
+    #[serde(default)]
+    client: ClientInfo,
+    // Also send the token as X-Emby-Token (some reverse proxies reject requests carrying both)
+    #[serde(default = "default_send_emby_token_header")]
+    send_emby_token_header: bool,
+    // Fetch /Users in pages of this many users, 0 for one request for all of them
+    #[serde(default = "default_user_page_size")]
+    user_page_size: usize,
+}
+
+// How an instance is sent its token
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum AuthMode {
+    #[default]
+    Token, // Authorization: MediaBrowser Token="..."
+    // X-Emby-Authorization: MediaBrowser Client="...", Device="...", DeviceId="...", Version="...",
+    // Token="...", for servers that reject the short form
+    EmbyAuthorization,
+}
+
+// [instance_old.client] / [instance_new.client]: how this tool introduces itself to the server
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+struct ClientInfo {
+    client: String,
+    device: String,
+    device_id: String,
+    version: String,
+}
+
+impl Default for ClientInfo {
+    fn default() -> Self {
+        ClientInfo {
+            client: "jellyfin_pr_migration".to_string(),
+            device: "jellyfin_pr_migration".to_string(),
+            device_id: "jellyfin_pr_migration".to_string(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+        }
+    }
+}
+
+impl ClientInfo {
+    fn authorization(&self, token: Option<&str>) -> String {
+        let mut value = format!(
+            "MediaBrowser Client=\"{}\", Device=\"{}\", DeviceId=\"{}\", Version=\"{}\"",
+            self.client, self.device, self.device_id, self.version
+        );
+        if let Some(token) = token {
+            value.push_str(&format!(", Token=\"{}\"", token));
+        }
+        value
+    }
+}
+
+fn default_send_emby_token_header() -> bool {
+    true
+}
+
+fn default_user_page_size() -> usize {
+    500
+}
+
+fn default_max_retries() -> u32 {
+    3
+}
+
+fn default_base_backoff_ms() -> u64 {
+    500
+}
+
+// The config is printed with --log-level debug, so the token and password are never shown
+impl fmt::Debug for InstanceConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InstanceConfig")
+            .field("base_url", &self.base_url)
+            .field("api_token", &self.api_token.as_ref().map(|_| "<redacted>"))
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| "<redacted>"))
+            .field("startup_grace_seconds", &self.startup_grace_seconds)
+            .field("max_retries", &self.max_retries)
+            .field("base_backoff_ms", &self.base_backoff_ms)
+            .field("auth_mode", &self.auth_mode)
+            .field("client", &self.client)
+            .field("send_emby_token_header", &self.send_emby_token_header)
+            .field("user_page_size", &self.user_page_size)
+            .finish()
+    }
+}
+
+impl Default for InstanceConfig {
+    fn default() -> Self {
+        InstanceConfig {
+            base_url: String::new(),
+            api_token: None,
+            username: None,
+            password: None,
+            startup_grace_seconds: 0,
+            max_retries: default_max_retries(),
+            base_backoff_ms: default_base_backoff_ms(),
+            auth_mode: AuthMode::default(),
+            client: ClientInfo::default(),
+            send_emby_token_header: default_send_emby_token_header(),
+            user_page_size: default_user_page_size(),
+        }
+    }
+}
+
+impl InstanceConfig {
+    fn validate_auth(&self, section: &str) -> Result<(), String> {
+        match (&self.api_token, &self.username) {
+            (Some(_), Some(_)) => Err(format!(
+                "[{}] sets both api_token and username. Use one or the other.",
+                section
+            )),
+            (None, None) => Err(format!(
+                "[{}] needs either api_token or username/password.",
+                section
+            )),
+            (Some(_), None) if self.password.is_some() => {
+                Err(format!("[{}] sets a password without a username.", section))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // The auth header for auth_mode, without a token when logging in with username/password
+    fn auth_header(&self, token: Option<&str>) -> (HeaderName, String) {
+        match (self.auth_mode, token) {
+            (AuthMode::Token, Some(token)) => {
+                (AUTHORIZATION, format!("MediaBrowser Token=\"{}\"", token))
+            }
+            (AuthMode::Token, None) => (AUTHORIZATION, self.client.authorization(None)),
+            (AuthMode::EmbyAuthorization, token) => (
+                HeaderName::from_static("x-emby-authorization"),
+                self.client.authorization(token),
+            ),
+        }
+    }
+}
+
+// Unknown fields are ignored. The aliases cover servers that answer in camelCase and must match
+// dto::USER_SHAPE, which is used to explain responses that don't deserialize.
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+#[serde(rename_all = "PascalCase")]
+pub struct JellyfinUser {
+    #[serde(alias = "id")]
+    pub id: String,
+    #[serde(alias = "name")]
+    pub name: String,
+    // Only used to match users whose name changed, see matching.rs
+    #[serde(default, alias = "email")]
+    pub email: Option<String>,
+    #[serde(default, alias = "connectUserName")]
+    pub connect_user_name: Option<String>,
+}
+
+// Placeholder for TSV record structure based on the provided headers
+// 0|DateCreated|DATETIME|1||0
+// 1|UserId|TEXT|0||0
+// 2|ItemId|TEXT|0||0
+// 3|ItemType|TEXT|0||0
+// 4|ItemName|TEXT|0||0
+// 5|PlaybackMethod|TEXT|0||0
+// 6|ClientName|TEXT|0||0
+// 7|DeviceName|TEXT|0||0
+// 8|PlayDuration|INT|0||0
+#[derive(Debug, Clone, PartialEq, Deserialize, serde::Serialize)]
+struct TsvRecord {
+    #[serde(rename = "DateCreated")]
+    date_created: String,
+    #[serde(rename = "UserId")]
+    user_id: String,
+    #[serde(rename = "ItemId")]
+    item_id: String,
+    #[serde(rename = "ItemType")]
+    item_type: String,
+    #[serde(rename = "ItemName")]
+    item_name: String,
+    #[serde(rename = "PlaybackMethod")]
+    playback_method: String,
+    #[serde(rename = "ClientName")]
+    client_name: String,
+    #[serde(rename = "DeviceName")]
+    device_name: String,
+    #[serde(rename = "PlayDuration")]
+    play_duration: String, // Canonicalized by playduration.rs when it is a number
+}
+
+impl TsvRecord {
+    // Field values in column order (see schema::column_names)
+    fn fields(&self) -> [&str; 9] {
+        [
+            &self.date_created,
+            &self.user_id,
+            &self.item_id,
+            &self.item_type,
+            &self.item_name,
+            &self.playback_method,
+            &self.client_name,
+            &self.device_name,
+            &self.play_duration,
+        ]
+    }
+
+    fn fields_mut(&mut self) -> [&mut String; 9] {
+        [
+            &mut self.date_created,
+            &mut self.user_id,
+            &mut self.item_id,
+            &mut self.item_type,
+            &mut self.item_name,
+            &mut self.playback_method,
+            &mut self.client_name,
+            &mut self.device_name,
+            &mut self.play_duration,
+        ]
+    }
+}
+
+const DEFAULT_CONFIG_FILE: &str = "config.toml";
+
+// User level config files (e.g. ~/.config/jellyfin_pr_migration/config.toml on Linux) that exist,
+// lowest precedence first: the shared file and then the one for this host.
+fn user_config_files() -> Vec<PathBuf> {
+    let Some(dirs) = ProjectDirs::from("", "", "jellyfin_pr_migration") else {
+        return Vec::new();
+    };
+    let dir = dirs.config_dir();
+    let mut files = vec![dir.join("config.toml")];
+    if let Some(host) = gethostname::gethostname().to_str() {
+        files.push(dir.join(format!("config.{}.toml", host)));
+    }
+    files.into_iter().filter(|path| path.is_file()).collect()
+}
+
+fn load_fallback_config() -> Result<Config, MigrationError> {
+    let fallback_builder = AppConfig::builder(); // Create a new builder for fallback
+    Ok(fallback_builder
+        .add_source(config::File::with_name("config.example.toml").required(true))
+        .build()
+        .and_then(strict::deserialize_config)?)
+}
+
+pub fn load_config(
+    config_path: Option<&str>,
+    use_user_config: bool,
+) -> Result<Config, MigrationError> {
+    let mut builder = AppConfig::builder();
+    let mut loaded_files: Vec<String> = Vec::new();
+
+    // The user config goes in first so anything in the run's own config file overrides it
+    if use_user_config {
+        for path in user_config_files() {
+            builder = builder.add_source(config::File::from(path.as_path()).required(true));
+            loaded_files.push(path.display().to_string());
+        }
+    }
+
+    // An explicitly passed file has to exist, the default one is only used when it is there
+    let primary_config_path = match config_path {
+        Some(path) => Some(path),
+        None if Path::new(DEFAULT_CONFIG_FILE).is_file() => Some(DEFAULT_CONFIG_FILE),
+        None => None,
+    };
+    if let Some(path) = primary_config_path {
+        builder = builder.add_source(config::File::with_name(path).required(true));
+        loaded_files.push(path.to_string());
+    }
+
+    if loaded_files.is_empty() {
+        warn!(
+            "No configuration found ('{}' or user config). Attempting fallback 'config.example.toml'.",
+            DEFAULT_CONFIG_FILE
+        );
+        return load_fallback_config();
+    }
+
+    match builder.build() {
+        Ok(settings) => {
+            info!("Successfully built configuration from (in load order, later files override earlier ones):");
+            for (i, path) in loaded_files.iter().enumerate() {
+                info!("  {}. {}", i + 1, path);
+            }
+            Ok(strict::deserialize_config(settings)?)
+        }
+        Err(e) => {
+            error!(
+                "Failed to load configuration from '{}': {}. Attempting fallback 'config.example.toml'.",
+                loaded_files.join("', '"),
+                e
+            );
+            // If the primary config failed (e.g. not found or malformed), try the example config as a fallback.
+            load_fallback_config()
+        }
+    }
+}
+
+// Builds the auth headers sent with every API request to an instance
+fn build_auth_headers(
+    instance_config: &InstanceConfig,
+) -> Result<HeaderMap, Box<dyn Error + Send + Sync>> {
+    let api_token = instance_config.api_token.as_deref().ok_or_else(|| {
+        format!(
+            "No API token for {} (not authenticated yet)",
+            instance_config.base_url
+        )
+    })?;
+    let mut headers = HeaderMap::new();
+    let (name, value) = instance_config.auth_header(Some(api_token));
+    match HeaderValue::from_str(&value) {
+        Ok(header_val) => {
+            headers.insert(name, header_val);
+        }
+        Err(e) => {
+            return Err(Box::new(e) as Box<dyn Error + Send + Sync>);
+        }
+    }
+    if !instance_config.send_emby_token_header {
+        return Ok(headers);
+    }
+    // Jellyfin also often requires X-Emby-Token
+    match HeaderValue::from_str(api_token) {
+        // Use the raw token for X-Emby-Token
+        Ok(header_val) => {
+            headers.insert("X-Emby-Token", header_val);
+        }
+        Err(e) => {
+            return Err(Box::new(e) as Box<dyn Error + Send + Sync>);
+        }
+    }
+    Ok(headers)
+}
+
+#[instrument(name = "instance", skip_all, fields(base_url = %instance_config.base_url))]
+pub async fn fetch_users_from_instance(
+    instance_config: &InstanceConfig,
+    api: &api::ApiClient,
+) -> Result<Vec<JellyfinUser>, MigrationError> {
+    let url = format!("{}/Users", instance_config.base_url);
+    info!("Fetching users from: {}", url);
+    let users = match instance_config.user_page_size {
+        0 => users::fetch_all(instance_config, api).await?,
+        page_size => users::fetch_paged(instance_config, api, page_size).await?,
+    };
+    Ok(users)
+}
+
+// With how long the fetch took, successful or not
+async fn fetch_users_timed(
+    instance_config: &InstanceConfig,
+    api: &api::ApiClient,
+) -> (Result<Vec<JellyfinUser>, MigrationError>, Duration) {
+    let started = Instant::now();
+    let result = fetch_users_from_instance(instance_config, api).await;
+    (result, started.elapsed())
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct UnmappedOldUsers {
+    ambiguous: u64, // Names, emails or similar names shared with other users
+    not_found: u64,
+}
+
+// Names shared on either instance are reported before matching, since otherwise they only show
+// up as the old users they leave unmapped
+fn report_duplicate_names(config: &Config, old_users: &[JellyfinUser], new_users: &[JellyfinUser]) {
+    let match_mode = config.user_match_mode();
+    let mut found = false;
+    for (old, users) in [(true, old_users), (false, new_users)] {
+        for group in matching::duplicate_names(users, match_mode) {
+            found = true;
+            let users = group
+                .iter()
+                .map(|user| format!("'{}' (ID: '{}')", user.name, user.id))
+                .collect::<Vec<_>>()
+                .join(", ");
+            let comparison = match_mode.describe();
+            if old {
+                warn!(
+                    "{}",
+                    msg!("map.duplicate_old", users = users, comparison = comparison)
+                );
+            } else {
+                warn!(
+                    "{}",
+                    msg!("map.duplicate_new", users = users, comparison = comparison)
+                );
+            }
+        }
+    }
+    if found && config.allow_ambiguous_first_match {
+        warn!("{}", msg!("map.duplicate_first_match"));
+    } else if found {
+        warn!("{}", msg!("map.duplicate_skipped"));
+    }
+}
+
+// With summary_only just the counts are printed instead of a line per user
+pub fn create_user_id_map(
+    config: &mut Config,
+    old_users: &[JellyfinUser],
+    new_users: &[JellyfinUser],
+    summary_only: bool,
+) -> Result<HashMap<String, String>, MigrationError> {
+    let mut user_id_map = HashMap::new();
+    let mut unmapped: Vec<(&JellyfinUser, &'static str)> = Vec::new();
+    let mut by_email = 0;
+    let mut by_similar_name = 0;
+    let mut conflicts = 0;
+    let mut not_found = 0;
+
+    info!("\n{}", msg!("map.title"));
+    config.user_filter = userfilter::UserFilter::new(config, old_users);
+    config.user_filter.print_left_out(old_users);
+    // Users left out by the filter aren't mapped at all
+    let old_users: Vec<JellyfinUser> = old_users
+        .iter()
+        .filter(|user| config.user_filter.keeps(&user.id))
+        .cloned()
+        .collect();
+    let old_users = old_users.as_slice();
+    report_duplicate_names(config, old_users, new_users);
+    let overridden = config
+        .user_map_overrides
+        .resolve(old_users, new_users)
+        .map_err(MigrationError::user_mapping)?;
+    for (old_user, new_id, source) in &overridden {
+        user_id_map.insert(old_user.id.clone(), new_id.clone());
+        if !summary_only {
+            info!(
+                "{}",
+                msg!(
+                    "map.override",
+                    old_name = old_user.name,
+                    old_id = old_user.id,
+                    new_id = new_id,
+                    source = source.describe()
+                )
+            );
+        }
+    }
+    let remaining_old_users: Vec<JellyfinUser> = old_users
+        .iter()
+        .filter(|user| !user_id_map.contains_key(&user.id))
+        .cloned()
+        .collect();
+    let match_mode = config.user_match_mode();
+    let outcomes = matching::match_users(
+        &remaining_old_users,
+        new_users,
+        match_mode,
+        config.fuzzy_match_threshold,
+        config.allow_ambiguous_first_match,
+    );
+    for (old_user, outcome) in outcomes {
+        match outcome {
+            matching::Outcome::ByName(new_user) => {
+                user_id_map.insert(old_user.id.clone(), new_user.id.clone());
+                if !summary_only && new_user.name != old_user.name {
+                    info!(
+                        "{}",
+                        msg!(
+                            "map.by_name_renamed",
+                            old_name = old_user.name,
+                            old_id = old_user.id,
+                            new_id = new_user.id,
+                            new_name = new_user.name
+                        )
+                    );
+                } else if !summary_only {
+                    info!(
+                        "{}",
+                        msg!(
+                            "map.by_name",
+                            old_name = old_user.name,
+                            old_id = old_user.id,
+                            new_id = new_user.id
+                        )
+                    );
+                }
+            }
+            matching::Outcome::NameCollision(new_users) => {
+                conflicts += 1;
+                unmapped.push((old_user, "name matches several new users"));
+                warn!(
+                    "{}",
+                    msg!(
+                        "map.name_collision",
+                        old_name = old_user.name,
+                        old_id = old_user.id,
+                        new_names = quoted_names(&new_users),
+                        comparison = match_mode.describe()
+                    )
+                );
+            }
+            matching::Outcome::OldNameCollision(other_old_users) => {
+                conflicts += 1;
+                unmapped.push((old_user, "name shared with another old user"));
+                warn!(
+                    "{}",
+                    msg!(
+                        "map.old_name_collision",
+                        old_name = old_user.name,
+                        old_id = old_user.id,
+                        old_users = other_old_users
+                            .iter()
+                            .map(|user| format!("'{}' (ID: '{}')", user.name, user.id))
+                            .collect::<Vec<_>>()
+                            .join(", "),
+                        comparison = match_mode.describe()
+                    )
+                );
+            }
+            matching::Outcome::ByEmail(new_user, email) => {
+                user_id_map.insert(old_user.id.clone(), new_user.id.clone());
+                by_email += 1;
+                if !summary_only {
+                    info!(
+                        "{}",
+                        msg!(
+                            "map.by_email",
+                            old_name = old_user.name,
+                            old_id = old_user.id,
+                            new_id = new_user.id,
+                            email = email,
+                            new_name = new_user.name
+                        )
+                    );
+                }
+            }
+            // Always shown, these users need mapping by hand
+            matching::Outcome::EmailConflict(new_user, email) => {
+                conflicts += 1;
+                unmapped.push((old_user, "email shared with another old user"));
+                warn!(
+                    "{}",
+                    msg!(
+                        "map.email_conflict",
+                        old_name = old_user.name,
+                        old_id = old_user.id,
+                        email = email,
+                        new_name = new_user.name
+                    )
+                );
+            }
+            matching::Outcome::BySimilarName(new_user, score) => {
+                user_id_map.insert(old_user.id.clone(), new_user.id.clone());
+                by_similar_name += 1;
+                if !summary_only {
+                    info!(
+                        "{}",
+                        msg!(
+                            "map.by_similar_name",
+                            old_name = old_user.name,
+                            old_id = old_user.id,
+                            new_id = new_user.id,
+                            new_name = new_user.name,
+                            score = format!("{:.2}", score)
+                        )
+                    );
+                }
+            }
+            matching::Outcome::SimilarNameTie(new_users, score) => {
+                conflicts += 1;
+                unmapped.push((old_user, "equally similar to several new users"));
+                warn!(
+                    "{}",
+                    msg!(
+                        "map.similar_name_tie",
+                        old_name = old_user.name,
+                        old_id = old_user.id,
+                        new_names = quoted_names(&new_users),
+                        score = format!("{:.2}", score)
+                    )
+                );
+            }
+            matching::Outcome::NotFound => {
+                not_found += 1;
+                unmapped.push((old_user, "not found by name or email"));
+                if !summary_only {
+                    info!(
+                        "{}",
+                        msg!(
+                            "map.not_found",
+                            old_name = old_user.name,
+                            old_id = old_user.id
+                        )
+                    );
+                }
+            }
+        }
+    }
+    if summary_only {
+        info!(
+            "{}",
+            msg!(
+                "map.counts",
+                mapped = display::format_count(user_id_map.len() as u64),
+                total = display::format_count(old_users.len() as u64),
+                by_email = display::format_count(by_email),
+                by_similar_name = display::format_count(by_similar_name),
+                not_found = display::format_count(not_found),
+                ambiguous = display::format_count(conflicts)
+            )
+        );
+    }
+    if !overridden.is_empty() {
+        let from = |source: overrides::Source| {
+            let count = overridden.iter().filter(|(_, _, s)| *s == source).count();
+            (count > 0).then(|| {
+                msg!(
+                    "map.source_count",
+                    count = display::format_count(count as u64),
+                    source = source.describe()
+                )
+            })
+        };
+        info!(
+            "{}",
+            msg!(
+                "map.sources",
+                overrides = [
+                    from(overrides::Source::Config),
+                    from(overrides::Source::File)
+                ]
+                .into_iter()
+                .flatten()
+                .collect::<Vec<_>>()
+                .join(", "),
+                automatic = display::format_count((user_id_map.len() - overridden.len()) as u64)
+            )
+        );
+    }
+    if user_id_map.is_empty() {
+        info!("{}", msg!("map.empty"));
+    }
+    if let Some(ref path) = config.unmapped_report_path {
+        unmapped::write_report(path, &unmapped, new_users, &user_id_map)?;
+    }
+    config.unmapped_old_users = UnmappedOldUsers {
+        ambiguous: conflicts,
+        not_found,
+    };
+    Ok(user_id_map)
+}
+
+// "'alice', 'Alice'" for the warnings about several matching users
+fn quoted_names(users: &[&JellyfinUser]) -> String {
+    users
+        .iter()
+        .map(|user| format!("'{}'", user.name))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// How many names from each side the empty-map diagnostic lists
+const DIAGNOSTIC_SAMPLE_NAMES: usize = 5;
+
+// Both instances returned users but no name matched, so every record would pass through unmapped.
+// Almost always a configuration problem, so explain the likely causes instead of carrying on quietly.
+fn report_empty_user_map(old_users: &[JellyfinUser], new_users: &[JellyfinUser]) {
+    warn!("\n{}", msg!("empty_map.title"));
+    warn!(
+        "{}",
+        msg!(
+            "empty_map.intro",
+            old_count = old_users.len(),
+            new_count = new_users.len()
+        )
+    );
+    let case_only: Vec<&str> = old_users
+        .iter()
+        .filter(|old| {
+            new_users
+                .iter()
+                .any(|new| new.name.to_lowercase() == old.name.to_lowercase())
+        })
+        .map(|old| old.name.as_str())
+        .collect();
+    if !case_only.is_empty() {
+        warn!(
+            "{}",
+            msg!(
+                "empty_map.case_only",
+                count = case_only.len(),
+                example = case_only[0]
+            )
+        );
+    }
+    // Same account on both sides (e.g. a restored database) with IDs written with/without dashes
+    let normalize_id = |id: &str| id.replace('-', "").to_lowercase();
+    let same_ids = old_users
+        .iter()
+        .filter(|old| {
+            new_users
+                .iter()
+                .any(|new| normalize_id(&new.id) == normalize_id(&old.id))
+        })
+        .count();
+    if same_ids > 0 {
+        warn!("{}", msg!("empty_map.same_ids", count = same_ids));
+    }
+    let sample = |users: &[JellyfinUser]| {
+        users
+            .iter()
+            .take(DIAGNOSTIC_SAMPLE_NAMES)
+            .map(|user| format!("'{}'", user.name))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    warn!("{}", msg!("empty_map.old_users", names = sample(old_users)));
+    warn!("{}", msg!("empty_map.new_users", names = sample(new_users)));
+    warn!("{}", msg!("empty_map.hint"));
+}
+
+// `dedup_columns` are indexes into TsvRecord::fields, see schema::dedup_columns
+fn check_and_insert_record_into_db(
+    conn: &Connection,
+    table_name: &str,
+    record: &TsvRecord,
+    dedup_columns: &[usize],
+    integer_play_duration: bool,
+) -> Result<bool, rusqlite::Error> {
+    // Returns true if inserted, false if skipped (duplicate)
+    // Check if a record matching on every dedup column already exists
+    let column_names = schema::column_names();
+    let conditions: Vec<String> = dedup_columns
+        .iter()
+        .enumerate()
+        .map(|(param, &column)| format!("{} = ?{}", column_names[column], param + 1))
+        .collect();
+    let check_query = format!(
+        "SELECT EXISTS(SELECT 1 FROM {} WHERE {} LIMIT 1)",
+        table_name,
+        conditions.join(" AND ")
+    );
+    let fields = record.fields();
+    let mut stmt_check = conn.prepare_cached(&check_query)?;
+    let exists: bool = stmt_check.query_row(
+        params_from_iter(dedup_columns.iter().map(|&column| fields[column])),
+        |row| row.get(0),
+    )?;
+
+    if exists {
+        Ok(false) // Record already exists, skip insertion
+    } else {
+        insert_record_into_db(conn, table_name, record, integer_play_duration)?;
+        Ok(true) // Record was inserted
+    }
+}
+
+// Inserts without checking for duplicates first
+// With `integer_play_duration` (the column has INTEGER affinity) a numeric PlayDuration is bound
+// as an integer, otherwise as the text that also goes to the TSV output
+fn insert_record_into_db(
+    conn: &Connection,
+    table_name: &str,
+    record: &TsvRecord,
+    integer_play_duration: bool,
+) -> Result<(), rusqlite::Error> {
+    let insert_query = format!(
+        "INSERT INTO {} (DateCreated, UserId, ItemId, ItemType, ItemName, PlaybackMethod, ClientName, DeviceName, PlayDuration) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        table_name
+    );
+    let mut stmt_insert = conn.prepare_cached(&insert_query)?;
+    stmt_insert.execute(params![
+        record.date_created,
+        record.user_id,
+        record.item_id,
+        record.item_type,
+        record.item_name,
+        record.playback_method,
+        record.client_name,
+        record.device_name,
+        match record.play_duration.parse::<i64>() {
+            Ok(n) if integer_play_duration => Value::Integer(n),
+            _ => Value::Text(record.play_duration.clone()),
+        },
+    ])?;
+    Ok(())
+}
+
+#[derive(Debug, Default)]
+struct ProcessingStats {
+    started_at: DateTime<Utc>,
+    records_processed: u64,
+    records_changed: u64,
+    records_unchanged: u64,
+    records_device_renamed: u64,
+    records_inserted_sqlite: u32, // Counter for SQLite inserts
+    records_skipped_sqlite: u32,  // Counter for skipped duplicate SQLite records
+    // Old_ID -> (New_ID, Count of changes for this Old_ID)
+    changes_summary: BTreeMap<String, (String, u32)>,
+    mode: RunMode,
+    dry_run_samples: Vec<String>, // A few example changes shown in dry runs
+    preload_duration: Option<Duration>, // Loading the existing dedup keys when opening SQLite
+    eta: Option<eta::EtaAccuracy>, // The progress bar's first estimate against the run
+    sinks_disabled: Vec<String>,  // Outputs dropped mid-run by sink_failure_policy, with the error
+    records_skipped_user_filter: u64, // Of users left out by include_users/exclude_users
+    records_dropped_retention: u64, // Older than retention allows, never reach an output
+    retention_dropped_per_user: BTreeMap<String, u64>, // Keyed by old user ID
+    unmapped_policy: mapping::UnmappedPolicyStats, // With unmapped_user_policy "drop" or "fallback"
+    fields_over_max_length: BTreeMap<&'static str, u64>, // Column name -> values truncated/rejected
+    records_rejected_field_length: u64, // With field_length_policy = "reject"
+    future_dates: futuredates::FutureDateStats, // DateCreated past future_date_slack_hours
+    duplicate_dates: duplicates::DuplicateDates, // Of the records skipped as duplicates
+    rollup: rollup::RollupStats,  // With output_mode = "daily_rollup"
+    destinations: Vec<sinks::DestinationChange>, // Size of each output before and after the run
+    session_merge: sessions::SessionMergeStats, // With session_merge_window_secs
+    // With mapping_direction = "auto", both are also counted in records_unchanged
+    records_already_migrated: u64, // UserId already belongs to the new instance
+    unmapped_user_ids: BTreeMap<String, u64>, // UserIds known to neither side -> records
+    tracked_users: tracked::TrackedUsers, // Caps the per-user maps above
+    play_duration: playduration::PlayDurationStats, // Values rounded or left as text
+    checkpoint: resume::CheckpointStats, // With --state-file
+    clock_skews: Vec<clock::ClockSkew>, // Of the instances, for the record
+    item_ids: itemid::ItemIdStats, // Unwrapped or reformatted ItemIds
+    item_map: items::ItemMapStats, // With map_item_ids
+    downsample: downsample::DownsampleStats, // With --downsample
+    split: split::SplitStats,      // With [[split_user]]
+    merges: merges::MergeStats,    // Of old users sharing a new user
+    user_map_warning: Option<String>, // The run went on with a partial or empty user map
+    explained: Vec<explain::Trace>, // With --explain, in line order
+}
+
+impl ProcessingStats {
+    // Records that were read but filtered out before reaching any output
+    fn records_dropped(&self) -> u64 {
+        self.records_skipped_user_filter
+            + self.records_dropped_retention
+            + self.records_rejected_field_length
+            + self.future_dates.records_rejected
+            + self.unmapped_policy.records_dropped
+    }
+
+    // Records sent to the outputs: every record that wasn't dropped or merged into another, with
+    // the rolled-up ones replaced by their synthetic rows, less the ones --downsample left out
+    fn records_to_outputs(&self) -> u64 {
+        self.records_processed
+            - self.records_dropped()
+            - self.session_merge.rows_merged
+            - self.rollup.records_rolled_up
+            + self.rollup.rows_written
+            - self.downsample.rows_dropped
+    }
+}
+
+// Whether a run writes its outputs or only reports what it would do
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum RunMode {
+    #[default]
+    Normal,
+    DryRun,       // No outputs are opened at all
+    DryRunWithDb, // SQLite is opened read-only to predict inserts/skips, nothing is written
+}
+
+impl RunMode {
+    fn is_dry_run(self) -> bool {
+        self != RunMode::Normal
+    }
+}
+
+// How many example changes a dry run prints
+const MAX_DRY_RUN_SAMPLES: usize = 5;
+
+// Describes which fields differ between the record as read and as it would be written
+fn describe_record_changes(record_number: u64, before: &TsvRecord, after: &TsvRecord) -> String {
+    let changes: Vec<String> = schema::column_names()
+        .iter()
+        .zip(before.fields().iter().zip(after.fields().iter()))
+        .filter(|(_, (old, new))| old != new)
+        .map(|(column, (old, new))| {
+            format!(
+                "{} '{}' -> '{}'",
+                column,
+                truncate_display(old, 60),
+                truncate_display(new, 60)
+            )
+        })
+        .collect();
+    format!("record {}: {}", record_number, changes.join(", "))
+}
+
+// Applies every configured rewrite to a record before it is written to the outputs. Returns
+// false when unmapped_user_policy drops the record, and an error when it stops the run.
+fn transform_record(
+    record: &mut TsvRecord,
+    config: &Config,
+    user_id_map: &HashMap<String, String>,
+    stats: &mut ProcessingStats,
+) -> Result<bool, String> {
+    if !config.split_plan.apply(record, stats)
+        && !mapping::apply_user_id_map(record, user_id_map, config, stats)?
+    {
+        return Ok(false);
+    }
+
+    if let Some(new_device_name) = config.device_name_map.get(&record.device_name) {
+        record.device_name = new_device_name.clone();
+        stats.records_device_renamed += 1;
+    }
+    itemid::normalize(
+        &mut record.item_id,
+        config.item_id_format,
+        &mut stats.item_ids,
+    );
+    config.item_id_map.apply(
+        &mut record.item_id,
+        config.item_id_format,
+        &mut stats.item_map,
+    );
+    playduration::canonicalize(
+        &mut record.play_duration,
+        config.play_duration_rounding,
+        &mut stats.play_duration,
+    );
+    config.duration_units.convert(
+        record,
+        config.play_duration_rounding,
+        &mut stats.play_duration,
+    );
+    Ok(true)
+}
+
+// Cross-checks the counters so counting regressions show up as soon as they happen.
+// Returns a description of every violated invariant.
+fn check_stats_invariants(config: &Config, stats: &ProcessingStats) -> Vec<String> {
+    let mut violations = Vec::new();
+    if stats.records_processed
+        != stats.records_changed + stats.records_unchanged + stats.records_dropped()
+    {
+        violations.push(format!(
+            "records_processed ({}) != records_changed ({}) + records_unchanged ({}) + records dropped by retention/field length/future dates/unmapped_user_policy ({})",
+            stats.records_processed,
+            stats.records_changed,
+            stats.records_unchanged,
+            stats.records_dropped()
+        ));
+    }
+    let changed_in_summary: u64 = stats
+        .changes_summary
+        .values()
+        .map(|(_, count)| *count as u64)
+        .sum();
+    if changed_in_summary != stats.records_changed {
+        violations.push(format!(
+            "sum of per-user changes ({}) != records_changed ({})",
+            changed_in_summary, stats.records_changed
+        ));
+    }
+    // Every record that isn't dropped (or rolled up) reaches SQLite when it is the only sink
+    // (predicted counts from --dry-run-with-db must add up the same way)
+    if stats.mode != RunMode::DryRun
+        && stats.sinks_disabled.is_empty()
+        && config.sqlite_db_path.is_some()
+        && config.output_tsv_file_path.is_none()
+    {
+        let sqlite_total =
+            stats.records_inserted_sqlite as u64 + stats.records_skipped_sqlite as u64;
+        if sqlite_total != stats.records_to_outputs() {
+            violations.push(format!(
+                "records_inserted_sqlite ({}) + records_skipped_sqlite ({}) != records_processed ({}) - records dropped ({}) - rows merged ({}) - records rolled up ({}) + rolled-up rows ({}) - rows downsampled away ({})",
+                stats.records_inserted_sqlite,
+                stats.records_skipped_sqlite,
+                stats.records_processed,
+                stats.records_dropped(),
+                stats.session_merge.rows_merged,
+                stats.rollup.records_rolled_up,
+                stats.rollup.rows_written,
+                stats.downsample.rows_dropped
+            ));
+        }
+    }
+    violations
+}
+
+fn report_stats_invariants(config: &Config, stats: &ProcessingStats) {
+    for violation in check_stats_invariants(config, stats) {
+        error!(
+            "BUG: internal record count invariant violated: {}. Please report this along with your configuration.",
+            violation
+        );
+    }
+}
+
+// Appended to the duplicate counts when the check doesn't compare every column
+fn dedup_note(config: &Config) -> String {
+    if config.dedup_ignore_columns.is_empty() {
+        String::new()
+    } else {
+        msg!(
+            "summary.dedup_note",
+            columns = config.dedup_ignore_columns.join(", ")
+        )
+    }
+}
+
+// Said again in the summary, since the mapping output has long scrolled away by then
+fn user_map_warning(config: &Config, user_id_map: &HashMap<String, String>) -> Option<String> {
+    if !config.user_fetch_failures.is_empty() {
+        return Some(format!(
+            "ran with {} user map, fetching the users of the {} instance failed (ignored with --ignore-fetch-errors / allow_partial_user_fetch). {} UserIds could be mapped.",
+            if user_id_map.is_empty() { "an empty" } else { "a partial" },
+            config.user_fetch_failures.join(" and "),
+            user_id_map.len()
+        ));
+    }
+    if user_id_map.is_empty() {
+        return Some("ran with an empty user map, no UserIds were mapped.".to_string());
+    }
+    None
+}
+
+fn print_processing_summary(config: &Config, stats: &ProcessingStats) {
+    let formatter = config.time_formatter();
+    let finished_at = timefmt::now();
+    info!(
+        "{}",
+        msg!("summary.started", time = formatter.format(stats.started_at))
+    );
+    info!(
+        "{}",
+        msg!("summary.finished", time = formatter.format(finished_at))
+    );
+    info!(
+        "{}",
+        msg!(
+            "summary.duration",
+            duration = humanize_duration(
+                (finished_at - stats.started_at)
+                    .to_std()
+                    .unwrap_or_default()
+            )
+        )
+    );
+    if let Some(preload) = stats.preload_duration {
+        info!(
+            "{}",
+            msg!(
+                "summary.dedup_preload",
+                duration = humanize_duration(preload)
+            )
+        );
+    }
+    if let Some(ref accuracy) = stats.eta {
+        info!(
+            "{}",
+            msg!(
+                "summary.eta_accuracy",
+                records = display::format_count(accuracy.after_records),
+                estimated = humanize_duration(accuracy.estimated),
+                actual = humanize_duration(accuracy.actual),
+                deviation = accuracy.deviation()
+            )
+        );
+    }
+    clock::print_summary(&stats.clock_skews);
+    if let Some(ref warning) = stats.user_map_warning {
+        info!("{}", msg!("summary.warning", warning = warning));
+    }
+    let unmapped = config.unmapped_old_users;
+    if unmapped.ambiguous + unmapped.not_found > 0 {
+        info!(
+            "{}",
+            msg!(
+                "summary.unmapped_users",
+                ambiguous = unmapped.ambiguous,
+                not_found = unmapped.not_found
+            )
+        );
+    }
+    if stats.mode.is_dry_run() {
+        info!("{}", msg!("summary.dry_run"));
+    }
+    info!(
+        "{}",
+        msg!("summary.records_processed", count = stats.records_processed)
+    );
+    info!(
+        "{}",
+        msg!("summary.records_changed", count = stats.records_changed)
+    );
+    if config.mapping_direction == mapping::MappingDirection::Auto {
+        mapping::print_auto_detection_summary(stats);
+    }
+    if config.item_id_map.enabled {
+        stats.item_map.print_summary();
+    }
+    if !config.split_user.is_empty() {
+        stats.split.print_summary();
+    }
+    if !config.merge_plan.is_empty() {
+        config.merge_plan.print_summary(&stats.merges);
+    }
+    if !config.device_name_map.is_empty() {
+        info!(
+            "{}",
+            msg!(
+                "summary.device_renamed",
+                count = stats.records_device_renamed
+            )
+        );
+    }
+    if config.user_filter.is_active() {
+        info!(
+            "{}",
+            msg!(
+                "summary.skipped_user_filter",
+                count = stats.records_skipped_user_filter
+            )
+        );
+    }
+    if config.retention_days.is_some() || !config.retention_overrides.is_empty() {
+        info!(
+            "{}",
+            msg!(
+                "summary.dropped_retention",
+                count = stats.records_dropped_retention
+            )
+        );
+        let mut dropped: Vec<(&String, &u64)> = stats.retention_dropped_per_user.iter().collect();
+        dropped.sort();
+        for (old_id, count) in dropped {
+            info!(
+                "{}",
+                msg!(
+                    "summary.dropped_retention_user",
+                    old_id = old_id,
+                    count = count
+                )
+            );
+        }
+    }
+    mapping::print_unmapped_policy_summary(config, stats);
+    if !stats.fields_over_max_length.is_empty() {
+        info!(
+            "{}",
+            match config.field_length_policy {
+                limits::FieldLengthPolicy::Truncate => msg!("summary.over_max_length_truncated"),
+                limits::FieldLengthPolicy::Reject => msg!("summary.over_max_length_rejected"),
+            }
+        );
+        for (column, count) in &stats.fields_over_max_length {
+            info!("    {}: {}", column, count);
+        }
+        if config.field_length_policy == limits::FieldLengthPolicy::Reject {
+            info!(
+                "{}",
+                msg!(
+                    "summary.rejected_field_length",
+                    count = stats.records_rejected_field_length
+                )
+            );
+        }
+    }
+    stats.future_dates.print_summary(config, &stats.clock_skews);
+    stats
+        .play_duration
+        .print_summary(config.play_duration_rounding);
+    stats.item_ids.print_summary(config.item_id_format);
+    stats.checkpoint.print_summary(stats.records_processed);
+    if config.session_merge_window_secs.is_some() {
+        stats.session_merge.print_summary();
+    }
+    if config.output_mode == rollup::OutputMode::DailyRollup
+        || !config.output_mode_overrides.is_empty()
+    {
+        stats.rollup.print_summary();
+    }
+    if let Some(ref downsample) = config.downsample {
+        stats.downsample.print_summary(downsample);
+    }
+    let records_kept = stats.records_to_outputs();
+    if stats.mode.is_dry_run() {
+        if let Some(ref path_str) = config.output_tsv_file_path {
+            info!(
+                "{}",
+                msg!(
+                    "summary.would_write_file",
+                    format = columnar::format_name(config),
+                    path = path_str,
+                    count = records_kept
+                )
+            );
+        }
+        match (&config.sqlite_db_path, stats.mode) {
+            (Some(db_path_str), RunMode::DryRunWithDb) => {
+                info!(
+                    "{}",
+                    msg!(
+                        "summary.would_insert_sqlite",
+                        path = db_path_str,
+                        count = stats.records_inserted_sqlite
+                    )
+                );
+                info!(
+                    "{}",
+                    msg!(
+                        "summary.would_skip_sqlite",
+                        count = stats.records_skipped_sqlite,
+                        dedup_note = dedup_note(config)
+                    )
+                );
+                stats.duplicate_dates.print_summary();
+            }
+            (Some(db_path_str), _) if !Path::new(db_path_str).exists() => info!(
+                "{}",
+                msg!(
+                    "summary.would_insert_new_sqlite",
+                    path = db_path_str,
+                    count = records_kept
+                )
+            ),
+            (Some(db_path_str), _) => info!(
+                "{}",
+                msg!(
+                    "summary.would_check_sqlite",
+                    path = db_path_str,
+                    count = records_kept
+                )
+            ),
+            (None, _) => {}
+        }
+        if !stats.dry_run_samples.is_empty() {
+            info!("{}", msg!("summary.sample_changes"));
+            for sample in &stats.dry_run_samples {
+                info!("    {}", sample);
+            }
+        }
+    } else if config.sqlite_db_path.is_some() {
+        // Only print SQLite stats if it was configured
+        info!(
+            "{}",
+            msg!(
+                "summary.inserted_sqlite",
+                count = stats.records_inserted_sqlite
+            )
+        );
+        info!(
+            "{}",
+            msg!(
+                "summary.skipped_sqlite",
+                count = stats.records_skipped_sqlite,
+                dedup_note = dedup_note(config)
+            )
+        );
+        stats.duplicate_dates.print_summary();
+    }
+    for destination in &stats.destinations {
+        if let Some(line) = destination.describe() {
+            info!("  {}", line);
+        }
+    }
+    if !stats.sinks_disabled.is_empty() {
+        // Their output is incomplete, and a disabled SQLite output rolled back its open transaction
+        info!("{}", msg!("summary.sinks_disabled"));
+        for disabled in &stats.sinks_disabled {
+            info!("    {}", disabled);
+        }
+    }
+    if !stats.changes_summary.is_empty() {
+        info!("{}", msg!("summary.changes_title"));
+        // HashMap iteration order changes between runs so sort to keep the output comparable
+        let mut sorted_changes: Vec<(&String, &(String, u32))> =
+            stats.changes_summary.iter().collect();
+        match config.summary_sort_order {
+            SummarySortOrder::OldId => sorted_changes.sort_by(|a, b| a.0.cmp(b.0)),
+            SummarySortOrder::ChangeCount => {
+                sorted_changes.sort_by(|a, b| b.1 .1.cmp(&a.1 .1).then_with(|| a.0.cmp(b.0)))
+            }
+        }
+        for (old_id, (new_id, count)) in sorted_changes {
+            info!(
+                "{}",
+                msg!(
+                    "summary.change",
+                    old_id = old_id,
+                    new_id = new_id,
+                    count = count
+                )
+            );
+        }
+    } else if stats.records_changed > 0 {
+        // This case should ideally not be hit if logic is correct
+        info!("{}", msg!("summary.changes_untracked"));
+    } else {
+        info!("{}", msg!("summary.no_changes"));
+    }
+    stats.tracked_users.print_warning();
+    explain::print_summary(&stats.explained);
+}
+
+// With --downsample the record is only buffered, the kept ones are written once the input is read
+fn write_or_downsample(
+    record: TsvRecord,
+    downsampler: &mut Option<downsample::Downsampler>,
+    sinks: &mut sinks::SinkSet,
+    stats: &mut ProcessingStats,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    match downsampler {
+        Some(downsampler) => downsampler.push(record),
+        None => sinks.write(&record, stats)?,
+    }
+    Ok(())
+}
+
+#[instrument(name = "processing", skip_all)]
+async fn process_tsv_file(
+    config: &Config,
+    user_id_map: &HashMap<String, String>,
+    retention: &retention::RetentionPolicy,
+    rollup: &rollup::RollupPolicy,
+    audit: Option<&history::RunAudit>,
+    mode: RunMode,
+    tui: bool,
+) -> Result<ProcessingStats, MigrationError> {
+    let dry_run = mode.is_dry_run();
+    if mode == RunMode::DryRunWithDb {
+        info!("\nStarting TSV/DB processing (DRY RUN, SQLite is only read to predict inserts, nothing will be written)...");
+    } else if dry_run {
+        info!("\nStarting TSV/DB processing (DRY RUN, no outputs will be opened or written)...");
+    } else {
+        info!("\nStarting TSV/DB processing...");
+    }
+    info!("Input TSV file: {}", config.input_tsv_file_path);
+
+    // Count lines for progress bar
+    let file_for_counting = fs::File::open(&config.input_tsv_file_path)?;
+    let reader_for_counting = BufReader::new(file_for_counting);
+    let total_lines = reader_for_counting.lines().count() as u64;
+
+    let pb = ProgressBar::new(total_lines);
+    pb.set_style(ProgressStyle::default_bar()
+        .template("{spinner:.green} [{bar:40.cyan/blue}] {pos}/{len} ({percent}%) elapsed {elapsed_precise}, remaining (est.) {prefix} - {msg}")
+        .expect("Progress bar style template is invalid")
+        .progress_chars("#>-"));
+    pb.set_prefix("--:--:--");
+    pb.set_message("Processing records...");
+    // Log lines are printed above the bar until the end of processing
+    let attached = logging::attach_progress_bar(&pb);
+
+    if user_id_map.is_empty() {
+        info!("User ID map is empty. No UserID replacements will be made, but data will be processed to configured outputs.");
+    }
+
+    let mut rdr = csv::ReaderBuilder::new()
+        .delimiter(b'\t')
+        .has_headers(false) // Input TSV does not have headers
+        .comment(Some(b'#')) // Skips the optional schema stamp of files written by this tool
+        .from_path(&config.input_tsv_file_path)?;
+
+    let mut stats = ProcessingStats {
+        started_at: timefmt::now(),
+        mode,
+        tracked_users: tracked::TrackedUsers::from_config(config),
+        clock_skews: config.clock_skews.clone(),
+        user_map_warning: user_map_warning(config, user_id_map),
+        ..Default::default()
+    };
+    let field_limits = limits::FieldLimits::new(config)?;
+    let future_dates = futuredates::FutureDates::new(config, Utc::now());
+    let mut explainer = explain::Explainer::new(config, dry_run)?;
+    let mut daily_rollup = rollup::DailyRollup::default();
+    let mut session_merger = config
+        .session_merge_window_secs
+        .map(|window| sessions::SessionMerger::new(window, config.session_merge_duration));
+    let mut downsampler = config.downsample.map(downsample::Downsampler::new);
+
+    // Open every configured output (nothing is opened for writing in dry runs)
+    let mut sinks = sinks::open_sinks(config, mode, audit)?;
+    stats.preload_duration = sinks.preload_duration();
+    sinks.begin(&mut stats)?;
+
+    if config.output_tsv_file_path.is_none() && config.sqlite_db_path.is_none() {
+        warn!("\nNo output (TSV or SQLite) is configured. The application will process data but not save it.");
+        // Early exit or just let it run through without outputting might be desired.
+        // For now, it will run through, which is fine for UserID mapping summary.
+    }
+
+    // Started once the outputs are open so their log lines have been printed
+    let mut dashboard = match (tui, io::stdout().is_terminal()) {
+        (true, true) => {
+            pb.set_draw_target(ProgressDrawTarget::hidden());
+            let mut dashboard = tui::Dashboard::start(total_lines)?;
+            dashboard.log(format!("Processing {}", config.input_tsv_file_path));
+            Some(dashboard)
+        }
+        (true, false) => {
+            info!("--tui needs a terminal, showing the progress bar instead.");
+            None
+        }
+        (false, _) => None,
+    };
+    let mut stopped_early = false;
+
+    // Records done by the run that wrote the state file
+    if let Some(ref checkpoint) = config.checkpoint {
+        let mut last_date_created = None;
+        for result in rdr.byte_records().take(checkpoint.records_done() as usize) {
+            let record = result?;
+            if let Some(position) = record.position() {
+                explainer.skipped(
+                    position.line(),
+                    "The line was done by the run that wrote the --state-file, skipped",
+                );
+            }
+            last_date_created = record
+                .get(0)
+                .map(|date| String::from_utf8_lossy(date).into_owned());
+            stats.checkpoint.records_skipped += 1;
+        }
+        checkpoint.check_skipped(
+            stats.checkpoint.records_skipped,
+            last_date_created.as_deref(),
+        )?;
+        stats.checkpoint.last_date_created = last_date_created;
+        pb.set_position(stats.checkpoint.records_skipped);
+    }
+
+    progress::PROGRESS.set_total(total_lines);
+    progress::PROGRESS.set_phase("processing");
+    let mut eta = eta::Eta::new(total_lines, pb.position());
+    for result in rdr.records() {
+        progress::PROGRESS.update(&stats);
+        let choice = match dashboard {
+            Some(ref mut dashboard) => dashboard.update(&stats)?,
+            None => None,
+        };
+        match choice {
+            Some(tui::StopChoice::Commit) => {
+                stopped_early = true;
+                break;
+            }
+            Some(tui::StopChoice::Rollback) => {
+                drop(dashboard.take()); // Restores the terminal
+                sinks.rollback(&mut stats)?;
+                return Err(format!(
+                    "Stopped from the dashboard after {} records. The SQLite transaction was rolled back (TSV lines already written stay in the file).",
+                    stats.records_processed
+                )
+                .into());
+            }
+            None => {}
+        }
+        if let Some(ref checkpoint) = config.checkpoint {
+            if checkpoint.out_of_time(stats.records_processed) {
+                stats.checkpoint.out_of_time = true;
+                stopped_early = true;
+                break;
+            }
+        }
+
+        let string_record = result?;
+        let line = string_record
+            .position()
+            .map_or(0, |position| position.line());
+        explainer.start(line);
+        let parsed = string_record.deserialize(None);
+        explainer.parsed(&parsed);
+        let mut record: TsvRecord = parsed?;
+        stats.records_processed += 1;
+        if config.checkpoint.is_some() {
+            stats.checkpoint.last_date_created = Some(record.date_created.clone());
+        }
+        pb.inc(1);
+        if let Some(remaining) = eta.record(pb.position()) {
+            pb.set_prefix(eta::format_precise(remaining));
+        }
+        let kept = config.user_filter.retain(&record, &mut stats);
+        if !explainer.rule("User filter (include_users/exclude_users)", kept, &record) {
+            continue;
+        }
+        let kept = retention.retain(&record, &mut stats);
+        if !explainer.rule("Retention", kept, &record) {
+            continue;
+        }
+        let kept = field_limits.apply(&mut record, &mut stats);
+        if !explainer.rule("Field length limits", kept, &record) {
+            continue;
+        }
+        let kept = future_dates.apply(&mut record, &mut stats);
+        if !explainer.rule(
+            &format!(
+                "Future DateCreated (future_date_policy = \"{}\")",
+                config.future_date_policy.name()
+            ),
+            kept,
+            &record,
+        ) {
+            continue;
+        }
+
+        let original =
+            (dry_run && stats.dry_run_samples.len() < MAX_DRY_RUN_SAMPLES).then(|| record.clone());
+        let rolled_up = rollup.applies_to(&record.user_id); // Decided by the old user ID
+        let shared_by = config
+            .merge_plan
+            .contains(&record.user_id)
+            .then(|| record.user_id.clone());
+        explainer.lookups(config, user_id_map, &record);
+        let transformed = transform_record(&mut record, config, user_id_map, &mut stats);
+        explainer.transformed(&transformed, &record);
+        if !transformed.map_err(|e| {
+            MigrationError::user_mapping(format!(
+                "Line {} of the input TSV: {} (unmapped_user_policy = \"error\"). Nothing was committed to SQLite (TSV lines already written stay in the file).",
+                line, e
+            ))
+        })? {
+            continue;
+        }
+        if let Some(ref old_user_id) = shared_by {
+            stats.merges.count(old_user_id, &record);
+        }
+        if let Some(original) = original.filter(|original| *original != record) {
+            let sample = describe_record_changes(stats.records_processed, &original, &record);
+            stats.dry_run_samples.push(sample);
+        }
+
+        if let Some(ref mut merger) = session_merger {
+            explainer.held("Held for session_merge_window_secs, it may be merged into an earlier row of its session before it is written");
+            merger.push(record, rolled_up);
+            continue;
+        }
+        if rolled_up && daily_rollup.add(&record, &mut stats) {
+            explainer.held("Rolled up into its user's daily row (output_mode = \"daily_rollup\"), not written on its own");
+            continue;
+        }
+        if downsampler.is_some() {
+            explainer.held("Held for --downsample, it may be left out and its PlayDuration is scaled before it is written");
+        } else {
+            explainer.output(&record, &sinks);
+        }
+        write_or_downsample(record, &mut downsampler, &mut sinks, &mut stats)?;
+    }
+    explainer.finish(&mut stats);
+    drop(dashboard);
+    stats.eta = eta.accuracy();
+    // Sessions can only be merged once every record has been read
+    if let Some(merger) = session_merger {
+        for (record, rolled_up) in merger.finish(&mut stats) {
+            if rolled_up && daily_rollup.add(&record, &mut stats) {
+                continue;
+            }
+            write_or_downsample(record, &mut downsampler, &mut sinks, &mut stats)?;
+        }
+    }
+    // The rolled-up rows can only be written once every record of their day has been read
+    for record in daily_rollup.into_records(&mut stats) {
+        write_or_downsample(record, &mut downsampler, &mut sinks, &mut stats)?;
+    }
+    // PlayDuration can only be scaled once every row of a stratum has been seen
+    if let Some(downsampler) = downsampler {
+        for record in downsampler.finish(&mut stats) {
+            sinks.write(&record, &mut stats)?;
+        }
+    }
+    drop(attached);
+    pb.finish_with_message("Record processing loop finished.");
+    if stats.checkpoint.out_of_time {
+        info!(
+            "Reached --max-runtime after {} of {} records, committing what was processed.",
+            stats.checkpoint.records_skipped + stats.records_processed,
+            total_lines
+        );
+    } else if stopped_early {
+        info!(
+            "Stopped from the dashboard after {} of {} records, committing what was processed.",
+            stats.records_processed, total_lines
+        );
+    }
+
+    progress::PROGRESS.update(&stats);
+    progress::PROGRESS.set_phase("finalizing");
+    // Nothing below is awaited, so the span can stay entered until the summary is printed
+    let _finalize = info_span!("finalize").entered();
+    let finalized = sinks.finalize(&mut stats)?;
+    progress::PROGRESS.update(&stats);
+    progress::PROGRESS.set_phase("finished");
+    if !dry_run {
+        for sink in &finalized {
+            info!(
+                "Finalized {}: {} records written, {} duplicates skipped.",
+                sink.name, sink.stats.written, sink.stats.skipped
+            );
+        }
+        sinks.end_of_run();
+        if let Some(ref manifest_path) = config.output_manifest_path {
+            manifest::write_manifest(config, manifest_path, &stats, &finalized)?;
+        }
+        if let Some(ref path) = config.merge_manifest_path {
+            config.merge_plan.write_manifest(path, &stats.merges)?;
+        }
+        if let Some(ref checkpoint) = config.checkpoint {
+            if stopped_early {
+                checkpoint.save(
+                    &config.input_tsv_file_path,
+                    &stats.checkpoint,
+                    stats.records_processed,
+                )?;
+            } else {
+                checkpoint.finish()?;
+            }
+        }
+    }
+
+    report_stats_invariants(config, &stats);
+
+    if dry_run {
+        info!("\n{}", msg!("summary.title_dry_run"));
+    } else {
+        info!("\n{}", msg!("summary.title"));
+    }
+    print_processing_summary(config, &stats);
+    Ok(stats)
+}
+
+// The old and the new instance's users and the user map built from them
+type UsersAndMap = (
+    Vec<JellyfinUser>,
+    Vec<JellyfinUser>,
+    HashMap<String, String>,
+);
+
+// Fetches the users of both instances and builds the user map from them
+async fn fetch_and_map_users(
+    config: &mut Config,
+    api: &api::ApiClient,
+    ignore_fetch_errors: bool,
+    summary_only: bool,
+) -> Result<UsersAndMap, MigrationError> {
+    let mut old_users_vec: Vec<JellyfinUser> = Vec::new();
+    let mut new_users_vec: Vec<JellyfinUser> = Vec::new();
+
+    // Both instances at once, which halves the wait on slow links. Each result is reported on its
+    // own, a failure on one side doesn't hide the other.
+    progress::PROGRESS.set_phase("fetching_users");
+    info!("\nFetching users from OLD and NEW instances...");
+    let ignore_fetch_errors = ignore_fetch_errors || config.allow_partial_user_fetch;
+    let mut fetch_error = None;
+    let (old_fetch, new_fetch) = tokio::join!(
+        fetch_users_timed(&config.instance_old, api),
+        fetch_users_timed(&config.instance_new, api)
+    );
+    for ((result, elapsed), side, users_vec) in [
+        (old_fetch, "old", &mut old_users_vec),
+        (new_fetch, "new", &mut new_users_vec),
+    ] {
+        match result {
+            Ok(users) => {
+                info!(
+                    "Successfully fetched {} users from {} instance in {}.",
+                    users.len(),
+                    side,
+                    timefmt::humanize_duration(elapsed)
+                );
+                for user in &users {
+                    debug!("  User: Name='{}', ID='{}'", user.name, user.id);
+                }
+                *users_vec = users; // Store fetched users
+            }
+            Err(MigrationError::Other(e)) if e.is::<api::MissingRecording>() => {
+                return Err(MigrationError::Other(e))
+            }
+            Err(e) => {
+                error!(
+                    "Error fetching users from {} instance after {}: {}",
+                    side,
+                    timefmt::humanize_duration(elapsed),
+                    e
+                );
+                config.user_fetch_failures.push(side);
+                fetch_error.get_or_insert(e);
+            }
+        }
+    }
+    // Carrying on would write the whole input with its UserIds unchanged
+    if let Some(e) = fetch_error {
+        if !ignore_fetch_errors {
+            error!(
+                "Stopping: without the users of the {} instance no UserIds can be mapped. Pass --ignore-fetch-errors (or set allow_partial_user_fetch = true) to continue anyway.",
+                config.user_fetch_failures.join(" and ")
+            );
+            return Err(e);
+        }
+        info!(
+            "Continuing without the users of the {} instance (--ignore-fetch-errors / allow_partial_user_fetch).",
+            config.user_fetch_failures.join(" and ")
+        );
+    }
+
+    if old_users_vec.is_empty() && new_users_vec.is_empty() {
+        // Corrected logic: if BOTH are empty, it's problematic for mapping.
+        info!("Both user lists are empty. Cannot create a meaningful user map. TSV processing will likely do nothing or copy the file.");
+        // Allow to proceed, create_user_id_map will return an empty map, and process_tsv_file handles an empty map.
+    } else if old_users_vec.is_empty() {
+        info!("Old user list is empty. No users to map from. TSV processing will likely do nothing or copy the file.");
+    } else if new_users_vec.is_empty() {
+        info!("New user list is empty. No users to map to. TSV processing will likely do nothing or copy the file.");
+    }
+
+    // These lines call the functions:
+    let user_id_map = create_user_id_map(config, &old_users_vec, &new_users_vec, summary_only)?;
+    if user_id_map.is_empty() && !old_users_vec.is_empty() && !new_users_vec.is_empty() {
+        report_empty_user_map(&old_users_vec, &new_users_vec);
+    }
+    Ok((old_users_vec, new_users_vec, user_id_map))
+}
+
+// Refuses unknown keys (only warns when `lenient`), translates the paths, normalizes both
+// instances' base_url and checks the settings that can be checked before anything is contacted or
+// written
+fn prepare_config(config: &mut Config, lenient: bool) -> Result<(), MigrationError> {
+    strict::check_unknown_keys(config, lenient).map_err(MigrationError::config)?;
+    paths::resolve_config_paths(config).map_err(MigrationError::config)?;
+    // With user_map_input_path the instances are never contacted and can be left out
+    let map_loaded = config.user_map_input_path.is_some();
+    for (section, instance) in [
+        ("instance_old", &mut config.instance_old),
+        ("instance_new", &mut config.instance_new),
+    ] {
+        if instance.base_url.is_empty() && map_loaded {
+            continue;
+        }
+        if instance.base_url.is_empty() {
+            return Err(MigrationError::config(format!(
+                "[{}] is missing. It can only be left out when user_map_input_path is set.",
+                section
+            )));
+        }
+        instance.base_url = endpoint::normalize_base_url(&instance.base_url)
+            .map_err(|e| MigrationError::config(format!("{}.base_url: {}", section, e)))?;
+        instance
+            .validate_auth(section)
+            .map_err(MigrationError::config)?;
+    }
+
+    debug!("Configuration loaded (and URLs normalized): {:?}", config);
+
+    // Fail early on a bad timezone or column list instead of when they are first used
+    TimeFormatter::new(config.report_timezone.as_deref()).map_err(MigrationError::config)?;
+    output::selected_columns(config).map_err(MigrationError::config)?;
+    schema::dedup_columns(&config.dedup_ignore_columns).map_err(MigrationError::config)?;
+    limits::FieldLimits::new(config).map_err(MigrationError::config)?;
+    split::validate(config).map_err(MigrationError::config)?;
+    columnar::validate(config).map_err(MigrationError::config)?;
+    mapping::validate(config).map_err(MigrationError::config)?;
+    mapfile::validate(config).map_err(MigrationError::config)?;
+    userfilter::validate(config).map_err(MigrationError::config)?;
+    futuredates::validate(config).map_err(MigrationError::config)?;
+    merges::validate(config).map_err(MigrationError::config)?;
+    let ignored = mapfile::ignored_settings(config);
+    if !ignored.is_empty() {
+        info!(
+            "Note: the user map is loaded from user_map_input_path, so these settings that only shape how it is built have no effect: {}.",
+            ignored.join(", ")
+        );
+    }
+    if config.match_case_insensitive && config.user_match_mode.is_some() {
+        return Err(MigrationError::config(
+            "match_case_insensitive is the older spelling of user_match_mode = \"case_insensitive\". Remove it and keep only user_match_mode.",
+        ));
+    }
+    if let Some(threshold) = config.fuzzy_match_threshold {
+        if !(0.0..=1.0).contains(&threshold) {
+            return Err(MigrationError::config(format!(
+                "fuzzy_match_threshold must be between 0.0 and 1.0, got {}.",
+                threshold
+            )));
+        }
+    }
+    config.user_map_overrides =
+        overrides::UserMapOverrides::from_config(config).map_err(MigrationError::config)?;
+    Ok(())
+}
+
+// Authenticates with both instances, then checks their credentials, that they are two different
+// servers and that their clocks agree with this machine's
+#[instrument(name = "connect", skip_all)]
+async fn connect_instances(
+    config: &mut Config,
+    api: &api::ApiClient,
+    allow_same_instance: bool,
+    check_only: bool,
+) -> Result<(), MigrationError> {
+    // Instances configured with a username/password get their token before anything is fetched.
+    // Both are checked before stopping, so every rejected token shows up in one run.
+    let old_preflight = endpoint::connect(api, &mut config.instance_old, "instance_old").await?;
+    let new_preflight = endpoint::connect(api, &mut config.instance_new, "instance_new").await?;
+    endpoint::check_credentials([
+        (&config.instance_old, "instance_old", &old_preflight),
+        (&config.instance_new, "instance_new", &new_preflight),
+    ])?;
+    // Mapping a server's users onto themselves rewrites nothing while looking like a success
+    match endpoint::same_instance(
+        &config.instance_old.base_url,
+        &config.instance_new.base_url,
+        &old_preflight,
+        &new_preflight,
+    ) {
+        Some(reason) if allow_same_instance => info!(
+            "instance_old and instance_new are the same server ({}), continuing with --allow-same-instance.",
+            reason
+        ),
+        Some(reason) => {
+            return Err(MigrationError::config(format!(
+                "instance_old and instance_new are the same server ({}). Check base_url and api_token of both, or pass --allow-same-instance to remap users within one server.",
+                reason
+            )))
+        }
+        None => match (&old_preflight.server_id, &new_preflight.server_id) {
+            (Some(old_id), Some(new_id)) => info!(
+                "instance_old and instance_new are different servers (server Ids {} and {}).",
+                old_id, new_id
+            ),
+            _ => info!(
+                "instance_old and instance_new have different base_urls (a server Id wasn't available to compare)."
+            ),
+        },
+    }
+    config.clock_skews = [old_preflight.clock_skew, new_preflight.clock_skew]
+        .into_iter()
+        .flatten()
+        .collect();
+    clock::check(
+        &config.clock_skews,
+        config.clock_skew_tolerance_secs,
+        check_only.then_some("--check-only"),
+    )?;
+    Ok(())
+}
+
+// The user map loaded from user_map_input_path, or built from both instances' users
+#[instrument(name = "user_mapping", skip_all)]
+async fn load_or_build_user_map(
+    config: &mut Config,
+    api: &api::ApiClient,
+    ignore_fetch_errors: bool,
+    summary_only: bool,
+) -> Result<UsersAndMap, MigrationError> {
+    let (old_users, new_users, user_id_map) = match config.user_map_input_path {
+        Some(ref path) => {
+            let loaded = mapfile::load(path).map_err(MigrationError::user_mapping)?;
+            loaded.print(path, summary_only);
+            config.user_filter = userfilter::UserFilter::new(config, &loaded.old_users);
+            config.user_filter.print_left_out(&loaded.old_users);
+            (loaded.old_users, loaded.new_users, loaded.user_id_map)
+        }
+        None => fetch_and_map_users(config, api, ignore_fetch_errors, summary_only).await?,
+    };
+    mapping::check_fallback_user(config, &new_users).map_err(MigrationError::user_mapping)?;
+    Ok((old_users, new_users, user_id_map))
+}
+
+// What processing needs besides the config once the user map is final
+struct Prepared {
+    retention: retention::RetentionPolicy,
+    rollup: rollup::RollupPolicy,
+    audit: history::RunAudit,
+}
+
+// Writes user_map_output_path, resolves the split users, builds the item map (unless
+// `fetch_items` is false), and extracts the input with input_source = "old_instance"
+async fn prepare_processing(
+    config: &mut Config,
+    api: &api::ApiClient,
+    old_users: &[JellyfinUser],
+    new_users: &[JellyfinUser],
+    user_id_map: &HashMap<String, String>,
+    fetch_items: bool,
+    strict_extraction: bool,
+) -> Result<Prepared, MigrationError> {
+    // After the review, so the file holds the map the run used
+    if let Some(ref path) = config.user_map_output_path {
+        mapfile::write(path, old_users, new_users, user_id_map)?;
+    }
+    split::resolve(config, old_users, new_users).map_err(MigrationError::user_mapping)?;
+    let intentional = config.intentional_merges.clone();
+    merges::resolve(config, old_users, new_users, user_id_map, &intentional)
+        .map_err(MigrationError::user_mapping)?;
+    if config.map_item_ids && fetch_items {
+        progress::PROGRESS.set_phase("fetching_items");
+        info!("\nFetching items from OLD and NEW instances...");
+        let (old_items, new_items) = tokio::join!(
+            items::fetch_items(&config.instance_old, api),
+            items::fetch_items(&config.instance_new, api)
+        );
+        let (old_items, new_items) = (old_items?, new_items?);
+        info!("\nCreating Item ID Map:");
+        let (item_id_map, match_stats) =
+            items::build_map(&old_items, &new_items, config.item_runtime_tolerance_secs);
+        match_stats.print(old_items.len());
+        config.item_id_map = item_id_map;
+    }
+    let retention = retention::RetentionPolicy::new(config, old_users, user_id_map, Utc::now());
+    retention.print_effective_retention();
+    let prepared = Prepared {
+        retention,
+        rollup: rollup::RollupPolicy::new(config, old_users),
+        audit: history::RunAudit::new(config, old_users, new_users, user_id_map),
+    };
+    if config.input_source == extract::InputSource::OldInstance {
+        progress::PROGRESS.set_phase("extracting");
+        extract::extract_to_tsv(config, api, strict_extraction)
+            .await?
+            .print(config);
+    }
+    // Needs the whole input, so after it has been extracted
+    units::resolve(config)?;
+    Ok(prepared)
+}
+
+// A client for the instances' APIs with the config's request timeout
+pub fn api_client(config: &Config) -> Result<ApiClient, MigrationError> {
+    Ok(ApiClient::new(
+        api::build_client(config.request_timeout_secs)?,
+        api::ApiRecording::Off,
+    )?)
+}
+
+// The whole migration as a run of the command line tool without flags does it (or with --dry-run
+// when the config has dry_run = true): connects to both instances, maps the users, processes the
+// input into the configured outputs and returns the counts. Everything it prints goes through
+// `tracing`, so it is only shown when the caller has set up a subscriber.
+pub async fn run_migration(mut config: Config) -> Result<MigrationSummary, MigrationError> {
+    // Unknown keys are refused unless the config has strict_config = false
+    prepare_config(&mut config, false)?;
+    // Before anything is fetched or written, like in the command line tool
+    if !config.dry_run {
+        if let Some(ref db_path) = config.sqlite_db_path {
+            schema::check_destination_db(db_path, false)?;
+        }
+        let unwritable = writable::check_outputs_writable(&config);
+        if !unwritable.is_empty() {
+            return Err(format!(
+                "Output check failed: {}. Use a writable destination.",
+                unwritable.join("; ")
+            )
+            .into());
+        }
+    }
+    let api = api_client(&config)?;
+    if config.user_map_input_path.is_none() {
+        connect_instances(&mut config, &api, false, false).await?;
+    }
+    let (old_users, new_users, user_id_map) =
+        load_or_build_user_map(&mut config, &api, false, false).await?;
+    let prepared = prepare_processing(
+        &mut config,
+        &api,
+        &old_users,
+        &new_users,
+        &user_id_map,
+        true,
+        false,
+    )
+    .await?;
+    let mode = if config.dry_run {
+        RunMode::DryRun
+    } else {
+        RunMode::Normal
+    };
+    let stats = process_tsv_file(
+        &config,
+        &user_id_map,
+        &prepared.retention,
+        &prepared.rollup,
+        Some(&prepared.audit),
+        mode,
+        false,
+    )
+    .await?;
+    Ok(MigrationSummary::from_stats(&stats))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn debug_output_redacts_the_token_and_password() {
+        let config = config_from_toml(
+            "input_tsv_file_path = \"in.tsv\"\n\
+             [instance_old]\nbase_url = \"http://old\"\napi_token = \"secret-token\"\n\
+             [instance_new]\nbase_url = \"http://new\"\nusername = \"admin\"\npassword = \"secret-password\"\n",
+        );
+        let printed = format!("{:?}", config);
+        assert!(!printed.contains("secret-token"), "{}", printed);
+        assert!(!printed.contains("secret-password"), "{}", printed);
+        assert!(
+            printed.contains("api_token: Some(\"<redacted>\")"),
+            "{}",
+            printed
+        );
+        assert!(
+            printed.contains("password: Some(\"<redacted>\")"),
+            "{}",
+            printed
+        );
+    }
+
+    fn sqlite_only_config() -> Config {
+        config_from_toml(
+            "input_tsv_file_path = \"in.tsv\"\nsqlite_db_path = \"out.db\"\n\
+             [instance_old]\nbase_url = \"http://old\"\napi_token = \"x\"\n\
+             [instance_new]\nbase_url = \"http://new\"\napi_token = \"y\"\n",
+        )
+    }
+
+    // 10 records: 4 changed (all of old-a), 5 unchanged, 1 dropped by retention; 7 inserted and
+    // 2 skipped as duplicates
+    fn consistent_stats() -> ProcessingStats {
+        ProcessingStats {
+            records_processed: 10,
+            records_changed: 4,
+            records_unchanged: 5,
+            records_dropped_retention: 1,
+            changes_summary: BTreeMap::from([("old-a".to_string(), ("new-a".to_string(), 4))]),
+            records_inserted_sqlite: 7,
+            records_skipped_sqlite: 2,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn stats_invariants_hold_for_consistent_counts() {
+        assert_eq!(
+            check_stats_invariants(&sqlite_only_config(), &consistent_stats()),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn stats_invariants_name_each_broken_count() {
+        let config = sqlite_only_config();
+
+        let stats = ProcessingStats {
+            records_unchanged: 6,
+            ..consistent_stats()
+        };
+        assert_eq!(
+            check_stats_invariants(&config, &stats),
+            vec!["records_processed (10) != records_changed (4) + records_unchanged (6) + records dropped by retention/field length/future dates/unmapped_user_policy (1)".to_string()]
+        );
+
+        let mut stats = consistent_stats();
+        stats.changes_summary.get_mut("old-a").unwrap().1 = 3;
+        assert_eq!(
+            check_stats_invariants(&config, &stats),
+            vec!["sum of per-user changes (3) != records_changed (4)".to_string()]
+        );
+
+        let stats = ProcessingStats {
+            records_inserted_sqlite: 8,
+            ..consistent_stats()
+        };
+        assert_eq!(
+            check_stats_invariants(&config, &stats),
+            vec!["records_inserted_sqlite (8) + records_skipped_sqlite (2) != records_processed (10) - records dropped (1) - rows merged (0) - records rolled up (0) + rolled-up rows (0) - rows downsampled away (0)".to_string()]
+        );
+        // Not compared in a dry run, which inserts nothing
+        let stats = ProcessingStats {
+            records_inserted_sqlite: 0,
+            records_skipped_sqlite: 0,
+            mode: RunMode::DryRun,
+            ..consistent_stats()
+        };
+        assert!(check_stats_invariants(&config, &stats).is_empty());
+    }
+
+    #[test]
+    fn broken_stats_invariants_are_reported_as_bugs() {
+        #[derive(Clone, Default)]
+        struct Captured(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+        impl std::io::Write for Captured {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .finish();
+        let mut stats = consistent_stats();
+        stats.changes_summary.clear();
+        tracing::subscriber::with_default(subscriber, || {
+            report_stats_invariants(&sqlite_only_config(), &consistent_stats());
+            report_stats_invariants(&sqlite_only_config(), &stats);
+        });
+        let output = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(output.lines().count(), 1, "{}", output);
+        assert!(
+            output.contains("BUG: internal record count invariant violated: sum of per-user changes (0) != records_changed (4). Please report this"),
+            "{}",
+            output
+        );
+    }
+}
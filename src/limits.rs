@@ -0,0 +1,158 @@
+// Maximum field lengths (in characters), so a corrupted row with a huge value (e.g. a whole NFO in
+// ItemName) can't bloat the destination. Oversized values are either cut down, ending in
+// TRUNCATION_MARKER so they can be found later, or the whole record is rejected.
+use crate::display::truncate_with_marker;
+use crate::{schema, Config, ProcessingStats, TsvRecord};
+use serde::Deserialize;
+
+pub const TRUNCATION_MARKER: &str = "…[truncated]";
+
+// Defaults well above anything the Playback Reporting plugin writes itself
+const DEFAULT_MAX_LENGTHS: &[(&str, usize)] = &[
+    ("DateCreated", 64),
+    ("UserId", 64),
+    ("ItemId", 64),
+    ("ItemType", 256),
+    ("ItemName", 1024),
+    ("PlaybackMethod", 256),
+    ("ClientName", 256),
+    ("DeviceName", 256),
+    ("PlayDuration", 32),
+];
+
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FieldLengthPolicy {
+    #[default]
+    Truncate,
+    Reject, // Drops the whole record
+}
+
+#[derive(Debug)]
+pub struct FieldLimits {
+    max_lengths: Vec<usize>, // Per column, in schema::column_names order
+    policy: FieldLengthPolicy,
+}
+
+impl FieldLimits {
+    pub fn new(config: &Config) -> Result<FieldLimits, String> {
+        let names = schema::column_names();
+        for (name, max) in &config.field_max_lengths {
+            if !names.contains(&name.as_str()) {
+                return Err(format!(
+                    "Unknown column '{}' in [field_max_lengths]. Known columns: {}",
+                    name,
+                    names.join(", ")
+                ));
+            }
+            if *max == 0 {
+                return Err(format!("[field_max_lengths] {} must be at least 1", name));
+            }
+        }
+        let max_lengths = names
+            .iter()
+            .zip(DEFAULT_MAX_LENGTHS)
+            .map(|(name, (_, default))| {
+                config
+                    .field_max_lengths
+                    .get(*name)
+                    .copied()
+                    .unwrap_or(*default)
+            })
+            .collect();
+        Ok(FieldLimits {
+            max_lengths,
+            policy: config.field_length_policy,
+        })
+    }
+
+    // Returns false if the record is rejected. Counts every oversized field either way.
+    pub fn apply(&self, record: &mut TsvRecord, stats: &mut ProcessingStats) -> bool {
+        let names = schema::column_names();
+        let mut rejected = false;
+        for ((value, max), name) in record
+            .fields_mut()
+            .into_iter()
+            .zip(&self.max_lengths)
+            .zip(names)
+        {
+            if value.chars().count() <= *max {
+                continue;
+            }
+            *stats.fields_over_max_length.entry(name).or_insert(0) += 1;
+            match self.policy {
+                FieldLengthPolicy::Truncate => {
+                    *value = truncate_with_marker(value, *max, TRUNCATION_MARKER)
+                }
+                FieldLengthPolicy::Reject => rejected = true,
+            }
+        }
+        if rejected {
+            stats.records_rejected_field_length += 1;
+        }
+        !rejected
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config_from_toml;
+
+    fn config_with(extra: &str) -> Config {
+        config_from_toml(&format!(
+            "input_tsv_file_path = \"unused.tsv\"\n{}\n[instance_old]\nbase_url = \"http://old\"\napi_token = \"x\"\n\
+            [instance_new]\nbase_url = \"http://new\"\napi_token = \"y\"\n",
+            extra
+        ))
+    }
+
+    fn record(item_name: &str) -> TsvRecord {
+        TsvRecord {
+            date_created: "2024-01-01 10:00:00".to_string(),
+            user_id: "u1".to_string(),
+            item_id: "item".to_string(),
+            item_type: "Movie".to_string(),
+            item_name: item_name.to_string(),
+            playback_method: "DirectPlay".to_string(),
+            client_name: "Web".to_string(),
+            device_name: "TV".to_string(),
+            play_duration: "60".to_string(),
+        }
+    }
+
+    #[test]
+    fn oversized_fields_are_truncated_with_a_marker() {
+        let limits = FieldLimits::new(&config_with("[field_max_lengths]\nItemName = 16")).unwrap();
+        let mut stats = ProcessingStats::default();
+
+        let mut long = record("千と千尋の神隠し Spirited Away");
+        assert!(limits.apply(&mut long, &mut stats));
+        assert_eq!(long.item_name, "千と千尋…[truncated]");
+        assert_eq!(long.item_name.chars().count(), 16);
+
+        let mut short = record("Short");
+        assert!(limits.apply(&mut short, &mut stats));
+        assert_eq!(short.item_name, "Short");
+        assert_eq!(stats.fields_over_max_length["ItemName"], 1);
+    }
+
+    #[test]
+    fn reject_policy_drops_the_record() {
+        let limits = FieldLimits::new(&config_with(
+            "field_length_policy = \"reject\"\n[field_max_lengths]\nItemName = 4",
+        ))
+        .unwrap();
+        let mut stats = ProcessingStats::default();
+        let mut long = record("Too long");
+        assert!(!limits.apply(&mut long, &mut stats));
+        assert_eq!(long.item_name, "Too long");
+        assert_eq!(stats.records_rejected_field_length, 1);
+    }
+
+    #[test]
+    fn unknown_columns_are_rejected() {
+        let err = FieldLimits::new(&config_with("[field_max_lengths]\nTitle = 10")).unwrap_err();
+        assert!(err.contains("Title"));
+    }
+}
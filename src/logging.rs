@@ -0,0 +1,153 @@
+// `--log-level`: the run's output goes through `tracing` events, written as plain lines like the
+// println!/eprintln! they replaced: info and more detailed levels on stdout, warnings and errors on
+// stderr, only the message with no level or timestamp. "info" (the default) is the output of
+// before: progress, the user mapping and the summary. "warn" and "error" leave only the problems,
+// "debug" adds the decision made for every record (mapped, kept, dropped, skipped as a duplicate),
+// every fetched user and the loaded configuration. While a progress bar is attached, lines are
+// written with it suspended so the bar is redrawn below them instead of being torn. Output that is
+// the product of a command (--check-only, --analyze, --summary-line, the history, bench and
+// self-test reports) is printed directly and not affected by the level. The run's phases (connect,
+// user_mapping, processing, finalize) and the requests to each instance are spans, for the
+// subscribers of code embedding the migration. The plain lines leave them out, only "trace"
+// prefixes each line with the spans it was written in. The level is left out on purpose too,
+// stderr is what sets warnings and errors apart, so their messages have no "Warning:" prefix.
+use clap::ValueEnum;
+use indicatif::ProgressBar;
+use std::fmt;
+use std::io::{self, Write};
+use std::sync::Mutex;
+use tracing::level_filters::LevelFilter;
+use tracing::{Event, Level, Metadata, Subscriber};
+use tracing_subscriber::fmt::format::{self, FormatEvent, FormatFields};
+use tracing_subscriber::fmt::{FmtContext, MakeWriter};
+use tracing_subscriber::registry::LookupSpan;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    fn filter(self) -> LevelFilter {
+        match self {
+            LogLevel::Error => LevelFilter::ERROR,
+            LogLevel::Warn => LevelFilter::WARN,
+            LogLevel::Info => LevelFilter::INFO,
+            LogLevel::Debug => LevelFilter::DEBUG,
+            LogLevel::Trace => LevelFilter::TRACE,
+        }
+    }
+}
+
+static PROGRESS_BAR: Mutex<Option<ProgressBar>> = Mutex::new(None);
+
+pub fn init(level: LogLevel) {
+    let builder = tracing_subscriber::fmt()
+        .with_max_level(level.filter())
+        .with_writer(Output)
+        .without_time()
+        .with_level(false)
+        .with_target(false)
+        .with_ansi(false);
+    // Only fails when a subscriber is already set, e.g. by a test
+    let _ = match level {
+        LogLevel::Trace => builder.try_init(),
+        _ => builder.event_format(Plain).try_init(),
+    };
+}
+
+// Only the message (and any other fields of the event), without the spans it was written in
+struct Plain;
+
+impl<S, N> FormatEvent<S, N> for Plain
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: format::Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        ctx.field_format().format_fields(writer.by_ref(), event)?;
+        writeln!(writer)
+    }
+}
+
+// Lines are written around `progress_bar` until the guard is dropped
+pub struct AttachedProgressBar;
+
+pub fn attach_progress_bar(progress_bar: &ProgressBar) -> AttachedProgressBar {
+    *PROGRESS_BAR.lock().unwrap_or_else(|e| e.into_inner()) = Some(progress_bar.clone());
+    AttachedProgressBar
+}
+
+impl Drop for AttachedProgressBar {
+    fn drop(&mut self) {
+        *PROGRESS_BAR.lock().unwrap_or_else(|e| e.into_inner()) = None;
+    }
+}
+
+struct Output;
+
+// One formatted event, written in one go when the subscriber is done with it
+pub struct Line {
+    stderr: bool,
+    buffer: Vec<u8>,
+}
+
+impl<'a> MakeWriter<'a> for Output {
+    type Writer = Line;
+
+    fn make_writer(&'a self) -> Line {
+        Line {
+            stderr: false,
+            buffer: Vec::new(),
+        }
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Line {
+        Line {
+            stderr: *meta.level() <= Level::WARN,
+            buffer: Vec::new(),
+        }
+    }
+}
+
+impl Write for Line {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for Line {
+    fn drop(&mut self) {
+        let write = || {
+            // Nothing sensible to do when the terminal is gone
+            let _ = if self.stderr {
+                io::stderr().write_all(&self.buffer)
+            } else {
+                io::stdout().write_all(&self.buffer)
+            };
+        };
+        let progress_bar = PROGRESS_BAR
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+        match progress_bar {
+            Some(progress_bar) => progress_bar.suspend(write),
+            None => write(),
+        }
+    }
+}
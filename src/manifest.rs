@@ -0,0 +1,174 @@
+// Writes `output_manifest_path`: a JSON inventory of every file a run wrote to, with its size and
+// how many records went into it, for archiving the results or handing them to other tools.
+use crate::sinks::{DestinationChange, FinalizedSink};
+use crate::timefmt;
+use crate::{Config, ProcessingStats};
+use chrono::SecondsFormat;
+use serde::Serialize;
+use std::error::Error;
+use std::fs;
+use tracing::info;
+
+#[derive(Debug, Serialize)]
+struct ManifestEntry {
+    kind: &'static str,
+    path: String,
+    size_bytes: u64,
+    records_written: u64,
+    records_skipped: u64, // Duplicates that were already in SQLite
+}
+
+#[derive(Debug, Serialize)]
+struct Manifest {
+    input_tsv_file_path: String,
+    started_at: String,
+    finished_at: String,
+    records_processed: u64,
+    outputs: Vec<ManifestEntry>,
+    // Rows (SQLite only) and bytes of each output before the first write and after the final commit
+    destinations: Vec<DestinationChange>,
+    // Outputs dropped mid-run by sink_failure_policy, their files are incomplete
+    disabled_outputs: Vec<String>,
+}
+
+pub fn write_manifest(
+    config: &Config,
+    path: &str,
+    stats: &ProcessingStats,
+    finalized: &[FinalizedSink],
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut outputs = Vec::new();
+    for sink in finalized {
+        let Some(ref file) = sink.output_file else {
+            continue;
+        };
+        outputs.push(ManifestEntry {
+            kind: file.kind,
+            path: file.path.clone(),
+            size_bytes: fs::metadata(&file.path)?.len(),
+            records_written: sink.stats.written,
+            records_skipped: sink.stats.skipped,
+        });
+    }
+    let manifest = Manifest {
+        input_tsv_file_path: config.input_tsv_file_path.clone(),
+        started_at: stats.started_at.to_rfc3339_opts(SecondsFormat::Secs, true),
+        finished_at: timefmt::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+        records_processed: stats.records_processed,
+        outputs,
+        destinations: stats.destinations.clone(),
+        disabled_outputs: stats.sinks_disabled.clone(),
+    };
+    fs::write(path, serde_json::to_string_pretty(&manifest)? + "\n")?;
+    info!(
+        "Output manifest written to: {} ({} outputs)",
+        path,
+        manifest.outputs.len()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::retention::RetentionPolicy;
+    use crate::rollup::RollupPolicy;
+    use crate::{config_from_toml, process_tsv_file, RunMode};
+    use chrono::DateTime;
+    use rusqlite::Connection;
+    use serde_json::Value;
+    use std::collections::HashMap;
+    use std::fs;
+
+    #[tokio::test]
+    async fn a_run_writes_its_outputs_into_the_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name).display().to_string();
+        let (input_path, tsv_path, db_path, manifest_path) = (
+            path("input.tsv"),
+            path("output.tsv"),
+            path("playback.db"),
+            path("manifest.json"),
+        );
+        Connection::open(&db_path)
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE PlaybackActivity (DateCreated DATETIME NOT NULL, UserId TEXT, ItemId TEXT, \
+                ItemType TEXT, ItemName TEXT, PlaybackMethod TEXT, ClientName TEXT, DeviceName TEXT, PlayDuration INT);
+                INSERT INTO PlaybackActivity VALUES ('2024-01-01 10:00:00', 'new-a', 'i1', 'Movie', 'One', 'DirectPlay', 'Web', 'TV', 100);",
+            )
+            .unwrap();
+        fs::write(
+            &input_path,
+            // The first is already in the database once mapped
+            "2024-01-01 10:00:00\told-a\ti1\tMovie\tOne\tDirectPlay\tWeb\tTV\t100\n\
+             2024-01-02 10:00:00\told-a\ti2\tMovie\tTwo\tDirectPlay\tWeb\tTV\t200\n\
+             2024-01-03 10:00:00\told-a\ti3\tMovie\tThree\tDirectPlay\tWeb\tTV\t300\n",
+        )
+        .unwrap();
+        let config = config_from_toml(&format!(
+            "input_tsv_file_path = {:?}\noutput_tsv_file_path = {:?}\nsqlite_db_path = {:?}\n\
+             output_manifest_path = {:?}\n\
+             [instance_old]\nbase_url = \"http://old\"\napi_token = \"x\"\n\
+             [instance_new]\nbase_url = \"http://new\"\napi_token = \"y\"\n",
+            input_path, tsv_path, db_path, manifest_path
+        ));
+        let stats = process_tsv_file(
+            &config,
+            &HashMap::from([("old-a".to_string(), "new-a".to_string())]),
+            &RetentionPolicy::default(),
+            &RollupPolicy::default(),
+            None,
+            RunMode::Normal,
+            false,
+        )
+        .await
+        .unwrap();
+
+        let manifest: Value =
+            serde_json::from_str(&fs::read_to_string(&manifest_path).unwrap()).unwrap();
+        assert_eq!(manifest["input_tsv_file_path"], input_path.as_str());
+        assert_eq!(manifest["records_processed"], 3);
+        assert_eq!(manifest["disabled_outputs"], serde_json::json!([]));
+        let started = DateTime::parse_from_rfc3339(manifest["started_at"].as_str().unwrap());
+        let finished = DateTime::parse_from_rfc3339(manifest["finished_at"].as_str().unwrap());
+        assert!(started.unwrap() <= finished.unwrap());
+
+        let outputs = manifest["outputs"].as_array().unwrap();
+        assert_eq!(outputs.len(), 2, "{:#}", manifest);
+        let output = |kind: &str| {
+            outputs
+                .iter()
+                .find(|output| output["kind"] == kind)
+                .unwrap_or_else(|| panic!("no {} output in {:#}", kind, manifest))
+        };
+        let tsv = output("tsv");
+        assert_eq!(tsv["path"], tsv_path.as_str());
+        assert_eq!(tsv["size_bytes"], fs::metadata(&tsv_path).unwrap().len());
+        assert_eq!(
+            (&tsv["records_written"], &tsv["records_skipped"]),
+            (&3.into(), &0.into())
+        );
+        let sqlite = output("sqlite");
+        assert_eq!(sqlite["path"], db_path.as_str());
+        assert_eq!(sqlite["size_bytes"], fs::metadata(&db_path).unwrap().len());
+        assert_eq!(
+            (&sqlite["records_written"], &sqlite["records_skipped"]),
+            (&2.into(), &1.into())
+        );
+        assert_eq!(
+            (stats.records_inserted_sqlite, stats.records_skipped_sqlite),
+            (2, 1)
+        );
+
+        let destinations = manifest["destinations"].as_array().unwrap();
+        assert_eq!(destinations.len(), 2, "{:#}", manifest);
+        let sqlite = destinations
+            .iter()
+            .find(|destination| destination["rows_before"].is_u64())
+            .unwrap();
+        assert_eq!(
+            (&sqlite["rows_before"], &sqlite["rows_after"]),
+            (&1.into(), &3.into())
+        );
+    }
+}
@@ -0,0 +1,358 @@
+// `user_map_output_path` and `user_map_input_path`: the user map of a run against both instances
+// is written to a TSV, reviewed and edited by hand, and loaded back by a later run that then
+// doesn't contact either instance (so [instance_old] and [instance_new] can be left out). One row
+// per user: every old user with the new user it is mapped to (empty new columns when it isn't
+// mapped), then the new users no old user is mapped to (empty old columns). Mapping one of those
+// by hand means filling in the new columns of the old user's row. Lines starting with '#' and
+// empty lines are skipped when loading, anything else that doesn't fit is reported by line. The
+// first line stamps the format version, see formats.rs.
+use crate::formats::{self, USER_MAP, USER_MAP_STAMP_PREFIX};
+use crate::messages::msg;
+use crate::unmapped::clean;
+use crate::{Config, JellyfinUser};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use tracing::info;
+
+const HEADER: &str = "old_id\told_name\tnew_id\tnew_name";
+
+// Settings that only shape how the map is built from the fetched users
+const MATCHING_SETTINGS: [&str; 4] = [
+    "user_map_override_path",
+    "[user_map]",
+    "fuzzy_match_threshold",
+    "unmapped_report_path",
+];
+
+// The users and the map of a loaded file, standing in for the fetched users
+#[derive(Debug, Default)]
+pub struct LoadedUserMap {
+    pub old_users: Vec<JellyfinUser>,
+    pub new_users: Vec<JellyfinUser>,
+    pub user_id_map: HashMap<String, String>,
+}
+
+// Features that talk to the instances can't be used with a loaded map
+pub fn validate(config: &Config) -> Result<(), String> {
+    if config.user_map_input_path.is_none() {
+        return Ok(());
+    }
+    if config.input_source == crate::extract::InputSource::OldInstance {
+        return Err("input_source = \"old_instance\" can't be used with user_map_input_path, which runs without contacting the instances.".to_string());
+    }
+    if config.map_item_ids {
+        return Err("map_item_ids can't be used with user_map_input_path, which runs without contacting the instances.".to_string());
+    }
+    Ok(())
+}
+
+// The matching settings that are set but have no effect since the map is loaded as it is
+pub fn ignored_settings(config: &Config) -> Vec<&'static str> {
+    let set = [
+        config.user_map_override_path.is_some(),
+        !config.user_map.is_empty(),
+        config.fuzzy_match_threshold.is_some(),
+        config.unmapped_report_path.is_some(),
+    ];
+    MATCHING_SETTINGS
+        .into_iter()
+        .zip(set)
+        .filter_map(|(name, set)| set.then_some(name))
+        .collect()
+}
+
+pub fn write(
+    path: &str,
+    old_users: &[JellyfinUser],
+    new_users: &[JellyfinUser],
+    user_id_map: &HashMap<String, String>,
+) -> io::Result<()> {
+    let new_names: HashMap<&str, &str> = new_users
+        .iter()
+        .map(|user| (user.id.as_str(), user.name.as_str()))
+        .collect();
+    let targeted: HashSet<&String> = user_id_map.values().collect();
+    let mut file = BufWriter::new(File::create(path)?);
+    writeln!(file, "{}{}", USER_MAP_STAMP_PREFIX, USER_MAP.current)?;
+    writeln!(file, "{}", HEADER)?;
+    let mut mapped = 0;
+    for user in old_users {
+        match user_id_map.get(&user.id) {
+            Some(new_id) => {
+                mapped += 1;
+                let new_name = new_names.get(new_id.as_str()).copied().unwrap_or("");
+                writeln!(
+                    file,
+                    "{}\t{}\t{}\t{}",
+                    user.id,
+                    clean(&user.name),
+                    new_id,
+                    clean(new_name)
+                )?;
+            }
+            None => writeln!(file, "{}\t{}\t\t", user.id, clean(&user.name))?,
+        }
+    }
+    let mut orphans = 0;
+    for user in new_users.iter().filter(|user| !targeted.contains(&user.id)) {
+        orphans += 1;
+        writeln!(file, "\t\t{}\t{}", user.id, clean(&user.name))?;
+    }
+    file.flush()?;
+    info!(
+        "  Wrote the user map ({} mapped and {} unmapped old user(s), {} new user(s) without a mapping) to '{}'.",
+        mapped,
+        old_users.len() - mapped,
+        orphans,
+        path
+    );
+    Ok(())
+}
+
+pub fn load(path: &str) -> Result<LoadedUserMap, String> {
+    let text =
+        fs::read_to_string(path).map_err(|e| format!("user_map_input_path '{}': {}", path, e))?;
+    // Files without the stamp are version 1, which has the same columns
+    let version = match text.lines().next().and_then(formats::user_map_version) {
+        Some(version) => version.map_err(|e| format!("user_map_input_path '{}': {}", path, e))?,
+        None => 1,
+    };
+    if USER_MAP.check(Path::new(path), version)? {
+        USER_MAP.print_upgrade(Path::new(path), version, false);
+    }
+    parse(&text).map_err(|problems| {
+        format!(
+            "user_map_input_path '{}' isn't a user map as written by user_map_output_path: {}",
+            path,
+            problems.join("; ")
+        )
+    })
+}
+
+// Every problem is collected with its line number, so a hand-edited file is fixed in one go
+fn parse(text: &str) -> Result<LoadedUserMap, Vec<String>> {
+    let mut loaded = LoadedUserMap::default();
+    let mut problems = Vec::new();
+    let mut header_seen = false;
+    let mut old_lines: HashMap<String, usize> = HashMap::new();
+    // New ID -> (name, line)
+    let mut new_lines: HashMap<String, (String, usize)> = HashMap::new();
+    for (i, line) in text.lines().enumerate() {
+        let number = i + 1;
+        let line = line.trim_end_matches('\r');
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if !header_seen {
+            header_seen = true;
+            if line != HEADER {
+                problems.push(format!(
+                    "line {}: expected the header '{}', got '{}'",
+                    number,
+                    HEADER.replace('\t', "<tab>"),
+                    line
+                ));
+                break;
+            }
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').map(str::trim).collect();
+        let [old_id, old_name, new_id, new_name] = fields[..] else {
+            problems.push(format!(
+                "line {}: expected 4 tab-separated columns (old_id, old_name, new_id, new_name), got {}",
+                number,
+                fields.len()
+            ));
+            continue;
+        };
+        if old_id.is_empty() && new_id.is_empty() {
+            problems.push(format!("line {}: neither old_id nor new_id is set", number));
+            continue;
+        }
+        if old_id.is_empty() && !old_name.is_empty() {
+            problems.push(format!(
+                "line {}: old_name '{}' without an old_id",
+                number, old_name
+            ));
+            continue;
+        }
+        if new_id.is_empty() && !new_name.is_empty() {
+            problems.push(format!(
+                "line {}: new_name '{}' without a new_id",
+                number, new_name
+            ));
+            continue;
+        }
+        if !old_id.is_empty() {
+            if let Some(first) = old_lines.insert(old_id.to_string(), number) {
+                problems.push(format!(
+                    "line {}: old ID '{}' is already listed on line {}",
+                    number, old_id, first
+                ));
+                continue;
+            }
+        }
+        if !new_id.is_empty() {
+            match new_lines.get(new_id) {
+                Some((name, first)) if name != new_name => {
+                    problems.push(format!(
+                        "line {}: new ID '{}' is named '{}' here but '{}' on line {}",
+                        number, new_id, new_name, name, first
+                    ));
+                    continue;
+                }
+                Some(_) => {}
+                None => {
+                    new_lines.insert(new_id.to_string(), (new_name.to_string(), number));
+                    loaded.new_users.push(user(new_id, new_name));
+                }
+            }
+        }
+        if !old_id.is_empty() {
+            loaded.old_users.push(user(old_id, old_name));
+            if !new_id.is_empty() {
+                loaded
+                    .user_id_map
+                    .insert(old_id.to_string(), new_id.to_string());
+            }
+        }
+    }
+    if !header_seen {
+        problems.push(format!(
+            "the file is empty, expected the header '{}'",
+            HEADER.replace('\t', "<tab>")
+        ));
+    }
+    if problems.is_empty() {
+        Ok(loaded)
+    } else {
+        Err(problems)
+    }
+}
+
+fn user(id: &str, name: &str) -> JellyfinUser {
+    JellyfinUser {
+        id: id.to_string(),
+        name: name.to_string(),
+        ..Default::default()
+    }
+}
+
+impl LoadedUserMap {
+    // Like the mapping lines of create_user_id_map, with the file as the source of every mapping
+    pub fn print(&self, path: &str, summary_only: bool) {
+        info!("\n{}", msg!("map.title"));
+        if !summary_only {
+            for old_user in &self.old_users {
+                if let Some(new_id) = self.user_id_map.get(&old_user.id) {
+                    info!(
+                        "{}",
+                        msg!(
+                            "map.override",
+                            old_name = old_user.name,
+                            old_id = old_user.id,
+                            new_id = new_id,
+                            source = "user_map_input_path"
+                        )
+                    );
+                } else {
+                    info!(
+                        "{}",
+                        msg!(
+                            "map.not_in_file",
+                            old_name = old_user.name,
+                            old_id = old_user.id
+                        )
+                    );
+                }
+            }
+        }
+        info!(
+            "{}",
+            msg!(
+                "map.loaded",
+                path = path,
+                mapped = self.user_id_map.len(),
+                total = self.old_users.len(),
+                new_users = self.new_users.len()
+            )
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn written_maps_load_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("user_map.tsv");
+        let path = path.to_str().unwrap();
+        let old = vec![user("old-a", "alice"), user("old-c", "carol\tc")];
+        let new = vec![user("new-a", "alice"), user("new-d", "dave")];
+        let map = HashMap::from([("old-a".to_string(), "new-a".to_string())]);
+        write(path, &old, &new, &map).unwrap();
+        assert_eq!(
+            fs::read_to_string(path).unwrap(),
+            "# jpm-user-map: v2\n\
+             old_id\told_name\tnew_id\tnew_name\n\
+             old-a\talice\tnew-a\talice\n\
+             old-c\tcarol c\t\t\n\
+             \t\tnew-d\tdave\n"
+        );
+
+        let loaded = load(path).unwrap();
+        assert_eq!(loaded.user_id_map, map);
+        let ids = |users: &[JellyfinUser]| -> Vec<String> {
+            users.iter().map(|user| user.id.clone()).collect()
+        };
+        assert_eq!(ids(&loaded.old_users), ["old-a", "old-c"]);
+        assert_eq!(ids(&loaded.new_users), ["new-a", "new-d"]);
+    }
+
+    #[test]
+    fn reads_unstamped_maps_and_refuses_newer_ones() {
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/formats");
+        let old = load(fixtures.join("user_map_v1.tsv").to_str().unwrap()).unwrap();
+        assert_eq!(old.user_id_map.len(), 2);
+        assert_eq!(old.new_users.len(), 3);
+        let error = load(fixtures.join("user_map_v99.tsv").to_str().unwrap()).unwrap_err();
+        assert!(error.contains("has format version 99"), "{}", error);
+        assert!(
+            error.ends_with("or write it again with user_map_output_path."),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn reports_every_malformed_line() {
+        let text = "# reviewed\n\
+                    old_id\told_name\tnew_id\tnew_name\n\
+                    old-a\talice\tnew-a\talice\n\
+                    old-b\tbob\n\
+                    \tbob\t\tbob\n\
+                    \tbob\tnew-b\tbob\n\
+                    old-a\talice\tnew-x\tx\n\
+                    old-c\tcarol\tnew-a\tanna\n";
+        let problems = parse(text).unwrap_err();
+        assert_eq!(
+            problems,
+            [
+                "line 4: expected 4 tab-separated columns (old_id, old_name, new_id, new_name), got 2",
+                "line 5: neither old_id nor new_id is set",
+                "line 6: old_name 'bob' without an old_id",
+                "line 7: old ID 'old-a' is already listed on line 3",
+                "line 8: new ID 'new-a' is named 'anna' here but 'alice' on line 3",
+            ]
+        );
+
+        let problems = parse("old_id,new_id\nold-a,new-a\n").unwrap_err();
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].starts_with("line 1: expected the header"));
+        assert_eq!(parse("").unwrap_err().len(), 1);
+    }
+}
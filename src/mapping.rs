@@ -0,0 +1,344 @@
+// Rewrites the UserId of each record with the old -> new user map. With
+// `mapping_direction = "auto"` inputs that mix old and new IDs (e.g. exported after a partial
+// migration) are handled too: IDs that already belong to the new instance are left alone and
+// counted as already migrated, and IDs known to neither side are counted as unmapped.
+// `unmapped_user_policy` decides what happens to a record whose UserId has no mapping: written
+// through unchanged (the default), dropped, given `fallback_user_id`, or the run stops there.
+use crate::messages::msg;
+use crate::tracked::OTHER_USERS;
+use crate::{Config, JellyfinUser, ProcessingStats, TsvRecord};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use tracing::{debug, info};
+
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MappingDirection {
+    #[default]
+    Forward, // Only old IDs are looked up, everything else counts as unchanged
+    Auto,
+}
+
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum UnmappedUserPolicy {
+    #[default]
+    Keep, // Written with the old UserId, which no user on the new instance has
+    Drop,
+    Fallback, // Written with fallback_user_id
+    Error,    // The run stops at the first one
+}
+
+impl UnmappedUserPolicy {
+    pub fn name(self) -> &'static str {
+        match self {
+            UnmappedUserPolicy::Keep => "keep",
+            UnmappedUserPolicy::Drop => "drop",
+            UnmappedUserPolicy::Fallback => "fallback",
+            UnmappedUserPolicy::Error => "error",
+        }
+    }
+}
+
+// The records unmapped_user_policy = "drop" or "fallback" acted on
+#[derive(Debug, Default)]
+pub struct UnmappedPolicyStats {
+    pub records_dropped: u64,
+    pub records_to_fallback: u64,
+    pub per_user: BTreeMap<String, u64>, // Keyed by old user ID
+}
+
+// Startup checks of unmapped_user_policy and fallback_user_id
+pub fn validate(config: &Config) -> Result<(), String> {
+    match (config.unmapped_user_policy, &config.fallback_user_id) {
+        (UnmappedUserPolicy::Fallback, None) => Err(
+            "unmapped_user_policy = \"fallback\" needs fallback_user_id, the ID of the new instance's user to give unmapped records.".to_string(),
+        ),
+        (UnmappedUserPolicy::Fallback, Some(_)) | (_, None) => Ok(()),
+        (policy, Some(_)) => Err(format!(
+            "fallback_user_id is only used with unmapped_user_policy = \"fallback\" (it is \"{}\").",
+            policy.name()
+        )),
+    }
+}
+
+// fallback_user_id has to be a user of the new instance (unless its users couldn't be fetched),
+// or every unmapped record would move to an account that doesn't exist either
+pub fn check_fallback_user(config: &Config, new_users: &[JellyfinUser]) -> Result<(), String> {
+    let Some(ref fallback) = config.fallback_user_id else {
+        return Ok(());
+    };
+    if new_users.is_empty() || new_users.iter().any(|user| user.id == *fallback) {
+        return Ok(());
+    }
+    Err(format!(
+        "fallback_user_id '{}' isn't a user on the new instance. Use one of the IDs from its /Users, e.g. '{}' ('{}').",
+        fallback, new_users[0].id, new_users[0].name
+    ))
+}
+
+// Returns false when unmapped_user_policy = "drop" leaves the record out, and an error naming its
+// UserId with "error"
+pub fn apply_user_id_map(
+    record: &mut TsvRecord,
+    user_id_map: &HashMap<String, String>,
+    config: &Config,
+    stats: &mut ProcessingStats,
+) -> Result<bool, String> {
+    let direction = config.mapping_direction;
+    // Reverse lookup first, so a new ID is never mapped again. The map only has one entry per
+    // user, so scanning its values is cheap.
+    if direction == MappingDirection::Auto
+        && user_id_map.values().any(|new_id| *new_id == record.user_id)
+    {
+        debug!(
+            "Record {} '{}': UserId '{}' already belongs to the new instance, kept",
+            record.date_created, record.item_name, record.user_id
+        );
+        stats.records_unchanged += 1;
+        stats.records_already_migrated += 1;
+        return Ok(true);
+    }
+    // Check if the current record's user_id is in our map
+    if let Some(new_user_id) = user_id_map.get(&record.user_id) {
+        debug!(
+            "Record {} '{}': UserId '{}' -> '{}'",
+            record.date_created, record.item_name, record.user_id, new_user_id
+        );
+        count_change(stats, &record.user_id, new_user_id);
+        record.user_id = new_user_id.clone(); // Update the record
+        return Ok(true);
+    }
+    debug!(
+        "Record {} '{}': UserId '{}' isn't in the user map, unmapped_user_policy = \"{}\"",
+        record.date_created,
+        record.item_name,
+        record.user_id,
+        config.unmapped_user_policy.name()
+    );
+    match config.unmapped_user_policy {
+        UnmappedUserPolicy::Keep => {
+            stats.records_unchanged += 1;
+            if direction == MappingDirection::Auto {
+                let key = stats.tracked_users.key(
+                    &record.user_id,
+                    stats.unmapped_user_ids.len(),
+                    stats.unmapped_user_ids.contains_key(&record.user_id),
+                );
+                *stats.unmapped_user_ids.entry(key).or_insert(0) += 1;
+            }
+            Ok(true)
+        }
+        UnmappedUserPolicy::Error => {
+            Err(format!("UserId '{}' isn't in the user map", record.user_id))
+        }
+        UnmappedUserPolicy::Drop => {
+            count_unmapped(stats, &record.user_id);
+            stats.unmapped_policy.records_dropped += 1;
+            Ok(false)
+        }
+        UnmappedUserPolicy::Fallback => {
+            let fallback = config.fallback_user_id.as_deref().unwrap_or_default();
+            count_unmapped(stats, &record.user_id);
+            stats.unmapped_policy.records_to_fallback += 1;
+            count_change(stats, &record.user_id, fallback);
+            record.user_id = fallback.to_string();
+            Ok(true)
+        }
+    }
+}
+
+fn count_unmapped(stats: &mut ProcessingStats, old_user_id: &str) {
+    let key = stats.tracked_users.key(
+        old_user_id,
+        stats.unmapped_policy.per_user.len(),
+        stats.unmapped_policy.per_user.contains_key(old_user_id),
+    );
+    *stats.unmapped_policy.per_user.entry(key).or_insert(0) += 1;
+}
+
+// Counts a record whose UserId is changed from old_user_id, under the old ID in the summary
+pub fn count_change(stats: &mut ProcessingStats, old_user_id: &str, new_id_in_summary: &str) {
+    let summary_key = stats.tracked_users.key(
+        old_user_id,
+        stats.changes_summary.len(),
+        stats.changes_summary.contains_key(old_user_id),
+    );
+    stats.records_changed += 1;
+
+    // Update summary: old_id -> (new_id, count)
+    let summary_new_id = if summary_key == OTHER_USERS {
+        OTHER_USERS
+    } else {
+        new_id_in_summary
+    };
+    let (_new_id_in_summary, count) = stats
+        .changes_summary
+        .entry(summary_key)
+        .or_insert_with(|| (summary_new_id.to_string(), 0));
+    *count += 1;
+}
+
+// How many unmapped IDs the summary lists
+const MAX_UNMAPPED_IDS_SHOWN: usize = 10;
+
+pub fn print_auto_detection_summary(stats: &ProcessingStats) {
+    let unmapped: u64 = stats.unmapped_user_ids.values().sum();
+    info!("  UserId detection (mapping_direction = \"auto\"):");
+    info!(
+        "    Mapped old -> new:                  {}",
+        stats.records_changed
+    );
+    info!(
+        "    Already migrated (new-instance ID): {}",
+        stats.records_already_migrated
+    );
+    info!("    Unmapped (known to neither side):   {}", unmapped);
+    let mut ids: Vec<(&String, &u64)> = stats.unmapped_user_ids.iter().collect();
+    ids.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+    for (user_id, count) in ids.iter().take(MAX_UNMAPPED_IDS_SHOWN) {
+        info!("      '{}': {} records", user_id, count);
+    }
+    if ids.len() > MAX_UNMAPPED_IDS_SHOWN {
+        info!(
+            "      ... and {} more IDs",
+            ids.len() - MAX_UNMAPPED_IDS_SHOWN
+        );
+    }
+}
+
+pub fn print_unmapped_policy_summary(config: &Config, stats: &ProcessingStats) {
+    let policy = config.unmapped_user_policy;
+    let counts = &stats.unmapped_policy;
+    let fallback = config.fallback_user_id.as_deref().unwrap_or_default();
+    match policy {
+        UnmappedUserPolicy::Drop => info!(
+            "{}",
+            msg!("summary.unmapped_dropped", count = counts.records_dropped)
+        ),
+        UnmappedUserPolicy::Fallback => info!(
+            "{}",
+            msg!(
+                "summary.unmapped_fallback",
+                user_id = fallback,
+                count = counts.records_to_fallback
+            )
+        ),
+        UnmappedUserPolicy::Keep | UnmappedUserPolicy::Error => return,
+    }
+    for (old_id, count) in &counts.per_user {
+        let line = if policy == UnmappedUserPolicy::Drop {
+            msg!(
+                "summary.unmapped_dropped_user",
+                old_id = old_id,
+                count = count
+            )
+        } else {
+            msg!(
+                "summary.unmapped_fallback_user",
+                old_id = old_id,
+                count = count
+            )
+        };
+        info!("{}", line);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config_from_toml;
+
+    fn config_with(extra: &str) -> Config {
+        config_from_toml(&format!(
+            "input_tsv_file_path = \"unused.tsv\"\n{}\n[instance_old]\nbase_url = \"http://old\"\napi_token = \"x\"\n\
+            [instance_new]\nbase_url = \"http://new\"\napi_token = \"y\"\n",
+            extra
+        ))
+    }
+
+    fn record(user_id: &str) -> TsvRecord {
+        TsvRecord {
+            date_created: "2024-01-01 10:00:00".to_string(),
+            user_id: user_id.to_string(),
+            item_id: "item".to_string(),
+            item_type: "Movie".to_string(),
+            item_name: "Name".to_string(),
+            playback_method: "DirectPlay".to_string(),
+            client_name: "Web".to_string(),
+            device_name: "TV".to_string(),
+            play_duration: "60".to_string(),
+        }
+    }
+
+    // The UserIds written (None for a dropped record) and the stats, for a config's settings
+    fn map_all(toml: &str) -> (Vec<Option<String>>, ProcessingStats) {
+        let config = config_with(toml);
+        let user_id_map = HashMap::from([
+            ("old-a".to_string(), "new-a".to_string()),
+            ("old-b".to_string(), "new-b".to_string()),
+        ]);
+        let mut stats = ProcessingStats::default();
+        let user_ids = ["old-a", "new-a", "old-b", "stranger", "new-b", "stranger"]
+            .into_iter()
+            .map(|user_id| {
+                let mut record = record(user_id);
+                apply_user_id_map(&mut record, &user_id_map, &config, &mut stats)
+                    .unwrap()
+                    .then_some(record.user_id)
+            })
+            .collect();
+        (user_ids, stats)
+    }
+
+    #[test]
+    fn auto_mode_separates_already_migrated_and_unmapped_ids() {
+        let (user_ids, stats) = map_all("mapping_direction = \"auto\"");
+        let user_ids: Vec<String> = user_ids.into_iter().flatten().collect();
+        assert_eq!(
+            user_ids,
+            vec!["new-a", "new-a", "new-b", "stranger", "new-b", "stranger"]
+        );
+        assert_eq!(stats.records_changed, 2);
+        assert_eq!(stats.records_already_migrated, 2);
+        assert_eq!(stats.unmapped_user_ids["stranger"], 2);
+        assert_eq!(stats.records_unchanged, 4);
+
+        let (forward_ids, stats) = map_all("");
+        assert_eq!(
+            forward_ids.into_iter().flatten().collect::<Vec<_>>(),
+            user_ids
+        );
+        assert_eq!(stats.records_already_migrated, 0);
+        assert!(stats.unmapped_user_ids.is_empty());
+    }
+
+    #[test]
+    fn unmapped_records_follow_the_policy() {
+        let (user_ids, stats) =
+            map_all("mapping_direction = \"auto\"\nunmapped_user_policy = \"drop\"");
+        assert_eq!(user_ids.iter().filter(|id| id.is_none()).count(), 2);
+        assert_eq!(stats.unmapped_policy.records_dropped, 2);
+        assert_eq!(stats.unmapped_policy.per_user["stranger"], 2);
+        assert_eq!(stats.records_unchanged, 2); // Only the already migrated ones
+        assert!(stats.unmapped_user_ids.is_empty());
+
+        let (user_ids, stats) =
+            map_all("mapping_direction = \"auto\"\nunmapped_user_policy = \"fallback\"\nfallback_user_id = \"new-x\"");
+        assert_eq!(user_ids[3].as_deref(), Some("new-x"));
+        assert_eq!(stats.unmapped_policy.records_to_fallback, 2);
+        assert_eq!(stats.changes_summary["stranger"], ("new-x".to_string(), 2));
+
+        let config = config_with("unmapped_user_policy = \"error\"");
+        let error = apply_user_id_map(
+            &mut record("stranger"),
+            &HashMap::new(),
+            &config,
+            &mut ProcessingStats::default(),
+        )
+        .unwrap_err();
+        assert!(error.contains("'stranger'"), "{}", error);
+        assert!(validate(&config_with("unmapped_user_policy = \"fallback\"")).is_err());
+        assert!(validate(&config_with("fallback_user_id = \"new-x\"")).is_err());
+    }
+}
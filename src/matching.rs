@@ -0,0 +1,392 @@
+// Which new user each old user becomes. Names are matched first, as `user_match_mode` says:
+// exactly (the default), ignoring case and surrounding whitespace, or "normalized", which also
+// collapses whitespace inside the name and compares in Unicode NFC (so a composed "é" matches
+// "e" + combining accent). Names shared once compared that way are ambiguous in every mode: new
+// users sharing a name leave the old users with that name unmapped, and so do old users sharing a
+// name that a new user has, rather than both being merged into one new user. With
+// --allow-ambiguous-first-match the first of the new users (in the order of /Users) is used
+// instead, for every old user with the name. An old user
+// whose name isn't on the new instance (e.g. an account renamed during the move) is matched by
+// email instead, from the `Email` or `ConnectUserName` field of /Users, compared ignoring case.
+// Only new users that no name matched are candidates. When several old users have the email of
+// the same new user none of them is mapped, since merging their histories is rarely what was meant.
+// With fuzzy_match_threshold, old users still unmatched then go to the most similar remaining new
+// name (ignoring case, spaces and punctuation, so "Bob Smith" finds "bobsmith") if it scores at
+// least the threshold. Several new names sharing the best score are ambiguous and none is used.
+use crate::messages::msg;
+use crate::JellyfinUser;
+use serde::Deserialize;
+use std::collections::HashMap;
+use unicode_normalization::UnicodeNormalization;
+
+// `user_match_mode`
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum UserMatchMode {
+    #[default]
+    Exact,
+    CaseInsensitive, // Also what match_case_insensitive = true selects
+    Normalized,
+}
+
+impl UserMatchMode {
+    // How names are compared, e.g. "names match ignoring case and surrounding whitespace"
+    pub fn describe(self) -> String {
+        match self {
+            UserMatchMode::Exact => msg!("compare.exact"),
+            UserMatchMode::CaseInsensitive => msg!("compare.case_insensitive"),
+            UserMatchMode::Normalized => msg!("compare.normalized"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Outcome<'a> {
+    ByName(&'a JellyfinUser),
+    // Several new users have the name once compared in the match mode
+    NameCollision(Vec<&'a JellyfinUser>),
+    // The other old users with the same name in the match mode, which a new user has
+    OldNameCollision(Vec<&'a JellyfinUser>),
+    ByEmail(&'a JellyfinUser, String),
+    // The other old users with the same email are listed in their own outcomes
+    EmailConflict(&'a JellyfinUser, String),
+    BySimilarName(&'a JellyfinUser, f64),
+    // The new users with the same best similarity
+    SimilarNameTie(Vec<&'a JellyfinUser>, f64),
+    NotFound,
+}
+
+impl JellyfinUser {
+    fn email(&self) -> Option<String> {
+        [&self.email, &self.connect_user_name]
+            .into_iter()
+            .flatten()
+            .map(|email| email.trim())
+            .find(|email| !email.is_empty())
+            .map(str::to_lowercase)
+    }
+}
+
+// Compares names ignoring case, spaces and punctuation so "Living Room TV" matches "LivingRoomTV"
+fn comparable(name: &str) -> String {
+    name.chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+// 0.0 (nothing in common) to 1.0 (the same once compared as above)
+pub fn similarity(a: &str, b: &str) -> f64 {
+    strsim::normalized_levenshtein(&comparable(a), &comparable(b))
+}
+
+fn name_key(name: &str, mode: UserMatchMode) -> String {
+    match mode {
+        UserMatchMode::Exact => name.to_string(),
+        UserMatchMode::CaseInsensitive => name.trim().to_lowercase(),
+        // Lowercased before composing, lowercasing can take a string out of NFC
+        UserMatchMode::Normalized => name
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+            .to_lowercase()
+            .nfc()
+            .collect(),
+    }
+}
+
+// The users sharing a name once compared in `mode`, in the order of `users`
+pub fn duplicate_names(users: &[JellyfinUser], mode: UserMatchMode) -> Vec<Vec<&JellyfinUser>> {
+    let mut groups: Vec<(String, Vec<&JellyfinUser>)> = Vec::new();
+    for user in users {
+        let key = name_key(&user.name, mode);
+        match groups.iter_mut().find(|(other, _)| *other == key) {
+            Some((_, group)) => group.push(user),
+            None => groups.push((key, vec![user])),
+        }
+    }
+    groups
+        .into_iter()
+        .map(|(_, group)| group)
+        .filter(|group| group.len() > 1)
+        .collect()
+}
+
+// One outcome per old user, in the order of `old_users`
+pub fn match_users<'a>(
+    old_users: &'a [JellyfinUser],
+    new_users: &'a [JellyfinUser],
+    mode: UserMatchMode,
+    fuzzy_threshold: Option<f64>,
+    first_match: bool,
+) -> Vec<(&'a JellyfinUser, Outcome<'a>)> {
+    let mut new_by_name: HashMap<String, Vec<&JellyfinUser>> = HashMap::new();
+    for new in new_users {
+        new_by_name
+            .entry(name_key(&new.name, mode))
+            .or_default()
+            .push(new);
+    }
+    let mut old_by_name: HashMap<String, Vec<&JellyfinUser>> = HashMap::new();
+    for old in old_users {
+        old_by_name
+            .entry(name_key(&old.name, mode))
+            .or_default()
+            .push(old);
+    }
+    let mut outcomes: Vec<(&JellyfinUser, Outcome)> = old_users
+        .iter()
+        .map(|old| {
+            let key = name_key(&old.name, mode);
+            let outcome = match new_by_name.get(&key).map(Vec::as_slice) {
+                Some([first, ..]) if first_match => Outcome::ByName(first),
+                Some(_) if old_by_name[&key].len() > 1 => Outcome::OldNameCollision(
+                    old_by_name[&key]
+                        .iter()
+                        .filter(|other| other.id != old.id)
+                        .copied()
+                        .collect(),
+                ),
+                Some([new]) => Outcome::ByName(new),
+                Some(news) => Outcome::NameCollision(news.to_vec()),
+                None => Outcome::NotFound,
+            };
+            (old, outcome)
+        })
+        .collect();
+
+    let claimed_by_name: Vec<&str> = outcomes
+        .iter()
+        .filter_map(|(_, outcome)| match outcome {
+            Outcome::ByName(new) => Some(new.id.as_str()),
+            _ => None,
+        })
+        .collect();
+    // An email shared by several new users doesn't say which one is meant
+    let mut new_by_email: HashMap<String, Option<&JellyfinUser>> = HashMap::new();
+    for new in new_users {
+        if claimed_by_name.contains(&new.id.as_str()) {
+            continue;
+        }
+        if let Some(email) = new.email() {
+            new_by_email
+                .entry(email)
+                .and_modify(|user| *user = None)
+                .or_insert(Some(new));
+        }
+    }
+    let mut claims: HashMap<&str, usize> = HashMap::new();
+    for (old, outcome) in outcomes.iter_mut() {
+        if *outcome != Outcome::NotFound {
+            continue;
+        }
+        let Some(email) = old.email() else {
+            continue;
+        };
+        if let Some(Some(new)) = new_by_email.get(&email) {
+            *claims.entry(new.id.as_str()).or_default() += 1;
+            *outcome = Outcome::ByEmail(new, email);
+        }
+    }
+    for (_, outcome) in outcomes.iter_mut() {
+        if let Outcome::ByEmail(new, email) = outcome {
+            if claims[new.id.as_str()] > 1 {
+                *outcome = Outcome::EmailConflict(new, std::mem::take(email));
+            }
+        }
+    }
+
+    let Some(threshold) = fuzzy_threshold else {
+        return outcomes;
+    };
+    let claimed: Vec<&str> = outcomes
+        .iter()
+        .filter_map(|(_, outcome)| match outcome {
+            Outcome::ByName(new) | Outcome::ByEmail(new, _) => Some(new.id.as_str()),
+            _ => None,
+        })
+        .collect();
+    let candidates: Vec<&JellyfinUser> = new_users
+        .iter()
+        .filter(|new| !claimed.contains(&new.id.as_str()))
+        .collect();
+    for (old, outcome) in outcomes.iter_mut() {
+        if *outcome != Outcome::NotFound {
+            continue;
+        }
+        let scored: Vec<(&JellyfinUser, f64)> = candidates
+            .iter()
+            .map(|new| (*new, similarity(&old.name, &new.name)))
+            .filter(|(_, score)| *score >= threshold)
+            .collect();
+        let best_score = scored.iter().map(|(_, score)| *score).fold(0.0, f64::max);
+        let best: Vec<&JellyfinUser> = scored
+            .into_iter()
+            .filter(|(_, score)| (score - best_score).abs() <= f64::EPSILON)
+            .map(|(new, _)| new)
+            .collect();
+        *outcome = match best[..] {
+            [] => Outcome::NotFound,
+            [new] => Outcome::BySimilarName(new, best_score),
+            _ => Outcome::SimilarNameTie(best, best_score),
+        };
+    }
+    outcomes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(name: &str, id: &str, email: Option<&str>) -> JellyfinUser {
+        JellyfinUser {
+            id: id.to_string(),
+            name: name.to_string(),
+            email: email.map(str::to_string),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn matches_by_name_then_by_email_and_skips_conflicts() {
+        let old = vec![
+            user("alice", "old-a", Some("alice@example.com")),
+            user("robert", "old-b", Some("Bob@Example.com")),
+            user("carol", "old-c", Some("shared@example.com")),
+            user("carl", "old-d", Some("shared@example.com")),
+            user("erin", "old-e", None),
+        ];
+        let mut new = vec![
+            user("alice", "new-a", Some("someone@example.com")),
+            user("bob", "new-b", None),
+            user("caz", "new-c", Some("shared@example.com")),
+        ];
+        new[1].connect_user_name = Some("bob@example.com ".to_string());
+
+        let outcomes = match_users(&old, &new, UserMatchMode::Exact, None, false);
+        let outcome = |i: usize| &outcomes[i].1;
+        assert_eq!(*outcome(0), Outcome::ByName(&new[0]));
+        assert_eq!(
+            *outcome(1),
+            Outcome::ByEmail(&new[1], "bob@example.com".to_string())
+        );
+        for i in [2, 3] {
+            assert_eq!(
+                *outcome(i),
+                Outcome::EmailConflict(&new[2], "shared@example.com".to_string())
+            );
+        }
+        assert_eq!(*outcome(4), Outcome::NotFound);
+    }
+
+    #[test]
+    fn case_insensitive_names_and_collisions() {
+        let old = vec![user("John", "old-j", None), user("Ann", "old-a", None)];
+        let new = vec![
+            user("john ", "new-j", None),
+            user("ann", "new-a1", None),
+            user("ANN", "new-a2", None),
+        ];
+        let outcomes = match_users(&old, &new, UserMatchMode::Exact, None, false);
+        assert!(outcomes.iter().all(|(_, o)| *o == Outcome::NotFound));
+
+        let outcomes = match_users(&old, &new, UserMatchMode::CaseInsensitive, None, false);
+        assert_eq!(outcomes[0].1, Outcome::ByName(&new[0]));
+        assert_eq!(
+            outcomes[1].1,
+            Outcome::NameCollision(vec![&new[1], &new[2]])
+        );
+    }
+
+    #[test]
+    fn similar_names_above_the_threshold_and_ties() {
+        let old = vec![
+            user("Bob Smith", "old-b", None),
+            user("jon", "old-j", None),
+            user("alice", "old-a", None),
+        ];
+        let new = vec![
+            user("bobsmith", "new-b", None),
+            user("jen", "new-j1", None),
+            user("jan", "new-j2", None),
+            user("alice", "new-a", None),
+        ];
+        assert_eq!(similarity("Bob Smith", "bobsmith"), 1.0);
+
+        let outcomes = match_users(&old, &new, UserMatchMode::Exact, Some(0.6), false);
+        assert_eq!(outcomes[0].1, Outcome::BySimilarName(&new[0], 1.0));
+        match &outcomes[1].1 {
+            Outcome::SimilarNameTie(tied, _) => assert_eq!(*tied, vec![&new[1], &new[2]]),
+            other => panic!("expected a tie, got {:?}", other),
+        }
+        // Exact matches come first and take their new user out of the candidates
+        assert_eq!(outcomes[2].1, Outcome::ByName(&new[3]));
+
+        let outcomes = match_users(&old, &new, UserMatchMode::Exact, Some(0.7), false);
+        assert_eq!(outcomes[1].1, Outcome::NotFound);
+        assert!(
+            match_users(&old, &new, UserMatchMode::Exact, None, false)[0].1 == Outcome::NotFound
+        );
+    }
+
+    #[test]
+    fn normalized_names_and_old_side_collisions() {
+        // "Zoë" with a combining diaeresis on the old side, precomposed on the new one
+        let old = vec![
+            user("Anna ", "old-a", None),
+            user("Zoe\u{308}", "old-z", None),
+            user("Mary  Jane", "old-m1", None),
+            user("mary jane", "old-m2", None),
+        ];
+        let new = vec![
+            user("anna", "new-a", None),
+            user("zo\u{eb}", "new-z", None),
+            user("Mary Jane", "new-m", None),
+        ];
+        let outcomes = match_users(&old, &new, UserMatchMode::CaseInsensitive, None, false);
+        assert_eq!(outcomes[0].1, Outcome::ByName(&new[0]));
+        assert_eq!(outcomes[1].1, Outcome::NotFound);
+
+        let outcomes = match_users(&old, &new, UserMatchMode::Normalized, None, false);
+        assert_eq!(outcomes[0].1, Outcome::ByName(&new[0]));
+        assert_eq!(outcomes[1].1, Outcome::ByName(&new[1]));
+        // Neither of the two old users collapsing to "mary jane" gets the new one
+        assert_eq!(outcomes[2].1, Outcome::OldNameCollision(vec![&old[3]]));
+        assert_eq!(outcomes[3].1, Outcome::OldNameCollision(vec![&old[2]]));
+    }
+
+    #[test]
+    fn duplicate_names_are_ambiguous_unless_the_first_match_is_allowed() {
+        let old = vec![
+            user("alice", "old-a", None),
+            user("bob", "old-b1", None),
+            user("bob", "old-b2", None),
+        ];
+        let new = vec![
+            user("alice", "new-a1", None),
+            user("alice", "new-a2", None),
+            user("bob", "new-b", None),
+        ];
+        assert_eq!(
+            duplicate_names(&new, UserMatchMode::Exact),
+            vec![vec![&new[0], &new[1]]]
+        );
+        assert_eq!(
+            duplicate_names(&old, UserMatchMode::Exact),
+            vec![vec![&old[1], &old[2]]]
+        );
+
+        let outcomes = match_users(&old, &new, UserMatchMode::Exact, None, false);
+        assert_eq!(
+            outcomes[0].1,
+            Outcome::NameCollision(vec![&new[0], &new[1]])
+        );
+        assert_eq!(outcomes[1].1, Outcome::OldNameCollision(vec![&old[2]]));
+        assert_eq!(outcomes[2].1, Outcome::OldNameCollision(vec![&old[1]]));
+
+        let outcomes = match_users(&old, &new, UserMatchMode::Exact, None, true);
+        assert_eq!(outcomes[0].1, Outcome::ByName(&new[0]));
+        assert_eq!(outcomes[1].1, Outcome::ByName(&new[2]));
+        assert_eq!(outcomes[2].1, Outcome::ByName(&new[2]));
+    }
+}
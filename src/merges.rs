@@ -0,0 +1,375 @@
+// Several old users mapped onto one new user, e.g. by user_map_override_path, [user_map] or the
+// fuzzy match: their histories end up combined under the new user. `many_to_one_policy` decides
+// what happens to such a group: "warn" (the default) lists it and carries on, "error" stops the run
+// before anything is written, and "merge" carries on and writes merge_manifest_path, a TSV of each
+// contributing old user with their record count and total PlayDuration. A merge picked on purpose
+// in --interactive is merged whatever the policy. Merged old users are recorded with the match
+// method "merge" in jpm_user_map.
+use crate::{Config, JellyfinUser, TsvRecord};
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::error::Error;
+use std::fs;
+use std::io::{BufWriter, Write};
+use tracing::{info, warn};
+
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ManyToOnePolicy {
+    #[default]
+    Warn,
+    Error,
+    Merge,
+}
+
+impl ManyToOnePolicy {
+    pub fn name(self) -> &'static str {
+        match self {
+            ManyToOnePolicy::Warn => "warn",
+            ManyToOnePolicy::Error => "error",
+            ManyToOnePolicy::Merge => "merge",
+        }
+    }
+}
+
+// Startup check of many_to_one_policy and merge_manifest_path
+pub fn validate(config: &Config) -> Result<(), String> {
+    match (config.many_to_one_policy, &config.merge_manifest_path) {
+        (ManyToOnePolicy::Merge, None) => Err(
+            "many_to_one_policy = \"merge\" needs merge_manifest_path, the TSV listing the merged users.".to_string(),
+        ),
+        (ManyToOnePolicy::Merge, Some(_)) | (_, None) => Ok(()),
+        (policy, Some(_)) => Err(format!(
+            "merge_manifest_path is only used with many_to_one_policy = \"merge\" (it is \"{}\").",
+            policy.name()
+        )),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Group {
+    new_id: String,
+    new_name: String,
+    old_users: Vec<(String, String)>, // (ID, name), by name
+    merged: bool,                     // By the policy or picked in --interactive
+    intentional: bool,                // Picked in --interactive
+}
+
+impl Group {
+    fn describe(&self) -> String {
+        let old_users: Vec<String> = self
+            .old_users
+            .iter()
+            .map(|(id, name)| format!("'{}' ({})", name, id))
+            .collect();
+        format!(
+            "'{}' ({}) <- {}",
+            self.new_name,
+            self.new_id,
+            old_users.join(", ")
+        )
+    }
+}
+
+// The new users several old users are mapped to, filled in by resolve
+#[derive(Debug, Default)]
+pub struct MergePlan {
+    groups: Vec<Group>,
+    by_old_id: HashMap<String, usize>, // Old user ID -> its group
+}
+
+// Records and PlayDuration of the old users in a group, keyed by old user ID
+#[derive(Debug, Default)]
+pub struct MergeStats {
+    pub per_old_user: BTreeMap<String, (u64, f64)>,
+}
+
+// Finds the groups in the final user map (after --interactive) and applies many_to_one_policy.
+// `intentional` holds the new user IDs picked for a merge in --interactive.
+pub fn resolve(
+    config: &mut Config,
+    old_users: &[JellyfinUser],
+    new_users: &[JellyfinUser],
+    user_id_map: &HashMap<String, String>,
+    intentional: &BTreeSet<String>,
+) -> Result<(), String> {
+    let mut by_new_id: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    for (old_id, new_id) in user_id_map {
+        by_new_id.entry(new_id).or_default().push(old_id);
+    }
+    let old_name = |id: &str| {
+        old_users
+            .iter()
+            .find(|user| user.id == id)
+            .map_or("?".to_string(), |user| user.name.clone())
+    };
+    let mut plan = MergePlan::default();
+    for (new_id, old_ids) in by_new_id {
+        if old_ids.len() < 2 {
+            continue;
+        }
+        let mut old_users: Vec<(String, String)> = old_ids
+            .into_iter()
+            .map(|id| (id.to_string(), old_name(id)))
+            .collect();
+        old_users.sort_by(|a, b| (&a.1, &a.0).cmp(&(&b.1, &b.0)));
+        let intentional = intentional.contains(new_id);
+        for (old_id, _) in &old_users {
+            plan.by_old_id.insert(old_id.clone(), plan.groups.len());
+        }
+        plan.groups.push(Group {
+            new_id: new_id.to_string(),
+            new_name: new_users
+                .iter()
+                .find(|user| user.id == new_id)
+                .map_or("?".to_string(), |user| user.name.clone()),
+            old_users,
+            merged: intentional || config.many_to_one_policy == ManyToOnePolicy::Merge,
+            intentional,
+        });
+    }
+
+    let unintended: Vec<String> = plan
+        .groups
+        .iter()
+        .filter(|group| !group.merged)
+        .map(Group::describe)
+        .collect();
+    if !unintended.is_empty() {
+        if config.many_to_one_policy == ManyToOnePolicy::Error {
+            return Err(format!(
+                "Several old users are mapped to the same new user, their histories would be combined: {}. Map them to different users, or set many_to_one_policy = \"merge\" (or \"warn\") to combine them.",
+                unintended.join("; ")
+            ));
+        }
+        warn!(
+            "Several old users are mapped to the same new user, their histories are combined (many_to_one_policy = \"warn\", \"merge\" also writes a merge manifest):"
+        );
+        for group in &unintended {
+            warn!("  {}", group);
+        }
+    }
+    if plan.groups.iter().any(|group| group.merged) {
+        info!("Merging old users into one new user:");
+        for group in plan.groups.iter().filter(|group| group.merged) {
+            info!(
+                "  {}{}",
+                group.describe(),
+                if group.intentional {
+                    " (picked in --interactive)"
+                } else {
+                    ""
+                }
+            );
+        }
+    }
+    config.merge_plan = plan;
+    Ok(())
+}
+
+impl MergePlan {
+    pub fn is_empty(&self) -> bool {
+        self.groups.is_empty()
+    }
+
+    // Whether the old user is part of a group that is merged on purpose
+    pub fn merges(&self, old_user_id: &str) -> bool {
+        self.by_old_id
+            .get(old_user_id)
+            .is_some_and(|&group| self.groups[group].merged)
+    }
+
+    pub fn contains(&self, old_user_id: &str) -> bool {
+        self.by_old_id.contains_key(old_user_id)
+    }
+
+    pub fn print_summary(&self, stats: &MergeStats) {
+        info!("  Old users sharing a new user:");
+        for group in &self.groups {
+            info!(
+                "    '{}' ({}){}:",
+                group.new_name,
+                group.new_id,
+                if group.merged { ", merged" } else { "" }
+            );
+            for (old_id, old_name) in &group.old_users {
+                let (records, play_duration) =
+                    stats.per_old_user.get(old_id).copied().unwrap_or_default();
+                info!(
+                    "      '{}' ({}): {} records, {} s PlayDuration",
+                    old_name,
+                    old_id,
+                    records,
+                    play_duration.round()
+                );
+            }
+        }
+    }
+
+    // The merged groups as a TSV, one line per contributing old user
+    pub fn write_manifest(
+        &self,
+        path: &str,
+        stats: &MergeStats,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut out = BufWriter::new(fs::File::create(path)?);
+        writeln!(
+            out,
+            "new_id\tnew_name\told_id\told_name\trecords\tplay_duration\tintentional"
+        )?;
+        for group in self.groups.iter().filter(|group| group.merged) {
+            for (old_id, old_name) in &group.old_users {
+                let (records, play_duration) =
+                    stats.per_old_user.get(old_id).copied().unwrap_or_default();
+                writeln!(
+                    out,
+                    "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                    group.new_id,
+                    group.new_name,
+                    old_id,
+                    old_name,
+                    records,
+                    play_duration.round(),
+                    group.intentional
+                )?;
+            }
+        }
+        out.flush()?;
+        info!("Merge manifest written to {}", path);
+        Ok(())
+    }
+}
+
+impl MergeStats {
+    // A mapped record of `old_user_id`, with its PlayDuration in seconds by now
+    pub fn count(&mut self, old_user_id: &str, record: &TsvRecord) {
+        let (records, play_duration) = self
+            .per_old_user
+            .entry(old_user_id.to_string())
+            .or_default();
+        *records += 1;
+        *play_duration += record.play_duration.parse::<f64>().unwrap_or(0.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config_from_toml;
+
+    const INSTANCES: &str = "[instance_old]\nbase_url = 'http://old'\napi_token = 'x'\n\
+        [instance_new]\nbase_url = 'http://new'\napi_token = 'y'\n";
+
+    fn config_with(settings: &str) -> Config {
+        config_from_toml(&format!(
+            "input_tsv_file_path = 'x'\n{}\n{}",
+            settings, INSTANCES
+        ))
+    }
+
+    fn user(name: &str, id: &str) -> JellyfinUser {
+        JellyfinUser {
+            id: id.to_string(),
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn record(play_duration: &str) -> TsvRecord {
+        TsvRecord {
+            date_created: "2024-01-01 10:00:00".to_string(),
+            user_id: "new-a".to_string(),
+            item_id: "i".to_string(),
+            item_type: "Episode".to_string(),
+            item_name: "E".to_string(),
+            playback_method: "DirectPlay".to_string(),
+            client_name: "Web".to_string(),
+            device_name: "TV".to_string(),
+            play_duration: play_duration.to_string(),
+        }
+    }
+
+    // alice and al both go to the new alice, bob to the new bob
+    fn resolve_with(config: &mut Config, intentional: &[&str]) -> Result<(), String> {
+        let user_id_map = HashMap::from([
+            ("old-a".to_string(), "new-a".to_string()),
+            ("old-al".to_string(), "new-a".to_string()),
+            ("old-b".to_string(), "new-b".to_string()),
+        ]);
+        resolve(
+            config,
+            &[
+                user("alice", "old-a"),
+                user("al", "old-al"),
+                user("bob", "old-b"),
+            ],
+            &[user("alice", "new-a"), user("bob", "new-b")],
+            &user_id_map,
+            &intentional.iter().map(|id| id.to_string()).collect(),
+        )
+    }
+
+    #[test]
+    fn validates_the_policy_and_the_manifest_path() {
+        validate(&config_with("")).unwrap();
+        validate(&config_with(
+            "many_to_one_policy = 'merge'\nmerge_manifest_path = 'm.tsv'",
+        ))
+        .unwrap();
+        let error = validate(&config_with("many_to_one_policy = 'merge'")).unwrap_err();
+        assert!(error.contains("needs merge_manifest_path"), "{}", error);
+        let error = validate(&config_with(
+            "many_to_one_policy = 'error'\nmerge_manifest_path = 'm.tsv'",
+        ))
+        .unwrap_err();
+        assert!(error.contains("(it is \"error\")"), "{}", error);
+    }
+
+    #[test]
+    fn finds_the_groups_and_applies_the_policy() {
+        // warn: the group is found but not merged on purpose
+        let mut config = config_with("");
+        resolve_with(&mut config, &[]).unwrap();
+        assert!(config.merge_plan.contains("old-a") && config.merge_plan.contains("old-al"));
+        assert!(!config.merge_plan.contains("old-b"));
+        assert!(!config.merge_plan.merges("old-a"));
+
+        let mut config = config_with("many_to_one_policy = 'error'");
+        let error = resolve_with(&mut config, &[]).unwrap_err();
+        assert!(
+            error.contains("'alice' (new-a) <- 'al' (old-al), 'alice' (old-a)"),
+            "{}",
+            error
+        );
+        // Picked in --interactive: merged even though the policy refuses the others
+        resolve_with(&mut config, &["new-a"]).unwrap();
+        assert!(config.merge_plan.merges("old-al"));
+
+        let mut config = config_with("many_to_one_policy = 'merge'\nmerge_manifest_path = 'm.tsv'");
+        resolve_with(&mut config, &[]).unwrap();
+        assert!(config.merge_plan.merges("old-a") && !config.merge_plan.merges("old-b"));
+    }
+
+    #[test]
+    fn writes_the_manifest_of_the_merged_users() {
+        let mut config = config_with("many_to_one_policy = 'merge'\nmerge_manifest_path = 'm.tsv'");
+        resolve_with(&mut config, &["new-a"]).unwrap();
+        let mut stats = MergeStats::default();
+        stats.count("old-a", &record("100"));
+        stats.count("old-a", &record("50.4"));
+        stats.count("old-al", &record("not a number"));
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("merges.tsv");
+        config
+            .merge_plan
+            .write_manifest(path.to_str().unwrap(), &stats)
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "new_id\tnew_name\told_id\told_name\trecords\tplay_duration\tintentional\n\
+             new-a\talice\told-al\tal\t1\t0\ttrue\n\
+             new-a\talice\told-a\talice\t2\t150\ttrue\n"
+        );
+    }
+}
@@ -0,0 +1,254 @@
+// Message catalog for the user ID map printout and the processing summary, so admins can read
+// them in their language (`--lang`). The catalogs are TOML files embedded from locales/, English
+// being the default and the fallback for any key a translation leaves out. Every catalog is
+// checked when it is loaded: a template with unbalanced braces, or one using a placeholder the
+// English message doesn't have (so the code never passes it), is an error at startup instead of a
+// garbled line in the middle of a run. Machine-readable outputs (the JPM_SUMMARY line, the
+// manifest, the unmapped report, the progress endpoint) don't go through here and stay English.
+use config::{Config as AppConfig, File, FileFormat};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Display;
+use std::sync::OnceLock;
+
+const ENGLISH: &str = include_str!("../locales/en.toml");
+// (language, catalog) of the translations, English excluded
+const TRANSLATIONS: &[(&str, &str)] = &[("de", include_str!("../locales/de.toml"))];
+
+static CATALOG: OnceLock<Catalog> = OnceLock::new();
+
+#[derive(Debug, Clone, PartialEq)]
+enum Piece {
+    Text(String),
+    Placeholder(String),
+}
+
+#[derive(Debug)]
+pub struct Catalog {
+    messages: BTreeMap<String, Vec<Piece>>, // "section.key" -> template
+}
+
+// "Mapped {mapped} of {total}" -> [Text("Mapped "), Placeholder("mapped"), ...]
+fn parse_template(template: &str) -> Result<Vec<Piece>, String> {
+    let mut pieces = Vec::new();
+    let mut text = String::new();
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                text.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                text.push('}');
+            }
+            '{' => {
+                let mut name = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) if c.is_ascii_alphanumeric() || c == '_' => name.push(c),
+                        _ => return Err(format!("unclosed or invalid placeholder '{{{}'", name)),
+                    }
+                }
+                if name.is_empty() {
+                    return Err("empty placeholder '{}'".to_string());
+                }
+                if !text.is_empty() {
+                    pieces.push(Piece::Text(std::mem::take(&mut text)));
+                }
+                pieces.push(Piece::Placeholder(name));
+            }
+            '}' => return Err("unmatched '}' (write '}}' for a literal brace)".to_string()),
+            c => text.push(c),
+        }
+    }
+    if !text.is_empty() {
+        pieces.push(Piece::Text(text));
+    }
+    Ok(pieces)
+}
+
+fn placeholders(pieces: &[Piece]) -> BTreeSet<&str> {
+    pieces
+        .iter()
+        .filter_map(|piece| match piece {
+            Piece::Placeholder(name) => Some(name.as_str()),
+            Piece::Text(_) => None,
+        })
+        .collect()
+}
+
+// Parses a catalog file into "section.key" -> template, checking every template
+fn parse_catalog(name: &str, text: &str) -> Result<BTreeMap<String, Vec<Piece>>, String> {
+    let sections: BTreeMap<String, BTreeMap<String, String>> = AppConfig::builder()
+        .add_source(File::from_str(text, FileFormat::Toml))
+        .build()
+        .and_then(AppConfig::try_deserialize)
+        .map_err(|e| format!("message catalog '{}': {}", name, e))?;
+    let mut messages = BTreeMap::new();
+    for (section, entries) in sections {
+        for (key, template) in entries {
+            let id = format!("{}.{}", section, key);
+            let pieces = parse_template(&template)
+                .map_err(|e| format!("message catalog '{}', {}: {}", name, id, e))?;
+            messages.insert(id, pieces);
+        }
+    }
+    Ok(messages)
+}
+
+impl Catalog {
+    pub fn load(lang: &str) -> Result<Catalog, String> {
+        let mut messages = parse_catalog("en", ENGLISH)?;
+        if lang == "en" {
+            return Ok(Catalog { messages });
+        }
+        let Some((_, text)) = TRANSLATIONS.iter().find(|(name, _)| *name == lang) else {
+            return Err(format!(
+                "No messages for language '{}'. Available: {}",
+                lang,
+                available_languages().join(", ")
+            ));
+        };
+        for (id, pieces) in parse_catalog(lang, text)? {
+            let Some(english) = messages.get(&id) else {
+                return Err(format!(
+                    "message catalog '{}': {} isn't a message (not in the English catalog)",
+                    lang, id
+                ));
+            };
+            let known = placeholders(english);
+            if let Some(unknown) = placeholders(&pieces)
+                .into_iter()
+                .find(|name| !known.contains(name))
+            {
+                return Err(format!(
+                    "message catalog '{}', {}: unknown placeholder {{{}}}, the message only has {}",
+                    lang,
+                    id,
+                    unknown,
+                    known
+                        .iter()
+                        .map(|name| format!("{{{}}}", name))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ));
+            }
+            messages.insert(id, pieces);
+        }
+        Ok(Catalog { messages })
+    }
+
+    pub fn render(&self, id: &str, args: &[(&str, &dyn Display)]) -> String {
+        // A missing message shows its ID rather than failing, the tests make sure none is missing
+        let Some(pieces) = self.messages.get(id) else {
+            return id.to_string();
+        };
+        let mut rendered = String::new();
+        for piece in pieces {
+            match piece {
+                Piece::Text(text) => rendered.push_str(text),
+                Piece::Placeholder(name) => {
+                    match args.iter().find(|(arg, _)| *arg == name.as_str()) {
+                        Some((_, value)) => rendered.push_str(&value.to_string()),
+                        None => {
+                            rendered.push('{');
+                            rendered.push_str(name);
+                            rendered.push('}');
+                        }
+                    }
+                }
+            }
+        }
+        rendered
+    }
+}
+
+pub fn available_languages() -> Vec<&'static str> {
+    std::iter::once("en")
+        .chain(TRANSLATIONS.iter().map(|(name, _)| *name))
+        .collect()
+}
+
+// Selects the language for the rest of the run, before anything is printed through the catalog
+pub fn init(lang: &str) -> Result<(), String> {
+    let catalog = Catalog::load(lang)?;
+    CATALOG
+        .set(catalog)
+        .map_err(|_| "the message catalog was already loaded".to_string())
+}
+
+pub fn render(id: &str, args: &[(&str, &dyn Display)]) -> String {
+    CATALOG
+        .get_or_init(|| Catalog::load("en").expect("the English message catalog is valid"))
+        .render(id, args)
+}
+
+// msg!("summary.records_processed", count = stats.records_processed)
+macro_rules! msg {
+    ($id:literal $(, $name:ident = $value:expr)* $(,)?) => {
+        $crate::messages::render(
+            $id,
+            &[$((stringify!($name), &$value as &dyn std::fmt::Display)),*],
+        )
+    };
+}
+pub(crate) use msg;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_catalog_loads_and_covers_the_messages_used() {
+        for lang in available_languages() {
+            Catalog::load(lang).unwrap();
+        }
+        let english = Catalog::load("en").unwrap();
+        // Every msg!("...") in the sources has an English message
+        let src = concat!(env!("CARGO_MANIFEST_DIR"), "/src");
+        for entry in std::fs::read_dir(src).unwrap() {
+            let path = entry.unwrap().path();
+            let source = std::fs::read_to_string(&path).unwrap();
+            for usage in source.split("msg!(\"").skip(1) {
+                let id = &usage[..usage.find('"').unwrap()];
+                if id == "..." {
+                    continue; // The example in the comment above
+                }
+                assert!(
+                    english.messages.contains_key(id),
+                    "{} uses {}, which en.toml doesn't have",
+                    path.display(),
+                    id
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn renders_placeholders_and_literal_braces() {
+        let catalog = Catalog {
+            messages: BTreeMap::from([(
+                "a.b".to_string(),
+                parse_template("{{{count}}} of {total}").unwrap(),
+            )]),
+        };
+        assert_eq!(
+            catalog.render("a.b", &[("count", &3), ("total", &"5")]),
+            "{3} of 5"
+        );
+        assert_eq!(catalog.render("a.missing", &[]), "a.missing");
+    }
+
+    #[test]
+    fn broken_templates_are_rejected() {
+        assert!(parse_template("Mapped {mapped").is_err());
+        assert!(parse_template("a } b").is_err());
+        assert!(parse_template("{}").is_err());
+        assert!(parse_template("{old name}").is_err());
+        assert!(parse_catalog("xx", "[map]\ntitle = \"{oops\"")
+            .unwrap_err()
+            .contains("map.title"));
+    }
+}
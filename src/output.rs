@@ -0,0 +1,180 @@
+// Opens the TSV output, making sure that appending to an existing file never mixes column layouts
+// (e.g. a file started by an older version of the tool) or repeats the header row.
+use crate::{schema, Config, TsvRecord};
+use std::error::Error;
+use std::fs::{self, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use tracing::info;
+
+// Bumped whenever the set or order of written columns changes
+pub const OUTPUT_SCHEMA_VERSION: u32 = 1;
+const SCHEMA_COMMENT_PREFIX: &str = "# jpm-schema: v";
+
+fn drift_error(path: &str, found: &str, expected: &str) -> Box<dyn Error + Send + Sync> {
+    format!(
+        "Refusing to append to '{}': the existing file has {} but this run writes {}. \
+        The output format has changed since the file was started (schema drift). \
+        Use a new output_tsv_file_path, or turn off output_tsv_append to overwrite the file.",
+        path, found, expected
+    )
+    .into()
+}
+
+// Reads the first line(s) of an existing output and checks they match what this run would write
+fn check_append_compatible(
+    path: &str,
+    columns: &[&str],
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let reader = BufReader::new(fs::File::open(path)?);
+    for line in reader.lines() {
+        let line = line?;
+        let line = line.trim_end_matches('\r');
+
+        if let Some(version) = line.strip_prefix(SCHEMA_COMMENT_PREFIX) {
+            if version.trim() != OUTPUT_SCHEMA_VERSION.to_string() {
+                return Err(drift_error(
+                    path,
+                    &format!("schema v{}", version.trim()),
+                    &format!("schema v{}", OUTPUT_SCHEMA_VERSION),
+                ));
+            }
+            continue;
+        }
+        if line.starts_with('#') || line.is_empty() {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split('\t').collect();
+        let looks_like_header = fields
+            .iter()
+            .any(|field| schema::column_names().contains(field));
+        if looks_like_header {
+            if fields != columns {
+                return Err(drift_error(
+                    path,
+                    &format!("header [{}]", fields.join(", ")),
+                    &format!("[{}]", columns.join(", ")),
+                ));
+            }
+        } else if fields.len() != columns.len() {
+            return Err(drift_error(
+                path,
+                &format!("{} columns in its first record", fields.len()),
+                &format!("{} columns", columns.len()),
+            ));
+        }
+        return Ok(());
+    }
+    Ok(())
+}
+
+// Indexes (into TsvRecord::fields) of the columns to write, all of them unless `output_columns` is set
+pub fn selected_columns(config: &Config) -> Result<Vec<usize>, String> {
+    let known = schema::column_names();
+    let Some(ref requested) = config.output_columns else {
+        return Ok((0..known.len()).collect());
+    };
+    if requested.is_empty() {
+        return Err("output_columns is empty. Remove it to write every column.".to_string());
+    }
+    let mut indexes = Vec::new();
+    for name in requested {
+        let index = known
+            .iter()
+            .position(|column| column == name)
+            .ok_or_else(|| {
+                format!(
+                    "Unknown column '{}' in output_columns. Known columns: {}",
+                    name,
+                    known.join(", ")
+                )
+            })?;
+        if indexes.contains(&index) {
+            return Err(format!(
+                "Column '{}' is listed twice in output_columns",
+                name
+            ));
+        }
+        indexes.push(index);
+    }
+    Ok(indexes)
+}
+
+// TSV writer that only emits the configured columns
+pub struct TsvWriter {
+    wtr: csv::Writer<fs::File>,
+    columns: Vec<usize>,
+}
+
+impl TsvWriter {
+    pub fn write(&mut self, record: &TsvRecord) -> Result<(), csv::Error> {
+        let fields = record.fields();
+        self.wtr
+            .write_record(self.columns.iter().map(|&index| fields[index]))
+    }
+
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.wtr.flush()
+    }
+}
+
+// The line `record` is written as (without the line ending), for --explain
+pub fn format_line(
+    columns: &[usize],
+    record: &TsvRecord,
+) -> Result<String, Box<dyn Error + Send + Sync>> {
+    let mut wtr = csv::WriterBuilder::new()
+        .delimiter(b'\t')
+        .has_headers(false)
+        .from_writer(Vec::new());
+    let fields = record.fields();
+    wtr.write_record(columns.iter().map(|&index| fields[index]))?;
+    let mut line = String::from_utf8(wtr.into_inner().map_err(|e| e.to_string())?)?;
+    line.truncate(line.trim_end_matches('\n').len());
+    Ok(line)
+}
+
+pub fn open_tsv_writer(
+    config: &Config,
+    path: &str,
+) -> Result<TsvWriter, Box<dyn Error + Send + Sync>> {
+    let indexes = selected_columns(config)?;
+    let all_columns = schema::column_names();
+    let columns: Vec<&str> = indexes.iter().map(|&index| all_columns[index]).collect();
+
+    let existing_len = if config.output_tsv_append {
+        fs::metadata(path).map(|m| m.len()).unwrap_or(0)
+    } else {
+        0
+    };
+    if existing_len > 0 {
+        check_append_compatible(path, &columns)?;
+        info!(
+            "Appending to existing TSV output '{}' ({} bytes, layout matches).",
+            path, existing_len
+        );
+    }
+
+    let mut file = if config.output_tsv_append {
+        OpenOptions::new().create(true).append(true).open(path)?
+    } else {
+        fs::File::create(path)?
+    };
+
+    // The header and schema stamp only belong at the top of a fresh file
+    let is_new_file = existing_len == 0;
+    if is_new_file && config.output_tsv_schema_comment {
+        writeln!(file, "{}{}", SCHEMA_COMMENT_PREFIX, OUTPUT_SCHEMA_VERSION)?;
+    }
+    let mut wtr = csv::WriterBuilder::new()
+        .delimiter(b'\t')
+        .has_headers(false) // Headers are written explicitly below so they are never repeated
+        .from_writer(file);
+    if is_new_file && config.output_tsv_headers {
+        wtr.write_record(&columns)?;
+    }
+    Ok(TsvWriter {
+        wtr,
+        columns: indexes,
+    })
+}
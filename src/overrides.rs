@@ -0,0 +1,277 @@
+// Mappings given by hand for users that automatic matching can never find (e.g. completely
+// different names on the two instances), from two places:
+// - `user_map_override_path`: a TSV or CSV file of `old_id,new_id` pairs. Lines starting with '#',
+//   empty lines and an `old_id,new_id` header are skipped. A new ID the new instance doesn't list
+//   is used as written, with a warning.
+// - `[user_map]`: old user ID or name -> new user ID or name, e.g. "steve" = "stephen". Both sides
+//   have to exist on their instance.
+// IDs are compared ignoring GUID dashes and case, names exactly. An old user listed there is mapped
+// as given and left out of the name and email matching. Every old user has to exist on the old
+// instance, a typo would otherwise silently leave that user's history unmapped.
+use crate::{Config, JellyfinUser};
+use std::collections::BTreeMap;
+use std::fs;
+use tracing::warn;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Source {
+    File,
+    Config,
+}
+
+impl Source {
+    pub fn describe(self) -> &'static str {
+        match self {
+            Source::File => "user_map_override_path",
+            Source::Config => "[user_map]",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Override {
+    pub old: String, // An ID, or with Source::Config also a name
+    pub new: String,
+    pub source: Source,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct UserMapOverrides {
+    pub entries: Vec<Override>,
+}
+
+fn comparable_id(id: &str) -> String {
+    id.replace('-', "").to_lowercase()
+}
+
+impl UserMapOverrides {
+    // The file (when configured) and [user_map], read at startup
+    pub fn from_config(config: &Config) -> Result<UserMapOverrides, String> {
+        let mut entries = match config.user_map_override_path {
+            Some(ref path) => load(path)?,
+            None => Vec::new(),
+        };
+        entries.extend(config.user_map.iter().map(|(old, new)| Override {
+            old: old.clone(),
+            new: new.clone(),
+            source: Source::Config,
+        }));
+        Ok(UserMapOverrides { entries })
+    }
+
+    // Old user -> new user ID (as the instances write them) and where the mapping came from.
+    // Errors for any entry whose user can't be found, and for an old user mapped twice.
+    pub fn resolve<'a>(
+        &self,
+        old_users: &'a [JellyfinUser],
+        new_users: &[JellyfinUser],
+    ) -> Result<Vec<(&'a JellyfinUser, String, Source)>, String> {
+        let mut resolved: Vec<(&JellyfinUser, String, Source)> = Vec::new();
+        let mut problems = Vec::new();
+        for entry in &self.entries {
+            let Some(old_user) = find(old_users, &entry.old, entry.source) else {
+                problems.push(format!(
+                    "{} lists old user '{}', which isn't a user on the old instance",
+                    entry.source.describe(),
+                    entry.old
+                ));
+                continue;
+            };
+            let new_id = match find(new_users, &entry.new, entry.source) {
+                Some(new_user) => new_user.id.clone(),
+                None if entry.source == Source::Config => {
+                    problems.push(format!(
+                        "[user_map] maps '{}' to '{}', which isn't a user on the new instance",
+                        entry.old, entry.new
+                    ));
+                    continue;
+                }
+                None => {
+                    if !new_users.is_empty() {
+                        warn!(
+                            "  user_map_override_path maps '{}' to '{}', which isn't a user on the new instance. Mapped anyway.",
+                            old_user.name, entry.new
+                        );
+                    }
+                    entry.new.clone()
+                }
+            };
+            if let Some((_, _, first)) = resolved.iter().find(|(user, _, _)| user.id == old_user.id)
+            {
+                problems.push(format!(
+                    "old user '{}' is mapped by both {} and {}",
+                    old_user.name,
+                    first.describe(),
+                    entry.source.describe()
+                ));
+                continue;
+            }
+            resolved.push((old_user, new_id, entry.source));
+        }
+        if !problems.is_empty() {
+            return Err(format!(
+                "User map overrides: {} ({} old and {} new users fetched). Check them against /Users of the instances.",
+                problems.join("; "),
+                old_users.len(),
+                new_users.len()
+            ));
+        }
+        Ok(resolved)
+    }
+}
+
+// By ID, or for [user_map] entries also by name
+fn find<'a>(users: &'a [JellyfinUser], key: &str, source: Source) -> Option<&'a JellyfinUser> {
+    users
+        .iter()
+        .find(|user| comparable_id(&user.id) == comparable_id(key))
+        .or_else(|| match source {
+            Source::Config => users.iter().find(|user| user.name == key),
+            Source::File => None,
+        })
+}
+
+fn load(path: &str) -> Result<Vec<Override>, String> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("user_map_override_path '{}': {}", path, e))?;
+    parse(path, &text)
+}
+
+fn parse(path: &str, text: &str) -> Result<Vec<Override>, String> {
+    let mut entries = Vec::new();
+    let mut seen: BTreeMap<String, usize> = BTreeMap::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        // Tab separated if the line has a tab, comma separated otherwise
+        let delimiter = if line.contains('\t') { '\t' } else { ',' };
+        let fields: Vec<&str> = line.split(delimiter).map(str::trim).collect();
+        let [old_id, new_id] = fields[..] else {
+            return Err(format!(
+                "user_map_override_path '{}' line {}: expected old_id,new_id, got '{}'",
+                path,
+                i + 1,
+                line
+            ));
+        };
+        if old_id.eq_ignore_ascii_case("old_id") {
+            continue; // Header
+        }
+        if old_id.is_empty() || new_id.is_empty() {
+            return Err(format!(
+                "user_map_override_path '{}' line {}: empty ID in '{}'",
+                path,
+                i + 1,
+                line
+            ));
+        }
+        if let Some(first) = seen.insert(comparable_id(old_id), i + 1) {
+            return Err(format!(
+                "user_map_override_path '{}' line {}: old ID '{}' is already mapped on line {}",
+                path,
+                i + 1,
+                old_id,
+                first
+            ));
+        }
+        entries.push(Override {
+            old: old_id.to_string(),
+            new: new_id.to_string(),
+            source: Source::File,
+        });
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(name: &str, id: &str) -> JellyfinUser {
+        JellyfinUser {
+            id: id.to_string(),
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn config_entry(old: &str, new: &str) -> Override {
+        Override {
+            old: old.to_string(),
+            new: new.to_string(),
+            source: Source::Config,
+        }
+    }
+
+    #[test]
+    fn parses_tsv_and_csv_and_checks_old_ids() {
+        let entries = parse(
+            "map.csv",
+            "# Renamed accounts\nold_id,new_id\nAAAA-0001, bbbb0001\nold-c\tnew-x\n\n",
+        )
+        .unwrap();
+        assert_eq!(entries.len(), 2);
+        assert!(parse("map.csv", "old-a\n").is_err());
+        assert!(parse("map.csv", "old-a,new-a\nOLD-A,new-b\n")
+            .unwrap_err()
+            .contains("line 2"));
+
+        let overrides = UserMapOverrides { entries };
+        let old = vec![user("alice", "aaaa0001"), user("carol", "old-c")];
+        let new = vec![user("alicia", "BBBB-0001")];
+        let resolved = overrides.resolve(&old, &new).unwrap();
+        assert_eq!(resolved[0].0.name, "alice");
+        assert_eq!(resolved[0].1, "BBBB-0001");
+        assert_eq!(resolved[1].1, "new-x");
+        assert_eq!(resolved[1].2, Source::File);
+
+        let error = overrides.resolve(&old[1..], &new).unwrap_err();
+        assert!(error.contains("'AAAA-0001'"), "{}", error);
+    }
+
+    #[test]
+    fn config_entries_accept_names_or_ids_on_both_sides() {
+        let old = vec![user("steve", "old-s"), user("ann", "old-a")];
+        let new = vec![user("stephen", "new-s"), user("annie", "new-a")];
+        let overrides = UserMapOverrides {
+            entries: vec![
+                config_entry("steve", "stephen"),
+                config_entry("old-a", "new-a"),
+            ],
+        };
+        let resolved = overrides.resolve(&old, &new).unwrap();
+        assert_eq!(
+            resolved
+                .iter()
+                .map(|(user, new_id, source)| (user.id.as_str(), new_id.as_str(), *source))
+                .collect::<Vec<_>>(),
+            vec![
+                ("old-s", "new-s", Source::Config),
+                ("old-a", "new-a", Source::Config)
+            ]
+        );
+
+        let overrides = UserMapOverrides {
+            entries: vec![
+                config_entry("steve", "stefan"),
+                config_entry("bob", "annie"),
+            ],
+        };
+        let error = overrides.resolve(&old, &new).unwrap_err();
+        assert!(error.contains("'stefan', which isn't a user on the new instance"));
+        assert!(error.contains("old user 'bob'"), "{}", error);
+
+        let mut entries = parse("map.csv", "old-s,new-a\n").unwrap();
+        entries.push(config_entry("steve", "stephen"));
+        let error = UserMapOverrides { entries }
+            .resolve(&old, &new)
+            .unwrap_err();
+        assert!(
+            error.contains("mapped by both user_map_override_path and [user_map]"),
+            "{}",
+            error
+        );
+    }
+}
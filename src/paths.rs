@@ -0,0 +1,196 @@
+// Paths in a config written on another OS, e.g. `C:\exports\playback.tsv` in a config copied from
+// a Windows machine to the Linux server. `[path_prefix_map]` entries translate them (old prefix ->
+// new prefix, longest match wins), and any path still in the other OS's style is refused with an
+// explanation instead of failing later with "No such file or directory".
+use crate::Config;
+use std::collections::BTreeMap;
+use std::path::{Path, MAIN_SEPARATOR_STR};
+use tracing::info;
+
+// Translates the configured paths in place, printing every change. Errors for a path that still
+// looks like it was written for the other OS.
+pub fn resolve_config_paths(config: &mut Config) -> Result<(), String> {
+    let map = &config.path_prefix_map;
+    let mut paths: Vec<(&str, &mut String)> =
+        vec![("input_tsv_file_path", &mut config.input_tsv_file_path)];
+    paths.extend(
+        config
+            .output_tsv_file_path
+            .as_mut()
+            .map(|p| ("output_tsv_file_path", p)),
+    );
+    paths.extend(
+        config
+            .sqlite_db_path
+            .as_mut()
+            .map(|p| ("sqlite_db_path", p)),
+    );
+    paths.extend(
+        config
+            .output_manifest_path
+            .as_mut()
+            .map(|p| ("output_manifest_path", p)),
+    );
+    paths.extend(
+        config
+            .merge_manifest_path
+            .as_mut()
+            .map(|p| ("merge_manifest_path", p)),
+    );
+    paths.extend(
+        config
+            .user_map_override_path
+            .as_mut()
+            .map(|p| ("user_map_override_path", p)),
+    );
+    paths.extend(
+        config
+            .unmapped_report_path
+            .as_mut()
+            .map(|p| ("unmapped_report_path", p)),
+    );
+    paths.extend(
+        config
+            .user_map_output_path
+            .as_mut()
+            .map(|p| ("user_map_output_path", p)),
+    );
+    paths.extend(
+        config
+            .user_map_input_path
+            .as_mut()
+            .map(|p| ("user_map_input_path", p)),
+    );
+
+    let mut problems = Vec::new();
+    for (key, path) in paths {
+        if let Some(translated) = translate(path, map) {
+            info!(
+                "Translated {} with path_prefix_map: '{}' -> '{}'",
+                key, path, translated
+            );
+            *path = translated;
+        }
+        if let Some(style) = foreign_style(path, cfg!(windows), |p| p.exists()) {
+            problems.push(format!("{} = '{}' looks like {}", key, path, style));
+        }
+    }
+    if problems.is_empty() {
+        return Ok(());
+    }
+    Err(format!(
+        "{}, but this is running on {}. The config was probably written on another machine: change the path(s) to where the files are on this machine, or add a [path_prefix_map] entry such as \"C:\\\\exports\" = \"/mnt/exports\".",
+        problems.join("; "),
+        std::env::consts::OS
+    ))
+}
+
+// Separators unified and case folded. Windows paths are case-insensitive and the config crate
+// lowercases the map's keys anyway.
+fn comparable(path: &str) -> String {
+    path.replace('\\', "/").trim_end_matches('/').to_lowercase()
+}
+
+// The path with its longest matching prefix replaced, using this OS's separator for the rest
+fn translate(path: &str, map: &BTreeMap<String, String>) -> Option<String> {
+    let comparable_path = comparable(path);
+    let (from, to) = map
+        .iter()
+        .map(|(from, to)| (comparable(from), to))
+        .filter(|(from, _)| {
+            !from.is_empty()
+                && comparable_path.starts_with(from.as_str())
+                && matches!(
+                    comparable_path.as_bytes().get(from.len()),
+                    None | Some(b'/')
+                )
+        })
+        .max_by_key(|(from, _)| from.len())?;
+    // Case folding keeps the length of paths that are ASCII, others are left alone
+    let rest = path.get(from.len()..).filter(|_| path.is_ascii())?;
+    let rest = rest.replace(['\\', '/'], MAIN_SEPARATOR_STR);
+    Some(format!("{}{}", to.trim_end_matches(['\\', '/']), rest))
+}
+
+fn has_drive_letter(path: &str) -> bool {
+    let bytes = path.as_bytes();
+    bytes.len() >= 3
+        && bytes[0].is_ascii_alphabetic()
+        && bytes[1] == b':'
+        && matches!(bytes[2], b'\\' | b'/')
+}
+
+// What kind of foreign path this is, if it's one. Only paths that don't exist are suspicious
+// (a Linux file name may contain a backslash).
+fn foreign_style(
+    path: &str,
+    on_windows: bool,
+    exists: impl Fn(&Path) -> bool,
+) -> Option<&'static str> {
+    if exists(Path::new(path)) {
+        return None;
+    }
+    if on_windows {
+        // e.g. /mnt/exports/playback.tsv, which Windows would look for as \mnt on the current drive
+        let root = path.strip_prefix('/')?.split('/').next()?;
+        return (!root.is_empty() && !exists(Path::new(&format!("/{}", root))))
+            .then_some("a Unix path");
+    }
+    if has_drive_letter(path) || path.starts_with("\\\\") {
+        Some("a Windows path")
+    } else if path.contains('\\') {
+        Some("a Windows path (it has backslashes)")
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config_from_toml;
+
+    #[test]
+    fn translates_prefixes_and_explains_foreign_paths() {
+        let config = config_from_toml(
+            "input_tsv_file_path = 'x'\n\
+            [path_prefix_map]\n'C:\\exports' = '/mnt/exports'\n'C:\\exports\\old' = '/srv/old/'\n\
+            [instance_old]\nbase_url = 'http://old'\napi_token = 'x'\n\
+            [instance_new]\nbase_url = 'http://new'\napi_token = 'y'\n",
+        );
+        let map = &config.path_prefix_map;
+        let sep = MAIN_SEPARATOR_STR;
+        assert_eq!(
+            translate("C:\\Exports\\2024\\playback.tsv", map),
+            Some(format!("/mnt/exports{}2024{}playback.tsv", sep, sep))
+        );
+        assert_eq!(
+            translate("c:/exports/old/playback.tsv", map),
+            Some(format!("/srv/old{}playback.tsv", sep))
+        );
+        assert_eq!(translate("C:\\exports2\\playback.tsv", map), None);
+        assert_eq!(translate("/data/playback.tsv", map), None);
+
+        let missing = |_: &Path| false;
+        assert_eq!(
+            foreign_style("C:\\exports\\a.tsv", false, missing),
+            Some("a Windows path")
+        );
+        assert_eq!(
+            foreign_style("\\\\nas\\share\\a.tsv", false, missing),
+            Some("a Windows path")
+        );
+        assert!(foreign_style("exports\\a.tsv", false, missing).is_some());
+        assert_eq!(foreign_style("exports\\a.tsv", false, |_| true), None);
+        assert_eq!(foreign_style("/mnt/exports/a.tsv", false, missing), None);
+        assert_eq!(
+            foreign_style("/mnt/exports/a.tsv", true, missing),
+            Some("a Unix path")
+        );
+        assert_eq!(
+            foreign_style("/mnt/exports/a.tsv", true, |p| p == Path::new("/mnt")),
+            None
+        );
+        assert_eq!(foreign_style("C:\\exports\\a.tsv", true, missing), None);
+    }
+}
@@ -0,0 +1,121 @@
+// PlayDuration is read as text. Values that are whole numbers are rewritten in their canonical
+// form ("0060" and "60.0" both become "60") so the TSV output and an INT column in SQLite end up
+// with the same value, and values with a fractional part are rounded per play_duration_rounding
+// and counted. Anything that isn't a number is passed through unchanged.
+use crate::display::format_count;
+use serde::Deserialize;
+use tracing::info;
+
+// What happens to a PlayDuration with a fractional part, e.g. "123.5"
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Rounding {
+    #[default]
+    Nearest, // Half away from zero
+    Down,
+    Up,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PlayDuration {
+    Integer(i64),
+    Rounded(i64), // Only parsed as a float
+    Unparsed,
+}
+
+pub fn parse(value: &str, rounding: Rounding) -> PlayDuration {
+    let trimmed = value.trim();
+    if let Ok(n) = trimmed.parse::<i64>() {
+        return PlayDuration::Integer(n);
+    }
+    // Range check so huge values aren't saturated into a different number
+    match trimmed.parse::<f64>() {
+        Ok(n) if n.is_finite() && n.abs() < i64::MAX as f64 => {
+            let rounded = match rounding {
+                Rounding::Nearest => n.round(),
+                Rounding::Down => n.floor(),
+                Rounding::Up => n.ceil(),
+            };
+            if rounded == n {
+                PlayDuration::Integer(n as i64) // "60.0"
+            } else {
+                PlayDuration::Rounded(rounded as i64)
+            }
+        }
+        _ => PlayDuration::Unparsed,
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct PlayDurationStats {
+    pub rounded: u64,
+    pub unparsed: u64,
+    pub converted_from_ticks: u64, // See units.rs
+}
+
+impl PlayDurationStats {
+    pub fn print_summary(&self, rounding: Rounding) {
+        if self.rounded > 0 {
+            info!(
+                "  PlayDuration values rounded ({}): {}",
+                match rounding {
+                    Rounding::Nearest => "to nearest",
+                    Rounding::Down => "down",
+                    Rounding::Up => "up",
+                },
+                format_count(self.rounded)
+            );
+        }
+        if self.converted_from_ticks > 0 {
+            info!(
+                "  PlayDuration values converted from ticks to seconds: {}",
+                format_count(self.converted_from_ticks)
+            );
+        }
+        if self.unparsed > 0 {
+            info!(
+                "  PlayDuration values that aren't numbers (kept as text): {}",
+                format_count(self.unparsed)
+            );
+        }
+    }
+}
+
+// Rewrites the value in place
+pub fn canonicalize(value: &mut String, rounding: Rounding, stats: &mut PlayDurationStats) {
+    match parse(value, rounding) {
+        PlayDuration::Integer(n) => *value = n.to_string(),
+        PlayDuration::Rounded(n) => {
+            *value = n.to_string();
+            stats.rounded += 1;
+        }
+        PlayDuration::Unparsed => stats.unparsed += 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn canonicalizes_and_rounds() {
+        let mut stats = PlayDurationStats::default();
+        let canonical = |value: &str, rounding: Rounding, stats: &mut PlayDurationStats| {
+            let mut value = value.to_string();
+            canonicalize(&mut value, rounding, stats);
+            value
+        };
+        assert_eq!(canonical(" 0060 ", Rounding::Nearest, &mut stats), "60");
+        assert_eq!(canonical("123.0", Rounding::Nearest, &mut stats), "123");
+        assert_eq!(canonical("-5", Rounding::Nearest, &mut stats), "-5");
+        assert_eq!(stats.rounded, 0);
+        assert_eq!(canonical("123.5", Rounding::Nearest, &mut stats), "124");
+        assert_eq!(canonical("123.5", Rounding::Down, &mut stats), "123");
+        assert_eq!(canonical("123.2", Rounding::Up, &mut stats), "124");
+        assert_eq!(stats.rounded, 3);
+        assert_eq!(canonical("", Rounding::Nearest, &mut stats), "");
+        assert_eq!(canonical("NaN", Rounding::Nearest, &mut stats), "NaN");
+        assert_eq!(canonical("1e300", Rounding::Nearest, &mut stats), "1e300");
+        assert_eq!(stats.unparsed, 3);
+    }
+}
@@ -0,0 +1,176 @@
+// `--progress-listen <addr>`: a tiny HTTP endpoint for dashboards that poll jobs. `GET /progress`
+// answers with the current phase, the records processed out of the total, the main counters and
+// the elapsed time as JSON. Only those counters are shared with the server thread, so nothing from
+// the configuration (URLs, tokens, paths) can end up in a response. Off unless the flag is given,
+// and the server stops when the run ends.
+use crate::ProcessingStats;
+use serde_json::json;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tracing::info;
+
+// How often the server checks for new connections and whether the run is over
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+const MAX_REQUEST_BYTES: usize = 8192;
+
+// Updated by the run as it goes, read by the server. Also updated without a server, which only
+// costs a few atomic stores per record.
+pub static PROGRESS: Progress = Progress::new();
+
+pub struct Progress {
+    phase: Mutex<&'static str>,
+    records_total: AtomicU64,
+    records_processed: AtomicU64,
+    records_changed: AtomicU64,
+    records_unchanged: AtomicU64,
+    records_dropped: AtomicU64,
+    records_inserted_sqlite: AtomicU64,
+    records_skipped_sqlite: AtomicU64,
+}
+
+impl Progress {
+    const fn new() -> Progress {
+        Progress {
+            phase: Mutex::new("starting"),
+            records_total: AtomicU64::new(0),
+            records_processed: AtomicU64::new(0),
+            records_changed: AtomicU64::new(0),
+            records_unchanged: AtomicU64::new(0),
+            records_dropped: AtomicU64::new(0),
+            records_inserted_sqlite: AtomicU64::new(0),
+            records_skipped_sqlite: AtomicU64::new(0),
+        }
+    }
+
+    pub fn set_phase(&self, phase: &'static str) {
+        *self.phase.lock().unwrap_or_else(|e| e.into_inner()) = phase;
+    }
+
+    pub fn set_total(&self, records_total: u64) {
+        self.records_total.store(records_total, Ordering::Relaxed);
+    }
+
+    // The counters are stored one by one, so a response can be a record apart between them
+    pub fn update(&self, stats: &ProcessingStats) {
+        let counters = [
+            (&self.records_processed, stats.records_processed),
+            (&self.records_changed, stats.records_changed),
+            (&self.records_unchanged, stats.records_unchanged),
+            (&self.records_dropped, stats.records_dropped()),
+            (
+                &self.records_inserted_sqlite,
+                stats.records_inserted_sqlite as u64,
+            ),
+            (
+                &self.records_skipped_sqlite,
+                stats.records_skipped_sqlite as u64,
+            ),
+        ];
+        for (counter, value) in counters {
+            counter.store(value, Ordering::Relaxed);
+        }
+    }
+
+    fn to_json(&self, started_at: Instant) -> String {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        json!({
+            "phase": *self.phase.lock().unwrap_or_else(|e| e.into_inner()),
+            "records_processed": load(&self.records_processed),
+            "records_total": load(&self.records_total),
+            "counters": {
+                "records_changed": load(&self.records_changed),
+                "records_unchanged": load(&self.records_unchanged),
+                "records_dropped": load(&self.records_dropped),
+                "records_inserted_sqlite": load(&self.records_inserted_sqlite),
+                "records_skipped_sqlite": load(&self.records_skipped_sqlite),
+            },
+            "elapsed_secs": started_at.elapsed().as_secs_f64(),
+        })
+        .to_string()
+    }
+}
+
+// Serves PROGRESS on its own thread until dropped
+pub struct ProgressServer {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ProgressServer {
+    pub fn start(addr: SocketAddr) -> Result<ProgressServer, String> {
+        let listener = TcpListener::bind(addr)
+            .map_err(|e| format!("--progress-listen couldn't listen on {}: {}", addr, e))?;
+        listener
+            .set_nonblocking(true)
+            .map_err(|e| format!("--progress-listen: {}", e))?;
+        info!(
+            "Serving progress on http://{}/progress",
+            listener.local_addr().unwrap_or(addr)
+        );
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = Arc::clone(&stop);
+        let started_at = Instant::now();
+        let thread = thread::spawn(move || {
+            while !thread_stop.load(Ordering::Relaxed) {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        // A client that goes away mid-request is its own problem
+                        let _ = respond(stream, started_at);
+                    }
+                    // Nothing waiting (or a failed accept, tried again next time)
+                    Err(_) => thread::sleep(POLL_INTERVAL),
+                }
+            }
+        });
+        Ok(ProgressServer {
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for ProgressServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn respond(mut stream: TcpStream, started_at: Instant) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(Duration::from_secs(2)))?;
+    // Only the request line matters, the headers are read so the client isn't reset
+    let mut request = Vec::new();
+    let mut buffer = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < MAX_REQUEST_BYTES {
+        let read = stream.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        request.extend_from_slice(&buffer[..read]);
+    }
+    let request = String::from_utf8_lossy(&request);
+    let mut request_line = request.lines().next().unwrap_or("").split_whitespace();
+    let (status, body) = match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some("/progress")) => ("200 OK", PROGRESS.to_json(started_at)),
+        (Some("GET"), _) => ("404 Not Found", json!({"error": "not found"}).to_string()),
+        _ => (
+            "405 Method Not Allowed",
+            json!({"error": "only GET /progress is served"}).to_string(),
+        ),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
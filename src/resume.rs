@@ -0,0 +1,298 @@
+// `--max-runtime` and `--state-file`: a run that would overrun a maintenance window stops reading
+// at the next check (every RUNTIME_CHECK_RECORDS records), commits and flushes what it has, writes
+// how far it got to the state file and exits with EXIT_PARTIAL. The next run with the same
+// --state-file skips the records that were already done, after checking that the last of them is
+// the one the state file names, and removes the state file once it gets to the end of the input.
+// The file's format version is checked on load, see formats.rs.
+use crate::formats::{self, STATE_FILE};
+use crate::timefmt;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tracing::info;
+
+// "Partial, resumable": stopped at --max-runtime with the state written
+pub const EXIT_PARTIAL: i32 = 3;
+// Reading the clock per record would be cheap too, this keeps the stops at round positions
+const RUNTIME_CHECK_RECORDS: u64 = 1000;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ResumeState {
+    #[serde(default)] // Checked before deserializing, see load
+    pub version: u32,
+    pub input_tsv_file_path: String,
+    pub records_done: u64, // Records read from the input, including ones dropped by filters
+    pub last_date_created: Option<String>,
+    pub stopped_at: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct Checkpoint {
+    pub state_file: PathBuf,
+    pub deadline: Option<Instant>,
+    pub resume_from: Option<ResumeState>,
+}
+
+#[derive(Debug, Default)]
+pub struct CheckpointStats {
+    pub records_skipped: u64, // Done by the runs before
+    pub last_date_created: Option<String>,
+    pub out_of_time: bool,
+}
+
+impl CheckpointStats {
+    pub fn print_summary(&self, records_processed: u64) {
+        if self.records_skipped > 0 {
+            info!(
+                "  Resumed after {} records done by earlier runs.",
+                self.records_skipped
+            );
+        }
+        if self.out_of_time {
+            info!(
+                "  Stopped at --max-runtime after record {} (last DateCreated {}). Run again with the same --state-file to continue.",
+                self.records_skipped + records_processed,
+                self.last_date_created.as_deref().unwrap_or("-")
+            );
+        }
+    }
+}
+
+impl Checkpoint {
+    pub fn new(
+        state_file: PathBuf,
+        max_runtime: Option<Duration>,
+        started: Instant,
+        input_tsv_file_path: &str,
+    ) -> Result<Checkpoint, Box<dyn Error + Send + Sync>> {
+        let resume_from = load(&state_file)?;
+        if let Some(ref state) = resume_from {
+            if state.input_tsv_file_path != input_tsv_file_path {
+                return Err(format!(
+                    "State file '{}' is for input '{}', not '{}'. Use another --state-file or remove it to start over.",
+                    state_file.display(),
+                    state.input_tsv_file_path,
+                    input_tsv_file_path
+                )
+                .into());
+            }
+            info!(
+                "Resuming from state file '{}': {} records were done by the run stopped at {} (last DateCreated {}).",
+                state_file.display(),
+                state.records_done,
+                state.stopped_at,
+                state.last_date_created.as_deref().unwrap_or("-")
+            );
+        }
+        Ok(Checkpoint {
+            state_file,
+            deadline: max_runtime.map(|max_runtime| started + max_runtime),
+            resume_from,
+        })
+    }
+
+    pub fn records_done(&self) -> u64 {
+        self.resume_from
+            .as_ref()
+            .map_or(0, |state| state.records_done)
+    }
+
+    // Whether to stop before reading the next record
+    pub fn out_of_time(&self, records_processed: u64) -> bool {
+        records_processed > 0
+            && records_processed.is_multiple_of(RUNTIME_CHECK_RECORDS)
+            && self
+                .deadline
+                .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    // The input's records before the last stop should be the same as then
+    pub fn check_skipped(
+        &self,
+        skipped: u64,
+        last_date_created: Option<&str>,
+    ) -> Result<(), String> {
+        let Some(ref state) = self.resume_from else {
+            return Ok(());
+        };
+        if skipped < state.records_done {
+            return Err(format!(
+                "The input has only {} records but state file '{}' says {} were done. Was the input replaced? Remove the state file to start over.",
+                skipped,
+                self.state_file.display(),
+                state.records_done
+            ));
+        }
+        if last_date_created != state.last_date_created.as_deref() {
+            return Err(format!(
+                "Record {} of the input has DateCreated {} but state file '{}' says {}. Was the input changed? Remove the state file to start over.",
+                skipped,
+                last_date_created.unwrap_or("-"),
+                self.state_file.display(),
+                state.last_date_created.as_deref().unwrap_or("-")
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn save(
+        &self,
+        input_tsv_file_path: &str,
+        stats: &CheckpointStats,
+        records_processed: u64,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let state = ResumeState {
+            version: STATE_FILE.current,
+            input_tsv_file_path: input_tsv_file_path.to_string(),
+            records_done: stats.records_skipped + records_processed,
+            last_date_created: stats.last_date_created.clone(),
+            stopped_at: timefmt::now().to_rfc3339(),
+        };
+        write(&self.state_file, &state)?;
+        info!(
+            "Wrote state file '{}': {} records done.",
+            self.state_file.display(),
+            state.records_done
+        );
+        Ok(())
+    }
+
+    // The whole input is done, the next run starts from the beginning again
+    pub fn finish(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if self.state_file.exists() {
+            fs::remove_file(&self.state_file)?;
+            info!(
+                "Reached the end of the input, removed state file '{}'.",
+                self.state_file.display()
+            );
+        }
+        Ok(())
+    }
+}
+
+// Replaced in one step so a crash can't leave half a state file
+fn write(path: &Path, state: &ResumeState) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let temporary = path.with_extension("tmp");
+    fs::write(&temporary, serde_json::to_string_pretty(state)?)?;
+    fs::rename(&temporary, path)?;
+    Ok(())
+}
+
+// The version is checked before the rest is read, a newer file may not have the same fields.
+// Older versions are rewritten in the current one.
+fn load(path: &Path) -> Result<Option<ResumeState>, Box<dyn Error + Send + Sync>> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("State file '{}': {}", path.display(), e).into()),
+    };
+    let unreadable = |e: String| format!("State file '{}' can't be read: {}", path.display(), e);
+    let value: serde_json::Value =
+        serde_json::from_str(&text).map_err(|e| unreadable(e.to_string()))?;
+    let version = formats::json_version(&value).map_err(unreadable)?;
+    let outdated = STATE_FILE.check(path, version)?;
+    let mut state: ResumeState =
+        serde_json::from_value(value).map_err(|e| unreadable(e.to_string()))?;
+    if outdated {
+        // Version 1 had the same fields
+        state.version = STATE_FILE.current;
+        write(path, &state)?;
+        STATE_FILE.print_upgrade(path, version, true);
+    }
+    Ok(Some(state))
+}
+
+// "45m", "1h30m", "90s" or a number of seconds
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let invalid = || format!("'{}' isn't a duration, use e.g. 45m, 1h30m or 90s", value);
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Ok(Duration::from_secs(seconds));
+    }
+    let mut total = 0;
+    let mut number = String::new();
+    for c in value.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let unit = match c {
+            'h' => 3600,
+            'm' => 60,
+            's' => 1,
+            _ => return Err(invalid()),
+        };
+        let n: u64 = number.parse().map_err(|_| invalid())?;
+        total += n * unit;
+        number.clear();
+    }
+    if !number.is_empty() || total == 0 {
+        return Err(invalid());
+    }
+    Ok(Duration::from_secs(total))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_durations_and_round_trips_the_state() {
+        assert_eq!(parse_duration("45m"), Ok(Duration::from_secs(2700)));
+        assert_eq!(parse_duration("1h30m"), Ok(Duration::from_secs(5400)));
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert!(parse_duration("10x").is_err());
+        assert!(parse_duration("m").is_err());
+        assert!(parse_duration("5m3").is_err());
+
+        let dir = tempfile::tempdir().unwrap();
+        let state_file = dir.path().join("state.json");
+        let fresh = Checkpoint::new(state_file.clone(), None, Instant::now(), "in.tsv").unwrap();
+        assert_eq!(fresh.records_done(), 0);
+        let stats = CheckpointStats {
+            records_skipped: 0,
+            last_date_created: Some("2024-01-02 10:00:00".to_string()),
+            out_of_time: true,
+        };
+        fresh.save("in.tsv", &stats, 2000).unwrap();
+
+        let resumed = Checkpoint::new(state_file.clone(), None, Instant::now(), "in.tsv").unwrap();
+        assert_eq!(resumed.records_done(), 2000);
+        assert!(resumed
+            .check_skipped(2000, Some("2024-01-02 10:00:00"))
+            .is_ok());
+        assert!(resumed
+            .check_skipped(2000, Some("2024-01-03 10:00:00"))
+            .is_err());
+        assert!(resumed.check_skipped(1500, None).is_err());
+        assert!(Checkpoint::new(state_file.clone(), None, Instant::now(), "other.tsv").is_err());
+        resumed.finish().unwrap();
+        assert!(!state_file.exists());
+    }
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/formats")
+            .join(name)
+    }
+
+    #[test]
+    fn upgrades_version_1_state_files_and_refuses_newer_ones() {
+        let dir = tempfile::tempdir().unwrap();
+        let state_file = dir.path().join("state.json");
+        fs::copy(fixture("state_v1.json"), &state_file).unwrap();
+        let resumed = Checkpoint::new(state_file.clone(), None, Instant::now(), "in.tsv").unwrap();
+        assert_eq!(resumed.records_done(), 2000);
+        let upgraded: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&state_file).unwrap()).unwrap();
+        assert_eq!(upgraded["version"], 2);
+        assert_eq!(upgraded["records_done"], 2000);
+
+        let error = Checkpoint::new(fixture("state_v99.json"), None, Instant::now(), "in.tsv")
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("has format version 99"), "{}", error);
+    }
+}
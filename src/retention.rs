@@ -0,0 +1,228 @@
+// Age-based retention: records whose DateCreated is older than `retention_days` (or the user's
+// entry in `[retention_overrides]`, keyed by user name) are dropped before they reach any output.
+// Cutoffs are computed once from the run's start time, so in watch mode they don't move.
+use crate::{Config, JellyfinUser, ProcessingStats, TsvRecord};
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use tracing::{debug, info, warn};
+
+// Formats DateCreated is written in by the Playback Reporting plugin (fractional seconds optional)
+const DATE_CREATED_FORMATS: &[&str] = &["%Y-%m-%d %H:%M:%S%.f", "%Y-%m-%dT%H:%M:%S%.f"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "RawRetention")]
+pub enum Retention {
+    Days(u64),
+    Unlimited,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawRetention {
+    Days(u64),
+    Keyword(String),
+}
+
+impl TryFrom<RawRetention> for Retention {
+    type Error = String;
+
+    fn try_from(raw: RawRetention) -> Result<Self, Self::Error> {
+        match raw {
+            RawRetention::Days(days) => Ok(Retention::Days(days)),
+            RawRetention::Keyword(keyword) if keyword == "unlimited" => Ok(Retention::Unlimited),
+            RawRetention::Keyword(other) => Err(format!(
+                "invalid retention '{}', expected a number of days or \"unlimited\"",
+                other
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Retention {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Retention::Days(days) => write!(f, "{} days", days),
+            Retention::Unlimited => f.write_str("unlimited"),
+        }
+    }
+}
+
+fn cutoff(retention: Retention, now: DateTime<Utc>) -> Option<NaiveDateTime> {
+    match retention {
+        Retention::Days(days) => Some((now - Duration::days(days as i64)).naive_utc()),
+        Retention::Unlimited => None,
+    }
+}
+
+pub(crate) fn parse_date_created(value: &str) -> Option<NaiveDateTime> {
+    DATE_CREATED_FORMATS
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(value.trim(), format).ok())
+}
+
+// Per-user retention, keyed by old user ID (records are filtered before IDs are mapped)
+#[derive(Debug, Default)]
+pub struct RetentionPolicy {
+    default: Option<NaiveDateTime>,
+    by_user: HashMap<String, Option<NaiveDateTime>>,
+    // (user name, effective retention) of each mapped user, for the summary
+    mapped_users: Vec<(String, Retention)>,
+}
+
+impl RetentionPolicy {
+    pub fn new(
+        config: &Config,
+        old_users: &[JellyfinUser],
+        user_id_map: &HashMap<String, String>,
+        now: DateTime<Utc>,
+    ) -> RetentionPolicy {
+        let global = config
+            .retention_days
+            .map_or(Retention::Unlimited, Retention::Days);
+        for name in config.retention_overrides.keys() {
+            if !old_users.iter().any(|user| &user.name == name) {
+                warn!(
+                    "[retention_overrides] entry '{}' doesn't match any user on the old instance.",
+                    name
+                );
+            }
+        }
+
+        let mut policy = RetentionPolicy {
+            default: cutoff(global, now),
+            ..Default::default()
+        };
+        for user in old_users {
+            let retention = config
+                .retention_overrides
+                .get(&user.name)
+                .copied()
+                .unwrap_or(global);
+            policy
+                .by_user
+                .insert(user.id.clone(), cutoff(retention, now));
+            if user_id_map.contains_key(&user.id) {
+                policy.mapped_users.push((user.name.clone(), retention));
+            }
+        }
+        policy.mapped_users.sort_by(|a, b| a.0.cmp(&b.0));
+        policy
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.default.is_some() || self.by_user.values().any(Option::is_some)
+    }
+
+    // Returns false (and counts it) if the record is older than its user's retention allows.
+    // Records with a DateCreated that can't be parsed are always kept.
+    pub fn retain(&self, record: &TsvRecord, stats: &mut ProcessingStats) -> bool {
+        let limit = self
+            .by_user
+            .get(&record.user_id)
+            .copied()
+            .unwrap_or(self.default);
+        let Some(limit) = limit else {
+            return true;
+        };
+        match parse_date_created(&record.date_created) {
+            Some(created) if created < limit => {
+                debug!(
+                    "Record {} '{}' of UserId '{}' is older than its retention, dropped",
+                    record.date_created, record.item_name, record.user_id
+                );
+                stats.records_dropped_retention += 1;
+                let key = stats.tracked_users.key(
+                    &record.user_id,
+                    stats.retention_dropped_per_user.len(),
+                    stats
+                        .retention_dropped_per_user
+                        .contains_key(&record.user_id),
+                );
+                *stats.retention_dropped_per_user.entry(key).or_insert(0) += 1;
+                false
+            }
+            _ => true,
+        }
+    }
+
+    pub fn print_effective_retention(&self) {
+        if !self.is_active() {
+            return;
+        }
+        info!("\nRetention applied per mapped user:");
+        for (name, retention) in &self.mapped_users {
+            info!("  '{}': {}", name, retention);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config_from_toml;
+    use chrono::TimeZone;
+
+    fn user(name: &str, id: &str) -> JellyfinUser {
+        JellyfinUser {
+            id: id.to_string(),
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn record(date_created: &str, user_id: &str) -> TsvRecord {
+        TsvRecord {
+            date_created: date_created.to_string(),
+            user_id: user_id.to_string(),
+            item_id: "item".to_string(),
+            item_type: "Movie".to_string(),
+            item_name: "Name".to_string(),
+            playback_method: "DirectPlay".to_string(),
+            client_name: "Web".to_string(),
+            device_name: "TV".to_string(),
+            play_duration: "60".to_string(),
+        }
+    }
+
+    #[test]
+    fn overrides_replace_the_global_retention_per_user() {
+        let config = config_from_toml(
+            "input_tsv_file_path = \"unused.tsv\"\n\
+            retention_days = 365\n\
+            [retention_overrides]\n\
+            kid = \"unlimited\"\n\
+            teen = 30\n\
+            [instance_old]\nbase_url = \"http://old\"\napi_token = \"x\"\n\
+            [instance_new]\nbase_url = \"http://new\"\napi_token = \"y\"\n",
+        );
+        let old_users = [user("adult", "a"), user("kid", "k"), user("teen", "t")];
+        let user_id_map: HashMap<String, String> = [("a", "new-a"), ("k", "new-k")]
+            .into_iter()
+            .map(|(old, new)| (old.to_string(), new.to_string()))
+            .collect();
+        let now = Utc.with_ymd_and_hms(2024, 6, 1, 0, 0, 0).unwrap();
+        let policy = RetentionPolicy::new(&config, &old_users, &user_id_map, now);
+        let mut stats = ProcessingStats::default();
+
+        assert!(policy.retain(&record("2024-01-01 10:00:00.0000000", "a"), &mut stats));
+        assert!(!policy.retain(&record("2023-01-01 10:00:00", "a"), &mut stats));
+        assert!(policy.retain(&record("2001-01-01 10:00:00", "k"), &mut stats));
+        assert!(!policy.retain(&record("2024-04-01 10:00:00", "t"), &mut stats));
+        // Users unknown to the old instance get the global retention
+        assert!(!policy.retain(&record("2023-01-01 10:00:00", "gone"), &mut stats));
+        // Unparseable dates are kept
+        assert!(policy.retain(&record("yesterday", "a"), &mut stats));
+
+        assert_eq!(stats.records_dropped_retention, 3);
+        assert_eq!(stats.retention_dropped_per_user["t"], 1);
+        assert_eq!(
+            policy.mapped_users,
+            vec![
+                ("adult".to_string(), Retention::Days(365)),
+                ("kid".to_string(), Retention::Unlimited)
+            ]
+        );
+    }
+}
@@ -0,0 +1,367 @@
+// `--interactive` review of the user map before anything is processed: every proposed mapping is
+// shown to be accepted, skipped or replaced by typing the name of another new user ("a" accepts it
+// and all the remaining ones), then each old user left unmatched can be given a new user by its
+// number in the list of the new instance's users. Only what is confirmed here is used for the run,
+// so skipping everything runs with an empty map. When the input ends (e.g. Ctrl-D) the rest is
+// treated as skipped. Typing a new user that another old user already has asks first whether to
+// merge the two into it; a merge confirmed here is carried out whatever many_to_one_policy says.
+use crate::JellyfinUser;
+use std::collections::{BTreeSet, HashMap};
+use std::io::{self, BufRead, Write};
+
+#[derive(Debug, Default)]
+struct ReviewCounts {
+    accepted: usize,
+    replaced: usize,
+    skipped: usize,
+    assigned: usize,
+}
+
+enum Answer {
+    Accept,
+    AcceptAll,
+    Skip,
+    Replace(String), // ID of the new user typed instead
+}
+
+// The confirmed map, and the new user IDs picked for a merge
+type Reviewed = (HashMap<String, String>, BTreeSet<String>);
+
+pub fn review_user_map(
+    old_users: &[JellyfinUser],
+    new_users: &[JellyfinUser],
+    user_id_map: &HashMap<String, String>,
+) -> io::Result<Reviewed> {
+    review(
+        old_users,
+        new_users,
+        user_id_map,
+        &mut io::stdin().lock(),
+        &mut io::stdout(),
+    )
+}
+
+fn review(
+    old_users: &[JellyfinUser],
+    new_users: &[JellyfinUser],
+    user_id_map: &HashMap<String, String>,
+    input: &mut impl BufRead,
+    out: &mut impl Write,
+) -> io::Result<Reviewed> {
+    let new_by_id: HashMap<&str, &JellyfinUser> = new_users
+        .iter()
+        .map(|user| (user.id.as_str(), user))
+        .collect();
+    let name_of = |id: &str| new_by_id.get(id).map_or("?", |user| user.name.as_str());
+    let mut confirmed = HashMap::new();
+    let mut merges = BTreeSet::new();
+    let mut counts = ReviewCounts::default();
+    let mut accept_all = false;
+    let mut input_ended = false;
+
+    let proposed: Vec<(&JellyfinUser, &String)> = old_users
+        .iter()
+        .filter_map(|user| user_id_map.get(&user.id).map(|new_id| (user, new_id)))
+        .collect();
+    if !proposed.is_empty() {
+        writeln!(
+            out,
+            "\nReview the user map: Enter or y accepts a mapping, s skips it, a accepts it and all the remaining ones, anything else is taken as the name of another new user."
+        )?;
+    }
+    for (i, (old_user, new_id)) in proposed.iter().enumerate() {
+        let answer = if accept_all {
+            Answer::Accept
+        } else if input_ended {
+            Answer::Skip
+        } else {
+            loop {
+                write!(
+                    out,
+                    "  [{}/{}] '{}' ({}) -> '{}' ({}) [Y/s/a/name]: ",
+                    i + 1,
+                    proposed.len(),
+                    old_user.name,
+                    old_user.id,
+                    name_of(new_id),
+                    new_id
+                )?;
+                out.flush()?;
+                let Some(line) = read_answer(input)? else {
+                    input_ended = true;
+                    writeln!(out)?;
+                    break Answer::Skip;
+                };
+                match line.to_lowercase().as_str() {
+                    "" | "y" | "yes" => break Answer::Accept,
+                    "s" | "skip" => break Answer::Skip,
+                    "a" | "all" => break Answer::AcceptAll,
+                    _ => match find_by_name(new_users, &line) {
+                        Some(user) => {
+                            if confirm_merge(old_users, &confirmed, old_user, user, input, out)? {
+                                break Answer::Replace(user.id.clone());
+                            }
+                        }
+                        None => writeln!(out, "    No new user is named '{}'.", line)?,
+                    },
+                }
+            }
+        };
+        match answer {
+            Answer::Accept => {
+                confirmed.insert(old_user.id.clone(), (*new_id).clone());
+                counts.accepted += 1;
+            }
+            Answer::AcceptAll => {
+                confirmed.insert(old_user.id.clone(), (*new_id).clone());
+                counts.accepted += 1;
+                accept_all = true;
+            }
+            Answer::Skip => counts.skipped += 1,
+            Answer::Replace(other_id) => {
+                writeln!(
+                    out,
+                    "    '{}' ({}) -> '{}' ({})",
+                    old_user.name,
+                    old_user.id,
+                    name_of(&other_id),
+                    other_id
+                )?;
+                if confirmed.values().any(|id| *id == other_id) {
+                    merges.insert(other_id.clone());
+                }
+                confirmed.insert(old_user.id.clone(), other_id);
+                counts.replaced += 1;
+            }
+        }
+    }
+
+    let unmatched: Vec<&JellyfinUser> = old_users
+        .iter()
+        .filter(|user| !user_id_map.contains_key(&user.id))
+        .collect();
+    if !unmatched.is_empty() && !new_users.is_empty() && !input_ended {
+        writeln!(
+            out,
+            "\nOld users without a mapping. Enter the number of the new user to map them to, or nothing to leave them unmapped:"
+        )?;
+        for (number, user) in new_users.iter().enumerate() {
+            writeln!(out, "  {:>3}. '{}' ({})", number + 1, user.name, user.id)?;
+        }
+        for old_user in unmatched {
+            loop {
+                write!(out, "  '{}' ({}) -> ", old_user.name, old_user.id)?;
+                out.flush()?;
+                let Some(line) = read_answer(input)? else {
+                    writeln!(out)?;
+                    break;
+                };
+                if line.is_empty() {
+                    break;
+                }
+                match line.parse::<usize>() {
+                    Ok(number) if (1..=new_users.len()).contains(&number) => {
+                        let new_user = &new_users[number - 1];
+                        if !confirm_merge(old_users, &confirmed, old_user, new_user, input, out)? {
+                            continue;
+                        }
+                        if confirmed.values().any(|id| *id == new_user.id) {
+                            merges.insert(new_user.id.clone());
+                        }
+                        confirmed.insert(old_user.id.clone(), new_user.id.clone());
+                        counts.assigned += 1;
+                        break;
+                    }
+                    _ => writeln!(
+                        out,
+                        "    Enter a number from 1 to {}, or nothing to leave '{}' unmapped.",
+                        new_users.len(),
+                        old_user.name
+                    )?,
+                }
+            }
+        }
+    }
+
+    writeln!(
+        out,
+        "\nConfirmed user map: {} accepted, {} changed, {} skipped, {} assigned by hand ({} mapped in total).",
+        counts.accepted,
+        counts.replaced,
+        counts.skipped,
+        counts.assigned,
+        confirmed.len()
+    )?;
+    for new_id in &merges {
+        writeln!(out, "Merging into '{}' ({}).", name_of(new_id), new_id)?;
+    }
+    Ok((confirmed, merges))
+}
+
+// Asks before giving `new_user` to a second old user; true when it is free or the merge is wanted
+fn confirm_merge(
+    old_users: &[JellyfinUser],
+    confirmed: &HashMap<String, String>,
+    old_user: &JellyfinUser,
+    new_user: &JellyfinUser,
+    input: &mut impl BufRead,
+    out: &mut impl Write,
+) -> io::Result<bool> {
+    let mut holders: Vec<String> = old_users
+        .iter()
+        .filter(|user| confirmed.get(&user.id) == Some(&new_user.id))
+        .map(|user| format!("'{}'", user.name))
+        .collect();
+    if holders.is_empty() {
+        return Ok(true);
+    }
+    holders.sort();
+    write!(
+        out,
+        "    '{}' ({}) is already the new user of {}. Merge '{}' into it, combining their histories? [y/N]: ",
+        new_user.name,
+        new_user.id,
+        holders.join(", "),
+        old_user.name
+    )?;
+    out.flush()?;
+    let answer = read_answer(input)?.unwrap_or_default().to_lowercase();
+    Ok(answer == "y" || answer == "yes")
+}
+
+// None once the input has ended
+fn read_answer(input: &mut impl BufRead) -> io::Result<Option<String>> {
+    let mut line = String::new();
+    if input.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    Ok(Some(line.trim().to_string()))
+}
+
+// The exact name, or failing that the only one matching ignoring case
+fn find_by_name<'a>(new_users: &'a [JellyfinUser], name: &str) -> Option<&'a JellyfinUser> {
+    if let Some(user) = new_users.iter().find(|user| user.name == name) {
+        return Some(user);
+    }
+    let mut matches = new_users
+        .iter()
+        .filter(|user| user.name.to_lowercase() == name.to_lowercase());
+    match (matches.next(), matches.next()) {
+        (Some(user), None) => Some(user),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn user(id: &str, name: &str) -> JellyfinUser {
+        JellyfinUser {
+            id: id.to_string(),
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn run(answers: &str) -> (HashMap<String, String>, String) {
+        let (confirmed, _, out) = run_with_merges(answers);
+        (confirmed, out)
+    }
+
+    fn run_with_merges(answers: &str) -> (HashMap<String, String>, BTreeSet<String>, String) {
+        let old = [
+            user("old-a", "alice"),
+            user("old-b", "bob"),
+            user("old-c", "carol"),
+            user("old-e", "erin"),
+        ];
+        let new = [
+            user("new-a", "alice"),
+            user("new-b", "bob"),
+            user("new-d", "dave"),
+        ];
+        let proposed = HashMap::from([
+            ("old-a".to_string(), "new-a".to_string()),
+            ("old-b".to_string(), "new-b".to_string()),
+        ]);
+        let mut out = Vec::new();
+        let (confirmed, merges) = review(
+            &old,
+            &new,
+            &proposed,
+            &mut Cursor::new(answers.to_string()),
+            &mut out,
+        )
+        .unwrap();
+        (confirmed, merges, String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn accepts_replaces_skips_and_assigns() {
+        // alice: an unknown name first, then "Dave" (case doesn't matter); bob skipped; carol
+        // gets new user 2 after an invalid number, erin is left unmapped
+        let (confirmed, out) = run("zed\nDave\ns\n9\n2\n\n");
+        assert_eq!(
+            confirmed,
+            HashMap::from([
+                ("old-a".to_string(), "new-d".to_string()),
+                ("old-c".to_string(), "new-b".to_string()),
+            ])
+        );
+        assert!(out.contains("No new user is named 'zed'."), "{}", out);
+        assert!(out.contains("Enter a number from 1 to 3"), "{}", out);
+        assert!(
+            out.contains(
+                "0 accepted, 1 changed, 1 skipped, 1 assigned by hand (2 mapped in total)"
+            ),
+            "{}",
+            out
+        );
+    }
+
+    #[test]
+    fn accept_all_and_end_of_input() {
+        let (confirmed, _) = run("a\n");
+        assert_eq!(confirmed.len(), 2);
+        assert_eq!(confirmed["old-b"], "new-b");
+
+        // Nothing answered at all: like declining everything
+        let (confirmed, out) = run("");
+        assert!(confirmed.is_empty());
+        assert!(out.contains("0 accepted, 0 changed, 2 skipped"), "{}", out);
+    }
+
+    #[test]
+    fn asks_before_merging_into_a_taken_user() {
+        // bob -> alice declined, then bob -> alice merged; carol -> 1 (alice) declined, then left
+        // unmapped; erin -> 3
+        let (confirmed, merges, out) = run_with_merges("\nalice\nn\nalice\ny\n1\n\n\n3\n");
+        assert_eq!(
+            confirmed,
+            HashMap::from([
+                ("old-a".to_string(), "new-a".to_string()),
+                ("old-b".to_string(), "new-a".to_string()),
+                ("old-e".to_string(), "new-d".to_string()),
+            ])
+        );
+        assert_eq!(merges, BTreeSet::from(["new-a".to_string()]));
+        assert!(
+            out.contains("'alice' (new-a) is already the new user of 'alice'. Merge 'bob' into it"),
+            "{}",
+            out
+        );
+        assert!(
+            out.contains("is already the new user of 'alice', 'bob'. Merge 'carol' into it"),
+            "{}",
+            out
+        );
+        assert!(out.contains("Merging into 'alice' (new-a)."), "{}", out);
+
+        // The mapping proposed for bob is to a user nobody else has: no question
+        let (_, merges, out) = run_with_merges("a\n");
+        assert!(merges.is_empty());
+        assert!(!out.contains("Merge"), "{}", out);
+    }
+}
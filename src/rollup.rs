@@ -0,0 +1,252 @@
+// `output_mode = "daily_rollup"`: instead of every timestamped play, only one synthetic row per
+// (UserId, ItemType, day of DateCreated) is written, with the plays counted and PlayDuration summed.
+// Set globally or per user in `[output_mode_overrides]`, keyed by user name on the old instance.
+use crate::retention::parse_date_created;
+use crate::{Config, JellyfinUser, ProcessingStats, TsvRecord};
+use chrono::NaiveDate;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use tracing::{info, warn};
+
+// ClientName of the synthetic rows, so they can be told apart from real plays
+pub const ROLLUP_CLIENT_NAME: &str = "migrated-rollup";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputMode {
+    #[default]
+    Rows,
+    DailyRollup,
+}
+
+// Which old user IDs are rolled up
+#[derive(Debug, Default)]
+pub struct RollupPolicy {
+    default: OutputMode,
+    by_user: HashMap<String, OutputMode>,
+}
+
+impl RollupPolicy {
+    pub fn new(config: &Config, old_users: &[JellyfinUser]) -> RollupPolicy {
+        for name in config.output_mode_overrides.keys() {
+            if !old_users.iter().any(|user| &user.name == name) {
+                warn!(
+                    "[output_mode_overrides] entry '{}' doesn't match any user on the old instance.",
+                    name
+                );
+            }
+        }
+        RollupPolicy {
+            default: config.output_mode,
+            by_user: old_users
+                .iter()
+                .filter_map(|user| {
+                    let mode = config.output_mode_overrides.get(&user.name)?;
+                    Some((user.id.clone(), *mode))
+                })
+                .collect(),
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.default == OutputMode::DailyRollup
+            || self
+                .by_user
+                .values()
+                .any(|mode| *mode == OutputMode::DailyRollup)
+    }
+
+    // Takes the record's UserId before it is mapped
+    pub fn applies_to(&self, old_user_id: &str) -> bool {
+        self.by_user
+            .get(old_user_id)
+            .copied()
+            .unwrap_or(self.default)
+            == OutputMode::DailyRollup
+    }
+}
+
+// Totals for the summary. PlayDuration is summed as integers so it can be compared exactly.
+#[derive(Debug, Default, Clone)]
+pub struct RollupStats {
+    pub records_rolled_up: u64,
+    pub rows_written: u64,
+    pub play_duration_in: u64,
+    pub play_duration_out: u64,
+    // DateCreated or PlayDuration couldn't be parsed, written as a normal row instead
+    pub records_kept_as_rows: u64,
+}
+
+impl RollupStats {
+    pub fn print_summary(&self) {
+        info!(
+            "  Daily rollup: {} original records -> {} rolled-up rows",
+            self.records_rolled_up, self.rows_written
+        );
+        if self.play_duration_in == self.play_duration_out {
+            info!(
+                "    Total PlayDuration preserved exactly: {}",
+                self.play_duration_in
+            );
+        } else {
+            warn!(
+                "    Total PlayDuration differs, {} in the original records but {} in the rolled-up rows",
+                self.play_duration_in, self.play_duration_out
+            );
+        }
+        if self.records_kept_as_rows > 0 {
+            info!(
+                "    Records written as rows because DateCreated or PlayDuration couldn't be parsed: {}",
+                self.records_kept_as_rows
+            );
+        }
+    }
+}
+
+// Collects the rolled-up records of a run. Groups are kept sorted so the rows are always
+// written in the same order.
+#[derive(Debug, Default)]
+pub struct DailyRollup {
+    // (UserId, ItemType, day) -> (plays, summed PlayDuration)
+    groups: BTreeMap<(String, String, NaiveDate), (u64, u64)>,
+}
+
+impl DailyRollup {
+    // Adds an (already mapped) record to its group. Returns false if it can't be rolled up, in
+    // which case the caller writes it as a normal row.
+    pub fn add(&mut self, record: &TsvRecord, stats: &mut ProcessingStats) -> bool {
+        let (Some(created), Ok(duration)) = (
+            parse_date_created(&record.date_created),
+            record.play_duration.trim().parse::<u64>(),
+        ) else {
+            stats.rollup.records_kept_as_rows += 1;
+            return false;
+        };
+        let group = self
+            .groups
+            .entry((
+                record.user_id.clone(),
+                record.item_type.clone(),
+                created.date(),
+            ))
+            .or_insert((0, 0));
+        group.0 += 1;
+        group.1 += duration;
+        stats.rollup.records_rolled_up += 1;
+        stats.rollup.play_duration_in += duration;
+        true
+    }
+
+    // The synthetic rows, one per group, dated midday of their day
+    pub fn into_records(self, stats: &mut ProcessingStats) -> Vec<TsvRecord> {
+        self.groups
+            .into_iter()
+            .map(|((user_id, item_type, day), (plays, duration))| {
+                stats.rollup.rows_written += 1;
+                stats.rollup.play_duration_out += duration;
+                TsvRecord {
+                    date_created: format!("{} 12:00:00.0000000", day.format("%Y-%m-%d")),
+                    user_id,
+                    item_id: String::new(),
+                    item_type,
+                    item_name: format!("{} plays", plays),
+                    playback_method: String::new(),
+                    client_name: ROLLUP_CLIENT_NAME.to_string(),
+                    device_name: String::new(),
+                    play_duration: duration.to_string(),
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(date_created: &str, user_id: &str, item_type: &str, duration: &str) -> TsvRecord {
+        TsvRecord {
+            date_created: date_created.to_string(),
+            user_id: user_id.to_string(),
+            item_id: "item".to_string(),
+            item_type: item_type.to_string(),
+            item_name: "Name".to_string(),
+            playback_method: "DirectPlay".to_string(),
+            client_name: "Web".to_string(),
+            device_name: "TV".to_string(),
+            play_duration: duration.to_string(),
+        }
+    }
+
+    #[test]
+    fn groups_by_user_item_type_and_day() {
+        let mut stats = ProcessingStats::default();
+        let mut rollup = DailyRollup::default();
+        assert!(rollup.add(
+            &record("2024-01-01 08:00:00.0000000", "u", "Movie", "100"),
+            &mut stats
+        ));
+        assert!(rollup.add(
+            &record("2024-01-01 23:59:59", "u", "Movie", "50"),
+            &mut stats
+        ));
+        assert!(rollup.add(
+            &record("2024-01-01 09:00:00", "u", "Episode", "7"),
+            &mut stats
+        ));
+        assert!(rollup.add(
+            &record("2024-01-02 09:00:00", "u", "Movie", "1"),
+            &mut stats
+        ));
+        assert!(rollup.add(
+            &record("2024-01-01 09:00:00", "v", "Movie", "2"),
+            &mut stats
+        ));
+        assert!(!rollup.add(&record("yesterday", "u", "Movie", "3"), &mut stats));
+        assert!(!rollup.add(
+            &record("2024-01-01 09:00:00", "u", "Movie", "1.5"),
+            &mut stats
+        ));
+
+        let rows = rollup.into_records(&mut stats);
+        let summary: Vec<(&str, &str, &str, &str, &str)> = rows
+            .iter()
+            .map(|row| {
+                (
+                    row.date_created.as_str(),
+                    row.user_id.as_str(),
+                    row.item_type.as_str(),
+                    row.item_name.as_str(),
+                    row.play_duration.as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (
+                    "2024-01-01 12:00:00.0000000",
+                    "u",
+                    "Episode",
+                    "1 plays",
+                    "7"
+                ),
+                (
+                    "2024-01-01 12:00:00.0000000",
+                    "u",
+                    "Movie",
+                    "2 plays",
+                    "150"
+                ),
+                ("2024-01-02 12:00:00.0000000", "u", "Movie", "1 plays", "1"),
+                ("2024-01-01 12:00:00.0000000", "v", "Movie", "1 plays", "2"),
+            ]
+        );
+        assert!(rows.iter().all(|row| row.client_name == ROLLUP_CLIENT_NAME));
+        assert_eq!(stats.rollup.records_rolled_up, 5);
+        assert_eq!(stats.rollup.rows_written, 4);
+        assert_eq!(stats.rollup.records_kept_as_rows, 2);
+        assert_eq!(stats.rollup.play_duration_in, 160);
+        assert_eq!(stats.rollup.play_duration_out, 160);
+    }
+}
@@ -0,0 +1,381 @@
+// Checks the destination table against the columns this tool writes and, when enabled,
+// adds any missing ones. Columns are never dropped or renamed.
+use rusqlite::{Connection, ErrorCode, OpenFlags};
+use std::error::Error;
+use std::path::Path;
+use tracing::{info, warn};
+
+// Columns written for each TsvRecord with the declaration used when adding them to an existing table.
+// Added columns need a default so rows that are already in the table stay valid.
+const EXPECTED_COLUMNS: &[(&str, &str)] = &[
+    ("DateCreated", "DATETIME NOT NULL DEFAULT ''"),
+    ("UserId", "TEXT DEFAULT ''"),
+    ("ItemId", "TEXT DEFAULT ''"),
+    ("ItemType", "TEXT DEFAULT ''"),
+    ("ItemName", "TEXT DEFAULT ''"),
+    ("PlaybackMethod", "TEXT DEFAULT ''"),
+    ("ClientName", "TEXT DEFAULT ''"),
+    ("DeviceName", "TEXT DEFAULT ''"),
+    ("PlayDuration", "INT DEFAULT 0"),
+];
+
+// (name, declaration) of the columns written for each record, in output order
+pub fn expected_columns() -> &'static [(&'static str, &'static str)] {
+    EXPECTED_COLUMNS
+}
+
+// Names of the columns written for each record, in output order
+pub fn column_names() -> Vec<&'static str> {
+    EXPECTED_COLUMNS.iter().map(|(name, _)| *name).collect()
+}
+
+// Indexes (into TsvRecord::fields) of the columns compared by the duplicate check: all of them
+// except the ignored ones
+pub fn dedup_columns(ignored: &[String]) -> Result<Vec<usize>, String> {
+    let names = column_names();
+    for name in ignored {
+        if !names.contains(&name.as_str()) {
+            return Err(format!(
+                "Unknown column '{}' in dedup_ignore_columns. Known columns: {}",
+                name,
+                names.join(", ")
+            ));
+        }
+    }
+    let columns: Vec<usize> = (0..names.len())
+        .filter(|&index| !ignored.iter().any(|name| name == names[index]))
+        .collect();
+    if columns.is_empty() {
+        return Err(
+            "dedup_ignore_columns lists every column, which would make every record a duplicate."
+                .to_string(),
+        );
+    }
+    Ok(columns)
+}
+
+// Tables of Jellyfin's own databases (library.db and jellyfin.db). Finding any of them means
+// sqlite_db_path points at the server's database instead of the Playback Reporting one.
+const JELLYFIN_CORE_TABLES: &[&str] = &[
+    "TypedBaseItems",
+    "BaseItems",
+    "UserDatas",
+    "ChapterInfos2",
+    "MediaStreams",
+    "AncestorIds",
+    "ItemValues",
+    "Users",
+    "ActivityLogs",
+    "Devices",
+    "__EFMigrationsHistory",
+];
+
+// A database copied while Jellyfin was writing to it is often only half there. SQLite reports it
+// as "database disk image is malformed" (or "file is not a database"), and for damaged table pages
+// only once they are reached, which without a check would be mid-run.
+pub fn is_malformed(e: &rusqlite::Error) -> bool {
+    matches!(
+        e.sqlite_error_code(),
+        Some(ErrorCode::DatabaseCorrupt | ErrorCode::NotADatabase)
+    )
+}
+
+pub fn malformed_db_error(db_path: &str, detail: &str) -> String {
+    format!(
+        "'{}' is damaged ({}). Nothing was written to it. This usually happens when the database is copied while Jellyfin is running: copy it again with the Jellyfin server stopped, or salvage what is readable with the sqlite3 shell (sqlite3 '{}' .recover | sqlite3 recovered.db) and point sqlite_db_path at the recovered copy.",
+        db_path, detail, db_path
+    )
+}
+
+// The table names, after SQLite's quick integrity check (it reads every page, so damage is found
+// before anything is written rather than when an insert reaches it)
+fn read_tables(db_path: &str) -> Result<Vec<String>, Box<dyn Error + Send + Sync>> {
+    let read = || -> Result<(Vec<String>, Vec<String>), rusqlite::Error> {
+        let conn = Connection::open_with_flags(db_path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        let problems: Vec<String> = conn
+            .prepare("PRAGMA quick_check")?
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        let tables: Vec<String> = conn
+            .prepare("SELECT name FROM sqlite_master WHERE type = 'table'")?
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        Ok((tables, problems))
+    };
+    match read() {
+        Ok((tables, problems)) if problems.iter().all(|problem| problem == "ok") => Ok(tables),
+        Ok((_, problems)) => {
+            let detail = format!("integrity check: {}", problems.join("; "));
+            Err(malformed_db_error(db_path, &detail).into())
+        }
+        Err(e) if is_malformed(&e) => Err(malformed_db_error(db_path, &e.to_string()).into()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Refuses a destination that is damaged, or that looks like one of Jellyfin's core databases
+// unless forced, and warns about an empty file whose name doesn't look like a playback reporting
+// database. Only reads.
+pub fn check_destination_db(
+    db_path: &str,
+    force: bool,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if !Path::new(db_path).is_file() {
+        info!(
+            "Destination database check: '{}' does not exist yet.",
+            db_path
+        );
+        return Ok(());
+    }
+    let tables = read_tables(db_path)?;
+
+    let core_tables: Vec<&str> = JELLYFIN_CORE_TABLES
+        .iter()
+        .copied()
+        .filter(|core| tables.iter().any(|table| table.eq_ignore_ascii_case(core)))
+        .collect();
+    if !core_tables.is_empty() {
+        let message = format!(
+            "'{}' looks like a Jellyfin server database (it contains {}), not the Playback Reporting database (usually playback_reporting.db).",
+            db_path,
+            core_tables.join(", ")
+        );
+        if !force {
+            return Err(format!(
+                "Destination database check failed: {} Refusing to write to it. Check sqlite_db_path or pass --force-unrecognized-db.",
+                message
+            )
+            .into());
+        }
+        warn!(
+            "Destination database check: {} Continuing because of --force-unrecognized-db.",
+            message
+        );
+        return Ok(());
+    }
+
+    let file_name = Path::new(db_path)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    if tables.is_empty() && !file_name.contains("playback") {
+        warn!(
+            "Destination database check: '{}' has no tables and its name doesn't look like a Playback Reporting database (usually playback_reporting.db). Check sqlite_db_path.",
+            db_path
+        );
+    } else {
+        info!(
+            "Destination database check: '{}' has {} table(s) and none of Jellyfin's server tables.",
+            db_path,
+            tables.len()
+        );
+    }
+    Ok(())
+}
+
+fn existing_columns(conn: &Connection, table_name: &str) -> Result<Vec<String>, rusqlite::Error> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table_name))?;
+    let columns = stmt
+        .query_map([], |row| row.get::<_, String>(1))?
+        .collect::<Result<Vec<String>, _>>()?;
+    Ok(columns)
+}
+
+// SQLite's first affinity rule: a declared type containing "INT" gives the column INTEGER affinity.
+// False when the table or column doesn't exist.
+pub fn has_integer_affinity(
+    conn: &Connection,
+    table_name: &str,
+    column: &str,
+) -> Result<bool, rusqlite::Error> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table_name))?;
+    let declared_types = stmt
+        .query_map([], |row| {
+            Ok((row.get::<_, String>(1)?, row.get::<_, String>(2)?))
+        })?
+        .collect::<Result<Vec<(String, String)>, _>>()?;
+    Ok(declared_types.iter().any(|(name, declared_type)| {
+        name.eq_ignore_ascii_case(column) && declared_type.to_uppercase().contains("INT")
+    }))
+}
+
+// Returns the ALTER TABLE statements that were executed (empty if the table already matched).
+// A missing table is left alone so the usual insert error is reported for it.
+pub fn reconcile_table_schema(
+    conn: &Connection,
+    table_name: &str,
+    auto_migrate: bool,
+) -> Result<Vec<String>, Box<dyn Error + Send + Sync>> {
+    let existing = existing_columns(conn, table_name)?;
+    if existing.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    // SQLite column names are case-insensitive
+    let missing: Vec<&(&str, &str)> = EXPECTED_COLUMNS
+        .iter()
+        .filter(|(name, _)| !existing.iter().any(|col| col.eq_ignore_ascii_case(name)))
+        .collect();
+    if missing.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let missing_names: Vec<&str> = missing.iter().map(|(name, _)| *name).collect();
+    if !auto_migrate {
+        return Err(format!(
+            "SQLite table '{}' is missing expected column(s): {}. Set `auto_migrate_schema = true` to add them automatically.",
+            table_name,
+            missing_names.join(", ")
+        )
+        .into());
+    }
+
+    let mut executed = Vec::new();
+    for (name, declaration) in missing {
+        let statement = format!(
+            "ALTER TABLE {} ADD COLUMN {} {}",
+            table_name, name, declaration
+        );
+        conn.execute_batch(&statement)?;
+        executed.push(statement);
+    }
+    Ok(executed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn db_with_tables(dir: &tempfile::TempDir, file_name: &str, tables: &[&str]) -> String {
+        let path = dir.path().join(file_name).display().to_string();
+        let conn = Connection::open(&path).unwrap();
+        for table in tables {
+            conn.execute_batch(&format!("CREATE TABLE {} (Id TEXT);", table))
+                .unwrap();
+        }
+        path
+    }
+
+    #[test]
+    fn jellyfin_server_database_is_refused_unless_forced() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = db_with_tables(&dir, "library.db", &["TypedBaseItems", "PlaybackActivity"]);
+
+        let err = check_destination_db(&path, false).unwrap_err();
+        assert!(err.to_string().contains("TypedBaseItems"));
+        assert!(check_destination_db(&path, true).is_ok());
+    }
+
+    #[test]
+    fn playback_reporting_database_passes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = db_with_tables(&dir, "playback_reporting.db", &["PlaybackActivity"]);
+        assert!(check_destination_db(&path, false).is_ok());
+        // Missing files are left to the normal open/insert errors
+        assert!(check_destination_db(&format!("{}.missing", path), false).is_ok());
+    }
+
+    #[test]
+    fn half_copied_database_is_refused_with_a_recovery_hint() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = db_with_tables(&dir, "playback_reporting.db", &["PlaybackActivity"]);
+        Connection::open(&path)
+            .unwrap()
+            .execute_batch(
+                "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 5000) \
+                 INSERT INTO PlaybackActivity SELECT printf('%0100d', i) FROM n;",
+            )
+            .unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &bytes[..bytes.len() / 2]).unwrap();
+
+        let err = check_destination_db(&path, false).unwrap_err().to_string();
+        assert!(err.contains("is damaged"), "{}", err);
+        assert!(err.contains(".recover"), "{}", err);
+
+        std::fs::write(&path, b"not a database, just some text that is long enough").unwrap();
+        let err = check_destination_db(&path, false).unwrap_err().to_string();
+        assert!(err.contains("is damaged"), "{}", err);
+    }
+
+    // A table without PlaybackMethod, ClientName and PlayDuration, holding one row
+    fn old_table(conn: &Connection) {
+        conn.execute_batch(
+            "CREATE TABLE PlaybackActivity (DateCreated DATETIME NOT NULL, UserId TEXT, ItemId TEXT, \
+             ItemType TEXT, ItemName TEXT, DeviceName TEXT); \
+             INSERT INTO PlaybackActivity VALUES ('2024-01-01 10:00:00', 'u1', 'i1', 'Movie', 'A', 'TV');",
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn missing_columns_are_added_with_their_default() {
+        let conn = Connection::open_in_memory().unwrap();
+        old_table(&conn);
+
+        let executed = reconcile_table_schema(&conn, "PlaybackActivity", true).unwrap();
+        assert_eq!(
+            executed,
+            [
+                "ALTER TABLE PlaybackActivity ADD COLUMN PlaybackMethod TEXT DEFAULT ''",
+                "ALTER TABLE PlaybackActivity ADD COLUMN ClientName TEXT DEFAULT ''",
+                "ALTER TABLE PlaybackActivity ADD COLUMN PlayDuration INT DEFAULT 0",
+            ]
+        );
+        // The existing row gets the defaults
+        let row: (String, String, i64) = conn
+            .query_row(
+                "SELECT PlaybackMethod, ClientName, PlayDuration FROM PlaybackActivity",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!(row, (String::new(), String::new(), 0));
+        // And a second run has nothing left to do
+        assert!(reconcile_table_schema(&conn, "PlaybackActivity", true)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn missing_columns_are_an_error_without_auto_migrate() {
+        let conn = Connection::open_in_memory().unwrap();
+        old_table(&conn);
+
+        let err = reconcile_table_schema(&conn, "PlaybackActivity", false)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains("missing expected column(s): PlaybackMethod, ClientName, PlayDuration."),
+            "{}",
+            err
+        );
+        assert!(err.contains("auto_migrate_schema = true"), "{}", err);
+        assert_eq!(
+            existing_columns(&conn, "PlaybackActivity").unwrap().len(),
+            6
+        );
+    }
+
+    #[test]
+    fn complete_or_missing_tables_are_left_alone() {
+        let conn = Connection::open_in_memory().unwrap();
+        // Column names compare case-insensitively
+        conn.execute_batch(
+            "CREATE TABLE PlaybackActivity (datecreated DATETIME NOT NULL, UserId TEXT, ItemId TEXT, \
+             ItemType TEXT, ItemName TEXT, PlaybackMethod TEXT, ClientName TEXT, DeviceName TEXT, \
+             PLAYDURATION INT);",
+        )
+        .unwrap();
+        for auto_migrate in [false, true] {
+            assert!(
+                reconcile_table_schema(&conn, "PlaybackActivity", auto_migrate)
+                    .unwrap()
+                    .is_empty()
+            );
+            assert!(reconcile_table_schema(&conn, "NoSuchTable", auto_migrate)
+                .unwrap()
+                .is_empty());
+        }
+    }
+}
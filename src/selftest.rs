@@ -0,0 +1,357 @@
+// `self-test` subcommand: runs the whole pipeline on generated data so it can be seen working
+// before it is trusted with a real export. Both user lists are made up in-process instead of being
+// fetched, so no config file, network or Jellyfin instance is needed. The generated input has the
+// awkward cases a real export can have (repeated rows, a user missing on the new instance,
+// fractional and non-numeric PlayDuration values, CRLF line endings), the outputs go to a
+// temporary directory and every expectation is checked and printed as PASS/FAIL.
+use crate::history::RunAudit;
+use crate::retention::RetentionPolicy;
+use crate::rollup::RollupPolicy;
+use crate::{
+    check_stats_invariants, create_user_id_map, process_tsv_file, schema, strict, Config,
+    JellyfinUser, ProcessingStats, RunMode,
+};
+use chrono::{Duration as ChronoDuration, NaiveDate, Utc};
+use config::{Config as AppConfig, File, FileFormat};
+use rusqlite::Connection;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const ROWS: u64 = 3_000;
+// Every this many rows the row is written twice
+const DUPLICATE_EVERY: u64 = 10;
+const TABLE: &str = "PlaybackActivity";
+
+// (ID, name) on each instance. carol has no account on the new instance and dave is new there.
+const OLD_USERS: [(&str, &str); 3] = [
+    ("0a1ce0000000000000000000000000a1", "alice"),
+    ("0b0b00000000000000000000000000b0", "bob"),
+    ("0ca201000000000000000000000000c0", "carol"),
+];
+const NEW_USERS: [(&str, &str); 3] = [
+    ("1a1ce0000000000000000000000000a1", "alice"),
+    ("1b0b00000000000000000000000000b0", "bob"),
+    ("1da0e0000000000000000000000000d0", "dave"),
+];
+
+// What the generated input should produce
+#[derive(Debug, Default)]
+struct Expected {
+    lines: u64,
+    duplicates: u64,
+    mapped: u64,           // Lines of users that exist on both instances
+    unmapped: u64,         // Lines of carol
+    rounded: u64,          // Lines with a fractional PlayDuration
+    non_numeric_rows: u64, // Distinct rows whose PlayDuration isn't a number
+}
+
+struct Check {
+    name: String,
+    passed: bool,
+    detail: String,
+}
+
+fn check(checks: &mut Vec<Check>, name: &str, expected: u64, actual: u64) {
+    checks.push(Check {
+        name: name.to_string(),
+        passed: expected == actual,
+        detail: format!("expected {}, got {}", expected, actual),
+    });
+}
+
+fn users(users: &[(&str, &str)]) -> Vec<JellyfinUser> {
+    users
+        .iter()
+        .map(|(id, name)| JellyfinUser {
+            id: id.to_string(),
+            name: name.to_string(),
+            ..Default::default()
+        })
+        .collect()
+}
+
+fn generate_input(path: &Path) -> Result<Expected, Box<dyn Error + Send + Sync>> {
+    let start = NaiveDate::from_ymd_opt(2024, 1, 1)
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+        .expect("valid date");
+    let mut expected = Expected::default();
+    let mut input = String::new();
+    for i in 0..ROWS {
+        let (user_id, name) = OLD_USERS[(i % 3) as usize];
+        let play_duration = match i {
+            _ if i % 100 == 50 => "90.5".to_string(),
+            _ if i % 250 == 125 => "n/a".to_string(),
+            _ => (i % 500 + 1).to_string(),
+        };
+        let line = format!(
+            "{}\t{}\titem{:04}\tMovie\tItem {}\tDirectPlay\tSelf Test\tDevice {}\t{}{}",
+            (start + ChronoDuration::minutes(i as i64)).format("%Y-%m-%d %H:%M:%S"),
+            user_id,
+            i % 50,
+            i % 50,
+            i % 4,
+            play_duration,
+            if i % 7 == 0 { "\r\n" } else { "\n" }
+        );
+        let copies = if i % DUPLICATE_EVERY == 0 { 2 } else { 1 };
+        for _ in 0..copies {
+            input.push_str(&line);
+            expected.lines += 1;
+            if name == "carol" {
+                expected.unmapped += 1;
+            } else {
+                expected.mapped += 1;
+            }
+            if play_duration.contains('.') {
+                expected.rounded += 1;
+            }
+        }
+        expected.duplicates += copies - 1;
+        if play_duration == "n/a" {
+            expected.non_numeric_rows += 1;
+        }
+    }
+    fs::write(path, input)?;
+    Ok(expected)
+}
+
+fn config_for(dir: &Path) -> Result<Config, Box<dyn Error + Send + Sync>> {
+    // Literal strings so Windows paths don't need escaping
+    let toml = format!(
+        "input_tsv_file_path = '{}'\noutput_tsv_file_path = '{}'\nsqlite_db_path = '{}'\n\
+        [instance_old]\nbase_url = 'http://self-test-old'\napi_token = 'unused'\n\
+        [instance_new]\nbase_url = 'http://self-test-new'\napi_token = 'unused'\n",
+        dir.join("input.tsv").display(),
+        dir.join("output.tsv").display(),
+        dir.join("output.db").display()
+    );
+    Ok(strict::deserialize_config(
+        AppConfig::builder()
+            .add_source(File::from_str(&toml, FileFormat::Toml))
+            .build()?,
+    )?)
+}
+
+fn create_table(db_path: &Path) -> Result<(), rusqlite::Error> {
+    let columns: Vec<String> = schema::expected_columns()
+        .iter()
+        .map(|(name, declaration)| format!("{} {}", name, declaration))
+        .collect();
+    Connection::open(db_path)?.execute_batch(&format!(
+        "CREATE TABLE {} ({});",
+        TABLE,
+        columns.join(", ")
+    ))
+}
+
+fn count(conn: &Connection, query: &str) -> Result<u64, rusqlite::Error> {
+    conn.query_row(query, [], |row| row.get::<_, i64>(0))
+        .map(|n| n as u64)
+}
+
+fn verify(
+    config: &Config,
+    dir: &Path,
+    expected: &Expected,
+    stats: &ProcessingStats,
+) -> Result<Vec<Check>, Box<dyn Error + Send + Sync>> {
+    let mut checks = Vec::new();
+    let unique = expected.lines - expected.duplicates;
+    check(
+        &mut checks,
+        "every input line was read",
+        expected.lines,
+        stats.records_processed,
+    );
+    check(
+        &mut checks,
+        "records of mapped users got their new UserId",
+        expected.mapped,
+        stats.records_changed,
+    );
+    check(
+        &mut checks,
+        "records of the unmapped user were left unchanged",
+        expected.unmapped,
+        stats.records_unchanged,
+    );
+    check(
+        &mut checks,
+        "fractional PlayDuration values were rounded",
+        expected.rounded,
+        stats.play_duration.rounded,
+    );
+    check(
+        &mut checks,
+        "repeated rows were inserted into SQLite once",
+        unique,
+        stats.records_inserted_sqlite as u64,
+    );
+    check(
+        &mut checks,
+        "repeated rows were skipped as duplicates",
+        expected.duplicates,
+        stats.records_skipped_sqlite as u64,
+    );
+
+    let tsv = fs::read_to_string(dir.join("output.tsv"))?;
+    let rows: Vec<Vec<&str>> = tsv.lines().map(|line| line.split('\t').collect()).collect();
+    check(
+        &mut checks,
+        "the TSV output has every record",
+        expected.lines,
+        rows.len() as u64,
+    );
+    check(
+        &mut checks,
+        "no TSV value kept a CR from CRLF input",
+        0,
+        tsv.matches('\r').count() as u64,
+    );
+    let old_mapped_ids = [OLD_USERS[0].0, OLD_USERS[1].0];
+    check(
+        &mut checks,
+        "no TSV row kept the old ID of a mapped user",
+        0,
+        rows.iter()
+            .filter(|row| old_mapped_ids.contains(&row[1]))
+            .count() as u64,
+    );
+    check(
+        &mut checks,
+        "TSV rows of the unmapped user kept their ID",
+        expected.unmapped,
+        rows.iter().filter(|row| row[1] == OLD_USERS[2].0).count() as u64,
+    );
+    check(
+        &mut checks,
+        "no TSV PlayDuration has a fractional part",
+        0,
+        rows.iter().filter(|row| row[8].contains('.')).count() as u64,
+    );
+
+    let conn = Connection::open(dir.join("output.db"))?;
+    check(
+        &mut checks,
+        "the SQLite table has every distinct record",
+        unique,
+        count(&conn, &format!("SELECT COUNT(*) FROM {}", TABLE))?,
+    );
+    check(
+        &mut checks,
+        "no SQLite row kept the old ID of a mapped user",
+        0,
+        count(
+            &conn,
+            &format!(
+                "SELECT COUNT(*) FROM {} WHERE UserId IN ('{}', '{}')",
+                TABLE, old_mapped_ids[0], old_mapped_ids[1]
+            ),
+        )?,
+    );
+    check(
+        &mut checks,
+        "only non-numeric PlayDuration values are stored as text in SQLite",
+        expected.non_numeric_rows,
+        count(
+            &conn,
+            &format!(
+                "SELECT COUNT(*) FROM {} WHERE typeof(PlayDuration) != 'integer'",
+                TABLE
+            ),
+        )?,
+    );
+    check(
+        &mut checks,
+        "the run was recorded in the migration history",
+        1,
+        count(&conn, "SELECT COUNT(*) FROM jpm_migrations")?,
+    );
+    let violations = check_stats_invariants(config, stats);
+    checks.push(Check {
+        name: "the run's counters add up".to_string(),
+        passed: violations.is_empty(),
+        detail: violations.join("; "),
+    });
+    Ok(checks)
+}
+
+async fn run_in(dir: &Path) -> Result<Vec<Check>, Box<dyn Error + Send + Sync>> {
+    let expected = generate_input(&dir.join("input.tsv"))?;
+    create_table(&dir.join("output.db"))?;
+    let mut config = config_for(dir)?;
+    let (old_users, new_users) = (users(&OLD_USERS), users(&NEW_USERS));
+    let user_id_map = create_user_id_map(&mut config, &old_users, &new_users, false)?;
+    let retention = RetentionPolicy::new(&config, &old_users, &user_id_map, Utc::now());
+    let rollup = RollupPolicy::new(&config, &old_users);
+    let audit = RunAudit::new(&config, &old_users, &new_users, &user_id_map);
+    let stats = process_tsv_file(
+        &config,
+        &user_id_map,
+        &retention,
+        &rollup,
+        Some(&audit),
+        RunMode::Normal,
+        false,
+    )
+    .await?;
+    verify(&config, dir, &expected, &stats)
+}
+
+// Returns an error when any check failed, so the exit code tells too
+pub async fn run_self_test(keep_artifacts: bool) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let dir: PathBuf = std::env::temp_dir().join(format!(
+        "jellyfin_pr_migration-self-test-{}",
+        std::process::id()
+    ));
+    if dir.exists() {
+        fs::remove_dir_all(&dir)?;
+    }
+    fs::create_dir_all(&dir)?;
+    println!(
+        "Self-test: generated data and outputs go to {}",
+        dir.display()
+    );
+
+    let result = run_in(&dir).await;
+    if keep_artifacts {
+        println!("\nSelf-test files kept in {}", dir.display());
+    } else if let Err(e) = fs::remove_dir_all(&dir) {
+        eprintln!("Failed to remove {}: {}", dir.display(), e);
+    }
+
+    let checks = result?;
+    println!("\nSelf-test results:");
+    for check in &checks {
+        if check.passed {
+            println!("  PASS  {}", check.name);
+        } else {
+            println!("  FAIL  {} ({})", check.name, check.detail);
+        }
+    }
+    let failed = checks.iter().filter(|check| !check.passed).count();
+    if failed > 0 {
+        return Err(format!("Self-test failed: {} of {} checks", failed, checks.len()).into());
+    }
+    println!("All {} checks passed.", checks.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn self_test_passes() {
+        let dir = tempfile::tempdir().unwrap();
+        let failed: Vec<String> = run_in(dir.path())
+            .await
+            .unwrap()
+            .into_iter()
+            .filter(|check| !check.passed)
+            .map(|check| format!("{} ({})", check.name, check.detail))
+            .collect();
+        assert!(failed.is_empty(), "{:?}", failed);
+    }
+}
@@ -0,0 +1,188 @@
+// Opt-in session merge (`session_merge_window_secs`): some servers logged one play as several rows
+// seconds apart, which doubles the watch time once migrated. Rows sharing UserId, ItemId and
+// DeviceName whose DateCreated is within the window of the previous row of the same session are
+// merged into one, keeping the earliest row and the max (or sum) of their PlayDuration.
+// Needs every record sorted by date, so the whole input is buffered and written in date order.
+use crate::retention::parse_date_created;
+use crate::{ProcessingStats, TsvRecord};
+use chrono::{Duration, NaiveDateTime};
+use serde::Deserialize;
+use std::collections::HashMap;
+use tracing::info;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MergeDuration {
+    #[default]
+    Max, // For rows that log the same playback more than once
+    Sum, // For rows that each log part of the playback
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct SessionMergeStats {
+    pub rows_merged: u64, // Rows folded into an earlier row, never reach an output
+    pub sessions_merged: u64,
+    pub reclaimed_seconds: u64, // PlayDuration no longer counted twice
+    // DateCreated or PlayDuration couldn't be parsed, written unmerged after the sorted rows
+    pub rows_unmergeable: u64,
+}
+
+impl SessionMergeStats {
+    pub fn print_summary(&self) {
+        info!(
+            "  Session merge: {} rows merged into {} sessions, {} duplicate seconds reclaimed",
+            self.rows_merged, self.sessions_merged, self.reclaimed_seconds
+        );
+        if self.rows_unmergeable > 0 {
+            info!(
+                "    Rows left unmerged because DateCreated or PlayDuration couldn't be parsed: {}",
+                self.rows_unmergeable
+            );
+        }
+    }
+}
+
+struct Session<T> {
+    record: TsvRecord,
+    tag: T,
+    duration: u64,
+    input_duration: u64, // Sum over every row merged into this one
+    rows: u64,
+}
+
+// Buffers the records of a run. Each record carries a tag the caller gets back with it.
+pub struct SessionMerger<T> {
+    window: Duration,
+    duration_mode: MergeDuration,
+    records: Vec<(NaiveDateTime, u64, TsvRecord, T)>,
+    unmergeable: Vec<(TsvRecord, T)>,
+}
+
+impl<T> SessionMerger<T> {
+    pub fn new(window_secs: u64, duration_mode: MergeDuration) -> SessionMerger<T> {
+        SessionMerger {
+            window: Duration::seconds(window_secs as i64),
+            duration_mode,
+            records: Vec::new(),
+            unmergeable: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, record: TsvRecord, tag: T) {
+        match (
+            parse_date_created(&record.date_created),
+            record.play_duration.trim().parse::<u64>(),
+        ) {
+            (Some(created), Ok(duration)) => self.records.push((created, duration, record, tag)),
+            _ => self.unmergeable.push((record, tag)),
+        }
+    }
+
+    // The merged records sorted by DateCreated, followed by the unmergeable ones in input order
+    pub fn finish(mut self, stats: &mut ProcessingStats) -> Vec<(TsvRecord, T)> {
+        // Stable, so rows with the same DateCreated keep their input order
+        self.records.sort_by_key(|(created, ..)| *created);
+        let mut sessions: Vec<Session<T>> = Vec::new();
+        // (UserId, ItemId, DeviceName) -> (index of the open session, DateCreated of its last row)
+        let mut open: HashMap<(String, String, String), (usize, NaiveDateTime)> = HashMap::new();
+        for (created, duration, record, tag) in self.records {
+            let key = (
+                record.user_id.clone(),
+                record.item_id.clone(),
+                record.device_name.clone(),
+            );
+            match open.get_mut(&key) {
+                Some((index, last)) if created - *last <= self.window => {
+                    let session = &mut sessions[*index];
+                    session.duration = match self.duration_mode {
+                        MergeDuration::Max => session.duration.max(duration),
+                        MergeDuration::Sum => session.duration + duration,
+                    };
+                    session.input_duration += duration;
+                    session.rows += 1;
+                    *last = created;
+                    stats.session_merge.rows_merged += 1;
+                }
+                _ => {
+                    open.insert(key, (sessions.len(), created));
+                    sessions.push(Session {
+                        record,
+                        tag,
+                        duration,
+                        input_duration: duration,
+                        rows: 1,
+                    });
+                }
+            }
+        }
+
+        stats.session_merge.rows_unmergeable += self.unmergeable.len() as u64;
+        let mut merged = Vec::with_capacity(sessions.len() + self.unmergeable.len());
+        for mut session in sessions {
+            if session.rows > 1 {
+                stats.session_merge.sessions_merged += 1;
+                stats.session_merge.reclaimed_seconds += session.input_duration - session.duration;
+                session.record.play_duration = session.duration.to_string();
+            }
+            merged.push((session.record, session.tag));
+        }
+        merged.extend(self.unmergeable);
+        merged
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INPUT: &str = include_str!("../tests/golden/session_merge/input.tsv");
+
+    fn merge(duration_mode: MergeDuration) -> (String, ProcessingStats) {
+        let mut stats = ProcessingStats::default();
+        let mut merger = SessionMerger::new(300, duration_mode);
+        let mut reader = csv::ReaderBuilder::new()
+            .delimiter(b'\t')
+            .has_headers(false)
+            .from_reader(INPUT.as_bytes());
+        for (line, record) in reader.deserialize::<TsvRecord>().enumerate() {
+            merger.push(record.unwrap(), line + 1);
+        }
+        let output = merger
+            .finish(&mut stats)
+            .into_iter()
+            .map(|(record, line)| format!("{}\t(line {})\n", record.fields().join("\t"), line))
+            .collect();
+        (output, stats)
+    }
+
+    // Regenerate with UPDATE_GOLDEN=1 cargo test, then review the diff
+    fn check_golden(name: &str, actual: &str) {
+        let path = format!(
+            "{}/tests/golden/session_merge/{}",
+            env!("CARGO_MANIFEST_DIR"),
+            name
+        );
+        if std::env::var_os("UPDATE_GOLDEN").is_some() {
+            std::fs::write(&path, actual).unwrap();
+        }
+        assert_eq!(actual, std::fs::read_to_string(&path).unwrap(), "{}", path);
+    }
+
+    #[test]
+    fn keeps_the_max_play_duration() {
+        let (output, stats) = merge(MergeDuration::Max);
+        check_golden("expected_max.tsv", &output);
+        assert_eq!(stats.session_merge.rows_merged, 3);
+        assert_eq!(stats.session_merge.sessions_merged, 2);
+        assert_eq!(stats.session_merge.reclaimed_seconds, 1500);
+        assert_eq!(stats.session_merge.rows_unmergeable, 1);
+    }
+
+    #[test]
+    fn sums_the_play_duration() {
+        let (output, stats) = merge(MergeDuration::Sum);
+        check_golden("expected_sum.tsv", &output);
+        assert_eq!(stats.session_merge.rows_merged, 3);
+        assert_eq!(stats.session_merge.reclaimed_seconds, 0);
+    }
+}
@@ -0,0 +1,229 @@
+// Simulated SQLite inserts for `--dry-run-with-db`. The destination is only ever opened with
+// SQLITE_OPEN_READ_ONLY, its existing dedup keys are loaded into memory, and each record is
+// "inserted" into that set instead of the database, so the inserted/skipped predictions match what
+// check_and_insert_record_into_db would do in a real run.
+use crate::keyset::DedupKeySet;
+use crate::sinks::{self, OutputSink, SinkStats, WriteOutcome};
+use crate::{schema, TsvRecord};
+use rusqlite::{Connection, OpenFlags};
+use std::error::Error;
+use std::path::Path;
+use std::time::Duration;
+
+pub struct ShadowDb {
+    path: String,
+    table_name: String,
+    dedup_columns: Vec<usize>,
+    conn: Connection, // Read-only, only queried by --explain once the keys are loaded
+    keys: DedupKeySet,
+    stats: SinkStats,
+}
+
+impl ShadowDb {
+    pub fn load(
+        db_path: &str,
+        table_name: &str,
+        auto_migrate: bool,
+        dedup_columns: Vec<usize>,
+    ) -> Result<ShadowDb, Box<dyn Error + Send + Sync>> {
+        if !Path::new(db_path).is_file() {
+            return Err(format!(
+                "SQLite database '{}' does not exist. A real run would create an empty file and fail because table '{}' is missing.",
+                db_path, table_name
+            )
+            .into());
+        }
+        // Never anything but read-only, this connection must not be able to change the destination
+        let conn = Connection::open_with_flags(
+            db_path,
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )?;
+
+        let table_exists: bool = conn.query_row(
+            "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1 COLLATE NOCASE)",
+            [table_name],
+            |row| row.get(0),
+        )?;
+        if !table_exists {
+            return Err(format!(
+                "SQLite table '{}' does not exist in '{}'. A real run would fail when inserting.",
+                table_name, db_path
+            )
+            .into());
+        }
+        if !auto_migrate {
+            // Same error a real run reports for missing columns (read-only, nothing is altered)
+            schema::reconcile_table_schema(&conn, table_name, false)?;
+        }
+
+        Ok(ShadowDb {
+            path: db_path.to_string(),
+            table_name: table_name.to_string(),
+            keys: DedupKeySet::load(&conn, table_name, dedup_columns.clone())?,
+            dedup_columns,
+            conn,
+            stats: SinkStats::default(),
+        })
+    }
+
+    pub fn existing_rows(&self) -> usize {
+        self.keys.len()
+    }
+}
+
+impl OutputSink for ShadowDb {
+    fn name(&self) -> String {
+        format!("SQLite '{}' (simulated, read-only)", self.path)
+    }
+
+    // Inserted if a real run would insert the record, Skipped if it would be a duplicate
+    fn write(&mut self, record: &TsvRecord) -> Result<WriteOutcome, Box<dyn Error + Send + Sync>> {
+        let outcome = if self.keys.insert(record) {
+            self.stats.written += 1;
+            WriteOutcome::Inserted
+        } else {
+            self.stats.skipped += 1;
+            WriteOutcome::Skipped
+        };
+        Ok(outcome)
+    }
+
+    fn finalize(&mut self) -> Result<SinkStats, Box<dyn Error + Send + Sync>> {
+        Ok(self.stats)
+    }
+
+    fn preload_duration(&self) -> Option<Duration> {
+        Some(self.keys.load_duration())
+    }
+
+    // Records "inserted" earlier in the run are only in the key set, not in the database
+    fn explain_write(&self, record: &TsvRecord) -> Result<String, Box<dyn Error + Send + Sync>> {
+        let columns = sinks::dedup_column_list(&self.dedup_columns);
+        let matching =
+            sinks::find_matching_row(&self.conn, &self.table_name, record, &self.dedup_columns)?;
+        Ok(match matching {
+            Some(row) => format!(
+                "duplicate of existing row {} (compared on {}), would be skipped",
+                row, columns
+            ),
+            None if self.keys.contains(record) => format!(
+                "duplicate of a record earlier in the input (compared on {}), would be skipped",
+                columns
+            ),
+            None => format!("no existing row matches on {}, would be inserted", columns),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::retention::RetentionPolicy;
+    use crate::rollup::RollupPolicy;
+    use crate::{config_from_toml, process_tsv_file, Config, RunMode};
+    use rusqlite::Connection;
+    use std::collections::HashMap;
+    use std::fs;
+
+    fn row_count(db_path: &str) -> i64 {
+        Connection::open(db_path)
+            .unwrap()
+            .query_row("SELECT COUNT(*) FROM PlaybackActivity", [], |row| {
+                row.get(0)
+            })
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn predictions_match_a_real_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("playback.db").display().to_string();
+        let input_path = dir.path().join("input.tsv").display().to_string();
+
+        let conn = Connection::open(&db_path).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE PlaybackActivity (DateCreated DATETIME NOT NULL, UserId TEXT, ItemId TEXT, \
+            ItemType TEXT, ItemName TEXT, PlaybackMethod TEXT, ClientName TEXT, DeviceName TEXT, PlayDuration INT);
+            INSERT INTO PlaybackActivity VALUES ('2024-01-01 10:00:00', 'new-a', 'i1', 'Movie', 'One', 'DirectPlay', 'Web', 'TV', 100);
+            INSERT INTO PlaybackActivity VALUES ('2024-01-02 10:00:00', 'old-a', 'i2', 'Movie', 'Two', 'DirectPlay', 'Web', 'TV', 200);
+            INSERT INTO PlaybackActivity VALUES ('2024-01-03 10:00:00', 'new-a', 'i3', 'Movie', 'Three', 'DirectPlay', 'Web', 'TV', NULL);",
+        )
+        .unwrap();
+        drop(conn);
+
+        fs::write(
+            &input_path,
+            [
+                // Already in the DB once mapped (and with PlayDuration written differently)
+                "2024-01-01 10:00:00\told-a\ti1\tMovie\tOne\tDirectPlay\tWeb\tTV\t100.0",
+                // Not a duplicate: the DB row has the unmapped user
+                "2024-01-02 10:00:00\told-a\ti2\tMovie\tTwo\tDirectPlay\tWeb\tTV\t200",
+                // NULL in the stored row never matches
+                "2024-01-03 10:00:00\told-a\ti3\tMovie\tThree\tDirectPlay\tWeb\tTV\t0",
+                // New, then repeated within the same input
+                "2024-01-04 10:00:00\told-a\ti4\tEpisode\tFour\tTranscode\tAndroid\tPhone\t400",
+                "2024-01-04 10:00:00\told-a\ti4\tEpisode\tFour\tTranscode\tAndroid\tPhone\t400",
+            ]
+            .join("\n"),
+        )
+        .unwrap();
+
+        let config: Config = config_from_toml(&format!(
+            r#"
+            input_tsv_file_path = "{}"
+            sqlite_db_path = "{}"
+            [instance_old]
+            base_url = "http://old"
+            api_token = "x"
+            [instance_new]
+            base_url = "http://new"
+            api_token = "y"
+            "#,
+            input_path, db_path
+        ));
+        let user_id_map: HashMap<String, String> =
+            HashMap::from([("old-a".to_string(), "new-a".to_string())]);
+
+        let predicted = process_tsv_file(
+            &config,
+            &user_id_map,
+            &RetentionPolicy::default(),
+            &RollupPolicy::default(),
+            None,
+            RunMode::DryRunWithDb,
+            false,
+        )
+        .await
+        .unwrap();
+        assert_eq!(row_count(&db_path), 3, "dry run must not write");
+
+        let actual = process_tsv_file(
+            &config,
+            &user_id_map,
+            &RetentionPolicy::default(),
+            &RollupPolicy::default(),
+            None,
+            RunMode::Normal,
+            false,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            (
+                predicted.records_inserted_sqlite,
+                predicted.records_skipped_sqlite
+            ),
+            (
+                actual.records_inserted_sqlite,
+                actual.records_skipped_sqlite
+            )
+        );
+        assert_eq!(
+            (
+                actual.records_inserted_sqlite,
+                actual.records_skipped_sqlite
+            ),
+            (3, 2)
+        );
+        assert_eq!(row_count(&db_path), 6);
+    }
+}
@@ -0,0 +1,1135 @@
+// Output sinks. Every configured output (TSV file, SQLite table, or the read-only SQLite
+// simulation of `--dry-run-with-db`) implements OutputSink, and the processing loops just hand each
+// record to every sink in turn.
+use crate::columnar::{self, OutputFormat};
+use crate::display::{format_bytes, format_count, truncate_display, MAX_RECORD_DISPLAY_CHARS};
+use crate::history::RunAudit;
+use crate::keyset::{self, DedupKeySet};
+use crate::{
+    check_and_insert_record_into_db, insert_record_into_db, output, schema, shadow, wal, Config,
+    ProcessingStats, RunMode, TsvRecord,
+};
+use rusqlite::{params_from_iter, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs;
+use std::time::Duration;
+use tracing::{debug, error, info, warn};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteOutcome {
+    Written,  // Appended unconditionally (TSV)
+    Inserted, // Passed the duplicate check and was inserted (or would be, when simulating)
+    Skipped,  // Already present, nothing written
+}
+
+// Counts since the last begin() (or since the sink was opened)
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SinkStats {
+    pub written: u64,
+    pub skipped: u64,
+}
+
+impl SinkStats {
+    pub(crate) fn count(&mut self, outcome: WriteOutcome) {
+        match outcome {
+            WriteOutcome::Written | WriteOutcome::Inserted => self.written += 1,
+            WriteOutcome::Skipped => self.skipped += 1,
+        }
+    }
+}
+
+// What happens to the run when a sink fails to begin, write or finalize
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum OnSinkFailure {
+    #[default]
+    Abort, // Stop the run and return the error
+    Disable, // Drop this sink and carry on with the others
+}
+
+// `[sink_failure_policy]`, one entry per kind of output
+#[derive(Debug, Deserialize, Default)]
+pub struct SinkFailurePolicy {
+    #[serde(default)]
+    pub tsv: OnSinkFailure,
+    #[serde(default)]
+    pub sqlite: OnSinkFailure,
+}
+
+// A file a sink writes to, for the output manifest
+pub struct OutputFile {
+    pub kind: &'static str, // "tsv", "parquet" or "sqlite"
+    pub path: String,
+}
+
+// What finalize() reports for each sink that is still active
+pub struct FinalizedSink {
+    pub name: String,
+    pub output_file: Option<OutputFile>,
+    pub stats: SinkStats,
+}
+
+// Size of a destination at one point in time
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Measurement {
+    pub label: String,     // e.g. "PlaybackActivity in 'playback.db'"
+    pub rows: Option<u64>, // Only known for SQLite tables
+    pub bytes: u64,
+}
+
+// A destination's size before the first write and after the final commit of the run
+#[derive(Debug, Clone, Serialize)]
+pub struct DestinationChange {
+    pub label: String,
+    pub rows_before: Option<u64>,
+    pub rows_after: Option<u64>,
+    pub bytes_before: u64,
+    pub bytes_after: Option<u64>, // None until the sink has been finalized
+}
+
+impl DestinationChange {
+    // e.g. "PlaybackActivity in 'playback.db': 412,331 → 1,018,552 rows (+606,221), file 58 MB → 141 MB"
+    pub fn describe(&self) -> Option<String> {
+        let bytes_after = self.bytes_after?;
+        let rows = match (self.rows_before, self.rows_after) {
+            (Some(before), Some(after)) => format!(
+                "{} → {} rows ({}{}), ",
+                format_count(before),
+                format_count(after),
+                if after >= before { "+" } else { "-" },
+                format_count(after.abs_diff(before))
+            ),
+            _ => String::new(),
+        };
+        Some(format!(
+            "{}: {}file {} → {}",
+            self.label,
+            rows,
+            format_bytes(self.bytes_before),
+            format_bytes(bytes_after)
+        ))
+    }
+}
+
+// Measuring is only for the summary, so a failure is printed and doesn't count as a sink failure
+fn measure_or_warn(sink: &mut dyn OutputSink) -> Option<Measurement> {
+    sink.measure().unwrap_or_else(|e| {
+        let name = sink.name();
+        warn!("Couldn't measure the size of {}: {}", name, e);
+        None
+    })
+}
+
+pub(crate) fn file_size(path: &str) -> u64 {
+    fs::metadata(path)
+        .map(|metadata| metadata.len())
+        .unwrap_or(0)
+}
+
+pub trait OutputSink {
+    // Used in log lines, e.g. "SQLite 'playback.db'"
+    fn name(&self) -> String;
+    // Starts a unit of work (the whole run, or one batch in watch mode)
+    fn begin(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        Ok(())
+    }
+    // An error leaves the sink rolled back, the caller should stop and propagate it
+    fn write(&mut self, record: &TsvRecord) -> Result<WriteOutcome, Box<dyn Error + Send + Sync>>;
+    // Makes everything since begin() durable (flush/commit)
+    fn finalize(&mut self) -> Result<SinkStats, Box<dyn Error + Send + Sync>>;
+    // Time spent loading existing dedup keys when the sink was opened
+    fn preload_duration(&self) -> Option<Duration> {
+        None
+    }
+    // The file written to, None for sinks that don't write anything
+    fn output_file(&self) -> Option<OutputFile> {
+        None
+    }
+    // Current size of the destination, None for sinks that don't write anything
+    fn measure(&mut self) -> Result<Option<Measurement>, Box<dyn Error + Send + Sync>> {
+        Ok(None)
+    }
+    // Abandons the unit of work started by begin(). Sinks that can't undo writes keep them.
+    fn rollback(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        Ok(())
+    }
+    // Once after the last finalize() of the run, for housekeeping that only reports problems
+    fn end_of_run(&mut self) {}
+    // For --explain, called before write(): what write() is about to do with the record and why
+    fn explain_write(&self, _record: &TsvRecord) -> Result<String, Box<dyn Error + Send + Sync>> {
+        Ok("written".to_string())
+    }
+}
+
+pub struct TsvSink {
+    path: String,
+    writer: output::TsvWriter,
+    stats: SinkStats,
+}
+
+impl TsvSink {
+    pub fn open(config: &Config, path: &str) -> Result<TsvSink, Box<dyn Error + Send + Sync>> {
+        Ok(TsvSink {
+            path: path.to_string(),
+            writer: output::open_tsv_writer(config, path)?,
+            stats: SinkStats::default(),
+        })
+    }
+}
+
+impl OutputSink for TsvSink {
+    fn name(&self) -> String {
+        format!("TSV '{}'", self.path)
+    }
+
+    fn output_file(&self) -> Option<OutputFile> {
+        Some(OutputFile {
+            kind: "tsv",
+            path: self.path.clone(),
+        })
+    }
+
+    fn begin(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.stats = SinkStats::default();
+        Ok(())
+    }
+
+    fn write(&mut self, record: &TsvRecord) -> Result<WriteOutcome, Box<dyn Error + Send + Sync>> {
+        self.writer.write(record)?;
+        self.stats.count(WriteOutcome::Written);
+        Ok(WriteOutcome::Written)
+    }
+
+    fn finalize(&mut self) -> Result<SinkStats, Box<dyn Error + Send + Sync>> {
+        self.writer.flush()?; // Ensure all TSV data is written
+        Ok(self.stats)
+    }
+
+    // Flushed first so buffered lines are counted
+    fn measure(&mut self) -> Result<Option<Measurement>, Box<dyn Error + Send + Sync>> {
+        self.writer.flush()?;
+        Ok(Some(Measurement {
+            label: self.name(),
+            rows: None,
+            bytes: file_size(&self.path),
+        }))
+    }
+
+    // Lines can't be taken back, flush so the file at least ends on a complete line
+    fn rollback(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+// Loads the existing dedup keys unless they are estimated to need more than max_preload_memory_mb
+fn preload_keys(
+    config: &Config,
+    conn: &Connection,
+    table_name: &str,
+    dedup_columns: &[usize],
+) -> Result<Option<DedupKeySet>, Box<dyn Error + Send + Sync>> {
+    let estimate_bytes = keyset::estimate_preload_bytes(conn, table_name, dedup_columns)?;
+    let estimate_mb = estimate_bytes.div_ceil(1024 * 1024);
+    if estimate_bytes > config.max_preload_memory_mb * 1024 * 1024 {
+        warn!(
+            "Preloading the dedup keys of '{}' would need about {} MB (max_preload_memory_mb = {}). Falling back to checking each record with a query.",
+            table_name, estimate_mb, config.max_preload_memory_mb
+        );
+        return Ok(None);
+    }
+    let keys = DedupKeySet::load(conn, table_name, dedup_columns.to_vec())?;
+    info!(
+        "Preloaded {} existing dedup keys from '{}' in {:.1}s (estimated {} MB).",
+        keys.len(),
+        table_name,
+        keys.load_duration().as_secs_f64(),
+        estimate_mb
+    );
+    Ok(Some(keys))
+}
+
+// Inserts into the destination table inside one transaction per begin()/finalize()
+pub struct SqliteSink {
+    path: String,
+    table_name: String,
+    dedup_columns: Vec<usize>,
+    preloaded_keys: Option<DedupKeySet>, // Replaces the per-record existence query when set
+    conn: Connection,
+    stats: SinkStats,
+    audit: Option<RunAudit>, // Written to the history tables in the same transaction as the records
+    integer_play_duration: bool, // PlayDuration column has INTEGER affinity
+    wal_checkpoint: wal::WalCheckpoint,
+}
+
+impl SqliteSink {
+    // Opens the database and makes sure the table has every column we write
+    pub fn open(
+        config: &Config,
+        path: &str,
+        audit: Option<&RunAudit>,
+    ) -> Result<SqliteSink, Box<dyn Error + Send + Sync>> {
+        let table_name = config
+            .sqlite_table_name
+            .as_deref()
+            .unwrap_or("PlaybackActivity")
+            .to_string();
+        let conn = Connection::open(path)?;
+        for statement in
+            schema::reconcile_table_schema(&conn, &table_name, config.auto_migrate_schema)?
+        {
+            info!("Schema migration applied: {}", statement);
+        }
+        let dedup_columns = schema::dedup_columns(&config.dedup_ignore_columns)?;
+        let integer_play_duration =
+            schema::has_integer_affinity(&conn, &table_name, "PlayDuration")?;
+        let preloaded_keys = if config.preload_dedup_keys {
+            preload_keys(config, &conn, &table_name, &dedup_columns)?
+        } else {
+            None
+        };
+        Ok(SqliteSink {
+            path: path.to_string(),
+            table_name,
+            dedup_columns,
+            preloaded_keys,
+            conn,
+            stats: SinkStats::default(),
+            audit: audit.cloned(),
+            integer_play_duration,
+            wal_checkpoint: config.sqlite_wal_checkpoint,
+        })
+    }
+}
+
+impl OutputSink for SqliteSink {
+    fn name(&self) -> String {
+        format!("SQLite '{}'", self.path)
+    }
+
+    fn preload_duration(&self) -> Option<Duration> {
+        self.preloaded_keys.as_ref().map(DedupKeySet::load_duration)
+    }
+
+    fn output_file(&self) -> Option<OutputFile> {
+        Some(OutputFile {
+            kind: "sqlite",
+            path: self.path.clone(),
+        })
+    }
+
+    fn begin(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.stats = SinkStats::default();
+        if let Err(e) = self.conn.execute_batch("BEGIN IMMEDIATE TRANSACTION;") {
+            error!("Failed to start SQLite transaction: {}", e);
+            return Err(Box::new(e));
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, record: &TsvRecord) -> Result<WriteOutcome, Box<dyn Error + Send + Sync>> {
+        let result = match self.preloaded_keys {
+            Some(ref mut keys) => {
+                if keys.insert(record) {
+                    insert_record_into_db(
+                        &self.conn,
+                        &self.table_name,
+                        record,
+                        self.integer_play_duration,
+                    )
+                    .map(|_| true)
+                } else {
+                    Ok(false)
+                }
+            }
+            None => check_and_insert_record_into_db(
+                &self.conn,
+                &self.table_name,
+                record,
+                &self.dedup_columns,
+                self.integer_play_duration,
+            ),
+        };
+        match result {
+            Ok(inserted) => {
+                let outcome = if inserted {
+                    WriteOutcome::Inserted
+                } else {
+                    WriteOutcome::Skipped
+                };
+                self.stats.count(outcome);
+                Ok(outcome)
+            }
+            Err(e) => {
+                error!(
+                    "Error checking/inserting record into SQLite: {}. Error: {}. Transaction will be rolled back.",
+                    truncate_display(&format!("{:?}", record), MAX_RECORD_DISPLAY_CHARS),
+                    e
+                );
+                // Attempt to rollback before propagating the error
+                if let Err(rb_err) = self.conn.execute_batch("ROLLBACK;") {
+                    error!("Failed to rollback SQLite transaction: {}", rb_err);
+                }
+                Err(Box::new(e)) // Propagate the original error
+            }
+        }
+    }
+
+    fn rollback(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.conn.execute_batch("ROLLBACK;")?;
+        Ok(())
+    }
+
+    fn end_of_run(&mut self) {
+        wal::checkpoint_and_report(&self.conn, &self.path, self.wal_checkpoint);
+    }
+
+    // The query sees the rows inserted earlier in the open transaction too, so it matches what
+    // write() decides with or without preloaded keys
+    fn explain_write(&self, record: &TsvRecord) -> Result<String, Box<dyn Error + Send + Sync>> {
+        let columns = dedup_column_list(&self.dedup_columns);
+        Ok(
+            match find_matching_row(&self.conn, &self.table_name, record, &self.dedup_columns)? {
+                Some(row) => format!(
+                    "duplicate of existing row {} (compared on {}), skipped",
+                    row, columns
+                ),
+                None => format!("no existing row matches on {}, inserted", columns),
+            },
+        )
+    }
+
+    // Includes the -wal file, committed rows can sit there until the next checkpoint
+    fn measure(&mut self) -> Result<Option<Measurement>, Box<dyn Error + Send + Sync>> {
+        let rows: i64 = self.conn.query_row(
+            &format!("SELECT COUNT(*) FROM \"{}\"", self.table_name),
+            [],
+            |row| row.get(0),
+        )?;
+        Ok(Some(Measurement {
+            label: format!("{} in '{}'", self.table_name, self.path),
+            rows: Some(rows as u64),
+            bytes: file_size(&self.path) + file_size(&format!("{}-wal", self.path)),
+        }))
+    }
+
+    fn finalize(&mut self) -> Result<SinkStats, Box<dyn Error + Send + Sync>> {
+        if let Some(audit) = &self.audit {
+            if let Err(e) = audit.write(&self.conn, &self.table_name, self.stats) {
+                error!(
+                    "Failed to record the run in the migration history: {}. Rolling back.",
+                    e
+                );
+                if let Err(rb_err) = self.conn.execute_batch("ROLLBACK;") {
+                    error!("Failed to rollback SQLite transaction: {}", rb_err);
+                }
+                return Err(Box::new(e));
+            }
+        }
+        match self.conn.execute_batch("COMMIT;") {
+            Ok(_) => Ok(self.stats),
+            Err(e) => {
+                error!(
+                    "Failed to commit SQLite transaction: {}. Attempting rollback.",
+                    e
+                );
+                if let Err(rb_err) = self.conn.execute_batch("ROLLBACK;") {
+                    error!("Failed to rollback SQLite transaction: {}", rb_err);
+                }
+                // Propagate the commit error
+                Err(Box::new(e))
+            }
+        }
+    }
+}
+
+// e.g. "DateCreated, UserId, ItemId" for the duplicate check's columns
+pub(crate) fn dedup_column_list(dedup_columns: &[usize]) -> String {
+    let column_names = schema::column_names();
+    dedup_columns
+        .iter()
+        .map(|&column| column_names[column])
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// For --explain: the first row of the table equal to `record` on every dedup column, e.g.
+// "rowid 12 (DateCreated '2024-01-01 10:00:00', UserId 'new-a', ...)"
+pub(crate) fn find_matching_row(
+    conn: &Connection,
+    table_name: &str,
+    record: &TsvRecord,
+    dedup_columns: &[usize],
+) -> Result<Option<String>, rusqlite::Error> {
+    let column_names = schema::column_names();
+    let conditions: Vec<String> = dedup_columns
+        .iter()
+        .enumerate()
+        .map(|(param, &column)| format!("{} = ?{}", column_names[column], param + 1))
+        .collect();
+    let selected: Vec<String> = column_names
+        .iter()
+        .map(|name| format!("CAST({} AS TEXT)", name))
+        .collect();
+    let query = format!(
+        "SELECT rowid, {} FROM {} WHERE {} LIMIT 1",
+        selected.join(", "),
+        table_name,
+        conditions.join(" AND ")
+    );
+    let fields = record.fields();
+    conn.query_row(
+        &query,
+        params_from_iter(dedup_columns.iter().map(|&column| fields[column])),
+        |row| {
+            let rowid: i64 = row.get(0)?;
+            let mut values = Vec::new();
+            for (index, name) in column_names.iter().enumerate() {
+                let value: Option<String> = row.get(index + 1)?;
+                values.push(match value {
+                    Some(value) => format!("{} '{}'", name, value),
+                    None => format!("{} NULL", name),
+                });
+            }
+            Ok(format!("rowid {} ({})", rowid, values.join(", ")))
+        },
+    )
+    .optional()
+}
+
+struct ActiveSink {
+    sink: Box<dyn OutputSink>,
+    on_failure: OnSinkFailure,
+}
+
+// The sinks of a run. Sinks whose policy is `disable` are dropped when they fail, any other
+// failure is returned to stop the run.
+#[derive(Default)]
+pub struct SinkSet {
+    active: Vec<ActiveSink>,
+}
+
+impl SinkSet {
+    pub fn is_empty(&self) -> bool {
+        self.active.is_empty()
+    }
+
+    // Runs `op` on every sink in order, returning the results of the ones that succeeded
+    fn for_each<T>(
+        &mut self,
+        stats: &mut ProcessingStats,
+        mut op: impl FnMut(&mut dyn OutputSink) -> Result<T, Box<dyn Error + Send + Sync>>,
+    ) -> Result<Vec<(String, T)>, Box<dyn Error + Send + Sync>> {
+        let mut results = Vec::new();
+        let mut index = 0;
+        while index < self.active.len() {
+            let entry = &mut self.active[index];
+            match op(entry.sink.as_mut()) {
+                Ok(result) => {
+                    results.push((entry.sink.name(), result));
+                    index += 1;
+                }
+                Err(e) if entry.on_failure == OnSinkFailure::Disable => {
+                    let name = entry.sink.name();
+                    warn!(
+                        "Disabling output {} after an error (sink_failure_policy = \"disable\"): {}. Continuing with the remaining outputs.",
+                        name, e
+                    );
+                    stats.sinks_disabled.push(format!(
+                        "{} (at record {}): {}",
+                        name, stats.records_processed, e
+                    ));
+                    self.active.remove(index);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(results)
+    }
+
+    // Starts a unit of work (the whole run, or one batch in watch mode) on every sink. Each
+    // destination's size is taken on the first begin(), after a TSV output has been truncated and
+    // any schema migration has run, so the before/after numbers only differ by what this run wrote.
+    pub fn begin(
+        &mut self,
+        stats: &mut ProcessingStats,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let measured = self.for_each(stats, |sink| {
+            sink.begin()?;
+            Ok(measure_or_warn(sink))
+        })?;
+        for measurement in measured.into_iter().filter_map(|(_, m)| m) {
+            if !stats
+                .destinations
+                .iter()
+                .any(|destination| destination.label == measurement.label)
+            {
+                stats.destinations.push(DestinationChange {
+                    label: measurement.label,
+                    rows_before: measurement.rows,
+                    rows_after: None,
+                    bytes_before: measurement.bytes,
+                    bytes_after: None,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    // For --explain, before write(): what each sink is about to do with `record`
+    pub fn explain_write(&self, record: &TsvRecord) -> Vec<String> {
+        self.active
+            .iter()
+            .map(|entry| {
+                let name = entry.sink.name();
+                match entry.sink.explain_write(record) {
+                    Ok(decision) => format!("{}: {}", name, decision),
+                    Err(e) => format!("{}: couldn't look for a matching row: {}", name, e),
+                }
+            })
+            .collect()
+    }
+
+    // Hands the record to every sink in order and counts the outcomes
+    pub fn write(
+        &mut self,
+        record: &TsvRecord,
+        stats: &mut ProcessingStats,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        for (_, outcome) in self.for_each(stats, |sink| sink.write(record))? {
+            match outcome {
+                WriteOutcome::Written => {}
+                WriteOutcome::Inserted => stats.records_inserted_sqlite += 1,
+                WriteOutcome::Skipped => {
+                    debug!(
+                        "Record {} '{}' of UserId '{}' is already in SQLite, skipped as a duplicate",
+                        record.date_created, record.item_name, record.user_id
+                    );
+                    stats.records_skipped_sqlite += 1;
+                    stats.duplicate_dates.record(&record.date_created);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Rolls back every sink's open unit of work instead of finalizing it
+    pub fn rollback(
+        &mut self,
+        stats: &mut ProcessingStats,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.for_each(stats, |sink| sink.rollback())?;
+        Ok(())
+    }
+
+    pub fn end_of_run(&mut self) {
+        for entry in &mut self.active {
+            entry.sink.end_of_run();
+        }
+    }
+
+    pub fn preload_duration(&self) -> Option<Duration> {
+        self.active
+            .iter()
+            .filter_map(|entry| entry.sink.preload_duration())
+            .reduce(|a, b| a + b)
+    }
+
+    // Flushes/commits every sink, returning what each one wrote
+    pub fn finalize(
+        &mut self,
+        stats: &mut ProcessingStats,
+    ) -> Result<Vec<FinalizedSink>, Box<dyn Error + Send + Sync>> {
+        let finalized = self.for_each(stats, |sink| {
+            let sink_stats = sink.finalize()?;
+            Ok((sink.output_file(), sink_stats, measure_or_warn(sink)))
+        })?;
+        Ok(finalized
+            .into_iter()
+            .map(|(name, (output_file, sink_stats, measurement))| {
+                if let Some(measurement) = measurement {
+                    if let Some(destination) = stats
+                        .destinations
+                        .iter_mut()
+                        .find(|destination| destination.label == measurement.label)
+                    {
+                        destination.rows_after = measurement.rows;
+                        destination.bytes_after = Some(measurement.bytes);
+                    }
+                }
+                FinalizedSink {
+                    name,
+                    output_file,
+                    stats: sink_stats,
+                }
+            })
+            .collect())
+    }
+}
+
+// The sink for output_tsv_file_path, in the format columnar::validate accepted
+fn open_file_sink(
+    config: &Config,
+    path: &str,
+) -> Result<Box<dyn OutputSink>, Box<dyn Error + Send + Sync>> {
+    match columnar::output_format(config) {
+        OutputFormat::Tsv => Ok(Box::new(TsvSink::open(config, path)?)),
+        #[cfg(feature = "parquet")]
+        OutputFormat::Parquet => Ok(Box::new(columnar::ParquetSink::open(config, path)?)),
+        #[cfg(not(feature = "parquet"))]
+        OutputFormat::Parquet => Err("Parquet output isn't included in this build.".into()),
+    }
+}
+
+// Opens every output configured for this run mode, logging what will (or would) be written
+pub fn open_sinks(
+    config: &Config,
+    mode: RunMode,
+    audit: Option<&RunAudit>,
+) -> Result<SinkSet, Box<dyn Error + Send + Sync>> {
+    let log = |message: String| info!("{}", message);
+    let policy = &config.sink_failure_policy;
+    let mut sinks = SinkSet { active: Vec::new() };
+    let mut add = |sink: Box<dyn OutputSink>, on_failure: OnSinkFailure| {
+        sinks.active.push(ActiveSink { sink, on_failure })
+    };
+
+    let format_name = columnar::format_name(config);
+    match (&config.output_tsv_file_path, mode.is_dry_run()) {
+        (Some(path_str), true) => log(format!(
+            "{} Output would be written to: {}",
+            format_name, path_str
+        )),
+        (Some(path_str), false) => {
+            log(format!(
+                "{} Output will be written to: {}",
+                format_name, path_str
+            ));
+            // sink_failure_policy.tsv covers the output file in either format
+            add(open_file_sink(config, path_str)?, policy.tsv);
+        }
+        (None, _) => log("TSV Output is not configured.".to_string()),
+    }
+
+    match (&config.sqlite_db_path, mode) {
+        (Some(db_path_str), RunMode::DryRunWithDb) => {
+            let shadow = shadow::ShadowDb::load(
+                db_path_str,
+                config
+                    .sqlite_table_name
+                    .as_deref()
+                    .unwrap_or("PlaybackActivity"),
+                config.auto_migrate_schema,
+                schema::dedup_columns(&config.dedup_ignore_columns)?,
+            )?;
+            log(format!(
+                "Simulating SQLite inserts against: {} (opened read-only, {} existing dedup keys loaded in {:.1}s)",
+                db_path_str,
+                shadow.existing_rows(),
+                shadow.preload_duration().unwrap_or_default().as_secs_f64()
+            ));
+            // Nothing is written so there is nothing that could fail part way
+            add(Box::new(shadow), OnSinkFailure::Abort);
+        }
+        (Some(db_path_str), RunMode::DryRun) => log(format!(
+            "SQLite Output would be written to: {} (not opened in dry run)",
+            db_path_str
+        )),
+        (Some(db_path_str), RunMode::Normal) => {
+            log(format!("SQLite Output will be written to: {}", db_path_str));
+            add(
+                Box::new(SqliteSink::open(config, db_path_str, audit)?),
+                policy.sqlite,
+            );
+        }
+        (None, _) => log("SQLite Output is not configured.".to_string()),
+    }
+    if config.sqlite_db_path.is_some() && !config.dedup_ignore_columns.is_empty() {
+        log(format!(
+            "SQLite duplicate check ignores: {} (records differing only in these count as duplicates)",
+            config.dedup_ignore_columns.join(", ")
+        ));
+    }
+    Ok(sinks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::retention::RetentionPolicy;
+    use crate::rollup::RollupPolicy;
+    use crate::{config_from_toml, process_tsv_file};
+    use std::collections::HashMap;
+    use std::fs;
+
+    fn record(date_created: &str, user_id: &str) -> TsvRecord {
+        TsvRecord {
+            date_created: date_created.to_string(),
+            user_id: user_id.to_string(),
+            item_id: "item".to_string(),
+            item_type: "Movie".to_string(),
+            item_name: "Name".to_string(),
+            playback_method: "DirectPlay".to_string(),
+            client_name: "Web".to_string(),
+            device_name: "TV".to_string(),
+            play_duration: "60".to_string(),
+        }
+    }
+
+    fn create_table(db_path: &str) {
+        Connection::open(db_path)
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE PlaybackActivity (DateCreated DATETIME NOT NULL, UserId TEXT, ItemId TEXT, \
+                ItemType TEXT, ItemName TEXT, PlaybackMethod TEXT, ClientName TEXT, DeviceName TEXT, PlayDuration INT);",
+            )
+            .unwrap();
+    }
+
+    fn row_count(db_path: &str) -> i64 {
+        Connection::open(db_path)
+            .unwrap()
+            .query_row("SELECT COUNT(*) FROM PlaybackActivity", [], |row| {
+                row.get(0)
+            })
+            .unwrap()
+    }
+
+    fn config_with(extra: &str) -> Config {
+        config_from_toml(&format!(
+            "{}\n[instance_old]\nbase_url = \"http://old\"\napi_token = \"x\"\n\
+            [instance_new]\nbase_url = \"http://new\"\napi_token = \"y\"\n",
+            extra
+        ))
+    }
+
+    #[test]
+    fn tsv_sink_writes_every_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.tsv").display().to_string();
+        let config = config_with("input_tsv_file_path = \"unused.tsv\"");
+
+        let mut sink = TsvSink::open(&config, &path).unwrap();
+        sink.begin().unwrap();
+        assert_eq!(
+            sink.write(&record("d1", "u1")).unwrap(),
+            WriteOutcome::Written
+        );
+        assert_eq!(
+            sink.write(&record("d1", "u1")).unwrap(),
+            WriteOutcome::Written
+        );
+        assert_eq!(
+            sink.finalize().unwrap(),
+            SinkStats {
+                written: 2,
+                skipped: 0
+            }
+        );
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 2);
+    }
+
+    #[test]
+    fn sqlite_sink_skips_duplicates_and_commits() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("out.db").display().to_string();
+        create_table(&db_path);
+        let config = config_with("input_tsv_file_path = \"unused.tsv\"");
+
+        let mut sink = SqliteSink::open(&config, &db_path, None).unwrap();
+        sink.begin().unwrap();
+        assert_eq!(
+            sink.write(&record("d1", "u1")).unwrap(),
+            WriteOutcome::Inserted
+        );
+        assert_eq!(
+            sink.write(&record("d1", "u1")).unwrap(),
+            WriteOutcome::Skipped
+        );
+        assert_eq!(
+            sink.write(&record("d2", "u1")).unwrap(),
+            WriteOutcome::Inserted
+        );
+        assert_eq!(
+            sink.finalize().unwrap(),
+            SinkStats {
+                written: 2,
+                skipped: 1
+            }
+        );
+        assert_eq!(row_count(&db_path), 2);
+    }
+
+    #[test]
+    fn play_duration_is_bound_by_column_affinity() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config_with("input_tsv_file_path = \"unused.tsv\"");
+        let stored_type = |column_type: &str| {
+            let db_path = dir
+                .path()
+                .join(format!("{}.db", column_type))
+                .display()
+                .to_string();
+            Connection::open(&db_path)
+                .unwrap()
+                .execute_batch(&format!(
+                    "CREATE TABLE PlaybackActivity (DateCreated DATETIME NOT NULL, UserId TEXT, ItemId TEXT, \
+                    ItemType TEXT, ItemName TEXT, PlaybackMethod TEXT, ClientName TEXT, DeviceName TEXT, PlayDuration {});",
+                    column_type
+                ))
+                .unwrap();
+            let mut sink = SqliteSink::open(&config, &db_path, None).unwrap();
+            sink.begin().unwrap();
+            sink.write(&record("d1", "u1")).unwrap();
+            sink.finalize().unwrap();
+            Connection::open(&db_path)
+                .unwrap()
+                .query_row(
+                    "SELECT typeof(PlayDuration) FROM PlaybackActivity",
+                    [],
+                    |row| row.get::<_, String>(0),
+                )
+                .unwrap()
+        };
+        assert_eq!(stored_type("INTEGER"), "integer");
+        assert_eq!(stored_type("BIGINT"), "integer");
+        assert_eq!(stored_type("TEXT"), "text");
+        // No affinity, so only binding an integer would store one
+        assert_eq!(stored_type("BLOB"), "text");
+    }
+
+    #[test]
+    fn ignored_columns_are_left_out_of_the_duplicate_check() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("out.db").display().to_string();
+        create_table(&db_path);
+        let config = config_with(
+            "input_tsv_file_path = \"unused.tsv\"\ndedup_ignore_columns = [\"DeviceName\"]",
+        );
+        let mut other_device = record("d1", "u1");
+        other_device.device_name = "Phone".to_string();
+
+        let mut shadow = shadow::ShadowDb::load(
+            &db_path,
+            "PlaybackActivity",
+            false,
+            schema::dedup_columns(&config.dedup_ignore_columns).unwrap(),
+        )
+        .unwrap();
+        let mut sink = SqliteSink::open(&config, &db_path, None).unwrap();
+        sink.begin().unwrap();
+        for (record, expected) in [
+            (record("d1", "u1"), WriteOutcome::Inserted),
+            (other_device, WriteOutcome::Skipped),
+            (record("d1", "u2"), WriteOutcome::Inserted),
+        ] {
+            assert_eq!(shadow.write(&record).unwrap(), expected);
+            assert_eq!(sink.write(&record).unwrap(), expected);
+        }
+        sink.finalize().unwrap();
+        assert_eq!(row_count(&db_path), 2);
+    }
+
+    #[test]
+    fn preloaded_keys_match_the_per_record_check() {
+        let dir = tempfile::tempdir().unwrap();
+        let seed = |name: &str| {
+            let db_path = dir.path().join(name).display().to_string();
+            create_table(&db_path);
+            Connection::open(&db_path)
+                .unwrap()
+                .execute_batch(
+                    "INSERT INTO PlaybackActivity VALUES ('d1', 'u1', 'item', 'Movie', 'Name', 'DirectPlay', 'Web', 'TV', 60);
+                    INSERT INTO PlaybackActivity VALUES ('d2', 'u1', 'item', 'Movie', 'Name', 'DirectPlay', 'Web', NULL, 60);",
+                )
+                .unwrap();
+            db_path
+        };
+        let (plain_db, preload_db) = (seed("plain.db"), seed("preload.db"));
+        let mut sixty_point_zero = record("d1", "u1");
+        sixty_point_zero.play_duration = "60.0".to_string();
+        let records = [
+            record("d1", "u1"), // Stored row
+            sixty_point_zero,   // Same row, compared numerically
+            record("d2", "u1"), // Stored row has a NULL so never matches
+            record("d3", "u1"), // New
+            record("d3", "u1"), // Repeated within the input
+        ];
+
+        let mut plain =
+            SqliteSink::open(&config_with("input_tsv_file_path = \"x\""), &plain_db, None).unwrap();
+        let mut preload = SqliteSink::open(
+            &config_with("input_tsv_file_path = \"x\"\npreload_dedup_keys = true"),
+            &preload_db,
+            None,
+        )
+        .unwrap();
+        assert!(preload.preload_duration().is_some());
+        plain.begin().unwrap();
+        preload.begin().unwrap();
+        for record in &records {
+            assert_eq!(plain.write(record).unwrap(), preload.write(record).unwrap());
+        }
+        assert_eq!(plain.finalize().unwrap(), preload.finalize().unwrap());
+        assert_eq!(row_count(&plain_db), row_count(&preload_db));
+
+        // Over the memory limit it falls back to the per-record check
+        let limited = SqliteSink::open(
+            &config_with(
+                "input_tsv_file_path = \"x\"\npreload_dedup_keys = true\nmax_preload_memory_mb = 0",
+            ),
+            &preload_db,
+            None,
+        )
+        .unwrap();
+        assert!(limited.preload_duration().is_none());
+    }
+
+    #[test]
+    fn sqlite_sink_rolls_back_on_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("out.db").display().to_string();
+        create_table(&db_path);
+        let config = config_with("input_tsv_file_path = \"unused.tsv\"");
+
+        let mut sink = SqliteSink::open(&config, &db_path, None).unwrap();
+        sink.begin().unwrap();
+        sink.write(&record("d1", "u1")).unwrap();
+        // Dropping the table inside the transaction makes the next write fail
+        sink.conn
+            .execute_batch("DROP TABLE PlaybackActivity;")
+            .unwrap();
+        assert!(sink.write(&record("d2", "u1")).is_err());
+        // The rollback undid the first insert and the DROP with it
+        assert_eq!(row_count(&db_path), 0);
+    }
+
+    #[test]
+    fn shadow_sink_predicts_without_writing() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("out.db").display().to_string();
+        create_table(&db_path);
+
+        let mut sink =
+            shadow::ShadowDb::load(&db_path, "PlaybackActivity", false, (0..9).collect()).unwrap();
+        assert_eq!(
+            sink.write(&record("d1", "u1")).unwrap(),
+            WriteOutcome::Inserted
+        );
+        assert_eq!(
+            sink.write(&record("d1", "u1")).unwrap(),
+            WriteOutcome::Skipped
+        );
+        assert_eq!(
+            sink.finalize().unwrap(),
+            SinkStats {
+                written: 1,
+                skipped: 1
+            }
+        );
+        assert_eq!(row_count(&db_path), 0);
+    }
+
+    #[tokio::test]
+    async fn disabled_sink_failure_leaves_other_sinks_running() {
+        let dir = tempfile::tempdir().unwrap();
+        let input_path = dir.path().join("in.tsv").display().to_string();
+        let tsv_path = dir.path().join("out.tsv").display().to_string();
+        // No table in the database, so every insert fails
+        let db_path = dir.path().join("empty.db").display().to_string();
+        fs::write(
+            &input_path,
+            "d1\told\ti\tMovie\tN\tDirectPlay\tWeb\tTV\t60\n\
+            d2\told\ti\tMovie\tN\tDirectPlay\tWeb\tTV\t60\n",
+        )
+        .unwrap();
+        let paths = format!(
+            "input_tsv_file_path = \"{}\"\noutput_tsv_file_path = \"{}\"\nsqlite_db_path = \"{}\"",
+            input_path, tsv_path, db_path
+        );
+
+        let abort = config_with(&paths);
+        assert!(process_tsv_file(
+            &abort,
+            &HashMap::new(),
+            &RetentionPolicy::default(),
+            &RollupPolicy::default(),
+            None,
+            RunMode::Normal,
+            false
+        )
+        .await
+        .is_err());
+
+        let disable = config_with(&format!(
+            "{}\n[sink_failure_policy]\nsqlite = \"disable\"",
+            paths
+        ));
+        let stats = process_tsv_file(
+            &disable,
+            &HashMap::new(),
+            &RetentionPolicy::default(),
+            &RollupPolicy::default(),
+            None,
+            RunMode::Normal,
+            false,
+        )
+        .await
+        .unwrap();
+        assert_eq!(stats.sinks_disabled.len(), 1);
+        assert!(stats.sinks_disabled[0].starts_with(&format!("SQLite '{}' (at record 1)", db_path)));
+        assert_eq!(fs::read_to_string(&tsv_path).unwrap().lines().count(), 2);
+    }
+
+    #[tokio::test]
+    async fn run_writes_to_every_sink() {
+        let dir = tempfile::tempdir().unwrap();
+        let input_path = dir.path().join("in.tsv").display().to_string();
+        let tsv_path = dir.path().join("out.tsv").display().to_string();
+        let db_path = dir.path().join("out.db").display().to_string();
+        create_table(&db_path);
+        fs::write(
+            &input_path,
+            "d1\told\ti\tMovie\tN\tDirectPlay\tWeb\tTV\t60\n\
+            d1\told\ti\tMovie\tN\tDirectPlay\tWeb\tTV\t60\n\
+            d2\tother\ti\tMovie\tN\tDirectPlay\tWeb\tTV\t60\n",
+        )
+        .unwrap();
+        let config = config_with(&format!(
+            "input_tsv_file_path = \"{}\"\noutput_tsv_file_path = \"{}\"\nsqlite_db_path = \"{}\"",
+            input_path, tsv_path, db_path
+        ));
+        let user_id_map = HashMap::from([("old".to_string(), "new".to_string())]);
+
+        let stats = process_tsv_file(
+            &config,
+            &user_id_map,
+            &RetentionPolicy::default(),
+            &RollupPolicy::default(),
+            None,
+            RunMode::Normal,
+            false,
+        )
+        .await
+        .unwrap();
+        assert_eq!(stats.records_processed, 3);
+        assert_eq!(stats.records_changed, 2);
+        assert_eq!(
+            (stats.records_inserted_sqlite, stats.records_skipped_sqlite),
+            (2, 1)
+        );
+        let tsv = fs::read_to_string(&tsv_path).unwrap();
+        assert_eq!(tsv.lines().count(), 3);
+        assert!(tsv.starts_with("d1\tnew\t"));
+        assert_eq!(row_count(&db_path), 2);
+        let rows: Vec<(Option<u64>, Option<u64>)> = stats
+            .destinations
+            .iter()
+            .map(|destination| (destination.rows_before, destination.rows_after))
+            .collect();
+        assert_eq!(rows, vec![(None, None), (Some(0), Some(2))]);
+        assert_eq!(stats.destinations[0].bytes_before, 0);
+        assert_eq!(stats.destinations[0].bytes_after, Some(tsv.len() as u64));
+    }
+}
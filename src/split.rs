@@ -0,0 +1,396 @@
+// `[[split_user]]`: one old user (e.g. a shared "Family" account) whose history is divided among
+// several new users by when each play happened. Every rule gives days and/or hours and a target;
+// a record goes to the rule its DateCreated falls in, or to the fallback when none matches (also
+// when DateCreated can't be read). Rules of one user may not overlap, so the order doesn't matter.
+use crate::mapping;
+use crate::retention::parse_date_created;
+use crate::{Config, JellyfinUser, ProcessingStats, TsvRecord};
+use chrono::{Datelike, Timelike};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use tracing::info;
+
+const DAY_NAMES: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct SplitUser {
+    pub old: String,      // User name on the old instance
+    pub fallback: String, // User name on the new instance
+    #[serde(default)]
+    pub rules: Vec<SplitRule>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct SplitRule {
+    // e.g. "mon-fri", "sat,sun", "weekdays" or "weekends". Every day when left out.
+    pub days: Option<String>,
+    // e.g. "18-23" (18:00 up to 23:00) or "22-2" (over midnight). Every hour when left out.
+    pub hours: Option<String>,
+    pub to: String, // User name on the new instance
+}
+
+impl SplitRule {
+    fn describe(&self) -> String {
+        format!(
+            "days {}, hours {} -> '{}'",
+            self.days.as_deref().unwrap_or("all"),
+            self.hours.as_deref().unwrap_or("all"),
+            self.to
+        )
+    }
+}
+
+// Bit h of element d: hour h on day d (Monday = 0)
+type Week = [u32; 7];
+
+fn parse_day(name: &str) -> Option<usize> {
+    // "mon", "Monday", ...
+    let name = name.trim().to_lowercase();
+    if name.len() < 3 || !name.chars().all(|c| c.is_ascii_alphabetic()) {
+        return None;
+    }
+    DAY_NAMES.iter().position(|day| name.starts_with(day))
+}
+
+fn parse_days(days: &str) -> Result<[bool; 7], String> {
+    let mut selected = [false; 7];
+    for part in days.split(',').map(str::trim) {
+        match part.to_lowercase().as_str() {
+            "weekdays" => selected[..5].iter_mut().for_each(|day| *day = true),
+            "weekends" => selected[5..].iter_mut().for_each(|day| *day = true),
+            _ => {
+                let invalid =
+                    || format!("invalid days '{}' (e.g. \"mon-fri\" or \"sat,sun\")", days);
+                let (first, last) = match part.split_once('-') {
+                    Some((first, last)) => (parse_day(first), parse_day(last)),
+                    None => (parse_day(part), parse_day(part)),
+                };
+                let (first, last) = first.zip(last).ok_or_else(invalid)?;
+                // A range may wrap around the week, e.g. "fri-mon"
+                let mut day = first;
+                loop {
+                    selected[day] = true;
+                    if day == last {
+                        break;
+                    }
+                    day = (day + 1) % 7;
+                }
+            }
+        }
+    }
+    Ok(selected)
+}
+
+fn parse_hours(hours: &str) -> Result<u32, String> {
+    let invalid = || format!("invalid hours '{}' (e.g. \"18-23\" or \"22-2\")", hours);
+    let mut selected = 0u32;
+    for part in hours.split(',').map(str::trim) {
+        let (start, end) = match part.split_once('-') {
+            Some((start, end)) => (start.trim().parse::<u32>(), end.trim().parse::<u32>()),
+            None => (
+                part.parse::<u32>(),
+                part.parse::<u32>().map(|hour| hour + 1),
+            ),
+        };
+        let (start, end) = match (start, end) {
+            (Ok(start), Ok(end)) if start < 24 && end <= 24 && start != end => (start, end),
+            _ => return Err(invalid()),
+        };
+        // Up to but not including the end hour, wrapping past midnight
+        let mut hour = start;
+        loop {
+            selected |= 1 << hour;
+            hour = (hour + 1) % 24;
+            if hour == end % 24 {
+                break;
+            }
+        }
+    }
+    Ok(selected)
+}
+
+fn rule_week(rule: &SplitRule) -> Result<Week, String> {
+    let days = match rule.days {
+        Some(ref days) => parse_days(days)?,
+        None => [true; 7],
+    };
+    let hours = match rule.hours {
+        Some(ref hours) => parse_hours(hours)?,
+        None => (1 << 24) - 1,
+    };
+    Ok(days.map(|selected| if selected { hours } else { 0 }))
+}
+
+// e.g. "mon 18:00"
+fn first_overlap(a: &Week, b: &Week) -> Option<String> {
+    (0..7).find_map(|day| {
+        let both = a[day] & b[day];
+        (both != 0).then(|| format!("{} {:02}:00", DAY_NAMES[day], both.trailing_zeros()))
+    })
+}
+
+// Checks the rules' syntax and that no two rules of a user overlap. Runs before the instances
+// are contacted, names are only checked once the users are known (see resolve).
+pub fn validate(config: &Config) -> Result<(), String> {
+    let mut old_names = Vec::new();
+    for split in &config.split_user {
+        if old_names.contains(&&split.old) {
+            return Err(format!(
+                "split_user: '{}' is listed more than once",
+                split.old
+            ));
+        }
+        old_names.push(&split.old);
+        let weeks = split
+            .rules
+            .iter()
+            .map(|rule| rule_week(rule).map_err(|e| format!("split_user '{}': {}", split.old, e)))
+            .collect::<Result<Vec<Week>, String>>()?;
+        for (i, a) in weeks.iter().enumerate() {
+            for (j, b) in weeks.iter().enumerate().skip(i + 1) {
+                if let Some(at) = first_overlap(a, b) {
+                    return Err(format!(
+                        "split_user '{}': rules {} ({}) and {} ({}) overlap, e.g. on {}. Every time may only belong to one rule.",
+                        split.old,
+                        i + 1,
+                        split.rules[i].describe(),
+                        j + 1,
+                        split.rules[j].describe(),
+                        at
+                    ));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+struct Target {
+    name: String,
+    id: String,
+}
+
+#[derive(Debug)]
+struct ResolvedSplit {
+    old_name: String,
+    rules: Vec<(Week, Target)>,
+    fallback: Target,
+}
+
+// Keyed by old user ID, filled in by resolve
+#[derive(Debug, Default)]
+pub struct SplitPlan {
+    by_old_id: HashMap<String, ResolvedSplit>,
+}
+
+#[derive(Debug, Default)]
+pub struct SplitStats {
+    // Old user name -> (target user name, whether by fallback) -> records
+    pub records: BTreeMap<String, BTreeMap<(String, bool), u64>>,
+}
+
+impl SplitStats {
+    pub fn print_summary(&self) {
+        info!("  Split users ([[split_user]]):");
+        for (old_name, targets) in &self.records {
+            let counts: Vec<String> = targets
+                .iter()
+                .map(|((target, fallback), count)| {
+                    format!(
+                        "{} -> '{}'{}",
+                        count,
+                        target,
+                        if *fallback { " (fallback)" } else { "" }
+                    )
+                })
+                .collect();
+            info!("    '{}': {}", old_name, counts.join(", "));
+        }
+    }
+}
+
+// Looks up the configured names on both instances and fills in config.split_plan. A split user
+// takes precedence over the name match of the user map.
+pub fn resolve(
+    config: &mut Config,
+    old_users: &[JellyfinUser],
+    new_users: &[JellyfinUser],
+) -> Result<(), String> {
+    let find_new = |name: &str| {
+        new_users
+            .iter()
+            .find(|user| user.name == name)
+            .map(|user| Target {
+                name: name.to_string(),
+                id: user.id.clone(),
+            })
+            .ok_or_else(|| format!("split_user: '{}' isn't a user on the new instance", name))
+    };
+    let mut plan = SplitPlan::default();
+    for split in &config.split_user {
+        let old_user = old_users
+            .iter()
+            .find(|user| user.name == split.old)
+            .ok_or_else(|| {
+                format!(
+                    "split_user: '{}' isn't a user on the old instance",
+                    split.old
+                )
+            })?;
+        let mut rules = Vec::new();
+        for rule in &split.rules {
+            rules.push((rule_week(rule)?, find_new(&rule.to)?));
+        }
+        info!(
+            "Splitting '{}' (old ID '{}') by date: {} rule(s), otherwise '{}'",
+            split.old,
+            old_user.id,
+            rules.len(),
+            split.fallback
+        );
+        plan.by_old_id.insert(
+            old_user.id.clone(),
+            ResolvedSplit {
+                old_name: split.old.clone(),
+                rules,
+                fallback: find_new(&split.fallback)?,
+            },
+        );
+    }
+    config.split_plan = plan;
+    Ok(())
+}
+
+impl SplitPlan {
+    pub fn splits(&self, user_id: &str) -> bool {
+        self.by_old_id.contains_key(user_id)
+    }
+
+    // Maps the record if its user is split, returning false for every other user
+    pub fn apply(&self, record: &mut TsvRecord, stats: &mut ProcessingStats) -> bool {
+        let Some(split) = self.by_old_id.get(&record.user_id) else {
+            return false;
+        };
+        let rule = parse_date_created(&record.date_created).and_then(|date| {
+            let day = date.weekday().num_days_from_monday() as usize;
+            split
+                .rules
+                .iter()
+                .find(|(week, _)| week[day] & (1 << date.hour()) != 0)
+        });
+        let (target, fallback) = match rule {
+            Some((_, target)) => (target, false),
+            None => (&split.fallback, true),
+        };
+        *stats
+            .split
+            .records
+            .entry(split.old_name.clone())
+            .or_default()
+            .entry((target.name.clone(), fallback))
+            .or_insert(0) += 1;
+        mapping::count_change(stats, &record.user_id, "(split by date)");
+        record.user_id = target.id.clone();
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config_from_toml;
+
+    const INSTANCES: &str = "[instance_old]\nbase_url = 'http://old'\napi_token = 'x'\n\
+        [instance_new]\nbase_url = 'http://new'\napi_token = 'y'\n";
+
+    fn config_with(split: &str) -> Config {
+        config_from_toml(&format!(
+            "input_tsv_file_path = 'x'\n{}\n{}",
+            split, INSTANCES
+        ))
+    }
+
+    fn user(name: &str, id: &str) -> JellyfinUser {
+        JellyfinUser {
+            id: id.to_string(),
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn splits_by_day_and_hour_and_refuses_overlaps() {
+        let mut config = config_with(
+            "[[split_user]]\nold = 'Family'\nfallback = 'alice'\nrules = [\n\
+             { days = 'mon-fri', hours = '18-23', to = 'alice' },\n\
+             { days = 'weekends', hours = '6-12', to = 'kids' },\n]",
+        );
+        validate(&config).unwrap();
+        resolve(
+            &mut config,
+            &[user("Family", "old-f"), user("bob", "old-b")],
+            &[user("alice", "new-a"), user("kids", "new-k")],
+        )
+        .unwrap();
+
+        let mut stats = ProcessingStats::default();
+        let mut target = |user_id: &str, date_created: &str| {
+            let mut record = TsvRecord {
+                date_created: date_created.to_string(),
+                user_id: user_id.to_string(),
+                item_id: "i".to_string(),
+                item_type: "Episode".to_string(),
+                item_name: "E".to_string(),
+                playback_method: "DirectPlay".to_string(),
+                client_name: "Web".to_string(),
+                device_name: "TV".to_string(),
+                play_duration: "100".to_string(),
+            };
+            config
+                .split_plan
+                .apply(&mut record, &mut stats)
+                .then_some(record.user_id)
+        };
+        // 2024-01-01 is a Monday
+        assert_eq!(
+            target("old-f", "2024-01-01 19:30:00").as_deref(),
+            Some("new-a")
+        );
+        assert_eq!(
+            target("old-f", "2024-01-06 08:00:00").as_deref(),
+            Some("new-k")
+        );
+        assert_eq!(
+            target("old-f", "2024-01-06 12:00:00").as_deref(),
+            Some("new-a")
+        );
+        assert_eq!(target("old-f", "not a date").as_deref(), Some("new-a"));
+        assert_eq!(target("old-b", "2024-01-06 08:00:00"), None);
+        let counts = &stats.split.records["Family"];
+        assert_eq!(counts[&("alice".to_string(), false)], 1);
+        assert_eq!(counts[&("alice".to_string(), true)], 2);
+        assert_eq!(counts[&("kids".to_string(), false)], 1);
+        assert_eq!(stats.records_changed, 4);
+
+        let overlapping = config_with(
+            "[[split_user]]\nold = 'Family'\nfallback = 'alice'\nrules = [\n\
+             { days = 'fri-sun', hours = '22-2', to = 'alice' },\n\
+             { days = 'sat', hours = '1', to = 'kids' },\n]",
+        );
+        let error = validate(&overlapping).unwrap_err();
+        assert!(error.contains("overlap, e.g. on sat 01:00"), "{}", error);
+        assert!(validate(&config_with_hours("25-3")).is_err());
+        assert!(validate(&config_with_hours("7")).is_ok());
+    }
+
+    fn config_with_hours(hours: &str) -> Config {
+        config_with(&format!(
+            "[[split_user]]\nold = 'Family'\nfallback = 'alice'\n\
+             rules = [{{ hours = '{}', to = 'kids' }}]",
+            hours
+        ))
+    }
+}
@@ -0,0 +1,146 @@
+// Unknown config keys. Serde silently ignores keys that don't match a field, so a typo like
+// `sqlite_table_nmae` just leaves the setting at its default. Every ignored key is collected while
+// deserializing and, with strict_config (the default), refused with the closest known key as a
+// suggestion. `--lenient-config` or `strict_config = false` only warns about them.
+use crate::{Config, InstanceConfig};
+use config::{Config as AppConfig, ConfigError};
+use serde::de::{self, Deserialize, Deserializer, Visitor};
+use serde::forward_to_deserialize_any;
+use tracing::warn;
+
+// Suggestions further away than this are more likely wrong than helpful
+const MAX_SUGGESTION_DISTANCE: usize = 3;
+
+// Deserializes the merged config, remembering the dotted path of every key that was ignored
+pub fn deserialize_config(settings: AppConfig) -> Result<Config, ConfigError> {
+    let mut unknown_keys = Vec::new();
+    let mut config: Config =
+        serde_ignored::deserialize(settings, |path| unknown_keys.push(path.to_string()))?;
+    unknown_keys.sort(); // The config crate's tables don't keep the file's order
+    config.unknown_keys = unknown_keys;
+    Ok(config)
+}
+
+// Errors (strict) or warns (lenient) about the unknown keys found by deserialize_config
+pub fn check_unknown_keys(config: &Config, lenient: bool) -> Result<(), String> {
+    if config.unknown_keys.is_empty() {
+        return Ok(());
+    }
+    let described: Vec<String> = config
+        .unknown_keys
+        .iter()
+        .map(|key| match suggestion(key) {
+            Some(known) => format!("'{}' (did you mean '{}'?)", key, known),
+            None => format!("'{}'", key),
+        })
+        .collect();
+    if config.strict_config && !lenient {
+        return Err(format!(
+            "Unknown configuration key(s): {}. Fix or remove them, or pass --lenient-config (or set strict_config = false) to only warn.",
+            described.join(", ")
+        ));
+    }
+    warn!(
+        "Ignoring unknown configuration key(s): {}.",
+        described.join(", ")
+    );
+    Ok(())
+}
+
+// Closest field of the section the key is in, e.g. "instance_old.api_tokn" -> "instance_old.api_token"
+fn suggestion(key: &str) -> Option<String> {
+    let (section, name) = match key.rsplit_once('.') {
+        Some((section, name)) => (Some(section), name),
+        None => (None, key),
+    };
+    let known = match section {
+        None => field_names::<Config>(),
+        Some("instance_old" | "instance_new") => field_names::<InstanceConfig>(),
+        Some("instance_old.client" | "instance_new.client") => field_names::<crate::ClientInfo>(),
+        Some("sink_failure_policy") => field_names::<crate::sinks::SinkFailurePolicy>(),
+        Some(_) => return None,
+    };
+    let (distance, closest) = known
+        .iter()
+        .map(|field| (strsim::levenshtein(name, field), field))
+        .min()?;
+    (distance <= MAX_SUGGESTION_DISTANCE).then(|| match section {
+        Some(section) => format!("{}.{}", section, closest),
+        None => closest.to_string(),
+    })
+}
+
+// The field names a derived Deserialize impl asks for, captured by a deserializer that accepts
+// nothing but a struct
+fn field_names<'de, T: Deserialize<'de>>() -> &'static [&'static str] {
+    let mut fields: &'static [&'static str] = &[];
+    let _ = T::deserialize(FieldNames {
+        fields: &mut fields,
+    });
+    fields
+}
+
+struct FieldNames<'a> {
+    fields: &'a mut &'static [&'static str],
+}
+
+impl<'de> Deserializer<'de> for FieldNames<'_> {
+    type Error = de::value::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
+        Err(de::Error::custom("only structs are supported"))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        _visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        *self.fields = fields;
+        Err(de::Error::custom("field names captured"))
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+        option unit unit_struct newtype_struct seq tuple tuple_struct map enum identifier
+        ignored_any
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config_from_toml;
+
+    const INSTANCES: &str = "[instance_old]\nbase_url = \"http://old\"\napi_token = \"x\"\n\
+        [instance_new]\nbase_url = \"http://new\"\napi_tokn = \"y\"\n";
+
+    #[test]
+    fn unknown_keys_are_refused_with_a_suggestion() {
+        let config = config_from_toml(&format!(
+            "input_tsv_file_path = \"in.tsv\"\nsqlite_table_nmae = \"PlaybackActivity\"\n\
+            completely_unrelated = 1\n{}",
+            INSTANCES
+        ));
+        let error = check_unknown_keys(&config, false).unwrap_err();
+        assert!(
+            error.contains("'sqlite_table_nmae' (did you mean 'sqlite_table_name'?)"),
+            "{}",
+            error
+        );
+        assert!(
+            error.contains("'instance_new.api_tokn' (did you mean 'instance_new.api_token'?)"),
+            "{}",
+            error
+        );
+        assert!(error.contains("'completely_unrelated',"), "{}", error); // Sorted
+        assert!(check_unknown_keys(&config, true).is_ok());
+
+        let config = config_from_toml(&format!(
+            "input_tsv_file_path = \"in.tsv\"\nstrict_config = false\nsqlite_table_nmae = \"x\"\n{}",
+            INSTANCES
+        ));
+        assert!(check_unknown_keys(&config, false).is_ok());
+    }
+}
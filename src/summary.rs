@@ -0,0 +1,153 @@
+// `--summary-line`: the run's stats as a single JSON line on stderr, for wrapper scripts that
+// can't parse the human readable summary. The prefix and the fields below are a stable interface,
+// new fields may be added but existing ones are never renamed or removed.
+// MigrationSummary is the same kind of record for code embedding the migration, returned by
+// run_migration.
+use crate::explain::Trace;
+use crate::sinks::DestinationChange;
+use crate::timefmt;
+use crate::{ProcessingStats, RunMode};
+use chrono::SecondsFormat;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+pub const SUMMARY_LINE_PREFIX: &str = "JPM_SUMMARY: ";
+const SUMMARY_LINE_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct UserChanges {
+    pub new_id: String,
+    pub count: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MigrationSummary {
+    pub dry_run: bool, // Nothing was written, the SQLite counts are what a run would insert
+    pub records_processed: u64,
+    pub records_changed: u64,
+    pub records_unchanged: u64,
+    pub records_dropped: u64, // By the filters and policies, before reaching any output
+    pub records_inserted_sqlite: u64,
+    pub records_skipped_sqlite: u64, // Already in the table
+    pub changes_per_user: BTreeMap<String, UserChanges>, // Keyed by old user ID
+}
+
+impl MigrationSummary {
+    pub(crate) fn from_stats(stats: &ProcessingStats) -> MigrationSummary {
+        MigrationSummary {
+            dry_run: stats.mode.is_dry_run(),
+            records_processed: stats.records_processed,
+            records_changed: stats.records_changed,
+            records_unchanged: stats.records_unchanged,
+            records_dropped: stats.records_dropped(),
+            records_inserted_sqlite: stats.records_inserted_sqlite as u64,
+            records_skipped_sqlite: stats.records_skipped_sqlite as u64,
+            changes_per_user: changes_per_user(stats),
+        }
+    }
+}
+
+fn changes_per_user(stats: &ProcessingStats) -> BTreeMap<String, UserChanges> {
+    stats
+        .changes_summary
+        .iter()
+        .map(|(old_id, (new_id, count))| {
+            (
+                old_id.clone(),
+                UserChanges {
+                    new_id: new_id.clone(),
+                    count: *count,
+                },
+            )
+        })
+        .collect()
+}
+
+#[derive(Debug, Serialize)]
+struct SummaryLine {
+    version: u32,
+    mode: &'static str, // "normal", "dry_run" or "dry_run_with_db"
+    started_at: String,
+    finished_at: String,
+    records_processed: u64,
+    records_changed: u64,
+    records_unchanged: u64,
+    records_already_migrated: u64, // mapping_direction = "auto" only, part of records_unchanged
+    records_unmapped: u64,         // Likewise
+    records_device_renamed: u64,
+    records_skipped_user_filter: u64,
+    records_dropped_retention: u64,
+    records_rejected_field_length: u64,
+    records_future_date: u64, // DateCreated past future_date_slack_hours, whatever the policy did
+    records_rejected_future_date: u64, // future_date_policy = "reject"
+    records_dropped_unmapped: u64, // unmapped_user_policy = "drop"
+    records_unmapped_to_fallback: u64, // unmapped_user_policy = "fallback", part of records_changed
+    rows_merged: u64,         // Folded into an earlier row of the same session
+    session_seconds_reclaimed: u64, // PlayDuration of merged rows no longer counted twice
+    records_rolled_up: u64,   // Replaced by rolled-up rows (output_mode = "daily_rollup")
+    rollup_rows_written: u64, // Synthetic rows sent to the outputs in their place
+    play_durations_rounded: u64, // PlayDuration values with a fractional part
+    play_durations_converted: u64, // PlayDuration values converted from ticks to seconds
+    records_inserted_sqlite: u32,
+    records_skipped_sqlite: u32,
+    outputs_disabled: Vec<String>,
+    // Rows (SQLite only) and bytes of each output before the first write and after the final commit
+    destinations: Vec<DestinationChange>,
+    changes_per_user: BTreeMap<String, UserChanges>, // Keyed by old user ID
+    // Over max_tracked_users distinct IDs, the rest are counted under "(other)"
+    per_user_stats_truncated: bool,
+    // "instance_old"/"instance_new" -> seconds ahead of this machine (negative when behind), for
+    // the instances whose preflight response had a Date header
+    clock_skew_secs: BTreeMap<&'static str, i64>,
+    // {line, steps} of each --explain line, empty without it
+    explained: Vec<Trace>,
+}
+
+pub fn summary_line(stats: &ProcessingStats) -> String {
+    let line = SummaryLine {
+        version: SUMMARY_LINE_VERSION,
+        mode: match stats.mode {
+            RunMode::Normal => "normal",
+            RunMode::DryRun => "dry_run",
+            RunMode::DryRunWithDb => "dry_run_with_db",
+        },
+        started_at: stats.started_at.to_rfc3339_opts(SecondsFormat::Secs, true),
+        finished_at: timefmt::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+        records_processed: stats.records_processed,
+        records_changed: stats.records_changed,
+        records_unchanged: stats.records_unchanged,
+        records_already_migrated: stats.records_already_migrated,
+        records_unmapped: stats.unmapped_user_ids.values().sum(),
+        records_device_renamed: stats.records_device_renamed,
+        records_skipped_user_filter: stats.records_skipped_user_filter,
+        records_dropped_retention: stats.records_dropped_retention,
+        records_rejected_field_length: stats.records_rejected_field_length,
+        records_future_date: stats.future_dates.records,
+        records_rejected_future_date: stats.future_dates.records_rejected,
+        records_dropped_unmapped: stats.unmapped_policy.records_dropped,
+        records_unmapped_to_fallback: stats.unmapped_policy.records_to_fallback,
+        rows_merged: stats.session_merge.rows_merged,
+        session_seconds_reclaimed: stats.session_merge.reclaimed_seconds,
+        records_rolled_up: stats.rollup.records_rolled_up,
+        rollup_rows_written: stats.rollup.rows_written,
+        play_durations_rounded: stats.play_duration.rounded,
+        play_durations_converted: stats.play_duration.converted_from_ticks,
+        records_inserted_sqlite: stats.records_inserted_sqlite,
+        records_skipped_sqlite: stats.records_skipped_sqlite,
+        outputs_disabled: stats.sinks_disabled.clone(),
+        destinations: stats.destinations.clone(),
+        changes_per_user: changes_per_user(stats),
+        per_user_stats_truncated: stats.tracked_users.truncated,
+        clock_skew_secs: stats
+            .clock_skews
+            .iter()
+            .map(|skew| (skew.instance, skew.skew_secs))
+            .collect(),
+        explained: stats.explained.clone(),
+    };
+    format!(
+        "{}{}",
+        SUMMARY_LINE_PREFIX,
+        serde_json::to_string(&line).expect("summary line always serializes")
+    )
+}
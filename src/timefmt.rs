@@ -0,0 +1,150 @@
+// Formatting of run-level timestamps and durations. Every timestamp is shown both as RFC3339 UTC
+// and in local time (the system timezone, or `report_timezone` when configured).
+use chrono::{DateTime, Local, Utc};
+use chrono_tz::Tz;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+// `--stable-timestamps`: every run-level timestamp is 2000-01-01T00:00:00Z and every measured
+// duration zero, so repeated runs print and write identical reports, summary lines, manifests and
+// history rows
+static STABLE_TIMESTAMPS: AtomicBool = AtomicBool::new(false);
+const STABLE_TIMESTAMP_SECS: i64 = 946_684_800;
+
+pub fn use_stable_timestamps() {
+    STABLE_TIMESTAMPS.store(true, Ordering::Relaxed);
+}
+
+pub fn stable_timestamps() -> bool {
+    STABLE_TIMESTAMPS.load(Ordering::Relaxed)
+}
+
+// The time for run-level timestamps (not for retention cutoffs, which always use the real time)
+pub fn now() -> DateTime<Utc> {
+    if STABLE_TIMESTAMPS.load(Ordering::Relaxed) {
+        return DateTime::from_timestamp(STABLE_TIMESTAMP_SECS, 0).expect("valid timestamp");
+    }
+    Utc::now()
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TimeFormatter {
+    timezone: Option<Tz>, // None means the system timezone
+}
+
+impl TimeFormatter {
+    pub fn new(report_timezone: Option<&str>) -> Result<Self, String> {
+        let timezone = match report_timezone {
+            Some(name) => Some(name.parse::<Tz>().map_err(|_| {
+                format!(
+                    "Unknown report_timezone '{}'. Use an IANA timezone name such as 'Europe/London' or 'America/New_York'.",
+                    name
+                )
+            })?),
+            None => None,
+        };
+        Ok(TimeFormatter { timezone })
+    }
+
+    pub fn format(&self, timestamp: DateTime<Utc>) -> String {
+        let local = match self.timezone {
+            Some(tz) => timestamp
+                .with_timezone(&tz)
+                .format("%Y-%m-%d %H:%M:%S %Z")
+                .to_string(),
+            None => timestamp
+                .with_timezone(&Local)
+                .format("%Y-%m-%d %H:%M:%S %:z")
+                .to_string(),
+        };
+        format!(
+            "{} (local {})",
+            timestamp.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            local
+        )
+    }
+}
+
+// e.g. "1h 12m 33s (4353.2s)"
+pub fn humanize_duration(duration: Duration) -> String {
+    let duration = if STABLE_TIMESTAMPS.load(Ordering::Relaxed) {
+        Duration::ZERO
+    } else {
+        duration
+    };
+    let total_secs = duration.as_secs();
+    let (hours, minutes, seconds) = (total_secs / 3600, (total_secs % 3600) / 60, total_secs % 60);
+    let human = if hours > 0 {
+        format!("{}h {}m {}s", hours, minutes, seconds)
+    } else if minutes > 0 {
+        format!("{}m {}s", minutes, seconds)
+    } else {
+        format!("{}s", seconds)
+    };
+    format!("{} ({:.1}s)", human, duration.as_secs_f64())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn utc(y: i32, mo: u32, d: u32, h: u32, mi: u32, s: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(y, mo, d, h, mi, s).unwrap()
+    }
+
+    #[test]
+    fn formats_both_utc_and_configured_timezone() {
+        let formatter = TimeFormatter::new(Some("Europe/Berlin")).unwrap();
+        assert_eq!(
+            formatter.format(utc(2024, 1, 15, 12, 0, 0)),
+            "2024-01-15T12:00:00Z (local 2024-01-15 13:00:00 CET)"
+        );
+    }
+
+    #[test]
+    fn spring_forward_boundary() {
+        // US clocks jump from 02:00 EST to 03:00 EDT on 2024-03-10
+        let formatter = TimeFormatter::new(Some("America/New_York")).unwrap();
+        assert_eq!(
+            formatter.format(utc(2024, 3, 10, 6, 59, 59)),
+            "2024-03-10T06:59:59Z (local 2024-03-10 01:59:59 EST)"
+        );
+        assert_eq!(
+            formatter.format(utc(2024, 3, 10, 7, 0, 0)),
+            "2024-03-10T07:00:00Z (local 2024-03-10 03:00:00 EDT)"
+        );
+    }
+
+    #[test]
+    fn fall_back_boundary_keeps_repeated_local_hour_distinct() {
+        // UK clocks go back from 02:00 BST to 01:00 GMT on 2024-10-27, so 01:30 happens twice
+        let formatter = TimeFormatter::new(Some("Europe/London")).unwrap();
+        assert_eq!(
+            formatter.format(utc(2024, 10, 27, 0, 30, 0)),
+            "2024-10-27T00:30:00Z (local 2024-10-27 01:30:00 BST)"
+        );
+        assert_eq!(
+            formatter.format(utc(2024, 10, 27, 1, 30, 0)),
+            "2024-10-27T01:30:00Z (local 2024-10-27 01:30:00 GMT)"
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_timezone() {
+        assert!(TimeFormatter::new(Some("Mars/Olympus_Mons")).is_err());
+    }
+
+    #[test]
+    fn humanizes_durations() {
+        assert_eq!(humanize_duration(Duration::from_millis(4_200)), "4s (4.2s)");
+        assert_eq!(
+            humanize_duration(Duration::from_secs(125)),
+            "2m 5s (125.0s)"
+        );
+        assert_eq!(
+            humanize_duration(Duration::from_secs(4353)),
+            "1h 12m 33s (4353.0s)"
+        );
+    }
+}
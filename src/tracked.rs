@@ -0,0 +1,99 @@
+// Bounds the per-user statistics (changes_summary, retention drops per user, unmapped IDs) on
+// pathological inputs with huge numbers of distinct UserIds. Once a map tracks
+// `max_tracked_users` IDs, further IDs are counted under "(other)". Only the statistics are
+// affected, every record is still mapped and written as usual.
+use crate::Config;
+use tracing::warn;
+
+pub const DEFAULT_MAX_TRACKED_USERS: usize = 100_000;
+pub const OTHER_USERS: &str = "(other)";
+
+#[derive(Debug, Clone)]
+pub struct TrackedUsers {
+    max: usize,
+    pub truncated: bool, // Some user was counted under "(other)"
+}
+
+impl Default for TrackedUsers {
+    fn default() -> Self {
+        TrackedUsers::new(DEFAULT_MAX_TRACKED_USERS)
+    }
+}
+
+impl TrackedUsers {
+    pub fn from_config(config: &Config) -> TrackedUsers {
+        TrackedUsers::new(
+            config
+                .max_tracked_users
+                .unwrap_or(DEFAULT_MAX_TRACKED_USERS),
+        )
+    }
+
+    pub fn new(max: usize) -> TrackedUsers {
+        TrackedUsers {
+            max,
+            truncated: false,
+        }
+    }
+
+    // The key to count `user_id` under in a map that has `len` entries, `known` if it is one of them
+    pub fn key(&mut self, user_id: &str, len: usize, known: bool) -> String {
+        if known || len < self.max {
+            user_id.to_string()
+        } else {
+            self.truncated = true;
+            OTHER_USERS.to_string()
+        }
+    }
+
+    pub fn print_warning(&self) {
+        if self.truncated {
+            warn!(
+                "  More than {} distinct user IDs, the per-user statistics count the rest as '{}' (max_tracked_users). Records were still mapped and written as usual.",
+                self.max, OTHER_USERS
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mapping::apply_user_id_map;
+    use crate::{config_from_toml, ProcessingStats, TsvRecord};
+    use std::collections::HashMap;
+
+    #[test]
+    fn caps_the_per_user_statistics_on_wide_inputs() {
+        let user_id_map = HashMap::from([("old-a".to_string(), "new-a".to_string())]);
+        let config = config_from_toml(
+            "input_tsv_file_path = \"unused.tsv\"\nmapping_direction = \"auto\"\n\
+             [instance_old]\nbase_url = \"http://old\"\napi_token = \"x\"\n\
+             [instance_new]\nbase_url = \"http://new\"\napi_token = \"y\"\n",
+        );
+        let mut stats = ProcessingStats {
+            tracked_users: TrackedUsers::new(100),
+            ..Default::default()
+        };
+        for i in 0..10_000 {
+            let mut record = TsvRecord {
+                date_created: "2024-01-01 10:00:00".to_string(),
+                user_id: format!("user-{}", i),
+                item_id: "item".to_string(),
+                item_type: "Movie".to_string(),
+                item_name: "Name".to_string(),
+                playback_method: "DirectPlay".to_string(),
+                client_name: "Web".to_string(),
+                device_name: "TV".to_string(),
+                play_duration: "60".to_string(),
+            };
+            apply_user_id_map(&mut record, &user_id_map, &config, &mut stats).unwrap();
+            // The record itself is untouched
+            assert_eq!(record.user_id, format!("user-{}", i));
+        }
+        assert_eq!(stats.unmapped_user_ids.len(), 101); // Plus "(other)"
+        assert_eq!(stats.unmapped_user_ids[OTHER_USERS], 9_900);
+        assert_eq!(stats.unmapped_user_ids.values().sum::<u64>(), 10_000);
+        assert!(stats.tracked_users.truncated);
+    }
+}
@@ -0,0 +1,430 @@
+// `--tui`: a full screen dashboard for long runs, drawn instead of the progress bar. Everything it
+// shows is read from the run's ProcessingStats, so it always agrees with the final summary.
+// Pressing q pauses the run and asks whether to commit what has been processed so far or roll back.
+use crate::timefmt::humanize_duration;
+use crate::ProcessingStats;
+use ratatui::backend::CrosstermBackend;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind};
+use ratatui::crossterm::execute;
+use ratatui::crossterm::terminal::{
+    disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen,
+};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Style, Stylize};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Borders, Clear, Gauge, List, Paragraph, Row, Table};
+use ratatui::{Frame, Terminal};
+use std::collections::VecDeque;
+use std::io::{self, Stdout};
+use std::time::{Duration, Instant};
+
+// Redraws are throttled so drawing doesn't slow processing down
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+// Lines kept in the log pane
+const MAX_LOG_LINES: usize = 200;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StopChoice {
+    Commit,   // Stop reading input and finalize the outputs with what was processed so far
+    Rollback, // Stop and undo the open SQLite transaction
+}
+
+// What a key press does, depending on whether the stop question is shown
+#[derive(Debug, PartialEq)]
+enum KeyAction {
+    Ignore,
+    AskToStop,
+    Stop(StopChoice),
+    KeepGoing,
+}
+
+fn key_action(key: KeyEvent, asking: bool) -> KeyAction {
+    if key.kind != KeyEventKind::Press {
+        return KeyAction::Ignore;
+    }
+    match (asking, key.code) {
+        (false, KeyCode::Char('q')) => KeyAction::AskToStop,
+        (true, KeyCode::Char('c')) => KeyAction::Stop(StopChoice::Commit),
+        (true, KeyCode::Char('r')) => KeyAction::Stop(StopChoice::Rollback),
+        (true, KeyCode::Esc) => KeyAction::KeepGoing,
+        _ => KeyAction::Ignore,
+    }
+}
+
+// The lines of the log pane, the oldest dropped past MAX_LOG_LINES
+#[derive(Debug, Default)]
+struct LogPane {
+    lines: VecDeque<String>,
+    disabled_logged: usize, // Entries of stats.sinks_disabled already in the log
+}
+
+impl LogPane {
+    fn push(&mut self, message: String) {
+        if self.lines.len() == MAX_LOG_LINES {
+            self.lines.pop_front();
+        }
+        self.lines.push_back(message);
+    }
+
+    // Logs the outputs disabled since the last call
+    fn note_disabled_sinks(&mut self, stats: &ProcessingStats) {
+        for disabled in &stats.sinks_disabled[self.disabled_logged..] {
+            self.push(format!("Output disabled: {}", disabled));
+        }
+        self.disabled_logged = stats.sinks_disabled.len();
+    }
+}
+
+pub struct Dashboard {
+    terminal: Terminal<CrosstermBackend<Stdout>>,
+    total_lines: u64,
+    started: Instant,
+    last_draw: Option<Instant>,
+    log: LogPane,
+}
+
+impl Dashboard {
+    pub fn start(total_lines: u64) -> io::Result<Dashboard> {
+        enable_raw_mode()?;
+        execute!(io::stdout(), EnterAlternateScreen)?;
+        Ok(Dashboard {
+            terminal: Terminal::new(CrosstermBackend::new(io::stdout()))?,
+            total_lines,
+            started: Instant::now(),
+            last_draw: None,
+            log: LogPane::default(),
+        })
+    }
+
+    pub fn log(&mut self, message: String) {
+        self.log.push(message);
+    }
+
+    // Redraws (at most every REDRAW_INTERVAL) and handles key presses. Returns the choice if the
+    // user asked to stop, after which the caller should stop processing records.
+    pub fn update(&mut self, stats: &ProcessingStats) -> io::Result<Option<StopChoice>> {
+        if self
+            .last_draw
+            .is_some_and(|last| last.elapsed() < REDRAW_INTERVAL)
+        {
+            return Ok(None);
+        }
+        self.log.note_disabled_sinks(stats);
+        self.draw(stats, false)?;
+        while event::poll(Duration::ZERO)? {
+            if let Event::Key(key) = event::read()? {
+                if key_action(key, false) == KeyAction::AskToStop {
+                    return self.ask_stop_choice(stats);
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    // Processing is paused while the question is shown
+    fn ask_stop_choice(&mut self, stats: &ProcessingStats) -> io::Result<Option<StopChoice>> {
+        self.draw(stats, true)?;
+        loop {
+            if let Event::Key(key) = event::read()? {
+                match key_action(key, true) {
+                    KeyAction::Stop(choice) => return Ok(Some(choice)),
+                    KeyAction::KeepGoing => {
+                        self.draw(stats, false)?;
+                        return Ok(None);
+                    }
+                    KeyAction::Ignore | KeyAction::AskToStop => {}
+                }
+            }
+        }
+    }
+
+    fn draw(&mut self, stats: &ProcessingStats, asking: bool) -> io::Result<()> {
+        let total_lines = self.total_lines;
+        let elapsed = self.started.elapsed();
+        let log = &self.log.lines;
+        self.terminal.draw(|frame| {
+            render(frame, stats, total_lines, elapsed, log);
+            if asking {
+                render_stop_prompt(frame);
+            }
+        })?;
+        self.last_draw = Some(Instant::now());
+        Ok(())
+    }
+}
+
+impl Drop for Dashboard {
+    fn drop(&mut self) {
+        // Also runs when processing fails, so the error is printed on a usable terminal
+        let _ = disable_raw_mode();
+        let _ = execute!(io::stdout(), LeaveAlternateScreen);
+    }
+}
+
+fn render(
+    frame: &mut Frame,
+    stats: &ProcessingStats,
+    total_lines: u64,
+    elapsed: Duration,
+    log: &VecDeque<String>,
+) {
+    let [progress_area, middle_area, log_area, help_area] = Layout::vertical([
+        Constraint::Length(3),
+        Constraint::Min(8),
+        Constraint::Length(8),
+        Constraint::Length(1),
+    ])
+    .areas(frame.area());
+    let [counters_area, users_area] =
+        Layout::horizontal([Constraint::Length(44), Constraint::Min(30)]).areas(middle_area);
+
+    let ratio = if total_lines == 0 {
+        0.0
+    } else {
+        (stats.records_processed as f64 / total_lines as f64).min(1.0)
+    };
+    frame.render_widget(
+        Gauge::default()
+            .block(Block::default().borders(Borders::ALL).title(" Progress "))
+            .gauge_style(Style::default().fg(Color::Cyan))
+            .ratio(ratio)
+            .label(format!(
+                "{}/{} ({:.0}%)",
+                stats.records_processed,
+                total_lines,
+                ratio * 100.0
+            )),
+        progress_area,
+    );
+
+    let mut lines = Vec::new();
+    if let Some(preload) = stats.preload_duration {
+        lines.push(format!("Dedup key preload: {}", humanize_duration(preload)));
+    }
+    lines.push(format!("Processing:        {}", humanize_duration(elapsed)));
+    lines.push(String::new());
+    lines.push(format!("Processed:         {}", stats.records_processed));
+    lines.push(format!("UserID changed:    {}", stats.records_changed));
+    lines.push(format!("Unchanged:         {}", stats.records_unchanged));
+    lines.push(format!(
+        "Inserted (SQLite): {}",
+        stats.records_inserted_sqlite
+    ));
+    lines.push(format!(
+        "Skipped (SQLite):  {}",
+        stats.records_skipped_sqlite
+    ));
+    lines.push(format!("Rejected/dropped:  {}", stats.records_dropped()));
+    if !stats.sinks_disabled.is_empty() {
+        lines.push(format!("Outputs disabled:  {}", stats.sinks_disabled.len()));
+    }
+    frame.render_widget(
+        Paragraph::new(lines.into_iter().map(Line::from).collect::<Vec<_>>())
+            .block(Block::default().borders(Borders::ALL).title(" Counters ")),
+        counters_area,
+    );
+
+    let mut users: Vec<(&String, &(String, u32))> = stats.changes_summary.iter().collect();
+    users.sort_by(|a, b| b.1 .1.cmp(&a.1 .1).then_with(|| a.0.cmp(b.0)));
+    frame.render_widget(
+        Table::new(
+            users.into_iter().map(|(old_id, (new_id, count))| {
+                Row::new(vec![old_id.clone(), new_id.clone(), count.to_string()])
+            }),
+            [
+                Constraint::Percentage(40),
+                Constraint::Percentage(40),
+                Constraint::Percentage(20),
+            ],
+        )
+        .header(Row::new(vec!["Old ID", "New ID", "Changes"]).bold())
+        .block(Block::default().borders(Borders::ALL).title(" Users ")),
+        users_area,
+    );
+
+    // Newest lines at the bottom, only as many as fit
+    let visible = log_area.height.saturating_sub(2) as usize;
+    frame.render_widget(
+        List::new(log.iter().skip(log.len().saturating_sub(visible)).cloned())
+            .block(Block::default().borders(Borders::ALL).title(" Log ")),
+        log_area,
+    );
+    frame.render_widget(Paragraph::new(" q: stop the run").dim(), help_area);
+}
+
+fn render_stop_prompt(frame: &mut Frame) {
+    let [_, row, _] = Layout::vertical([
+        Constraint::Fill(1),
+        Constraint::Length(5),
+        Constraint::Fill(1),
+    ])
+    .areas(frame.area());
+    let [_, area, _] = Layout::horizontal([
+        Constraint::Fill(1),
+        Constraint::Length(60),
+        Constraint::Fill(1),
+    ])
+    .areas(row);
+    frame.render_widget(Clear, area);
+    frame.render_widget(
+        Paragraph::new(vec![
+            Line::from(" c: commit the records processed so far and stop"),
+            Line::from(" r: roll back the open SQLite transaction and stop"),
+            Line::from(" Esc: keep going"),
+        ])
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title(" Stop the run? "),
+        ),
+        area,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ratatui::backend::TestBackend;
+    use ratatui::crossterm::event::KeyModifiers;
+    use std::collections::BTreeMap;
+
+    fn press(code: KeyCode) -> KeyEvent {
+        KeyEvent::new(code, KeyModifiers::NONE)
+    }
+
+    // The drawn screen, one string per row
+    fn draw(
+        stats: &ProcessingStats,
+        total_lines: u64,
+        log: &VecDeque<String>,
+        asking: bool,
+    ) -> Vec<String> {
+        let mut terminal = Terminal::new(TestBackend::new(100, 24)).unwrap();
+        terminal
+            .draw(|frame| {
+                render(frame, stats, total_lines, Duration::from_secs(90), log);
+                if asking {
+                    render_stop_prompt(frame);
+                }
+            })
+            .unwrap();
+        let buffer = terminal.backend().buffer();
+        (0..buffer.area.height)
+            .map(|y| {
+                (0..buffer.area.width)
+                    .map(|x| buffer[(x, y)].symbol())
+                    .collect::<String>()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn q_asks_and_the_answer_picks_commit_or_rollback() {
+        assert_eq!(
+            key_action(press(KeyCode::Char('q')), false),
+            KeyAction::AskToStop
+        );
+        // Only c, r and Esc answer the question, q or a stray key leave it shown
+        assert_eq!(
+            key_action(press(KeyCode::Char('c')), true),
+            KeyAction::Stop(StopChoice::Commit)
+        );
+        assert_eq!(
+            key_action(press(KeyCode::Char('r')), true),
+            KeyAction::Stop(StopChoice::Rollback)
+        );
+        assert_eq!(key_action(press(KeyCode::Esc), true), KeyAction::KeepGoing);
+        assert_eq!(
+            key_action(press(KeyCode::Char('q')), true),
+            KeyAction::Ignore
+        );
+        assert_eq!(
+            key_action(press(KeyCode::Char('x')), true),
+            KeyAction::Ignore
+        );
+        // c and r do nothing before q, and releasing q doesn't count as pressing it
+        assert_eq!(
+            key_action(press(KeyCode::Char('c')), false),
+            KeyAction::Ignore
+        );
+        let mut release = press(KeyCode::Char('q'));
+        release.kind = KeyEventKind::Release;
+        assert_eq!(key_action(release, false), KeyAction::Ignore);
+    }
+
+    #[test]
+    fn log_pane_keeps_the_newest_lines_and_logs_each_disabled_output_once() {
+        let mut log = LogPane::default();
+        for i in 0..MAX_LOG_LINES + 5 {
+            log.push(format!("line {}", i));
+        }
+        assert_eq!(log.lines.len(), MAX_LOG_LINES);
+        assert_eq!(log.lines.front().unwrap(), "line 5");
+
+        let mut log = LogPane::default();
+        let mut stats = ProcessingStats::default();
+        stats.sinks_disabled.push("TSV: disk full".to_string());
+        log.note_disabled_sinks(&stats);
+        log.note_disabled_sinks(&stats);
+        stats.sinks_disabled.push("SQLite: locked".to_string());
+        log.note_disabled_sinks(&stats);
+        assert_eq!(
+            log.lines,
+            [
+                "Output disabled: TSV: disk full",
+                "Output disabled: SQLite: locked"
+            ]
+        );
+    }
+
+    #[test]
+    fn shows_the_progress_and_the_counters() {
+        let stats = ProcessingStats {
+            records_processed: 250,
+            records_changed: 200,
+            records_unchanged: 48,
+            records_dropped_retention: 2,
+            records_inserted_sqlite: 180,
+            records_skipped_sqlite: 68,
+            changes_summary: BTreeMap::from([
+                ("old-a".to_string(), ("new-a".to_string(), 50)),
+                ("old-b".to_string(), ("new-b".to_string(), 150)),
+            ]),
+            ..Default::default()
+        };
+        let log = VecDeque::from(["Processing in.tsv".to_string()]);
+        let screen = draw(&stats, 1000, &log, false);
+        let text = screen.join("\n");
+        for expected in [
+            "250/1000 (25%)",
+            "Processing:        1m 30s",
+            "Processed:         250",
+            "UserID changed:    200",
+            "Unchanged:         48",
+            "Inserted (SQLite): 180",
+            "Skipped (SQLite):  68",
+            "Rejected/dropped:  2",
+            "Processing in.tsv",
+        ] {
+            assert!(text.contains(expected), "{} not in\n{}", expected, text);
+        }
+        assert!(!text.contains("Outputs disabled"), "{}", text);
+        // Users with the most changes first
+        let row = |id: &str| screen.iter().position(|line| line.contains(id)).unwrap();
+        assert!(row("old-b") < row("old-a"), "{}", text);
+
+        // More lines than the input had (it grew) stay at 100%, an empty input at 0%
+        let text = draw(&stats, 200, &log, false).join("\n");
+        assert!(text.contains("250/200 (100%)"), "{}", text);
+        let text = draw(&ProcessingStats::default(), 0, &log, false).join("\n");
+        assert!(text.contains("0/0 (0%)"), "{}", text);
+
+        let text = draw(&stats, 1000, &log, true).join("\n");
+        assert!(text.contains("Stop the run?"), "{}", text);
+        assert!(
+            text.contains("r: roll back the open SQLite transaction"),
+            "{}",
+            text
+        );
+    }
+}
@@ -0,0 +1,506 @@
+// PlayDuration units. The plugin stores seconds, but at least one fork stored ticks (100ns) and a
+// database that went through both ends up with a mix, which makes every chart absurd. Values are
+// classified by magnitude: a play can't plausibly last 115 days (10,000,000 seconds) and a real
+// play is rarely under 0.1s (1,000,000 ticks). play_duration_unit is "seconds" (default, nothing
+// is converted), "ticks", or "auto" to classify the whole input before processing. A mix is never
+// converted automatically, [duration_unit_overrides] has to say which DateCreated ranges are which.
+use crate::playduration::{PlayDurationStats, Rounding};
+use crate::retention::parse_date_created;
+use crate::{Config, TsvRecord};
+use chrono::{NaiveDate, NaiveDateTime};
+use serde::Deserialize;
+use std::error::Error;
+use tracing::info;
+
+const TICKS_PER_SECOND: i64 = 10_000_000;
+// Values below this are taken as seconds (11.5 days)
+const SECONDS_BELOW: i64 = 1_000_000;
+// Values from this up are taken as ticks (1 second)
+const TICKS_FROM: i64 = TICKS_PER_SECOND;
+// Each unit has at least this share of the classified values in a mixed input
+const MIXED_MIN_SHARE: f64 = 0.02;
+// Below this share of the dominant unit (mostly ambiguous values) nothing is detected
+const MIN_CONFIDENCE: f64 = 0.5;
+
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Unit {
+    #[default]
+    Seconds,
+    Ticks,
+}
+
+impl Unit {
+    fn name(self) -> &'static str {
+        match self {
+            Unit::Seconds => "seconds",
+            Unit::Ticks => "ticks",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum UnitSetting {
+    #[default]
+    Seconds,
+    Ticks,
+    Auto,
+}
+
+// A key of [duration_unit_overrides], e.g. "2019-01-01/2021-06-30" (whole days, both included).
+// Either end may be left out: "/2021-06-30", "2021-07-01/".
+#[derive(Debug, Clone, PartialEq)]
+struct DateRange {
+    key: String,
+    from: Option<NaiveDate>,
+    to: Option<NaiveDate>,
+}
+
+impl DateRange {
+    fn parse(key: &str) -> Result<DateRange, String> {
+        let invalid = || {
+            format!(
+                "Invalid duration_unit_overrides range '{}', expected e.g. \"2019-01-01/2021-06-30\" (either end may be left out)",
+                key
+            )
+        };
+        let (from, to) = key.split_once('/').ok_or_else(invalid)?;
+        let date = |value: &str| match value.trim() {
+            "" => Ok(None),
+            value => NaiveDate::parse_from_str(value, "%Y-%m-%d")
+                .map(Some)
+                .map_err(|_| invalid()),
+        };
+        let range = DateRange {
+            key: key.to_string(),
+            from: date(from)?,
+            to: date(to)?,
+        };
+        match (range.from, range.to) {
+            (None, None) => Err(invalid()),
+            (Some(from), Some(to)) if from > to => Err(invalid()),
+            _ => Ok(range),
+        }
+    }
+
+    fn contains(&self, date: NaiveDate) -> bool {
+        self.from.is_none_or(|from| date >= from) && self.to.is_none_or(|to| date <= to)
+    }
+
+    fn overlaps(&self, other: &DateRange) -> bool {
+        let start = self.from.max(other.from); // None sorts first, like an open start
+        let end = match (self.to, other.to) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        match (start, end) {
+            (Some(start), Some(end)) => start <= end,
+            _ => true,
+        }
+    }
+}
+
+fn parse_overrides(config: &Config) -> Result<Vec<(DateRange, Unit)>, String> {
+    let mut overrides = config
+        .duration_unit_overrides
+        .iter()
+        .map(|(key, unit)| Ok((DateRange::parse(key)?, *unit)))
+        .collect::<Result<Vec<_>, String>>()?;
+    overrides.sort_by(|(a, _), (b, _)| a.key.cmp(&b.key));
+    for (i, (a, _)) in overrides.iter().enumerate() {
+        if let Some((b, _)) = overrides[i + 1..].iter().find(|(b, _)| a.overlaps(b)) {
+            return Err(format!(
+                "duration_unit_overrides ranges '{}' and '{}' overlap",
+                a.key, b.key
+            ));
+        }
+    }
+    Ok(overrides)
+}
+
+fn override_for(overrides: &[(DateRange, Unit)], date_created: &str) -> Option<Unit> {
+    let date = parse_date_created(date_created)?.date();
+    overrides
+        .iter()
+        .find(|(range, _)| range.contains(date))
+        .map(|(_, unit)| *unit)
+}
+
+// The unit of each record's PlayDuration, resolved before processing
+#[derive(Debug, Default)]
+pub struct UnitPlan {
+    default: Unit,
+    overrides: Vec<(DateRange, Unit)>,
+}
+
+impl UnitPlan {
+    // Converts a PlayDuration in ticks to seconds, after playduration::canonicalize
+    pub fn convert(
+        &self,
+        record: &mut TsvRecord,
+        rounding: Rounding,
+        stats: &mut PlayDurationStats,
+    ) {
+        let unit = override_for(&self.overrides, &record.date_created).unwrap_or(self.default);
+        if unit != Unit::Ticks {
+            return;
+        }
+        if let Ok(ticks) = record.play_duration.parse::<i64>() {
+            record.play_duration = ticks_to_seconds(ticks, rounding).to_string();
+            stats.converted_from_ticks += 1;
+        }
+    }
+}
+
+fn ticks_to_seconds(ticks: i64, rounding: Rounding) -> i64 {
+    let seconds = ticks as f64 / TICKS_PER_SECOND as f64;
+    (match rounding {
+        Rounding::Nearest => seconds.round(),
+        Rounding::Down => seconds.floor(),
+        Rounding::Up => seconds.ceil(),
+    }) as i64
+}
+
+// Values of one unit and the DateCreated span they were logged in
+#[derive(Debug, Default)]
+struct Band {
+    count: u64,
+    first: Option<NaiveDateTime>,
+    last: Option<NaiveDateTime>,
+}
+
+impl Band {
+    fn add(&mut self, date: Option<NaiveDateTime>) {
+        self.count += 1;
+        if let Some(date) = date {
+            self.first = Some(self.first.map_or(date, |first| first.min(date)));
+            self.last = Some(self.last.map_or(date, |last| last.max(date)));
+        }
+    }
+
+    fn span(&self) -> String {
+        match (self.first, self.last) {
+            (Some(first), Some(last)) => format!(
+                ", DateCreated {} to {}",
+                first.format("%Y-%m-%d"),
+                last.format("%Y-%m-%d")
+            ),
+            _ => String::new(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Classification {
+    Unknown,
+    Uniform(Unit, f64), // With the share of values in that unit's range
+    Mixed,
+}
+
+#[derive(Debug, Default)]
+pub struct UnitAnalysis {
+    seconds: Band,
+    ticks: Band,
+    ambiguous: u64,   // Between the two ranges
+    zero: u64,        // The same in either unit
+    not_numeric: u64, // Left as text by playduration.rs
+    covered_by_overrides: u64,
+}
+
+impl UnitAnalysis {
+    fn add(&mut self, date_created: &str, play_duration: &str, overrides: &[(DateRange, Unit)]) {
+        if override_for(overrides, date_created).is_some() {
+            self.covered_by_overrides += 1;
+            return;
+        }
+        let Ok(value) = play_duration.trim().parse::<f64>() else {
+            self.not_numeric += 1;
+            return;
+        };
+        let date = parse_date_created(date_created);
+        match value.abs() as i64 {
+            0 => self.zero += 1,
+            n if n < SECONDS_BELOW => self.seconds.add(date),
+            n if n >= TICKS_FROM => self.ticks.add(date),
+            _ => self.ambiguous += 1,
+        }
+    }
+
+    // Every value that could tell the units apart is in an override's range
+    fn is_all_covered(&self) -> bool {
+        self.covered_by_overrides > 0 && self.seconds.count + self.ticks.count + self.ambiguous == 0
+    }
+
+    pub fn classification(&self) -> Classification {
+        let classified = self.seconds.count + self.ticks.count + self.ambiguous;
+        if classified == 0 {
+            return Classification::Unknown;
+        }
+        let share = |count: u64| count as f64 / classified as f64;
+        let (seconds, ticks) = (share(self.seconds.count), share(self.ticks.count));
+        if seconds >= MIXED_MIN_SHARE && ticks >= MIXED_MIN_SHARE {
+            Classification::Mixed
+        } else if seconds.max(ticks) < MIN_CONFIDENCE {
+            Classification::Unknown
+        } else if seconds >= ticks {
+            Classification::Uniform(Unit::Seconds, seconds)
+        } else {
+            Classification::Uniform(Unit::Ticks, ticks)
+        }
+    }
+
+    fn print(&self) {
+        let classified = (self.seconds.count + self.ticks.count + self.ambiguous).max(1);
+        let percent = |count: u64| 100.0 * count as f64 / classified as f64;
+        println!(
+            "  In the seconds range (below {}): {} ({:.1}%){}",
+            SECONDS_BELOW,
+            self.seconds.count,
+            percent(self.seconds.count),
+            self.seconds.span()
+        );
+        println!(
+            "  In the ticks range ({} and up): {} ({:.1}%){}",
+            TICKS_FROM,
+            self.ticks.count,
+            percent(self.ticks.count),
+            self.ticks.span()
+        );
+        println!(
+            "  Ambiguous (in between): {} ({:.1}%)",
+            self.ambiguous,
+            percent(self.ambiguous)
+        );
+        println!(
+            "  Not counted: {} zero, {} not numbers, {} covered by duration_unit_overrides",
+            self.zero, self.not_numeric, self.covered_by_overrides
+        );
+        match self.classification() {
+            Classification::Unknown => {
+                println!("  Classification: unknown (too few values in either range)")
+            }
+            Classification::Uniform(unit, share) => println!(
+                "  Classification: {} (confidence {:.1}%)",
+                unit.name(),
+                100.0 * share
+            ),
+            Classification::Mixed => {
+                println!("  Classification: MIXED, the input has values in both units");
+                println!("{}", self.mixed_advice());
+            }
+        }
+    }
+
+    // Suggests overrides when the two units were logged in separate periods
+    fn mixed_advice(&self) -> String {
+        match (self.seconds.last, self.ticks.first, self.ticks.last, self.seconds.first) {
+            (Some(seconds_last), Some(ticks_first), _, _) if seconds_last < ticks_first => {
+                format!(
+                    "  Seconds were logged until {} and ticks from {}, e.g.:\n  [duration_unit_overrides]\n  \"/{}\" = \"seconds\"\n  \"{}/\" = \"ticks\"",
+                    seconds_last.format("%Y-%m-%d"),
+                    ticks_first.format("%Y-%m-%d"),
+                    seconds_last.format("%Y-%m-%d"),
+                    ticks_first.format("%Y-%m-%d")
+                )
+            }
+            (_, _, Some(ticks_last), Some(seconds_first)) if ticks_last < seconds_first => {
+                format!(
+                    "  Ticks were logged until {} and seconds from {}, e.g.:\n  [duration_unit_overrides]\n  \"/{}\" = \"ticks\"\n  \"{}/\" = \"seconds\"",
+                    ticks_last.format("%Y-%m-%d"),
+                    seconds_first.format("%Y-%m-%d"),
+                    ticks_last.format("%Y-%m-%d"),
+                    seconds_first.format("%Y-%m-%d")
+                )
+            }
+            _ => "  The periods of the two units overlap, so [duration_unit_overrides] ranges have to be chosen by hand.".to_string(),
+        }
+    }
+}
+
+fn analyze_input(
+    path: &str,
+    overrides: &[(DateRange, Unit)],
+) -> Result<UnitAnalysis, Box<dyn Error + Send + Sync>> {
+    let mut rdr = csv::ReaderBuilder::new()
+        .delimiter(b'\t')
+        .has_headers(false)
+        .comment(Some(b'#'))
+        .flexible(true) // Short lines are reported by the run itself
+        .from_path(path)?;
+    let mut analysis = UnitAnalysis::default();
+    for result in rdr.records() {
+        let record = result?;
+        analysis.add(
+            record.get(0).unwrap_or(""),
+            record.get(8).unwrap_or(""),
+            overrides,
+        );
+    }
+    Ok(analysis)
+}
+
+// Fills in config.duration_units, classifying the input first with play_duration_unit = "auto"
+pub fn resolve(config: &mut Config) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let overrides = parse_overrides(config)?;
+    let default = match config.play_duration_unit {
+        UnitSetting::Seconds => Unit::Seconds,
+        UnitSetting::Ticks => Unit::Ticks,
+        UnitSetting::Auto => {
+            let analysis = analyze_input(&config.input_tsv_file_path, &overrides)?;
+            match analysis.classification() {
+                Classification::Uniform(unit, share) => {
+                    info!(
+                        "PlayDuration unit detected: {} ({:.1}% of the values){}",
+                        unit.name(),
+                        100.0 * share,
+                        if unit == Unit::Ticks {
+                            ", converting them to seconds"
+                        } else {
+                            ""
+                        }
+                    );
+                    unit
+                }
+                Classification::Unknown if analysis.is_all_covered() => {
+                    info!("PlayDuration units are set by duration_unit_overrides for the whole input.");
+                    Unit::Seconds
+                }
+                Classification::Unknown => {
+                    info!("PlayDuration unit couldn't be detected, assuming seconds.");
+                    Unit::Seconds
+                }
+                Classification::Mixed => {
+                    println!("\nPlayDuration unit analysis:");
+                    analysis.print();
+                    return Err("PlayDuration values are a mix of seconds and ticks, so they aren't converted automatically. Add [duration_unit_overrides] for the DateCreated ranges of each unit (see above), or set play_duration_unit.".into());
+                }
+            }
+        }
+    };
+    config.duration_units = UnitPlan { default, overrides };
+    Ok(())
+}
+
+// The unit part of --analyze
+pub fn print_analysis(config: &Config) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let overrides = parse_overrides(config)?;
+    let analysis = analyze_input(&config.input_tsv_file_path, &overrides)?;
+    println!(
+        "\nPlayDuration unit analysis of '{}':",
+        config.input_tsv_file_path
+    );
+    analysis.print();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn analysis(rows: &[(&str, i64)], overrides: &[(DateRange, Unit)]) -> UnitAnalysis {
+        let mut analysis = UnitAnalysis::default();
+        for (date, duration) in rows {
+            analysis.add(date, &duration.to_string(), overrides);
+        }
+        analysis
+    }
+
+    // A play a day of 10 to 59 minutes from `start` on, in seconds or ticks
+    fn plays(start: &str, days: i64, unit: Unit) -> Vec<(String, i64)> {
+        let start = NaiveDate::parse_from_str(start, "%Y-%m-%d").unwrap();
+        (0..days)
+            .map(|day| {
+                let seconds = 600 + (day * 37) % 3000;
+                (
+                    format!("{} 20:00:00", start + chrono::Duration::days(day)),
+                    match unit {
+                        Unit::Seconds => seconds,
+                        Unit::Ticks => seconds * TICKS_PER_SECOND,
+                    },
+                )
+            })
+            .collect()
+    }
+
+    fn rows(plays: &[(String, i64)]) -> Vec<(&str, i64)> {
+        plays.iter().map(|(date, n)| (date.as_str(), *n)).collect()
+    }
+
+    #[test]
+    fn classifies_seconds_ticks_and_mixed_inputs() {
+        let seconds = plays("2020-01-01", 400, Unit::Seconds);
+        assert_eq!(
+            analysis(&rows(&seconds), &[]).classification(),
+            Classification::Uniform(Unit::Seconds, 1.0)
+        );
+
+        let ticks = plays("2021-02-04", 300, Unit::Ticks);
+        assert_eq!(
+            analysis(&rows(&ticks), &[]).classification(),
+            Classification::Uniform(Unit::Ticks, 1.0)
+        );
+
+        let mixed: Vec<(String, i64)> = seconds.iter().chain(&ticks).cloned().collect();
+        let mixed_analysis = analysis(&rows(&mixed), &[]);
+        assert_eq!(mixed_analysis.classification(), Classification::Mixed);
+        let advice = mixed_analysis.mixed_advice();
+        assert!(
+            advice.contains("\"/2021-02-03\" = \"seconds\""),
+            "{}",
+            advice
+        );
+        assert!(advice.contains("\"2021-02-04/\" = \"ticks\""), "{}", advice);
+
+        // With the ticks period covered only seconds are left to classify
+        let overrides = vec![(DateRange::parse("2021-02-04/").unwrap(), Unit::Ticks)];
+        assert_eq!(
+            analysis(&rows(&mixed), &overrides).classification(),
+            Classification::Uniform(Unit::Seconds, 1.0)
+        );
+        let plan = UnitPlan {
+            default: Unit::Seconds,
+            overrides,
+        };
+        let mut stats = PlayDurationStats::default();
+        let mut record = |date: &str, duration: &str| {
+            let mut record = TsvRecord {
+                date_created: date.to_string(),
+                user_id: String::new(),
+                item_id: String::new(),
+                item_type: String::new(),
+                item_name: String::new(),
+                playback_method: String::new(),
+                client_name: String::new(),
+                device_name: String::new(),
+                play_duration: duration.to_string(),
+            };
+            plan.convert(&mut record, Rounding::Nearest, &mut stats);
+            record.play_duration
+        };
+        assert_eq!(record("2021-02-04 20:00:00", "36000000000"), "3600");
+        assert_eq!(record("2021-02-03 20:00:00", "3600"), "3600");
+        assert_eq!(stats.converted_from_ticks, 1);
+    }
+
+    #[test]
+    fn parses_and_checks_override_ranges() {
+        let range = DateRange::parse("2019-01-01/2021-06-30").unwrap();
+        let date = |value: &str| NaiveDate::parse_from_str(value, "%Y-%m-%d").unwrap();
+        assert!(range.contains(date("2021-06-30")));
+        assert!(!range.contains(date("2021-07-01")));
+        assert!(DateRange::parse("/2021-06-30").is_ok());
+        assert!(DateRange::parse("/").is_err());
+        assert!(DateRange::parse("2021-06-30").is_err());
+        assert!(DateRange::parse("2021-06-30/2019-01-01").is_err());
+        let overlaps = |a: &str, b: &str| {
+            DateRange::parse(a)
+                .unwrap()
+                .overlaps(&DateRange::parse(b).unwrap())
+        };
+        assert!(overlaps("/2021-06-30", "2021-06-30/"));
+        assert!(!overlaps("/2021-06-30", "2021-07-01/"));
+        assert!(overlaps("2020-01-01/", "2021-01-01/"));
+        assert!(!overlaps("2020-01-01/2020-12-31", "2021-01-01/2021-12-31"));
+    }
+}
@@ -0,0 +1,81 @@
+// `unmapped_report_path`: a TSV of the users the mapping left out on both sides, to audit after a
+// run whose mapping output has long scrolled away. Old users that got no mapping are listed with
+// why, new users that no mapping targets as orphans. Written on every run that builds the map,
+// also (and especially) when the map ends up empty.
+use crate::JellyfinUser;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use tracing::info;
+
+pub fn write_report(
+    path: &str,
+    unmapped_old: &[(&JellyfinUser, &'static str)],
+    new_users: &[JellyfinUser],
+    user_id_map: &HashMap<String, String>,
+) -> io::Result<()> {
+    let targeted: HashSet<&String> = user_id_map.values().collect();
+    let orphans: Vec<&JellyfinUser> = new_users
+        .iter()
+        .filter(|user| !targeted.contains(&user.id))
+        .collect();
+    let mut file = BufWriter::new(File::create(path)?);
+    writeln!(file, "Instance\tId\tName\tReason")?;
+    for (user, reason) in unmapped_old {
+        writeln!(file, "old\t{}\t{}\t{}", user.id, clean(&user.name), reason)?;
+    }
+    for user in &orphans {
+        writeln!(
+            file,
+            "new\t{}\t{}\tno old user is mapped to it",
+            user.id,
+            clean(&user.name)
+        )?;
+    }
+    file.flush()?;
+    info!(
+        "  Wrote {} unmapped old user(s) and {} new user(s) without a mapping to '{}'.",
+        unmapped_old.len(),
+        orphans.len(),
+        path
+    );
+    Ok(())
+}
+
+// A tab or newline in a user name would break the row
+pub fn clean(name: &str) -> String {
+    name.replace(['\t', '\n', '\r'], " ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(name: &str, id: &str) -> JellyfinUser {
+        JellyfinUser {
+            id: id.to_string(),
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn lists_both_sides_even_with_an_empty_map() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("unmapped.tsv");
+        let path = path.to_str().unwrap();
+        let carol = user("carol\tc", "old-c");
+        let new = vec![user("alice", "new-a"), user("dave", "new-d")];
+        let map = HashMap::from([("old-a".to_string(), "new-a".to_string())]);
+        write_report(path, &[(&carol, "not found by name or email")], &new, &map).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(path).unwrap(),
+            "Instance\tId\tName\tReason\n\
+             old\told-c\tcarol c\tnot found by name or email\n\
+             new\tnew-d\tdave\tno old user is mapped to it\n"
+        );
+
+        write_report(path, &[], &new, &HashMap::new()).unwrap();
+        assert_eq!(std::fs::read_to_string(path).unwrap().lines().count(), 3);
+    }
+}
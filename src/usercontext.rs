@@ -0,0 +1,139 @@
+// Details that help recognize the same person on both instances when their names have nothing in
+// common (e.g. everyone picked a new handle on the new server): whether they have an avatar and
+// its tag, how many libraries they can access, whether they are an administrator and when they
+// were last active. Only shown, never used to map anyone: pairs found this way go in
+// user_map_override_path. Fetched lazily from /Users/{id}, and only for users left unmatched. A
+// user the server no longer has (404) or a failed request just shows no details.
+use crate::api::ApiClient;
+use crate::error::MigrationError;
+use crate::{InstanceConfig, JellyfinUser};
+use reqwest::StatusCode;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use tracing::{info, warn};
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct UserDetails {
+    #[serde(default)]
+    primary_image_tag: Option<String>, // None when the user has no avatar
+    #[serde(default)]
+    last_activity_date: Option<String>,
+    #[serde(default)]
+    policy: Option<UserPolicy>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct UserPolicy {
+    #[serde(default)]
+    is_administrator: bool,
+    #[serde(default)]
+    enable_all_folders: bool,
+    #[serde(default)]
+    enabled_folders: Vec<String>,
+}
+
+// e.g. "avatar tag 8f2c1e, 3 libraries, admin, last active 2024-05-01"
+fn describe(details: &UserDetails) -> String {
+    let mut parts = vec![match details.primary_image_tag {
+        Some(ref tag) => format!("avatar tag {}", tag),
+        None => "no avatar".to_string(),
+    }];
+    if let Some(ref policy) = details.policy {
+        parts.push(if policy.enable_all_folders {
+            "all libraries".to_string()
+        } else {
+            format!("{} libraries", policy.enabled_folders.len())
+        });
+        if policy.is_administrator {
+            parts.push("admin".to_string());
+        }
+    }
+    parts.push(match details.last_activity_date {
+        // Only the date, the time of day doesn't help telling people apart
+        Some(ref date) => format!("last active {}", date.get(..10).unwrap_or(date)),
+        None => "never active".to_string(),
+    });
+    parts.join(", ")
+}
+
+async fn fetch_details(
+    api: &ApiClient,
+    instance_config: &InstanceConfig,
+    user: &JellyfinUser,
+) -> Option<UserDetails> {
+    let path = format!("/Users/{}", user.id);
+    match api.get_json_at_startup(instance_config, &path).await {
+        Ok(details) => Some(details),
+        Err(e) => {
+            let gone = matches!(
+                e.downcast_ref::<MigrationError>(),
+                Some(MigrationError::ApiRequest { status, .. }) if *status == StatusCode::NOT_FOUND
+            );
+            if !gone {
+                warn!("  Couldn't fetch details of '{}': {}", user.name, e);
+            }
+            None
+        }
+    }
+}
+
+async fn print_users(api: &ApiClient, instance_config: &InstanceConfig, users: &[&JellyfinUser]) {
+    for user in users {
+        let details = match fetch_details(api, instance_config, user).await {
+            Some(details) => describe(&details),
+            None => "no details".to_string(),
+        };
+        info!("    '{}' (ID: '{}'): {}", user.name, user.id, details);
+    }
+}
+
+// Old users without a mapping and new users nobody was mapped to, side by side
+pub async fn print_unmatched(
+    api: &ApiClient,
+    instance_old: &InstanceConfig,
+    instance_new: &InstanceConfig,
+    old_users: &[JellyfinUser],
+    new_users: &[JellyfinUser],
+    user_id_map: &HashMap<String, String>,
+) {
+    let claimed: HashSet<&String> = user_id_map.values().collect();
+    let unmatched_old: Vec<&JellyfinUser> = old_users
+        .iter()
+        .filter(|user| !user_id_map.contains_key(&user.id))
+        .collect();
+    let unmatched_new: Vec<&JellyfinUser> = new_users
+        .iter()
+        .filter(|user| !claimed.contains(&user.id))
+        .collect();
+    if unmatched_old.is_empty() {
+        return;
+    }
+    info!("\nUnmatched users, with details to recognize them by (map them in user_map_override_path):");
+    info!("  Old instance:");
+    print_users(api, instance_old, &unmatched_old).await;
+    info!("  New instance (users nobody was mapped to):");
+    print_users(api, instance_new, &unmatched_new).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn describes_avatar_libraries_and_activity() {
+        let details: UserDetails = serde_json::from_str(
+            r#"{"PrimaryImageTag": "8f2c1e", "LastActivityDate": "2024-05-01T18:22:10.000Z",
+                "Policy": {"IsAdministrator": true, "EnabledFolders": ["a", "b", "c"]}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            describe(&details),
+            "avatar tag 8f2c1e, 3 libraries, admin, last active 2024-05-01"
+        );
+        let details: UserDetails =
+            serde_json::from_str(r#"{"Policy": {"EnableAllFolders": true}}"#).unwrap();
+        assert_eq!(describe(&details), "no avatar, all libraries, never active");
+    }
+}
@@ -0,0 +1,146 @@
+// `include_users` / `exclude_users`: carry over the history of only some of the old instance's
+// users. Entries are names or IDs of old users. Excluded users (with include_users, every user not
+// listed, and UserIds the old instance doesn't know at all) aren't mapped, and their records are
+// skipped before retention and the outputs, counted as skipped by the user filter. Only one of the
+// lists can be set. Entries matching no old user are warned about and otherwise ignored.
+use crate::{Config, JellyfinUser, ProcessingStats, TsvRecord};
+use std::collections::HashSet;
+use tracing::{debug, info, warn};
+
+#[derive(Debug, Default)]
+pub struct UserFilter {
+    include: bool,                 // Otherwise `ids` are the excluded users
+    ids: HashSet<String>,          // Old user IDs the lists resolved to
+    setting: Option<&'static str>, // None without a filter
+}
+
+pub fn validate(config: &Config) -> Result<(), String> {
+    if !config.include_users.is_empty() && !config.exclude_users.is_empty() {
+        return Err("include_users and exclude_users can't both be set. List the users to migrate in include_users, or the ones to leave out in exclude_users.".to_string());
+    }
+    Ok(())
+}
+
+impl UserFilter {
+    pub fn new(config: &Config, old_users: &[JellyfinUser]) -> UserFilter {
+        let (include, setting, entries) = if !config.include_users.is_empty() {
+            (true, "include_users", &config.include_users)
+        } else if !config.exclude_users.is_empty() {
+            (false, "exclude_users", &config.exclude_users)
+        } else {
+            return UserFilter::default();
+        };
+        let mut ids = HashSet::new();
+        for entry in entries {
+            let matched: Vec<&JellyfinUser> = old_users
+                .iter()
+                .filter(|user| user.id == *entry || user.name == *entry)
+                .collect();
+            if matched.is_empty() {
+                warn!(
+                    "{} entry '{}' doesn't match the name or ID of any user on the old instance.",
+                    setting, entry
+                );
+            }
+            ids.extend(matched.into_iter().map(|user| user.id.clone()));
+        }
+        UserFilter {
+            include,
+            ids,
+            setting: Some(setting),
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.setting.is_some()
+    }
+
+    pub fn keeps(&self, old_id: &str) -> bool {
+        match self.setting {
+            None => true,
+            Some(_) => self.ids.contains(old_id) == self.include,
+        }
+    }
+
+    // Returns false (and counts it) if the record's user is filtered out
+    pub fn retain(&self, record: &TsvRecord, stats: &mut ProcessingStats) -> bool {
+        if self.keeps(&record.user_id) {
+            return true;
+        }
+        debug!(
+            "Record {} '{}' of UserId '{}' is left out by the user filter, skipped",
+            record.date_created, record.item_name, record.user_id
+        );
+        stats.records_skipped_user_filter += 1;
+        false
+    }
+
+    // e.g. "  Leaving out 2 old users (exclude_users): 'bob' (old-b), 'carol' (old-c)"
+    pub fn print_left_out(&self, old_users: &[JellyfinUser]) {
+        let Some(setting) = self.setting else {
+            return;
+        };
+        let left_out: Vec<String> = old_users
+            .iter()
+            .filter(|user| !self.keeps(&user.id))
+            .map(|user| format!("'{}' ({})", user.name, user.id))
+            .collect();
+        if left_out.is_empty() {
+            info!("  No old users are left out by {}.", setting);
+        } else {
+            info!(
+                "  Leaving out {} old user(s) ({}): {}",
+                left_out.len(),
+                setting,
+                left_out.join(", ")
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config_from_toml;
+
+    fn user(id: &str, name: &str) -> JellyfinUser {
+        JellyfinUser {
+            id: id.to_string(),
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn config(lists: &str) -> Config {
+        config_from_toml(&format!("input_tsv_file_path = \"in.tsv\"\n{}", lists))
+    }
+
+    #[test]
+    fn includes_or_excludes_by_name_or_id() {
+        let old = [
+            user("old-a", "alice"),
+            user("old-b", "bob"),
+            user("old-c", "carol"),
+        ];
+        let filter = UserFilter::new(
+            &config("include_users = [\"alice\", \"old-c\", \"zed\"]"),
+            &old,
+        );
+        assert!(filter.keeps("old-a"));
+        assert!(!filter.keeps("old-b"));
+        assert!(filter.keeps("old-c"));
+        assert!(!filter.keeps("unknown"));
+
+        let filter = UserFilter::new(&config("exclude_users = [\"bob\"]"), &old);
+        assert!(filter.keeps("old-a"));
+        assert!(!filter.keeps("old-b"));
+        assert!(filter.keeps("unknown"));
+
+        let filter = UserFilter::new(&config(""), &old);
+        assert!(!filter.is_active());
+        assert!(filter.keeps("old-b"));
+
+        let both = config("include_users = [\"alice\"]\nexclude_users = [\"bob\"]");
+        assert!(validate(&both).is_err());
+    }
+}
@@ -0,0 +1,197 @@
+// Fetching the user list. /Users is requested in pages of an instance's user_page_size (500 by
+// default, `startIndex`/`limit`) with a progress bar until a page comes back short, so instances
+// with thousands of users aren't fetched in one large response that a proxy may time out or the
+// server may truncate. user_page_size = 0 fetches it in one request. Servers that ignore the
+// paging parameters are detected and the list is fetched in one request after all.
+use crate::api::ApiClient;
+use crate::{dto, InstanceConfig, JellyfinUser};
+use indicatif::{ProgressBar, ProgressStyle};
+use serde_json::Value;
+use std::error::Error;
+use std::time::Duration;
+use tracing::info;
+
+pub async fn fetch_all(
+    instance_config: &InstanceConfig,
+    api: &ApiClient,
+) -> Result<Vec<JellyfinUser>, Box<dyn Error + Send + Sync>> {
+    let url = format!("{}/Users", instance_config.base_url);
+    let value = api.get_json_at_startup(instance_config, "/Users").await?;
+    let (items, _) = split_query_result(value);
+    dto::parse_list(items, &dto::USER_SHAPE, &url)
+}
+
+pub async fn fetch_paged(
+    instance_config: &InstanceConfig,
+    api: &ApiClient,
+    page_size: usize,
+) -> Result<Vec<JellyfinUser>, Box<dyn Error + Send + Sync>> {
+    let page_size = page_size.max(1);
+    let mut users: Vec<JellyfinUser> = Vec::new();
+    let mut progress: Option<ProgressBar> = None;
+    loop {
+        let path = format!("/Users?startIndex={}&limit={}", users.len(), page_size);
+        let url = format!("{}{}", instance_config.base_url, path);
+        let value = api.get_json_at_startup(instance_config, &path).await?;
+        let (items, total) = split_query_result(value);
+        let page: Vec<JellyfinUser> = dto::parse_list(items, &dto::USER_SHAPE, &url)?;
+
+        if users.is_empty() && page.len() > page_size {
+            info!(
+                "{} ignored the page size and returned all {} users at once.",
+                instance_config.base_url,
+                page.len()
+            );
+            return Ok(page);
+        }
+        // A server that honours limit but not startIndex returns the first page again
+        if !users.is_empty() && page.first().map(|user| &user.id) == Some(&users[0].id) {
+            if let Some(progress) = progress {
+                progress.finish_and_clear();
+            }
+            info!(
+                "{} doesn't support paging /Users, fetching all users in one request.",
+                instance_config.base_url
+            );
+            return fetch_all(instance_config, api).await;
+        }
+
+        let progress = progress.get_or_insert_with(|| new_progress(total));
+        let page_len = page.len();
+        users.extend(page);
+        progress.set_position(users.len() as u64);
+        let reached_total = total.is_some_and(|total| users.len() as u64 >= total);
+        if page_len < page_size || reached_total {
+            progress.finish_and_clear();
+            return Ok(users);
+        }
+    }
+}
+
+// A bar when the server reports the total, a counter otherwise
+fn new_progress(total: Option<u64>) -> ProgressBar {
+    match total {
+        Some(total) => {
+            let progress = ProgressBar::new(total);
+            progress.set_style(
+                ProgressStyle::default_bar()
+                    .template("Fetching users [{bar:40.cyan/blue}] {pos}/{len}")
+                    .expect("valid template")
+                    .progress_chars("#>-"),
+            );
+            progress
+        }
+        None => {
+            let progress = ProgressBar::new_spinner();
+            progress.set_style(
+                ProgressStyle::default_spinner()
+                    .template("{spinner} Fetching users: {pos}")
+                    .expect("valid template"),
+            );
+            progress.enable_steady_tick(Duration::from_millis(100));
+            progress
+        }
+    }
+}
+
+// /Users is a plain array, but paged endpoints (and some proxies in front of them) answer with a
+// query result: {"Items": [...], "TotalRecordCount": n}
+pub fn split_query_result(value: Value) -> (Value, Option<u64>) {
+    match value {
+        Value::Object(mut object) if object.get("Items").is_some_and(Value::is_array) => {
+            let total = object.get("TotalRecordCount").and_then(Value::as_u64);
+            (object.remove("Items").unwrap_or_default(), total)
+        }
+        other => (other, None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::ApiRecording;
+    use reqwest::Client;
+    use serde_json::json;
+    use wiremock::matchers::{method, path, query_param};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn instance(base_url: &str, user_page_size: usize) -> InstanceConfig {
+        InstanceConfig {
+            base_url: base_url.to_string(),
+            api_token: Some("token".to_string()),
+            username: None,
+            password: None,
+            startup_grace_seconds: 0,
+            max_retries: 0,
+            base_backoff_ms: 0,
+            auth_mode: crate::AuthMode::Token,
+            client: crate::ClientInfo::default(),
+            send_emby_token_header: true,
+            user_page_size,
+        }
+    }
+
+    fn user_list(range: std::ops::Range<usize>) -> Value {
+        range
+            .map(|i| json!({"Id": format!("id{}", i), "Name": format!("user{}", i)}))
+            .collect()
+    }
+
+    async fn fetch(server: &MockServer, page_size: usize) -> Vec<String> {
+        let api = ApiClient::new(Client::new(), ApiRecording::Off).unwrap();
+        fetch_paged(&instance(&server.uri(), page_size), &api, page_size)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|user| user.name)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn pages_through_users_and_falls_back_when_paging_is_ignored() {
+        let all: Vec<String> = (0..5).map(|i| format!("user{}", i)).collect();
+
+        let paging = MockServer::start().await;
+        for start in [0, 2, 4] {
+            Mock::given(method("GET"))
+                .and(path("/Users"))
+                .and(query_param("startIndex", start.to_string()))
+                .and(query_param("limit", "2"))
+                .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                    "Items": user_list(start..(start + 2).min(5)),
+                    "TotalRecordCount": 5
+                })))
+                .expect(1)
+                .mount(&paging)
+                .await;
+        }
+        assert_eq!(fetch(&paging, 2).await, all);
+
+        // Everything at once: the first response is the whole list
+        let ignoring = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/Users"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(user_list(0..5)))
+            .expect(1)
+            .mount(&ignoring)
+            .await;
+        assert_eq!(fetch(&ignoring, 2).await, all);
+
+        // limit honoured but startIndex ignored: the first page comes back again
+        let first_page_only = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/Users"))
+            .and(query_param("limit", "2"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(user_list(0..2)))
+            .expect(2)
+            .mount(&first_page_only)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/Users"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(user_list(0..5)))
+            .expect(1)
+            .mount(&first_page_only)
+            .await;
+        assert_eq!(fetch(&first_page_only, 2).await, all);
+    }
+}
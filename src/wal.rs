@@ -0,0 +1,228 @@
+// WAL checkpoint at the end of a run. A destination in WAL journal mode keeps recently committed
+// rows in the `-wal` file next to the database until a checkpoint copies them in, so right after a
+// big migration most of the data can sit there, and copying only the .db loses it. With
+// `sqlite_wal_checkpoint` (TRUNCATE by default) the rows are moved into the database and the -wal
+// file is emptied, then the files on disk are listed with what has to be copied. A checkpoint that
+// can't finish, typically because Jellyfin or another process has the database open, is warned
+// about loudly: the run did commit, but the .db alone isn't the whole table.
+use crate::display::format_bytes;
+use rusqlite::Connection;
+use serde::Deserialize;
+use std::fs;
+use tracing::{info, warn};
+
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum WalCheckpoint {
+    Off,
+    Passive, // Copies what it can without waiting for other connections
+    Full,    // Waits for writers, then copies everything
+    Restart, // Like full, and makes the next writer start the -wal file from the beginning
+    #[default]
+    Truncate, // Like restart, and truncates the -wal file to zero bytes
+}
+
+impl WalCheckpoint {
+    fn pragma_argument(self) -> Option<&'static str> {
+        match self {
+            WalCheckpoint::Off => None,
+            WalCheckpoint::Passive => Some("PASSIVE"),
+            WalCheckpoint::Full => Some("FULL"),
+            WalCheckpoint::Restart => Some("RESTART"),
+            WalCheckpoint::Truncate => Some("TRUNCATE"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct CheckpointResult {
+    pub wal_bytes_before: u64,
+    pub wal_bytes_after: u64,
+    // Every committed row is in the database file, the -wal file isn't needed for a copy
+    pub complete: bool,
+    pub problem: Option<String>,
+}
+
+fn file_size(path: &str) -> Option<u64> {
+    fs::metadata(path).ok().map(|metadata| metadata.len())
+}
+
+// None when the database isn't in WAL mode (nothing to do) or checkpointing is off
+pub fn checkpoint(
+    conn: &Connection,
+    path: &str,
+    mode: WalCheckpoint,
+) -> Result<Option<CheckpointResult>, rusqlite::Error> {
+    let journal_mode: String = conn.query_row("PRAGMA journal_mode", [], |row| row.get(0))?;
+    if !journal_mode.eq_ignore_ascii_case("wal") {
+        return Ok(None);
+    }
+    let Some(argument) = mode.pragma_argument() else {
+        return Ok(None);
+    };
+    let wal_path = format!("{}-wal", path);
+    let wal_bytes_before = file_size(&wal_path).unwrap_or(0);
+    // (busy, frames in the -wal file, frames copied into the database)
+    let (busy, frames, copied): (i64, i64, i64) =
+        conn.query_row(&format!("PRAGMA wal_checkpoint({})", argument), [], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+        })?;
+    let wal_bytes_after = file_size(&wal_path).unwrap_or(0);
+    let problem = if busy != 0 {
+        Some(
+            "another connection has the database open and kept the checkpoint from finishing"
+                .to_string(),
+        )
+    } else if copied < frames {
+        Some(format!(
+            "only {} of {} -wal frames were copied into the database",
+            copied, frames
+        ))
+    } else if mode == WalCheckpoint::Truncate && wal_bytes_after > 0 {
+        Some(format!(
+            "the -wal file is still {} after the checkpoint",
+            format_bytes(wal_bytes_after)
+        ))
+    } else {
+        None
+    };
+    Ok(Some(CheckpointResult {
+        wal_bytes_before,
+        wal_bytes_after,
+        complete: busy == 0 && copied >= frames,
+        problem,
+    }))
+}
+
+// The database's files as they are on disk now, e.g. "'playback.db' (141 MB), 'playback.db-wal' (0 B)"
+fn file_set(path: &str) -> String {
+    ["", "-wal", "-shm"]
+        .iter()
+        .filter_map(|suffix| {
+            let file = format!("{}{}", path, suffix);
+            file_size(&file).map(|size| format!("'{}' ({})", file, format_bytes(size)))
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// Runs the checkpoint and prints its outcome with the files to copy. Never fails the run, the
+// rows are committed either way.
+pub fn checkpoint_and_report(conn: &Connection, path: &str, mode: WalCheckpoint) {
+    let result = match checkpoint(conn, path, mode) {
+        Ok(Some(result)) => result,
+        Ok(None) if mode == WalCheckpoint::Off && file_size(&format!("{}-wal", path)).is_some() => {
+            info!(
+                "SQLite files on disk (sqlite_wal_checkpoint = \"off\", copy them all together): {}",
+                file_set(path)
+            );
+            return;
+        }
+        Ok(None) => return,
+        Err(e) => {
+            warn!(
+                "\n!!! WARNING: the WAL checkpoint of '{}' failed: {} !!!\n  The migrated rows are committed, but some may only be in '{}-wal'. Copy it together with the database: {}",
+                path, e, path, file_set(path)
+            );
+            return;
+        }
+    };
+    match result.problem {
+        None => {
+            info!(
+                "WAL checkpoint of '{}': -wal file {} → {}.",
+                path,
+                format_bytes(result.wal_bytes_before),
+                format_bytes(result.wal_bytes_after)
+            );
+            info!(
+                "SQLite files on disk: {}. '{}' holds every migrated row{}.",
+                file_set(path),
+                path,
+                if result.wal_bytes_after > 0 {
+                    ", the -wal file doesn't need to be copied"
+                } else {
+                    ""
+                }
+            );
+        }
+        Some(ref problem) => {
+            warn!(
+                "\n!!! WARNING: the WAL checkpoint of '{}' didn't finish: {} !!!",
+                path, problem
+            );
+            warn!(
+                "  The migrated rows are committed, but {}.",
+                if result.complete {
+                    "the -wal file couldn't be emptied"
+                } else {
+                    "some of them are still only in the -wal file"
+                }
+            );
+            warn!("  SQLite files on disk: {}", file_set(path));
+            warn!(
+                "  Copy the database together with its -wal file, or stop the process using it (e.g. Jellyfin) and run `PRAGMA wal_checkpoint(TRUNCATE);` on '{}' before copying only the .db.",
+                path
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wal_database(path: &str) -> Connection {
+        let conn = Connection::open(path).unwrap();
+        conn.query_row("PRAGMA journal_mode = WAL", [], |row| {
+            row.get::<_, String>(0)
+        })
+        .unwrap();
+        // Keep the automatic checkpoint out of the way so the rows stay in the -wal file
+        conn.execute_batch(
+            "PRAGMA wal_autocheckpoint = 0; CREATE TABLE t (v TEXT); \
+             INSERT INTO t VALUES ('a'), ('b'), ('c');",
+        )
+        .unwrap();
+        conn
+    }
+
+    #[test]
+    fn truncate_empties_the_wal_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.db").display().to_string();
+        let conn = wal_database(&path);
+        let result = checkpoint(&conn, &path, WalCheckpoint::Truncate)
+            .unwrap()
+            .unwrap();
+        assert!(result.wal_bytes_before > 0);
+        assert_eq!(result.wal_bytes_after, 0);
+        assert!(result.complete);
+        assert_eq!(result.problem, None);
+
+        assert_eq!(checkpoint(&conn, &path, WalCheckpoint::Off).unwrap(), None);
+        let rollback_journal = dir.path().join("plain.db").display().to_string();
+        let conn = Connection::open(&rollback_journal).unwrap();
+        assert_eq!(
+            checkpoint(&conn, &rollback_journal, WalCheckpoint::Truncate).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn a_reader_keeps_the_wal_file_from_being_truncated() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.db").display().to_string();
+        let conn = wal_database(&path);
+        conn.busy_timeout(std::time::Duration::ZERO).unwrap();
+        let reader = Connection::open(&path).unwrap();
+        reader
+            .execute_batch("BEGIN; SELECT COUNT(*) FROM t;")
+            .unwrap();
+        let result = checkpoint(&conn, &path, WalCheckpoint::Truncate)
+            .unwrap()
+            .unwrap();
+        assert!(result.wal_bytes_after > 0);
+        assert!(result.problem.unwrap().contains("another connection"));
+    }
+}
@@ -0,0 +1,372 @@
+// Watch mode: keeps the process alive and feeds lines appended to the input TSV
+// through the same pipeline as a normal run, committing SQLite once per batch.
+use crate::futuredates::FutureDates;
+use crate::limits::FieldLimits;
+use crate::progress;
+use crate::retention::RetentionPolicy;
+use crate::sinks::{self, SinkSet};
+use crate::timefmt;
+use crate::tracked;
+use crate::{
+    print_processing_summary, report_stats_invariants, transform_record, user_map_warning, Config,
+    ProcessingStats, RunMode, TsvRecord,
+};
+use chrono::Utc;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::time::Duration;
+use tracing::{info, warn};
+
+// Tracks how far into the input file we have processed and which file that was
+#[derive(Debug, Default)]
+struct WatchState {
+    offset: u64,
+    identity: Option<u64>,
+}
+
+#[cfg(unix)]
+fn file_identity(metadata: &fs::Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Some(metadata.ino())
+}
+
+#[cfg(not(unix))]
+fn file_identity(_metadata: &fs::Metadata) -> Option<u64> {
+    // No cheap inode equivalent, rotation is only detected through truncation here
+    None
+}
+
+// Returns the newly appended complete lines (up to and including the last '\n') if there are any.
+// A trailing partial line is left in the file until it has been completed.
+fn read_new_complete_lines(path: &str, state: &mut WatchState) -> io::Result<Option<Vec<u8>>> {
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) => metadata,
+        // The file can briefly disappear while it is being rotated
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    // A fresh file is read from its start again. SQLite's duplicate check skips the records it
+    // already has, the TSV output gets them a second time if the new file repeats them.
+    let identity = file_identity(&metadata);
+    if state.identity.is_some() && identity != state.identity {
+        info!(
+            "Input file '{}' was replaced (rotated). Treating it as a fresh file.",
+            path
+        );
+        state.offset = 0;
+    } else if metadata.len() < state.offset {
+        info!(
+            "Input file '{}' shrank from {} to {} bytes (truncated). Treating it as a fresh file.",
+            path,
+            state.offset,
+            metadata.len()
+        );
+        state.offset = 0;
+    }
+    state.identity = identity;
+
+    if metadata.len() == state.offset {
+        return Ok(None);
+    }
+
+    let mut file = fs::File::open(path)?;
+    file.seek(SeekFrom::Start(state.offset))?;
+    let mut buf = Vec::new();
+    file.read_to_end(&mut buf)?;
+
+    match buf.iter().rposition(|&b| b == b'\n') {
+        Some(last_newline) => {
+            buf.truncate(last_newline + 1);
+            state.offset += buf.len() as u64;
+            Ok(Some(buf))
+        }
+        None => Ok(None), // Only a partial line so far
+    }
+}
+
+// Runs one batch of lines through the pipeline inside its own SQLite transaction
+fn process_batch(
+    batch: &[u8],
+    config: &Config,
+    user_id_map: &HashMap<String, String>,
+    retention: &RetentionPolicy,
+    field_limits: &FieldLimits,
+    sinks: &mut SinkSet,
+    stats: &mut ProcessingStats,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut rdr = csv::ReaderBuilder::new()
+        .delimiter(b'\t')
+        .has_headers(false) // Input TSV does not have headers
+        .comment(Some(b'#')) // Skips the optional schema stamp of files written by this tool
+        .from_reader(batch);
+
+    sinks.begin(stats)?;
+    // Appended lines are compared with the time they were picked up, not the start of watching
+    let future_dates = FutureDates::new(config, Utc::now());
+
+    for result in rdr.deserialize() {
+        let mut record: TsvRecord = result?;
+        stats.records_processed += 1;
+        if !config.user_filter.retain(&record, stats)
+            || !retention.retain(&record, stats)
+            || !field_limits.apply(&mut record, stats)
+            || !future_dates.apply(&mut record, stats)
+        {
+            continue;
+        }
+
+        let kept = transform_record(&mut record, config, user_id_map, stats).map_err(|e| {
+            format!(
+                "A record appended to the input TSV (DateCreated '{}'): {} (unmapped_user_policy = \"error\"). This batch wasn't committed to SQLite.",
+                record.date_created, e
+            )
+        })?;
+        if kept {
+            sinks.write(&record, stats)?;
+        }
+    }
+
+    sinks.finalize(stats)?;
+    Ok(())
+}
+
+// Waits for SIGTERM/SIGINT (or Ctrl-C where unix signals aren't available).
+// The unix streams are created once up front so signals arriving mid-batch aren't lost.
+struct ShutdownSignals {
+    #[cfg(unix)]
+    terminate: tokio::signal::unix::Signal,
+    #[cfg(unix)]
+    interrupt: tokio::signal::unix::Signal,
+}
+
+impl ShutdownSignals {
+    fn new() -> io::Result<Self> {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+            Ok(ShutdownSignals {
+                terminate: signal(SignalKind::terminate())?,
+                interrupt: signal(SignalKind::interrupt())?,
+            })
+        }
+        #[cfg(not(unix))]
+        {
+            Ok(ShutdownSignals {})
+        }
+    }
+
+    async fn recv(&mut self) -> &'static str {
+        #[cfg(unix)]
+        {
+            tokio::select! {
+                _ = self.terminate.recv() => "SIGTERM",
+                _ = self.interrupt.recv() => "SIGINT",
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = tokio::signal::ctrl_c().await;
+            "Ctrl-C"
+        }
+    }
+}
+
+pub async fn watch_tsv_file(
+    config: &Config,
+    user_id_map: &HashMap<String, String>,
+    retention: &RetentionPolicy,
+    poll_interval: Duration,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    info!(
+        "\nStarting watch mode on: {} (polling every {}s, stop with SIGTERM or Ctrl-C)",
+        config.input_tsv_file_path,
+        poll_interval.as_secs()
+    );
+
+    if user_id_map.is_empty() {
+        info!("User ID map is empty. No UserID replacements will be made, but data will be processed to configured outputs.");
+    }
+
+    let mut signals = ShutdownSignals::new()?;
+
+    // Transactions are opened per batch
+    let mut sinks = sinks::open_sinks(config, RunMode::Normal, None)?;
+    if sinks.is_empty() {
+        warn!("\nNo output (TSV or SQLite) is configured. The application will process data but not save it.");
+    }
+
+    let mut state = WatchState::default();
+    let mut stats = ProcessingStats {
+        started_at: timefmt::now(),
+        preload_duration: sinks.preload_duration(),
+        tracked_users: tracked::TrackedUsers::from_config(config),
+        clock_skews: config.clock_skews.clone(),
+        user_map_warning: user_map_warning(config, user_id_map),
+        ..Default::default()
+    };
+    let field_limits = FieldLimits::new(config)?;
+    let formatter = config.time_formatter();
+    let mut batches = 0u64;
+
+    progress::PROGRESS.set_phase("watching");
+    loop {
+        if let Some(batch) = read_new_complete_lines(&config.input_tsv_file_path, &mut state)? {
+            let processed_before = stats.records_processed;
+            process_batch(
+                &batch,
+                config,
+                user_id_map,
+                retention,
+                &field_limits,
+                &mut sinks,
+                &mut stats,
+            )?;
+            batches += 1;
+            progress::PROGRESS.update(&stats);
+            info!(
+                "Batch {} at {}: {} new records. Running totals: processed {}, UserID changed {}, inserted into SQLite {}, duplicates skipped {}",
+                batches,
+                formatter.format(timefmt::now()),
+                stats.records_processed - processed_before,
+                stats.records_processed,
+                stats.records_changed,
+                stats.records_inserted_sqlite,
+                stats.records_skipped_sqlite
+            );
+        }
+
+        // Signals are only acted on between batches so the in-flight batch always completes
+        tokio::select! {
+            _ = tokio::time::sleep(poll_interval) => {}
+            signal_name = signals.recv() => {
+                info!("\nReceived {}. Stopping watch mode.", signal_name);
+                break;
+            }
+        }
+    }
+
+    sinks.end_of_run();
+    report_stats_invariants(config, &stats);
+
+    info!("\nWatch Mode Summary ({} batches):", batches);
+    print_processing_summary(config, &stats);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config_from_toml;
+    use rusqlite::Connection;
+
+    const LINE_A: &str = "2024-01-01 10:00:00\told-a\ti1\tMovie\tA\tDirectPlay\tWeb\tTV\t60\n";
+    const LINE_B: &str = "2024-01-02 10:00:00\told-a\ti2\tMovie\tB\tDirectPlay\tWeb\tTV\t60\n";
+
+    fn append(path: &std::path::Path, text: &str) {
+        use std::io::Write;
+        fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(path)
+            .unwrap()
+            .write_all(text.as_bytes())
+            .unwrap();
+    }
+
+    #[test]
+    fn reads_appended_complete_lines_and_starts_over_on_rotation_or_truncation() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("in.tsv");
+        let path = input.to_str().unwrap();
+        let mut state = WatchState::default();
+        assert_eq!(read_new_complete_lines(path, &mut state).unwrap(), None);
+
+        append(&input, LINE_A);
+        let batch = read_new_complete_lines(path, &mut state).unwrap();
+        assert_eq!(batch.as_deref(), Some(LINE_A.as_bytes()));
+        assert_eq!(read_new_complete_lines(path, &mut state).unwrap(), None);
+
+        // A partial line waits until it is completed
+        let (start, rest) = LINE_B.split_at(20);
+        append(&input, start);
+        assert_eq!(read_new_complete_lines(path, &mut state).unwrap(), None);
+        append(&input, rest);
+        let batch = read_new_complete_lines(path, &mut state).unwrap();
+        assert_eq!(batch.as_deref(), Some(LINE_B.as_bytes()));
+
+        // Truncated: shorter than what was read, so read from the start
+        fs::write(&input, LINE_B).unwrap();
+        let batch = read_new_complete_lines(path, &mut state).unwrap();
+        assert_eq!(batch.as_deref(), Some(LINE_B.as_bytes()));
+
+        // Rotated: a new file (inode) moved into place, read from the start even though it is longer
+        let rotated = dir.path().join("in.tsv.new");
+        fs::write(&rotated, format!("{}{}", LINE_A, LINE_B)).unwrap();
+        fs::rename(&rotated, &input).unwrap();
+        let batch = read_new_complete_lines(path, &mut state).unwrap();
+        if cfg!(unix) {
+            assert_eq!(
+                batch.as_deref(),
+                Some(format!("{}{}", LINE_A, LINE_B).as_bytes())
+            );
+        }
+
+        // Briefly missing while it is being rotated
+        fs::remove_file(&input).unwrap();
+        assert_eq!(read_new_complete_lines(path, &mut state).unwrap(), None);
+    }
+
+    #[test]
+    fn each_batch_is_committed_on_its_own() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = dir.path().join("playback.db");
+        Connection::open(&db)
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE PlaybackActivity (DateCreated DATETIME NOT NULL, UserId TEXT, ItemId TEXT, \
+                ItemType TEXT, ItemName TEXT, PlaybackMethod TEXT, ClientName TEXT, DeviceName TEXT, PlayDuration INT);",
+            )
+            .unwrap();
+        let config = config_from_toml(&format!(
+            "input_tsv_file_path = \"in.tsv\"\nsqlite_db_path = \"{}\"\nunmapped_user_policy = \"error\"",
+            db.display()
+        ));
+        let user_id_map = HashMap::from([("old-a".to_string(), "new-a".to_string())]);
+        let field_limits = FieldLimits::new(&config).unwrap();
+        let mut sinks = sinks::open_sinks(&config, RunMode::Normal, None).unwrap();
+        let mut stats = ProcessingStats::default();
+        let mut batch = |lines: &str, sinks: &mut SinkSet| {
+            process_batch(
+                lines.as_bytes(),
+                &config,
+                &user_id_map,
+                &RetentionPolicy::default(),
+                &field_limits,
+                sinks,
+                &mut stats,
+            )
+        };
+        let rows = || -> i64 {
+            Connection::open(&db)
+                .unwrap()
+                .query_row("SELECT COUNT(*) FROM PlaybackActivity", [], |row| {
+                    row.get(0)
+                })
+                .unwrap()
+        };
+
+        batch(LINE_A, &mut sinks).unwrap();
+        assert_eq!(rows(), 1);
+        // The second batch fails at its unmapped user, its first record isn't committed either
+        let failing = format!("{}{}", LINE_B, LINE_A.replace("old-a", "old-x"));
+        assert!(batch(&failing, &mut sinks)
+            .unwrap_err()
+            .to_string()
+            .contains("This batch wasn't committed to SQLite"));
+        drop(sinks);
+        assert_eq!(rows(), 1);
+    }
+}
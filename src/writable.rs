@@ -0,0 +1,196 @@
+// Startup check that every output can actually be written, so a read-only destination (e.g. a
+// read-only bind mount) is reported before users are fetched or the input is read instead of as
+// "attempt to write a readonly database" minutes into the run. Nothing is left behind: SQLite is
+// probed inside a savepoint that is rolled back and files with a probe file that is removed again.
+use crate::Config;
+use rusqlite::{Connection, ErrorCode, OpenFlags};
+use std::fs::{self, OpenOptions};
+use std::path::{Path, PathBuf};
+
+const PROBE_TABLE: &str = "jpm_write_probe";
+
+// Returns one description per output that can't be written
+pub fn check_outputs_writable(config: &Config) -> Vec<String> {
+    let mut problems = Vec::new();
+    let mut files: Vec<&str> = Vec::new();
+    if config.input_source == crate::extract::InputSource::OldInstance {
+        files.push(&config.input_tsv_file_path); // The extracted records are written there
+    }
+    files.extend(config.output_tsv_file_path.as_deref());
+    files.extend(config.output_manifest_path.as_deref());
+    files.extend(config.merge_manifest_path.as_deref());
+    files.extend(config.unmapped_report_path.as_deref());
+    files.extend(config.user_map_output_path.as_deref());
+    for path in files {
+        if let Err(e) = probe_file(Path::new(path)) {
+            problems.push(describe(path, &e));
+        }
+    }
+    if let Some(ref db_path) = config.sqlite_db_path {
+        let result = if Path::new(db_path).is_file() {
+            probe_sqlite(db_path)
+        } else {
+            probe_file(Path::new(db_path)) // Created on open, so its directory has to be writable
+        };
+        if let Err(e) = result {
+            problems.push(describe(db_path, &e));
+        }
+    }
+    problems
+}
+
+fn describe(path: &str, error: &str) -> String {
+    match read_only_mount(Path::new(path)) {
+        Some(mount) => format!(
+            "'{}' is not writable ({}), '{}' is mounted read-only",
+            path,
+            error,
+            mount.display()
+        ),
+        None => format!("'{}' is not writable ({})", path, error),
+    }
+}
+
+// An existing file is opened for appending (which changes nothing), a new one is checked by
+// creating and removing a probe file next to where it will be
+fn probe_file(path: &Path) -> Result<(), String> {
+    if path.is_file() {
+        return OpenOptions::new()
+            .append(true)
+            .open(path)
+            .map(|_| ())
+            .map_err(|e| e.to_string());
+    }
+    let dir = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let probe = dir.join(format!(".jpm-write-probe-{}", std::process::id()));
+    fs::write(&probe, b"").map_err(|e| e.to_string())?;
+    let _ = fs::remove_file(&probe);
+    Ok(())
+}
+
+// Only a read-only database (or journal that can't be created) fails the check. Anything else,
+// e.g. the database being locked by another process right now, is left for the run to report.
+fn probe_sqlite(db_path: &str) -> Result<(), String> {
+    let conn = Connection::open_with_flags(
+        db_path,
+        OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )
+    .map_err(|e| e.to_string())?;
+    let result = conn.execute_batch(&format!(
+        "SAVEPOINT jpm_probe; CREATE TABLE {} (x); ROLLBACK TO jpm_probe; RELEASE jpm_probe;",
+        PROBE_TABLE
+    ));
+    match result {
+        Err(rusqlite::Error::SqliteFailure(e, message))
+            if matches!(e.code, ErrorCode::ReadOnly | ErrorCode::CannotOpen) =>
+        {
+            Err(message.unwrap_or_else(|| e.to_string()))
+        }
+        _ => Ok(()),
+    }
+}
+
+// The mount point holding `path` if it is mounted read-only (Linux only)
+#[cfg(target_os = "linux")]
+fn read_only_mount(path: &Path) -> Option<PathBuf> {
+    let mounts = fs::read_to_string("/proc/mounts").ok()?;
+    // The path itself may not exist yet, its closest existing ancestor is on the same mount
+    let existing = path.ancestors().find_map(|ancestor| {
+        let ancestor = if ancestor.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            ancestor
+        };
+        fs::canonicalize(ancestor).ok()
+    })?;
+    read_only_mount_in(&mounts, &existing)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn read_only_mount(_path: &Path) -> Option<PathBuf> {
+    None
+}
+
+// The longest mount point in /proc/mounts containing `path`, if its options include "ro"
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn read_only_mount_in(mounts: &str, path: &Path) -> Option<PathBuf> {
+    mounts
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let mount_point = PathBuf::from(unescape_mount_field(fields.nth(1)?));
+            let options = fields.nth(1)?;
+            Some((mount_point, options.split(',').any(|option| option == "ro")))
+        })
+        .filter(|(mount_point, _)| path.starts_with(mount_point))
+        .max_by_key(|(mount_point, _)| mount_point.components().count())
+        .and_then(|(mount_point, read_only)| read_only.then_some(mount_point))
+}
+
+// Spaces and the like are written as octal escapes, e.g. "\040"
+fn unescape_mount_field(field: &str) -> String {
+    let mut unescaped = String::new();
+    let mut rest = field;
+    while let Some(index) = rest.find('\\') {
+        unescaped.push_str(&rest[..index]);
+        let code = rest.get(index + 1..index + 4);
+        match code.and_then(|code| u8::from_str_radix(code, 8).ok()) {
+            Some(byte) => {
+                unescaped.push(byte as char);
+                rest = &rest[index + 4..];
+            }
+            None => {
+                unescaped.push('\\');
+                rest = &rest[index + 1..];
+            }
+        }
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_the_read_only_mount_of_a_path() {
+        let mounts = "/dev/sda1 / ext4 rw,relatime 0 0\n\
+            /dev/sdb1 /mnt/backup ext4 ro,relatime 0 0\n\
+            /dev/sdb2 /mnt/backup/scratch ext4 rw,relatime 0 0\n\
+            /dev/sdc1 /mnt/my\\040media xfs ro 0 0\n";
+        let mount = |path: &str| read_only_mount_in(mounts, Path::new(path));
+        assert_eq!(
+            mount("/mnt/backup/playback_reporting.db"),
+            Some(PathBuf::from("/mnt/backup"))
+        );
+        assert_eq!(mount("/mnt/backup/scratch/out.tsv"), None);
+        assert_eq!(mount("/mnt/backupx/out.tsv"), None);
+        assert_eq!(
+            mount("/mnt/my media/out.tsv"),
+            Some(PathBuf::from("/mnt/my media"))
+        );
+        assert_eq!(mount("/home/out.tsv"), None);
+    }
+
+    #[test]
+    fn writable_outputs_pass_and_leave_nothing_behind() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("out.db");
+        Connection::open(&db_path)
+            .unwrap()
+            .execute_batch("CREATE TABLE PlaybackActivity (x);")
+            .unwrap();
+        assert_eq!(probe_sqlite(db_path.to_str().unwrap()), Ok(()));
+        assert_eq!(probe_file(&dir.path().join("new.tsv")), Ok(()));
+        let tables: i64 = Connection::open(&db_path)
+            .unwrap()
+            .query_row("SELECT COUNT(*) FROM sqlite_master", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(tables, 1);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}
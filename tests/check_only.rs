@@ -0,0 +1,42 @@
+// --check-only exits 0 when up to date, 2 when newer records were found and 1 on any error, so a
+// cron alert only has to tell those three apart
+use std::fs;
+use std::process::Command;
+
+#[test]
+fn every_error_of_a_check_exits_1() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = dir.path().join("config.toml");
+    // A misspelled key, a config error (10) in a normal run
+    fs::write(
+        &config,
+        "input_tsv_file_path = \"input.tsv\"\nsqlite_db_pth = \"playback.db\"\n\
+         [instance_old]\nbase_url = \"http://old.invalid\"\napi_token = \"x\"\n\
+         [instance_new]\nbase_url = \"http://new.invalid\"\napi_token = \"y\"\n",
+    )
+    .unwrap();
+    let run = |extra_args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_jellyfin_pr_migration"))
+            .arg("--no-user-config")
+            .arg("-c")
+            .arg(&config)
+            .args(extra_args)
+            .output()
+            .unwrap()
+    };
+
+    let result = run(&[]);
+    assert_eq!(result.status.code(), Some(10));
+    let result = run(&["--check-only"]);
+    let errors = String::from_utf8_lossy(&result.stderr);
+    assert_eq!(result.status.code(), Some(1), "{}", errors);
+    assert!(errors.contains("sqlite_db_pth"), "{}", errors);
+
+    // clap's usage errors exit 2 elsewhere, which would read as "newer records found"
+    let result = run(&["--check-only", "--no-such-flag"]);
+    let errors = String::from_utf8_lossy(&result.stderr);
+    assert_eq!(result.status.code(), Some(1), "{}", errors);
+    assert!(errors.contains("--no-such-flag"), "{}", errors);
+    assert_eq!(run(&["--no-such-flag"]).status.code(), Some(2));
+    assert_eq!(run(&["--check-only", "--help"]).status.code(), Some(0));
+}
@@ -0,0 +1,158 @@
+// `dry_run = true` against a SQLite destination that doesn't exist yet: every record is counted as
+// inserted and neither output is created
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+fn write_recording(dir: &Path, url: &str, body: &str) {
+    let file_name: String = format!("GET_{}", url)
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    fs::write(
+        dir.join(format!("{}.json", file_name)),
+        format!(
+            "{{\"method\": \"GET\", \"url\": \"{}\", \"status\": 200, \"body\": {}}}",
+            url, body
+        ),
+    )
+    .unwrap();
+}
+
+#[test]
+fn dry_run_without_a_database_counts_every_record_as_inserted() {
+    let dir = tempfile::tempdir().unwrap();
+    let recording = dir.path().join("recording");
+    fs::create_dir(&recording).unwrap();
+    write_recording(
+        &recording,
+        "http://old.invalid/Users?startIndex=0&limit=500",
+        r#"[{"Name": "alice", "Id": "old-a"}]"#,
+    );
+    write_recording(
+        &recording,
+        "http://new.invalid/Users?startIndex=0&limit=500",
+        r#"[{"Name": "alice", "Id": "new-a"}]"#,
+    );
+    let input = dir.path().join("input.tsv");
+    fs::write(
+        &input,
+        "2024-01-01 10:00:00\told-a\ti1\tMovie\tA\tDirectPlay\tWeb\tTV\t60\n\
+         2024-01-02 10:00:00\told-a\ti2\tMovie\tB\tDirectPlay\tWeb\tTV\t60\n\
+         2024-01-03 10:00:00\told-a\ti3\tMovie\tC\tDirectPlay\tWeb\tTV\t60\n",
+    )
+    .unwrap();
+    let output = dir.path().join("output.tsv");
+    let db = dir.path().join("playback.db");
+    let config = dir.path().join("config.toml");
+    fs::write(
+        &config,
+        format!(
+            "input_tsv_file_path = {:?}\noutput_tsv_file_path = {:?}\nsqlite_db_path = {:?}\n\
+             dry_run = true\n\
+             [instance_old]\nbase_url = \"http://old.invalid\"\napi_token = \"x\"\n\
+             [instance_new]\nbase_url = \"http://new.invalid\"\napi_token = \"y\"\n",
+            input.display().to_string(),
+            output.display().to_string(),
+            db.display().to_string()
+        ),
+    )
+    .unwrap();
+
+    let result = Command::new(env!("CARGO_BIN_EXE_jellyfin_pr_migration"))
+        .arg("--no-user-config")
+        .arg("-c")
+        .arg(&config)
+        .arg("--replay-api")
+        .arg(&recording)
+        .output()
+        .unwrap();
+    let report = String::from_utf8_lossy(&result.stdout);
+    assert!(result.status.success(), "{:?}", result);
+    assert!(
+        report.contains("TSV Processing Summary (DRY RUN):"),
+        "{}",
+        report
+    );
+    assert!(report.contains("(doesn't exist yet): 3"), "{}", report);
+    assert!(!db.exists());
+    assert!(!output.exists());
+}
+
+// `--dry-run` against a database another process holds an exclusive lock on: opening it at all
+// (even read-only) would fail with "database is locked", so the run succeeding shows it never does
+#[test]
+fn dry_run_never_opens_an_existing_database() {
+    let dir = tempfile::tempdir().unwrap();
+    let recording = dir.path().join("recording");
+    fs::create_dir(&recording).unwrap();
+    write_recording(
+        &recording,
+        "http://old.invalid/Users?startIndex=0&limit=500",
+        r#"[{"Name": "alice", "Id": "old-a"}]"#,
+    );
+    write_recording(
+        &recording,
+        "http://new.invalid/Users?startIndex=0&limit=500",
+        r#"[{"Name": "alice", "Id": "new-a"}]"#,
+    );
+    let input = dir.path().join("input.tsv");
+    fs::write(
+        &input,
+        "2024-01-01 10:00:00\told-a\ti1\tMovie\tA\tDirectPlay\tWeb\tTV\t60\n\
+         2024-01-02 10:00:00\told-a\ti2\tMovie\tB\tDirectPlay\tWeb\tTV\t60\n",
+    )
+    .unwrap();
+    let db = dir.path().join("playback.db");
+    let lock = rusqlite::Connection::open(&db).unwrap();
+    lock.execute_batch(
+        "CREATE TABLE PlaybackActivity (DateCreated DATETIME NOT NULL, UserId TEXT, ItemId TEXT, \
+         ItemType TEXT, ItemName TEXT, PlaybackMethod TEXT, ClientName TEXT, DeviceName TEXT, PlayDuration INT);\
+         BEGIN EXCLUSIVE;",
+    )
+    .unwrap();
+    let size_before = fs::metadata(&db).unwrap().len();
+    let config = dir.path().join("config.toml");
+    fs::write(
+        &config,
+        format!(
+            "input_tsv_file_path = {:?}\nsqlite_db_path = {:?}\n\
+             [instance_old]\nbase_url = \"http://old.invalid\"\napi_token = \"x\"\n\
+             [instance_new]\nbase_url = \"http://new.invalid\"\napi_token = \"y\"\n",
+            input.display().to_string(),
+            db.display().to_string()
+        ),
+    )
+    .unwrap();
+
+    let result = Command::new(env!("CARGO_BIN_EXE_jellyfin_pr_migration"))
+        .arg("--no-user-config")
+        .arg("-c")
+        .arg(&config)
+        .arg("--replay-api")
+        .arg(&recording)
+        .arg("--dry-run")
+        .output()
+        .unwrap();
+    let report = String::from_utf8_lossy(&result.stdout);
+    assert!(result.status.success(), "{:?}", result);
+    assert!(
+        report.contains("Total records with UserID changed: 2"),
+        "{}",
+        report
+    );
+    assert!(
+        report.contains("checked for duplicates and inserted into SQLite"),
+        "{}",
+        report
+    );
+    lock.execute_batch("COMMIT;").unwrap();
+    assert_eq!(fs::metadata(&db).unwrap().len(), size_before);
+    assert!(!dir.path().join("playback.db-journal").exists());
+}
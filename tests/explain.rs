@@ -0,0 +1,169 @@
+// `--explain` through the binary: a dry run traces the line up to the outputs it would be written
+// to, and a run resumed with --state-file says which requested lines the earlier run had done
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+fn write_recording(dir: &Path, url: &str, body: &str) {
+    let file_name: String = format!("GET_{}", url)
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    fs::write(
+        dir.join(format!("{}.json", file_name)),
+        format!(
+            "{{\"method\": \"GET\", \"url\": \"{}\", \"status\": 200, \"body\": {}}}",
+            url, body
+        ),
+    )
+    .unwrap();
+}
+
+// Writes the recording, a three line input and the config, returning the config's path
+fn setup(dir: &Path) -> std::path::PathBuf {
+    let recording = dir.join("recording");
+    fs::create_dir(&recording).unwrap();
+    write_recording(
+        &recording,
+        "http://old.invalid/Users?startIndex=0&limit=500",
+        r#"[{"Name": "alice", "Id": "old-a"}]"#,
+    );
+    write_recording(
+        &recording,
+        "http://new.invalid/Users?startIndex=0&limit=500",
+        r#"[{"Name": "alice", "Id": "new-a"}]"#,
+    );
+    let input = dir.join("input.tsv");
+    fs::write(
+        &input,
+        "2024-01-01 10:00:00\told-a\ti1\tMovie\tA\tDirectPlay\tWeb\tTV\t60\n\
+         2024-01-02 10:00:00\told-a\ti2\tMovie\tB\tDirectPlay\tWeb\tTV\t60\n\
+         2024-01-03 10:00:00\told-a\ti3\tMovie\tC\tDirectPlay\tWeb\tTV\t60\n",
+    )
+    .unwrap();
+    let config = dir.join("config.toml");
+    fs::write(
+        &config,
+        format!(
+            "input_tsv_file_path = {:?}\noutput_tsv_file_path = {:?}\n\
+             [instance_old]\nbase_url = \"http://old.invalid\"\napi_token = \"x\"\n\
+             [instance_new]\nbase_url = \"http://new.invalid\"\napi_token = \"y\"\n",
+            input.display().to_string(),
+            dir.join("output.tsv").display().to_string()
+        ),
+    )
+    .unwrap();
+    config
+}
+
+// The `explained` field of the --summary-line
+fn explain(dir: &Path, config: &Path, extra_args: &[&str]) -> Vec<Value> {
+    let result = Command::new(env!("CARGO_BIN_EXE_jellyfin_pr_migration"))
+        .arg("--no-user-config")
+        .arg("-c")
+        .arg(config)
+        .arg("--replay-api")
+        .arg(dir.join("recording"))
+        .args(["--explain", "1", "--explain", "3", "--summary-line"])
+        .args(extra_args)
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert!(result.status.success(), "{:?}", result);
+    let line = stderr
+        .lines()
+        .find_map(|line| line.strip_prefix("JPM_SUMMARY: "))
+        .unwrap();
+    let summary: Value = serde_json::from_str(line).unwrap();
+    summary["explained"].as_array().unwrap().clone()
+}
+
+fn steps(trace: &Value) -> Vec<&str> {
+    trace["steps"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|step| step.as_str().unwrap())
+        .collect()
+}
+
+#[test]
+fn a_dry_run_traces_the_line_without_writing_it() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = setup(dir.path());
+
+    let explained = explain(dir.path(), &config, &["--dry-run"]);
+    assert_eq!(explained.len(), 2);
+    assert_eq!(explained[1]["line"], 3);
+    let steps = steps(&explained[1]);
+    assert!(
+        steps.contains(&"User map lookup of UserId 'old-a': found, -> 'new-a'"),
+        "{:?}",
+        steps
+    );
+    assert!(
+        steps
+            .iter()
+            .any(|step| step.starts_with("Output line: 2024-01-03 10:00:00\\tnew-a\\t")),
+        "{:?}",
+        steps
+    );
+    assert!(
+        steps
+            .last()
+            .unwrap()
+            .starts_with("Not written: no output is opened in a dry run"),
+        "{:?}",
+        steps
+    );
+    assert!(!dir.path().join("output.tsv").exists());
+}
+
+#[test]
+fn a_line_done_before_a_resume_says_so() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = setup(dir.path());
+    let state_file = dir.path().join("migration.state");
+    fs::write(
+        &state_file,
+        format!(
+            "{{\"version\": 2, \"input_tsv_file_path\": {:?}, \"records_done\": 2, \
+             \"last_date_created\": \"2024-01-02 10:00:00\", \"stopped_at\": \"2024-06-01 12:00:00\"}}",
+            dir.path().join("input.tsv").display().to_string()
+        ),
+    )
+    .unwrap();
+
+    let explained = explain(
+        dir.path(),
+        &config,
+        &["--state-file", state_file.to_str().unwrap()],
+    );
+    assert_eq!(
+        steps(&explained[0]),
+        [
+            "Raw line (57 bytes): 2024-01-01 10:00:00\\told-a\\ti1\\tMovie\\tA\\tDirectPlay\\tWeb\\tTV\\t60",
+            "The line was done by the run that wrote the --state-file, skipped"
+        ]
+    );
+    // The line after the resume point is processed as usual
+    assert!(
+        steps(&explained[1]).contains(&"User map lookup of UserId 'old-a': found, -> 'new-a'"),
+        "{:?}",
+        explained[1]
+    );
+    assert_eq!(
+        fs::read_to_string(dir.path().join("output.tsv"))
+            .unwrap()
+            .lines()
+            .count(),
+        1
+    );
+}
@@ -0,0 +1,190 @@
+// input_source = "old_instance" against a plugin that answers the window's query with a huge
+// body: the rows are written to the input TSV while the body arrives, so the binary's peak memory
+// stays far below the size of the response. Peak memory is read from /proc, so Linux only. A 64 MB
+// response runs with every `cargo test`, the 500 MB one only with `cargo test -- --ignored`.
+#![cfg(target_os = "linux")]
+
+use std::fs;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+const MB: usize = 1024 * 1024;
+
+// A long ItemName makes each row about 1 KB
+fn row() -> String {
+    format!(
+        "[\"2024-01-01 08:00:00.0000000\",\"old-a\",\"item\",\"Movie\",\"{}\",\"DirectPlay\",\"Web\",\"TV\",60]",
+        "x".repeat(900)
+    )
+}
+
+fn respond(mut stream: TcpStream, users: &str, response_bytes: usize) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).unwrap();
+    let mut content_length = 0;
+    loop {
+        let mut header = String::new();
+        reader.read_line(&mut header).unwrap();
+        if header.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().unwrap();
+            }
+        }
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).unwrap();
+    let body = String::from_utf8(body).unwrap();
+
+    let mut out = BufWriter::with_capacity(64 * 1024, &mut stream);
+    let ok = "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nConnection: close\r\n\r\n";
+    // The query string (e.g. the user paging) doesn't matter
+    let target = request_line.split_whitespace().nth(1).unwrap_or_default();
+    let path = target.split('?').next().unwrap_or_default();
+    if request_line.starts_with("GET ") && path == "/Users" {
+        out.write_all(ok.as_bytes()).unwrap();
+        out.write_all(users.as_bytes()).unwrap();
+    } else if !(request_line.starts_with("POST ")
+        && path == "/user_usage_stats/submit_custom_query")
+    {
+        // e.g. the preflight's /System/Info, only a warning
+        out.write_all(b"HTTP/1.1 404 Not Found\r\nConnection: close\r\n\r\n")
+            .unwrap();
+    } else if body.contains("SELECT MIN") {
+        out.write_all(ok.as_bytes()).unwrap();
+        // A single window
+        out.write_all(
+            br#"{"colums": ["MIN", "MAX"], "results": [["2024-01-01 08:00:00.0000000", "2024-01-01 09:00:00.0000000"]], "message": ""}"#,
+        )
+        .unwrap();
+    } else {
+        // The body ends with the connection, like a server that doesn't know its length upfront
+        out.write_all(ok.as_bytes()).unwrap();
+        let row = row();
+        out.write_all(br#"{"colums": [], "results": ["#).unwrap();
+        let mut written = 0;
+        while written < response_bytes {
+            if written > 0 {
+                out.write_all(b",").unwrap();
+            }
+            out.write_all(row.as_bytes()).unwrap();
+            written += row.len() + 1;
+        }
+        out.write_all(br#"], "message": ""}"#).unwrap();
+    }
+    out.flush().unwrap();
+}
+
+fn instance(users: &'static str, response_bytes: usize) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let uri = format!("http://{}", listener.local_addr().unwrap());
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = stream.unwrap();
+            thread::spawn(move || respond(stream, users, response_bytes));
+        }
+    });
+    uri
+}
+
+// VmHWM of a running process, None once it has exited
+fn peak_memory_kb(pid: u32) -> Option<u64> {
+    let status = fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmHWM:"))?
+        .trim()
+        .trim_end_matches(" kB")
+        .parse()
+        .ok()
+}
+
+// Extracts a window whose query answers with `response_bytes`, checks the rows made it to the
+// input TSV and that the binary's peak memory stayed under `max_peak_memory_kb`
+fn check_streamed_extraction(response_bytes: usize, max_peak_memory_kb: u64) {
+    let old = instance(r#"[{"Name": "alice", "Id": "old-a"}]"#, response_bytes);
+    let new = instance(r#"[{"Name": "alice", "Id": "new-a"}]"#, response_bytes);
+    let dir = tempfile::tempdir().unwrap();
+    let input = dir.path().join("input.tsv");
+    // --check-only stops the run once the input has been extracted and compared
+    let db = dir.path().join("playback.db");
+    rusqlite::Connection::open(&db)
+        .unwrap()
+        .execute_batch("CREATE TABLE PlaybackActivity (DateCreated TEXT, UserId TEXT);")
+        .unwrap();
+    let config = dir.path().join("config.toml");
+    fs::write(
+        &config,
+        format!(
+            "input_tsv_file_path = {:?}\ninput_source = \"old_instance\"\nsqlite_db_path = {:?}\n\
+             request_timeout_secs = 0\n\
+             [instance_old]\nbase_url = {:?}\napi_token = \"x\"\nmax_retries = 0\n\
+             [instance_new]\nbase_url = {:?}\napi_token = \"y\"\n",
+            input.display().to_string(),
+            db.display().to_string(),
+            old,
+            new
+        ),
+    )
+    .unwrap();
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_jellyfin_pr_migration"))
+        .arg("--no-user-config")
+        .arg("-c")
+        .arg(&config)
+        .arg("--check-only")
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let pid = child.id();
+    let mut peak = 0;
+    while child.try_wait().unwrap().is_none() {
+        peak = peak_memory_kb(pid).unwrap_or(peak).max(peak);
+        thread::sleep(Duration::from_millis(50));
+    }
+    let mut report = String::new();
+    child
+        .stdout
+        .take()
+        .unwrap()
+        .read_to_string(&mut report)
+        .unwrap();
+    // 2: the input has records newer than the (empty) database
+    assert_eq!(child.wait().unwrap().code(), Some(2), "{}", report);
+
+    let rows = response_bytes.div_ceil(row().len() + 1);
+    assert!(
+        report.contains(&format!("Extracted {} rows", rows)),
+        "{}",
+        report
+    );
+    assert_eq!(
+        fs::metadata(&input).unwrap().len() as usize,
+        rows * (row().len() - 17), // No quotes or brackets, tabs and a newline instead of commas
+    );
+    assert!(
+        peak > 0 && peak < max_peak_memory_kb,
+        "peak memory {} kB for a {} byte response",
+        peak,
+        response_bytes
+    );
+}
+
+// The peak is mostly the binary itself, a buffered response would add the whole 64 MB
+#[test]
+fn a_large_response_is_written_while_it_arrives() {
+    check_streamed_extraction(64 * MB, 48 * 1024);
+}
+
+// Takes a couple of minutes in a debug build
+#[test]
+#[ignore = "pulls a 500 MB response, run with --ignored"]
+fn a_huge_response_is_written_while_it_arrives() {
+    check_streamed_extraction(500 * MB, 100 * 1024);
+}
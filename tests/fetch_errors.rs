@@ -0,0 +1,99 @@
+// A rejected API token on one instance stops the run before anything is written, unless
+// --ignore-fetch-errors is passed, and then the summary says the user map was empty
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+fn write_recording(dir: &Path, url: &str, status: u16, body: &str) {
+    let file_name: String = format!("GET_{}", url)
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    fs::write(
+        dir.join(format!("{}.json", file_name)),
+        format!(
+            "{{\"method\": \"GET\", \"url\": \"{}\", \"status\": {}, \"body\": {}}}",
+            url, status, body
+        ),
+    )
+    .unwrap();
+}
+
+#[test]
+fn failed_user_fetch_stops_the_run_unless_ignored() {
+    let dir = tempfile::tempdir().unwrap();
+    let recording = dir.path().join("recording");
+    fs::create_dir(&recording).unwrap();
+    write_recording(
+        &recording,
+        "http://old.invalid/Users?startIndex=0&limit=500",
+        200,
+        r#"[{"Name": "alice", "Id": "old-a"}]"#,
+    );
+    write_recording(
+        &recording,
+        "http://new.invalid/Users?startIndex=0&limit=500",
+        401,
+        r#""Invalid token""#,
+    );
+    let input = dir.path().join("input.tsv");
+    fs::write(
+        &input,
+        "2024-01-01 10:00:00\told-a\ti1\tMovie\tA\tDirectPlay\tWeb\tTV\t60\n",
+    )
+    .unwrap();
+    let output = dir.path().join("output.tsv");
+    let config = dir.path().join("config.toml");
+    fs::write(
+        &config,
+        format!(
+            "input_tsv_file_path = {:?}\noutput_tsv_file_path = {:?}\n\
+             [instance_old]\nbase_url = \"http://old.invalid\"\napi_token = \"x\"\n\
+             [instance_new]\nbase_url = \"http://new.invalid\"\napi_token = \"y\"\n",
+            input.display().to_string(),
+            output.display().to_string()
+        ),
+    )
+    .unwrap();
+    let run = |extra_args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_jellyfin_pr_migration"))
+            .arg("--no-user-config")
+            .arg("-c")
+            .arg(&config)
+            .arg("--replay-api")
+            .arg(&recording)
+            .args(extra_args)
+            .output()
+            .unwrap()
+    };
+
+    let result = run(&[]);
+    let errors = String::from_utf8_lossy(&result.stderr);
+    // 11: an instance answered with an error status
+    assert_eq!(result.status.code(), Some(11), "{}", errors);
+    assert!(
+        errors.contains("http://new.invalid/Users?startIndex=0&limit=500: 401 Unauthorized"),
+        "{}",
+        errors
+    );
+    assert!(errors.contains("--ignore-fetch-errors"), "{}", errors);
+    assert!(!output.exists());
+
+    let result = run(&["--ignore-fetch-errors"]);
+    let report = String::from_utf8_lossy(&result.stdout);
+    assert!(result.status.success(), "{:?}", result);
+    assert!(
+        report.contains(
+            "WARNING: ran with an empty user map, fetching the users of the new instance failed"
+        ),
+        "{}",
+        report
+    );
+    assert!(output.exists());
+}
@@ -0,0 +1,115 @@
+// `map_item_ids = true` against a new instance that rescanned its library: ItemIds are replaced
+// with the new IDs of the same items and the summary counts them apart from the UserId changes
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+const ITEMS_QUERY: &str = "/Items?Recursive=true&IncludeItemTypes=Movie,Episode,Audio,MusicVideo,Video,AudioBook&Fields=ProviderIds,Path&startIndex=0&limit=1000";
+
+fn write_recording(dir: &Path, url: &str, body: &str) {
+    let file_name: String = format!("GET_{}", url)
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    fs::write(
+        dir.join(format!("{}.json", file_name)),
+        format!(
+            "{{\"method\": \"GET\", \"url\": \"{}\", \"status\": 200, \"body\": {}}}",
+            url, body
+        ),
+    )
+    .unwrap();
+}
+
+#[test]
+fn item_ids_are_mapped_by_provider_id() {
+    let dir = tempfile::tempdir().unwrap();
+    let recording = dir.path().join("recording");
+    fs::create_dir(&recording).unwrap();
+    write_recording(
+        &recording,
+        "http://old.invalid/Users?startIndex=0&limit=500",
+        r#"[{"Name": "alice", "Id": "old-a"}]"#,
+    );
+    write_recording(
+        &recording,
+        "http://new.invalid/Users?startIndex=0&limit=500",
+        r#"[{"Name": "alice", "Id": "new-a"}]"#,
+    );
+    write_recording(
+        &recording,
+        &format!("http://old.invalid{}", ITEMS_QUERY),
+        r#"{"Items": [
+            {"Id": "0a", "Name": "Dune", "Type": "Movie", "ProviderIds": {"Imdb": "tt0087182"}},
+            {"Id": "0b", "Name": "Dune", "Type": "Movie", "ProviderIds": {"Imdb": "tt1160419"}}
+        ], "TotalRecordCount": 2}"#,
+    );
+    write_recording(
+        &recording,
+        &format!("http://new.invalid{}", ITEMS_QUERY),
+        r#"{"Items": [
+            {"Id": "1b", "Name": "Dune", "Type": "Movie", "ProviderIds": {"Imdb": "tt1160419"}},
+            {"Id": "1a", "Name": "Dune", "Type": "Movie", "ProviderIds": {"Imdb": "tt0087182"}}
+        ], "TotalRecordCount": 2}"#,
+    );
+    let input = dir.path().join("input.tsv");
+    fs::write(
+        &input,
+        "2024-01-01 10:00:00\told-a\t0a\tMovie\tDune\tDirectPlay\tWeb\tTV\t60\n\
+         2024-01-02 10:00:00\told-a\t0b\tMovie\tDune\tDirectPlay\tWeb\tTV\t60\n\
+         2024-01-03 10:00:00\told-a\t0c\tMovie\tGone\tDirectPlay\tWeb\tTV\t60\n",
+    )
+    .unwrap();
+    let output = dir.path().join("output.tsv");
+    let config = dir.path().join("config.toml");
+    fs::write(
+        &config,
+        format!(
+            "input_tsv_file_path = {:?}\noutput_tsv_file_path = {:?}\nmap_item_ids = true\n\
+             [instance_old]\nbase_url = \"http://old.invalid\"\napi_token = \"x\"\n\
+             [instance_new]\nbase_url = \"http://new.invalid\"\napi_token = \"y\"\n",
+            input.display().to_string(),
+            output.display().to_string()
+        ),
+    )
+    .unwrap();
+
+    let result = Command::new(env!("CARGO_BIN_EXE_jellyfin_pr_migration"))
+        .arg("--no-user-config")
+        .arg("-c")
+        .arg(&config)
+        .arg("--replay-api")
+        .arg(&recording)
+        .output()
+        .unwrap();
+    let report = String::from_utf8_lossy(&result.stdout);
+    assert!(result.status.success(), "{:?}", result);
+    assert!(
+        report.contains("Mapped 2 of 2 old items (2 by provider ID,"),
+        "{}",
+        report
+    );
+    assert!(
+        report.contains("Total records with ItemId changed: 2"),
+        "{}",
+        report
+    );
+    assert!(
+        report.contains("Records whose ItemId has no match on the new instance (kept as is): 1"),
+        "{}",
+        report
+    );
+    let written = fs::read_to_string(&output).unwrap();
+    let item_ids: Vec<&str> = written
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .map(|line| line.split('\t').nth(2).unwrap())
+        .collect();
+    assert_eq!(item_ids, ["1a", "1b", "0c"]);
+}
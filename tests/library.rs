@@ -0,0 +1,141 @@
+// The migration called as a library instead of through the binary: load_config, the user fetch
+// and map, and run_migration returning the counts of the run
+use jellyfin_pr_migration::{
+    api_client, create_user_id_map, fetch_users_from_instance, load_config, run_migration,
+    MigrationError, UserChanges,
+};
+use rusqlite::Connection;
+use serde_json::{json, Value};
+use std::fs;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+async fn instance(users: Value) -> MockServer {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/Users"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(users))
+        .mount(&server)
+        .await;
+    server
+}
+
+#[tokio::test]
+async fn run_migration_returns_the_counts_of_the_run() {
+    let old = instance(json!([
+        {"Name": "alice", "Id": "old-a"},
+        {"Name": "bob", "Id": "old-b"}
+    ]))
+    .await;
+    let new = instance(json!([
+        {"Name": "alice", "Id": "new-a"},
+        {"Name": "bob", "Id": "new-b"}
+    ]))
+    .await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let input = dir.path().join("input.tsv");
+    fs::write(
+        &input,
+        "2024-01-01 10:00:00\told-a\ti1\tMovie\tA\tDirectPlay\tWeb\tTV\t60\n\
+         2024-01-02 10:00:00\told-a\ti2\tMovie\tB\tDirectPlay\tWeb\tTV\t60\n\
+         2024-01-03 10:00:00\told-b\ti3\tMovie\tC\tDirectPlay\tWeb\tTV\t60\n",
+    )
+    .unwrap();
+    // The first record is already in the destination under its new user ID
+    let db = dir.path().join("playback.db");
+    Connection::open(&db)
+        .unwrap()
+        .execute_batch(
+            "CREATE TABLE PlaybackActivity (DateCreated DATETIME NOT NULL, UserId TEXT, ItemId TEXT, \
+             ItemType TEXT, ItemName TEXT, PlaybackMethod TEXT, ClientName TEXT, DeviceName TEXT, \
+             PlayDuration INT);
+             INSERT INTO PlaybackActivity VALUES ('2024-01-01 10:00:00', 'new-a', 'i1', 'Movie', 'A', \
+             'DirectPlay', 'Web', 'TV', 60);",
+        )
+        .unwrap();
+    let config_path = dir.path().join("config.toml");
+    fs::write(
+        &config_path,
+        format!(
+            "input_tsv_file_path = {:?}\nsqlite_db_path = {:?}\n\
+             [instance_old]\nbase_url = {:?}\napi_token = \"x\"\n\
+             [instance_new]\nbase_url = {:?}\napi_token = \"y\"\n",
+            input.display().to_string(),
+            db.display().to_string(),
+            old.uri(),
+            new.uri()
+        ),
+    )
+    .unwrap();
+    let config_path = config_path.display().to_string();
+
+    // The steps on their own
+    let mut config = load_config(Some(&config_path), false).unwrap();
+    let api = api_client(&config).unwrap();
+    let old_users = fetch_users_from_instance(config.instance_old(), &api)
+        .await
+        .unwrap();
+    let new_users = fetch_users_from_instance(config.instance_new(), &api)
+        .await
+        .unwrap();
+    let user_id_map = create_user_id_map(&mut config, &old_users, &new_users, true).unwrap();
+    assert_eq!(user_id_map.get("old-b").map(String::as_str), Some("new-b"));
+
+    // And the whole run
+    let config = load_config(Some(&config_path), false).unwrap();
+    let summary = run_migration(config).await.unwrap();
+    assert!(!summary.dry_run);
+    assert_eq!(summary.records_processed, 3);
+    assert_eq!(summary.records_changed, 3);
+    assert_eq!(summary.records_dropped, 0);
+    assert_eq!(
+        (
+            summary.records_inserted_sqlite,
+            summary.records_skipped_sqlite
+        ),
+        (2, 1)
+    );
+    assert_eq!(
+        summary.changes_per_user.get("old-a"),
+        Some(&UserChanges {
+            new_id: "new-a".to_string(),
+            count: 2
+        })
+    );
+    let rows: i64 = Connection::open(&db)
+        .unwrap()
+        .query_row("SELECT COUNT(*) FROM PlaybackActivity", [], |row| {
+            row.get(0)
+        })
+        .unwrap();
+    assert_eq!(rows, 3);
+}
+
+#[tokio::test]
+async fn run_migration_refuses_unknown_keys_like_the_binary() {
+    let dir = tempfile::tempdir().unwrap();
+    let config_path = dir.path().join("config.toml");
+    fs::write(
+        &config_path,
+        "input_tsv_file_path = \"input.tsv\"\nsqlite_db_pth = \"playback.db\"\n\
+         [instance_old]\nbase_url = \"http://127.0.0.1:1\"\napi_token = \"x\"\n\
+         [instance_new]\nbase_url = \"http://127.0.0.1:2\"\napi_token = \"y\"\n",
+    )
+    .unwrap();
+    let config = load_config(Some(&config_path.display().to_string()), false).unwrap();
+    let error = run_migration(config).await.unwrap_err();
+    assert!(
+        matches!(error, MigrationError::ConfigLoad { .. }),
+        "{:?}",
+        error
+    );
+    assert!(error.to_string().contains("sqlite_db_pth"), "{}", error);
+}
+
+// Embedders spawn the run on a multi-threaded runtime, which needs the future to be Send
+#[allow(dead_code)]
+fn run_migration_can_be_spawned(config: jellyfin_pr_migration::Config) {
+    fn assert_send<T: Send>(_: &T) {}
+    assert_send(&run_migration(config));
+}
@@ -0,0 +1,120 @@
+// Runs the binary against recorded API responses and checks what `--log-level` lets through
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Output};
+
+fn write_recording(dir: &Path, url: &str, body: &str) {
+    let file_name: String = format!("GET_{}", url)
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    fs::write(
+        dir.join(format!("{}.json", file_name)),
+        format!(
+            "{{\"method\": \"GET\", \"url\": \"{}\", \"status\": 200, \"body\": {}}}",
+            url, body
+        ),
+    )
+    .unwrap();
+}
+
+fn run(dir: &Path, level: &str) -> (String, String) {
+    let recording = dir.join("recording");
+    let config = dir.join("config.toml");
+    let result: Output = Command::new(env!("CARGO_BIN_EXE_jellyfin_pr_migration"))
+        .arg("--no-user-config")
+        .arg("-c")
+        .arg(&config)
+        .arg("--replay-api")
+        .arg(&recording)
+        .arg("--log-level")
+        .arg(level)
+        .output()
+        .unwrap();
+    assert!(result.status.success(), "{:?}", result);
+    (
+        String::from_utf8(result.stdout).unwrap(),
+        String::from_utf8(result.stderr).unwrap(),
+    )
+}
+
+#[test]
+fn log_level_picks_the_detail_of_the_output() {
+    let dir = tempfile::tempdir().unwrap();
+    let recording = dir.path().join("recording");
+    fs::create_dir(&recording).unwrap();
+    write_recording(
+        &recording,
+        "http://old.invalid/Users?startIndex=0&limit=500",
+        r#"[{"Name": "alice", "Id": "old-a"}, {"Name": "bob", "Id": "old-b"}]"#,
+    );
+    write_recording(
+        &recording,
+        "http://new.invalid/Users?startIndex=0&limit=500",
+        r#"[{"Name": "alice", "Id": "new-a"}]"#,
+    );
+    let input = dir.path().join("input.tsv");
+    fs::write(
+        &input,
+        "2024-01-01 10:00:00\told-a\ti1\tMovie\tA\tDirectPlay\tWeb\tTV\t100\n\
+         2024-01-01 12:00:00\told-b\ti1\tMovie\tA\tDirectPlay\tWeb\tTV\t100\n",
+    )
+    .unwrap();
+    fs::write(
+        dir.path().join("config.toml"),
+        format!(
+            "input_tsv_file_path = {:?}\noutput_tsv_file_path = {:?}\n\
+             [instance_old]\nbase_url = \"http://old.invalid\"\napi_token = \"x\"\n\
+             [instance_new]\nbase_url = \"http://new.invalid\"\napi_token = \"y\"\n",
+            input.display().to_string(),
+            dir.path().join("output.tsv").display().to_string()
+        ),
+    )
+    .unwrap();
+
+    let (stdout, _) = run(dir.path(), "info");
+    assert!(stdout.contains("Jellyfin TSV updater finished successfully."));
+    assert!(!stdout.contains("UserId 'old-a' -> 'new-a'"), "{}", stdout);
+
+    let (stdout, _) = run(dir.path(), "debug");
+    assert!(
+        stdout.contains("Record 2024-01-01 10:00:00 'A': UserId 'old-a' -> 'new-a'"),
+        "{}",
+        stdout
+    );
+    assert!(
+        stdout.contains(
+            "Record 2024-01-01 12:00:00 'A': UserId 'old-b' isn't in the user map, unmapped_user_policy = \"keep\""
+        ),
+        "{}",
+        stdout
+    );
+
+    // Only trace shows the phase and instance each line was written in
+    assert!(
+        stdout.contains("\nFetching users from: http://old.invalid/Users\n"),
+        "{}",
+        stdout
+    );
+    let (stdout, _) = run(dir.path(), "trace");
+    assert!(
+        stdout.contains("user_mapping:instance{base_url=http://old.invalid}: Fetching users from: http://old.invalid/Users"),
+        "{}",
+        stdout
+    );
+    assert!(
+        stdout.contains("processing:finalize: Finalized TSV"),
+        "{}",
+        stdout
+    );
+
+    let (stdout, stderr) = run(dir.path(), "error");
+    assert_eq!(stdout, "");
+    assert_eq!(stderr, "");
+}
@@ -0,0 +1,122 @@
+// Polls `--progress-listen` while the binary works through a slow synthetic run: a large input
+// and a new instance that takes a while to list its users
+use serde_json::{json, Value};
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::{Command, Stdio};
+use std::time::Duration;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+const RECORDS: usize = 100_000;
+const TOKENS: [&str; 2] = ["old-secret-token", "new-secret-token"];
+
+async fn instance(users: Value, delay: Duration) -> MockServer {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/Users"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_json(users)
+                .set_delay(delay),
+        )
+        .mount(&server)
+        .await;
+    server
+}
+
+// None once nothing listens anymore
+fn get_progress(port: u16) -> Option<Value> {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).ok()?;
+    stream
+        .write_all(b"GET /progress HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .ok()?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).ok()?;
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+    for token in TOKENS {
+        assert!(!response.contains(token), "{}", response);
+    }
+    let (_, body) = response.split_once("\r\n\r\n")?;
+    Some(serde_json::from_str(body).unwrap())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn progress_can_be_polled_during_a_run() {
+    let old = instance(json!([{"Name": "alice", "Id": "old-a"}]), Duration::ZERO).await;
+    let new = instance(
+        json!([{"Name": "alice", "Id": "new-a"}]),
+        Duration::from_millis(1500),
+    )
+    .await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let input = dir.path().join("input.tsv");
+    let lines: String = (0..RECORDS)
+        .map(|i| {
+            format!(
+                "2024-01-01 10:00:00\told-a\ti{}\tMovie\tA\tDirectPlay\tWeb\tTV\t100\n",
+                i
+            )
+        })
+        .collect();
+    fs::write(&input, lines).unwrap();
+    let config = dir.path().join("config.toml");
+    fs::write(
+        &config,
+        format!(
+            "input_tsv_file_path = {:?}\noutput_tsv_file_path = {:?}\n\
+             [instance_old]\nbase_url = {:?}\napi_token = {:?}\n\
+             [instance_new]\nbase_url = {:?}\napi_token = {:?}\n",
+            input.display().to_string(),
+            dir.path().join("output.tsv").display().to_string(),
+            old.uri(),
+            TOKENS[0],
+            new.uri(),
+            TOKENS[1]
+        ),
+    )
+    .unwrap();
+
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_jellyfin_pr_migration"))
+        .arg("--no-user-config")
+        .arg("-c")
+        .arg(&config)
+        .arg("--progress-listen")
+        .arg(format!("127.0.0.1:{}", port))
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    let mut snapshots = Vec::new();
+    while child.try_wait().unwrap().is_none() {
+        if let Some(snapshot) = get_progress(port) {
+            snapshots.push(snapshot);
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    assert!(child.wait().unwrap().success());
+    // Shut down with the run
+    assert!(TcpStream::connect(("127.0.0.1", port)).is_err());
+
+    assert!(snapshots.iter().any(|s| s["phase"] == "fetching_users"));
+    let midway = snapshots
+        .iter()
+        .find(|s| s["phase"] == "processing" && s["records_processed"].as_u64() > Some(0))
+        .expect("a snapshot taken while processing");
+    assert_eq!(midway["records_total"], RECORDS as u64);
+    assert!(midway["records_processed"].as_u64().unwrap() < RECORDS as u64);
+    assert!(midway["counters"]["records_changed"].is_u64());
+    assert!(midway["elapsed_secs"].as_f64().unwrap() > 1.0);
+    let processed: Vec<u64> = snapshots
+        .iter()
+        .filter_map(|s| s["records_processed"].as_u64())
+        .collect();
+    assert!(processed.windows(2).all(|w| w[0] <= w[1]));
+}
@@ -0,0 +1,128 @@
+// Runs the binary twice on the same input, config and seed and checks that the report, the
+// summary line, the output TSV and the manifest are byte for byte the same
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::Path;
+use std::process::Command;
+
+fn write_recording(dir: &Path, url: &str, body: &str) {
+    let file_name: String = format!("GET_{}", url)
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    fs::write(
+        dir.join(format!("{}.json", file_name)),
+        format!(
+            "{{\"method\": \"GET\", \"url\": \"{}\", \"status\": 200, \"body\": {}}}",
+            url, body
+        ),
+    )
+    .unwrap();
+}
+
+fn digest(bytes: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    bytes.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[test]
+fn repeated_runs_produce_identical_output() {
+    let dir = tempfile::tempdir().unwrap();
+    let recording = dir.path().join("recording");
+    fs::create_dir(&recording).unwrap();
+    write_recording(
+        &recording,
+        "http://old.invalid/Users?startIndex=0&limit=500",
+        r#"[{"Name": "alice", "Id": "old-a"}, {"Name": "bob", "Id": "old-b"}, {"Name": "carol", "Id": "old-c"}]"#,
+    );
+    write_recording(
+        &recording,
+        "http://new.invalid/Users?startIndex=0&limit=500",
+        r#"[{"Name": "alice", "Id": "new-a"}, {"Name": "bob", "Id": "new-b"}]"#,
+    );
+
+    let input = dir.path().join("input.tsv");
+    let devices = ["TV", "Laptop", "Phone", "Tablet"];
+    let lines: String = (0..400)
+        .map(|i| {
+            format!(
+                "2024-01-{:02} 10:00:00\told-{}\ti{}\t{}\tA\tDirectPlay\tWeb\t{}\t{}\n",
+                i % 28 + 1,
+                ["a", "b", "c"][i % 3],
+                i % 17,
+                ["Movie", "Episode"][i % 2],
+                devices[i % 4],
+                60 + i * 13 % 3000
+            )
+        })
+        .collect();
+    fs::write(&input, lines).unwrap();
+    let output = dir.path().join("output.tsv");
+    let manifest = dir.path().join("manifest.json");
+    let config = dir.path().join("config.toml");
+    fs::write(
+        &config,
+        format!(
+            "input_tsv_file_path = {:?}\noutput_tsv_file_path = {:?}\noutput_manifest_path = {:?}\n\
+             [device_name_map]\nTV = \"Living Room\"\nLaptop = \"Work Laptop\"\nPhone = \"Pixel\"\n\
+             [retention_overrides]\nalice = \"unlimited\"\nbob = \"unlimited\"\n\
+             [instance_old]\nbase_url = \"http://old.invalid\"\napi_token = \"x\"\n\
+             [instance_new]\nbase_url = \"http://new.invalid\"\napi_token = \"y\"\n",
+            input.display().to_string(),
+            output.display().to_string(),
+            manifest.display().to_string()
+        ),
+    )
+    .unwrap();
+
+    let run = || {
+        let result = Command::new(env!("CARGO_BIN_EXE_jellyfin_pr_migration"))
+            .arg("--no-user-config")
+            .arg("-c")
+            .arg(&config)
+            .arg("--replay-api")
+            .arg(&recording)
+            .arg("--summary-line")
+            .arg("--stable-timestamps")
+            .arg("--downsample")
+            .arg("0.5")
+            .arg("--downsample-seed")
+            .arg("7")
+            .output()
+            .unwrap();
+        assert!(result.status.success(), "{:?}", result);
+        [
+            ("report", result.stdout),
+            ("summary line", result.stderr),
+            ("output TSV", fs::read(&output).unwrap()),
+            ("manifest", fs::read(&manifest).unwrap()),
+        ]
+    };
+    let first = run();
+    let second = run();
+    for ((name, a), (_, b)) in first.iter().zip(second.iter()) {
+        assert!(!a.is_empty(), "{} is empty", name);
+        assert_eq!(
+            digest(a),
+            digest(b),
+            "{} differs between runs:\n{}\n---\n{}",
+            name,
+            String::from_utf8_lossy(a),
+            String::from_utf8_lossy(b)
+        );
+    }
+    let report = String::from_utf8_lossy(&first[0].1);
+    assert!(
+        report.contains("Finished: 2000-01-01T00:00:00Z"),
+        "{}",
+        report
+    );
+}
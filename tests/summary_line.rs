@@ -0,0 +1,100 @@
+// Runs the binary against recorded API responses and checks the `--summary-line` output
+use std::fs;
+use std::process::Command;
+
+const PREFIX: &str = "JPM_SUMMARY: ";
+
+fn write_recording(dir: &std::path::Path, url: &str, body: &str) {
+    let file_name: String = format!("GET_{}", url)
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    fs::write(
+        dir.join(format!("{}.json", file_name)),
+        format!(
+            "{{\"method\": \"GET\", \"url\": \"{}\", \"status\": 200, \"body\": {}}}",
+            url, body
+        ),
+    )
+    .unwrap();
+}
+
+#[test]
+fn summary_line_is_printed_on_stderr_as_json() {
+    let dir = tempfile::tempdir().unwrap();
+    let recording = dir.path().join("recording");
+    fs::create_dir(&recording).unwrap();
+    write_recording(
+        &recording,
+        "http://old.invalid/Users?startIndex=0&limit=500",
+        r#"[{"Name": "alice", "Id": "old-a"}, {"Name": "bob", "Id": "old-b"}]"#,
+    );
+    write_recording(
+        &recording,
+        "http://new.invalid/Users?startIndex=0&limit=500",
+        r#"[{"Name": "alice", "Id": "new-a"}]"#,
+    );
+
+    let input = dir.path().join("input.tsv");
+    fs::write(
+        &input,
+        "2024-01-01 10:00:00\told-a\ti1\tMovie\tA\tDirectPlay\tWeb\tTV\t100\n\
+         2024-01-01 11:00:00\told-a\ti1\tMovie\tA\tDirectPlay\tWeb\tTV\t100\n\
+         2024-01-01 12:00:00\told-b\ti1\tMovie\tA\tDirectPlay\tWeb\tTV\t100\n",
+    )
+    .unwrap();
+    let output = dir.path().join("output.tsv");
+    let config = dir.path().join("config.toml");
+    fs::write(
+        &config,
+        format!(
+            "input_tsv_file_path = {:?}\noutput_tsv_file_path = {:?}\n\
+             [instance_old]\nbase_url = \"http://old.invalid\"\napi_token = \"x\"\n\
+             [instance_new]\nbase_url = \"http://new.invalid\"\napi_token = \"y\"\n",
+            input.display().to_string(),
+            output.display().to_string()
+        ),
+    )
+    .unwrap();
+
+    let result = Command::new(env!("CARGO_BIN_EXE_jellyfin_pr_migration"))
+        .arg("--no-user-config")
+        .arg("-c")
+        .arg(&config)
+        .arg("--replay-api")
+        .arg(&recording)
+        .arg("--summary-line")
+        .output()
+        .unwrap();
+    assert!(result.status.success(), "{:?}", result);
+
+    let stderr = String::from_utf8(result.stderr).unwrap();
+    let lines: Vec<&str> = stderr.lines().filter(|l| l.starts_with(PREFIX)).collect();
+    assert_eq!(lines.len(), 1, "stderr: {}", stderr);
+    let summary: serde_json::Value = serde_json::from_str(&lines[0][PREFIX.len()..]).unwrap();
+    assert_eq!(summary["version"], 1);
+    assert_eq!(summary["mode"], "normal");
+    assert_eq!(summary["records_processed"], 3);
+    assert_eq!(summary["records_changed"], 2);
+    assert_eq!(summary["records_unchanged"], 1);
+    assert_eq!(summary["changes_per_user"]["old-a"]["new_id"], "new-a");
+    assert_eq!(summary["changes_per_user"]["old-a"]["count"], 2);
+
+    // Off by default
+    let result = Command::new(env!("CARGO_BIN_EXE_jellyfin_pr_migration"))
+        .arg("--no-user-config")
+        .arg("-c")
+        .arg(&config)
+        .arg("--replay-api")
+        .arg(&recording)
+        .output()
+        .unwrap();
+    assert!(result.status.success());
+    assert!(!String::from_utf8(result.stderr).unwrap().contains(PREFIX));
+}
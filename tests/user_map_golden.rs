@@ -0,0 +1,181 @@
+// Golden tests for the user ID map printout and the processing summary, so any change to their
+// wording shows up in review. The users in tests/fixtures/user_map are matched by name, by name in
+// another case, by email and by similar name, plus the not found and ambiguous cases. Regenerate
+// the expected files with UPDATE_GOLDEN=1 cargo test --test user_map_golden.
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+fn write_recording(dir: &Path, url: &str, body: &str) {
+    let file_name: String = format!("GET_{}", url)
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    fs::write(
+        dir.join(format!("{}.json", file_name)),
+        format!(
+            "{{\"method\": \"GET\", \"url\": \"{}\", \"status\": 200, \"body\": {}}}",
+            url, body
+        ),
+    )
+    .unwrap();
+}
+
+fn check_golden(name: &str, actual: &str) {
+    let path = format!(
+        "{}/tests/golden/user_map/{}",
+        env!("CARGO_MANIFEST_DIR"),
+        name
+    );
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        fs::write(&path, actual).unwrap();
+    }
+    assert_eq!(actual, fs::read_to_string(&path).unwrap(), "{}", path);
+}
+
+// The lines of `output` from the first one starting with `title` up to the next blank line
+fn section(output: &str, title: &str) -> String {
+    output
+        .lines()
+        .skip_while(|line| !line.starts_with(title))
+        .take_while(|line| !line.is_empty())
+        .map(|line| format!("{}\n", line))
+        .collect()
+}
+
+fn run(lang: &str) -> (String, String) {
+    let dir = tempfile::tempdir().unwrap();
+    let recording = dir.path().join("recording");
+    fs::create_dir(&recording).unwrap();
+    let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/user_map");
+    for (url, file) in [
+        (
+            "http://old.invalid/Users?startIndex=0&limit=500",
+            "old_users.json",
+        ),
+        (
+            "http://new.invalid/Users?startIndex=0&limit=500",
+            "new_users.json",
+        ),
+    ] {
+        write_recording(
+            &recording,
+            url,
+            &fs::read_to_string(fixtures.join(file)).unwrap(),
+        );
+    }
+    let input = dir.path().join("input.tsv");
+    fs::write(
+        &input,
+        "2024-01-01 10:00:00\told-a\ti1\tMovie\tA\tDirectPlay\tWeb\tTV\t60\n\
+         2024-01-02 10:00:00\told-b\ti2\tMovie\tA\tDirectPlay\tWeb\tTV\t60\n\
+         2024-01-03 10:00:00\told-b\ti3\tEpisode\tA\tDirectPlay\tWeb\tTV\t60\n\
+         2024-01-04 10:00:00\told-c\ti4\tMovie\tA\tDirectPlay\tWeb\tTV\t60\n\
+         2024-01-05 10:00:00\told-d\ti5\tMovie\tA\tDirectPlay\tWeb\tTV\t60\n",
+    )
+    .unwrap();
+    let output = dir.path().join("output.tsv");
+    let config = dir.path().join("config.toml");
+    fs::write(
+        &config,
+        format!(
+            "input_tsv_file_path = {:?}\noutput_tsv_file_path = {:?}\n\
+             user_match_mode = \"case_insensitive\"\nfuzzy_match_threshold = 0.9\n\
+             [instance_old]\nbase_url = \"http://old.invalid\"\napi_token = \"x\"\n\
+             [instance_new]\nbase_url = \"http://new.invalid\"\napi_token = \"y\"\n",
+            input.display().to_string(),
+            output.display().to_string()
+        ),
+    )
+    .unwrap();
+    let result = Command::new(env!("CARGO_BIN_EXE_jellyfin_pr_migration"))
+        .arg("--no-user-config")
+        .arg("-c")
+        .arg(&config)
+        .arg("--replay-api")
+        .arg(&recording)
+        .arg("--stable-timestamps")
+        .arg("--lang")
+        .arg(lang)
+        .env("TZ", "UTC")
+        .output()
+        .unwrap();
+    assert!(result.status.success(), "{:?}", result);
+    // The output paths are in the temporary directory, different on every run
+    let dir = dir.path().display().to_string();
+    (
+        String::from_utf8(result.stdout)
+            .unwrap()
+            .replace(&dir, "<dir>"),
+        String::from_utf8(result.stderr)
+            .unwrap()
+            .replace(&dir, "<dir>"),
+    )
+}
+
+// The mapping (stdout), its warnings (stderr) and the summary, without the run's duration
+fn rendering(stdout: &str, stderr: &str, map_title: &str, summary_title: &str) -> String {
+    let warnings: String = stderr
+        .lines()
+        .filter(|line| line.starts_with("  W"))
+        .map(|line| format!("{}\n", line))
+        .collect();
+    let summary: String = section(stdout, summary_title)
+        .lines()
+        .filter(|line| !line.starts_with("  Duration:") && !line.starts_with("  Dauer:"))
+        .map(|line| format!("{}\n", line))
+        .collect();
+    format!(
+        "{}--- stderr ---\n{}--- summary ---\n{}",
+        section(stdout, map_title),
+        warnings,
+        summary
+    )
+}
+
+#[test]
+fn user_map_and_summary_in_english() {
+    let (stdout, stderr) = run("en");
+    check_golden(
+        "en.txt",
+        &rendering(
+            &stdout,
+            &stderr,
+            "Creating User ID Map:",
+            "TSV Processing Summary:",
+        ),
+    );
+}
+
+#[test]
+fn user_map_and_summary_in_german() {
+    let (stdout, stderr) = run("de");
+    check_golden(
+        "de.txt",
+        &rendering(
+            &stdout,
+            &stderr,
+            "Benutzer-ID-Zuordnung wird erstellt:",
+            "Zusammenfassung der TSV-Verarbeitung:",
+        ),
+    );
+}
+
+#[test]
+fn unknown_language_is_a_config_error() {
+    let result = Command::new(env!("CARGO_BIN_EXE_jellyfin_pr_migration"))
+        .arg("--no-user-config")
+        .arg("--lang")
+        .arg("xx")
+        .output()
+        .unwrap();
+    assert_eq!(result.status.code(), Some(10));
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert!(stderr.contains("Available: en, de"), "{}", stderr);
+}